@@ -1,7 +1,19 @@
 ---@param instance Instance
 ---@return boolean
 function is_q35(instance)
-  return (instance.chipset == "q35" or string.find(instance.chipset, "pc-q35") == 0)
+  return instance.machine_type == "q35"
+end
+
+---@param instance Instance
+---@return boolean
+function is_microvm(instance)
+  return instance.machine_type == "microvm"
+end
+
+---@param instance Instance
+---@return boolean
+function is_x86(instance)
+  return instance.arch == "x86_64"
 end
 
 ---@param instance Instance
@@ -22,8 +34,14 @@ function ensure_pci(instance, vm)
     end
 
     return vm, pci_bridge
+  elseif instance.machine_type == "i440fx" then
+    -- i440fx only has plain PCI, so devices can go on the root bus directly
+    return vm, "pci.0"
+  elseif instance.machine_type == "virt" then
+    -- PCI devices work as integrated endpoints on the PCIe root bus of virt
+    return vm, "pcie.0"
   else
-    error("No support for non-q35 instances")
+    error("No PCI bus on " .. instance.machine_type .. " instances")
   end
 end
 
@@ -43,22 +61,114 @@ function add_shared_memory(instance, vm, mem_path, size, id)
   return vm
 end
 
+---@param boot_index number|nil
+---@return string
+function bootindex(boot_index)
+  if boot_index == nil then
+    return ""
+  end
+
+  return ",bootindex=" .. tostring(boot_index)
+end
+
+--- Escape commas in a value of a QEMU option string
+---@param value string
+---@return string
+function qemu_escape(value)
+  return (string.gsub(value, ",", ",,"))
+end
+
+---@param disk Disk
+---@return string
+function share_rw(disk)
+  if not disk.shared then
+    return ""
+  end
+
+  -- other VM's write to it as well, QEMU would refuse to open it otherwise
+  return ",share-rw=on"
+end
+
+---@param vm VM
+---@param type number
+---@param fields table<string, string>
+---@return VM
+function add_smbios(vm, type, fields)
+  local keys = {}
+  for key, _ in pairs(fields) do
+    table.insert(keys, key)
+  end
+
+  if #keys == 0 then
+    return vm
+  end
+
+  -- sorted, so the same config always gives the same arguments
+  table.sort(keys)
+  local arg = "type=" .. type
+  for _, key in ipairs(keys) do
+    -- commas are escaped by doubling them
+    arg = arg .. "," .. key .. "=" .. string.gsub(fields[key], ",", ",,")
+  end
+
+  vm:arg("-smbios", arg)
+  return vm
+end
+
 vore:set_build_command(function(instance, vm)
-  vm:arg("-rtc", "driftfix=slew")
-  vm:arg("-no-hpet")
+  local rtc = "driftfix=slew"
+  if not is_x86(instance) then
+    -- driftfix only exists for the x86 RTC
+    rtc = "clock=host"
+  end
+
+  if instance.timezone ~= nil then
+    rtc = "base=" .. vore:rtc_base(instance.timezone) .. "," .. rtc
+  end
+
+  vm:arg("-rtc", rtc)
+
+  if is_x86(instance) then
+    vm:arg("-no-hpet")
+  end
   vm:arg("-boot", "strict=on")
 
   if instance.kvm then
     vm:arg("-enable-kvm")
   end
 
-  if instance.arch == "x86_64" or instance.arch == "i868" then
+  if is_x86(instance) and instance.kvm then
     vm:arg("-global", "kvm-pit.lost_tick_policy=discard")
   end
 
   --this disables the QEMU GUI
   vm:arg("-display", "none")
 
+  local video = instance.video
+  if video.model ~= "default" then
+    if vm:get_counter("disabled_display", 0) == 0 then
+      vm:arg("-vga", "none")
+    end
+
+    if video.model == "std" then
+      local def = "VGA"
+      if video.vram ~= nil then
+        def = def .. ",vgamem_mb=" .. tostring(video.vram)
+      end
+      vm:arg("-device", def)
+    elseif video.model == "qxl" then
+      local def = "qxl-vga,max_outputs=" .. tostring(video.heads)
+      if video.vram ~= nil then
+        def = def .. ",vgamem_mb=" .. tostring(video.vram)
+      end
+      vm:arg("-device", def)
+    elseif video.model == "virtio-gpu" then
+      vm:arg("-device", "virtio-gpu-pci,max_outputs=" .. tostring(video.heads))
+    elseif video.model == "virtio-vga" then
+      vm:arg("-device", "virtio-vga,max_outputs=" .. tostring(video.heads))
+    end
+  end
+
   vm:arg("-no-user-config")
   --vm:arg("-nodefaults")
   vm:arg("-no-shutdown")
@@ -77,12 +187,88 @@ vore:set_build_command(function(instance, vm)
     )
   )
 
+  local first_cpu = 0
+  for idx, node in ipairs(instance.numa) do
+    local id = idx - 1
+    local mem = "memory-backend-ram,id=numa-mem" .. id .. ",size=" .. node.memory .. "M"
+    if node.host_node ~= nil then
+      mem = mem .. ",host-nodes=" .. node.host_node .. ",policy=bind"
+    end
+
+    vm:arg("-object", mem)
+    vm:arg(
+      "-numa",
+      string.format("node,nodeid=%d,cpus=%d-%d,memdev=numa-mem%d", id, first_cpu, first_cpu + node.cpus - 1, id)
+    )
+    first_cpu = first_cpu + node.cpus
+  end
+
+  if instance.balloon then
+    -- give memory back to the host instead of letting the guest run out of it
+    vm:arg("-device", "virtio-balloon-pci,id=balloon0,deflate-on-oom=on")
+  end
+
+  if instance.smbios.uuid ~= nil then
+    vm:arg("-uuid", instance.smbios.uuid)
+  end
+
+  vm = add_smbios(vm, 0, instance.smbios.bios)
+  vm = add_smbios(vm, 1, instance.smbios.system)
+  vm = add_smbios(vm, 2, instance.smbios.baseboard)
+  vm = add_smbios(vm, 3, instance.smbios.chassis)
+
+  local net = instance.net
+  local netdev = "user,id=vore-net,ipv4=" .. (net.ipv4 and "on" or "off") .. ",ipv6=" .. (net.ipv6 and "on" or "off")
+  if net.ipv6_prefix ~= nil then
+    netdev = netdev .. ",ipv6-prefix=" .. net.ipv6_prefix
+  end
+
+  if net.ipv6_prefix_len ~= nil then
+    netdev = netdev .. ",ipv6-prefixlen=" .. tostring(net.ipv6_prefix_len)
+  end
+
+  if net.dns ~= nil then
+    netdev = netdev .. ",dns=" .. net.dns
+  end
+
+  if net.ipv6_dns ~= nil then
+    netdev = netdev .. ",ipv6-dns=" .. net.ipv6_dns
+  end
+
+  for _, domain in ipairs(net.dns_search) do
+    netdev = netdev .. ",dnssearch=" .. domain
+  end
+
+  if net.tftp ~= nil then
+    netdev = netdev .. ",tftp=" .. net.tftp
+  end
+
+  if net.bootfile ~= nil then
+    netdev = netdev .. ",bootfile=" .. net.bootfile
+  end
+
+  if net.tftp_server_name ~= nil then
+    netdev = netdev .. ",tftp-server-name=" .. net.tftp_server_name
+  end
+
+  vm:arg("-netdev", netdev)
+
+  local nic = net.model .. ",netdev=vore-net" .. bootindex(net.boot_index)
+  if net.romfile ~= nil then
+    nic = nic .. ",romfile=" .. net.romfile
+  end
+
+  vm:arg("-device", nic)
+
   if instance.uefi.enabled and is_q35(instance) then
     -- OVMF will hang if S3 is not disabled
     -- disable S4 too, since libvirt does that 🤷
     -- https://bugs.archlinux.org/task/59465#comment172528
     vm:arg("-global", "ICH9-LPC.disable_s3=1")
     vm:arg("-global", "ICH9-LPC.disable_s4=1")
+  elseif instance.uefi.enabled and instance.machine_type == "i440fx" then
+    vm:arg("-global", "PIIX4_PM.disable_s3=1")
+    vm:arg("-global", "PIIX4_PM.disable_s4=1")
   end
 
   for idx, disk in ipairs(instance.disks) do
@@ -90,9 +276,21 @@ vore:set_build_command(function(instance, vm)
   end
 
   if instance.uefi.enabled then
+    -- OVMF for x86_64 guests, [uefi.<arch>] (e.g. AAVMF) for the others
+    local firmware = global.uefi.default
+    local uefi_vars = "uefi/OVMF_VARS.fd"
+    if not is_x86(instance) then
+      firmware = global.uefi[instance.arch]
+      uefi_vars = "uefi/" .. instance.arch .. "_VARS.fd"
+    end
+
+    if instance.uefi.vars_path ~= "" then
+      uefi_vars = instance.uefi.vars_path
+    end
+
     vm:arg(
-      "-drive", "if=pflash,format=raw,unit=0,file=" .. global.uefi.default.boot_code .. ",readonly=on",
-      "-drive", "if=pflash,format=raw,unit=1,file=" .. vore:get_file("uefi/OVMF_VARS.fd", global.uefi.default.template)
+      "-drive", "if=pflash,format=raw,unit=0,file=" .. firmware.boot_code .. ",readonly=on",
+      "-drive", "if=pflash,format=raw,unit=1,file=" .. vore:get_file(uefi_vars, firmware.template)
     )
   end
 
@@ -106,6 +304,14 @@ vore:set_build_command(function(instance, vm)
       def = def .. ",multifunction=on"
     end
 
+    if vfio.romfile ~= nil then
+      def = def .. ",romfile=" .. vfio.romfile
+    end
+
+    if not vfio.rombar then
+      def = def .. ",rombar=0"
+    end
+
     if vfio.graphics and vm:get_counter("disabled_display", 0) == 0 then
       vm:arg("-vga", "none")
     end
@@ -113,20 +319,163 @@ vore:set_build_command(function(instance, vm)
     vm:arg("-device", def)
   end
 
+  for _, mdev in ipairs(instance.mdev) do
+    local def = "vfio-pci,sysfsdev=/sys/bus/mdev/devices/" .. mdev.uuid
+    if mdev.display then
+      def = def .. ",display=on"
+      -- vfio display exposes a generated EDID with this as the preferred mode
+      local preferred = instance.looking_glass.resolutions[1]
+      if instance.looking_glass.enabled and preferred ~= nil then
+        def = def .. string.format(",xres=%d,yres=%d", preferred.width, preferred.height)
+      end
+    end
+
+    vm:arg("-device", def)
+  end
+
+  if instance.input.grab_toggle == nil then
+    for _, device in ipairs(instance.input.devices) do
+      vm:arg("-device", "virtio-input-host-pci,evdev=" .. device)
+    end
+  elseif #instance.input.devices > 0 then
+    -- input-linux can hand the devices back to the host, it feeds the virtual keyboard and mouse instead
+    for idx, device in ipairs(instance.input.devices) do
+      vm:arg("-object", "input-linux,id=vore-input-" .. idx .. ",evdev=" .. device .. ",grab_all=on,repeat=on,grab-toggle=" .. instance.input.grab_toggle)
+    end
+
+    vm:arg("-device", "virtio-keyboard-pci", "-device", "virtio-mouse-pci")
+  end
+
+  for idx = 1, instance.pci_hotplug_slots do
+    vm:arg("-device", "pcie-root-port,id=vore-hotplug-" .. idx .. ",chassis=" .. vm:get_counter("chassis", 1) .. ",bus=pcie.0")
+  end
+
+  -- Always there, so USB devices can be attached while the VM runs
+  if not is_microvm(instance) then
+    vm:arg("-device", "qemu-xhci,id=vore-usb")
+  end
+
+  for idx, usb in ipairs(instance.usb) do
+    local def = "usb-host,bus=vore-usb.0,id=vore-usb-" .. idx
+    if usb.vendor ~= nil then
+      def = def .. string.format(",vendorid=0x%04x,productid=0x%04x", usb.vendor, usb.product)
+    else
+      def = def .. ",hostbus=" .. usb.bus .. ",hostport=" .. usb.port
+    end
+
+    vm:arg("-device", def)
+  end
+
   if instance.looking_glass.enabled then
     vm = add_shared_memory(instance, vm, instance.looking_glass.mem_path, instance.looking_glass.buffer_size, "lg")
+
+    -- every seat gets its own device, looking-glass-host in the guest picks which one it uses
+    for _, seat in ipairs(instance.seats) do
+      if seat.looking_glass then
+        vm = add_shared_memory(instance, vm, seat.looking_glass_mem_path, instance.looking_glass.buffer_size, "lg-" .. seat.name)
+      end
+    end
   end
 
-  if instance.scream.enabled then
+  if instance.scream.enabled and instance.scream.mode == "ivshmem" then
     vm = add_shared_memory(instance, vm, instance.scream.mem_path, instance.scream.buffer_size, "scream")
   end
 
+  if instance.tpm.enabled then
+    -- swtpm is started by vored, QEMU talks to it over its socket
+    vm:arg("-chardev", "socket,id=vore-tpm-chardev,path=" .. qemu_escape(instance.tpm.socket_path))
+    vm:arg("-tpmdev", "emulator,id=vore-tpm,chardev=vore-tpm-chardev")
+    if is_x86(instance) then
+      vm:arg("-device", "tpm-crb,tpmdev=vore-tpm")
+    else
+      vm:arg("-device", "tpm-tis-device,tpmdev=vore-tpm")
+    end
+  end
+
   if instance.spice.enabled then
-    vm:arg("-spice", "unix,addr=" .. instance.spice.socket_path .. ",disable-ticketing=on,seamless-migration=on")
+    local spice = instance.spice
+    local def
+    if spice.port == nil and spice.tls_port == nil then
+      def = "unix,addr=" .. qemu_escape(spice.socket_path)
+    else
+      def = "addr=" .. spice.listen
+      if spice.port ~= nil then
+        def = def .. ",port=" .. tostring(spice.port)
+      end
+
+      if spice.tls_port ~= nil then
+        def = def .. ",tls-port=" .. tostring(spice.tls_port)
+      end
+    end
+
+    for _, option in ipairs({ "x509_dir", "x509_cert_file", "x509_key_file", "x509_cacert_file" }) do
+      if spice[option] ~= nil then
+        def = def .. "," .. option:gsub("_", "-") .. "=" .. qemu_escape(spice[option])
+      end
+    end
+
+    for _, option in ipairs({ "image_compression", "jpeg_wan_compression", "zlib_glz_wan_compression", "streaming_video" }) do
+      if spice[option] ~= nil then
+        def = def .. "," .. option:gsub("_", "-") .. "=" .. spice[option]
+      end
+    end
+
+    if spice.password_path ~= nil then
+      -- QEMU reads the password from a file, so it isn't visible in its command line
+      vm:arg("-object", "secret,id=vore-spice-password,file=" .. qemu_escape(spice.password_path))
+      def = def .. ",password-secret=vore-spice-password"
+    else
+      def = def .. ",disable-ticketing=on"
+    end
+
+    vm:arg("-spice", def .. ",seamless-migration=on")
+
+    if spice.agent then
+      vm:arg("-device", "virtio-serial-pci,id=vore-spice-serial")
+      vm:arg("-chardev", "spicevmc,id=vore-vdagent,name=vdagent")
+      vm:arg("-device", "virtserialport,bus=vore-spice-serial.0,chardev=vore-vdagent,name=com.redhat.spice.0")
+    end
+  end
+
+  if instance.guest_actions.enabled then
+    -- The guest sees this as /dev/virtio-ports/me.eater.vore.actions
+    vm:arg("-device", "virtio-serial-pci,id=vore-serial")
+    vm:arg("-chardev", "socket,id=vore-actions,path=" .. instance.guest_actions.socket_path .. ",server=on,wait=off")
+    vm:arg("-device", "virtserialport,bus=vore-serial.0,chardev=vore-actions,name=me.eater.vore.actions")
+  end
+
+  if instance.clipboard.enabled then
+    -- vored talks to spice-vdagent in the guest itself, so no SPICE client is needed for the clipboard
+    vm:arg("-device", "virtio-serial-pci,id=vore-clipboard-serial")
+    vm:arg("-chardev", "socket,id=vore-clipboard,path=" .. instance.clipboard.socket_path .. ",server=on,wait=off")
+    vm:arg("-device", "virtserialport,bus=vore-clipboard-serial.0,chardev=vore-clipboard,name=com.redhat.spice.0")
+  end
+
+  if instance.guest_agent.enabled then
+    -- vored uses qemu-ga in the guest to set its clock and timezone
+    vm:arg("-device", "virtio-serial-pci,id=vore-agent-serial")
+    vm:arg("-chardev", "socket,id=vore-agent,path=" .. instance.guest_agent.socket_path .. ",server=on,wait=off")
+    vm:arg("-device", "virtserialport,bus=vore-agent-serial.0,chardev=vore-agent,name=org.qemu.guest_agent.0")
   end
 
   if instance.jack.enabled then
+    local jack = instance.jack
+    local client_name = qemu_escape(jack.client_name or ("vore-" .. instance.name))
+    local def = "jack,id=jack0,in.client-name=" .. client_name .. ",out.client-name=" .. client_name
+    if jack.server_name ~= nil then
+      def = def .. ",in.server-name=" .. qemu_escape(jack.server_name) .. ",out.server-name=" .. qemu_escape(jack.server_name)
+    end
+
+    if jack.connect_ports ~= nil then
+      def = def .. ",out.connect-ports=" .. qemu_escape(jack.connect_ports)
+    end
+
+    if jack.input_connect_ports ~= nil then
+      def = def .. ",in.connect-ports=" .. qemu_escape(jack.input_connect_ports)
+    end
 
+    vm:arg("-device", "intel-hda", "-device", "hda-duplex,audiodev=jack0")
+    vm:arg("-audiodev", def)
   end
 
   if instance.pulse.enabled then
@@ -134,16 +483,74 @@ vore:set_build_command(function(instance, vm)
     vm:arg("-audiodev", "pa,server=/run/user/1000/pulse/native,id=pa0")
   end
 
-  vm:arg(
-    "-machine",
-    "q35,accel=kvm,usb=off,vmport=off,dump-guest-core=off,kernel_irqchip=on"
-  )
+  local machine = instance.chipset .. ",usb=off,dump-guest-core=off"
+  if instance.kvm then
+    machine = machine .. ",accel=kvm,kernel_irqchip=on"
+  else
+    machine = machine .. ",accel=tcg"
+  end
 
-  -- Pls update
-  vm:arg(
-    "-cpu",
-    "host,hv-time,hv-relaxed,hv-vapic,hv-spinlocks=0x1fff,hv-vendor-id=whatever,kvm=off,+topoext"
-  )
+  if instance.machine_type == "virt" then
+    if instance.arch == "aarch64" then
+      -- Whatever GIC the host (or TCG) has, KVM can't emulate another one
+      machine = machine .. ",gic-version=max"
+    end
+  elseif is_microvm(instance) then
+    -- Without a PCIe host bridge microvm only has virtio-mmio, which none of the devices below use
+    machine = machine .. ",pcie=on"
+  else
+    machine = machine .. ",vmport=off"
+  end
+  if instance.sev.enabled then
+    -- cbitpos and reduced_phys_bits are read from the CPU on prepare if they're not configured
+    local sev = instance.sev
+    local object = "sev-guest"
+    if sev.snp then
+      object = "sev-snp-guest"
+    end
+
+    vm:arg(
+      "-object",
+      object .. ",id=vore-sev,policy=" .. string.format("0x%x", sev.policy)
+        .. ",cbitpos=" .. sev.cbitpos .. ",reduced-phys-bits=" .. sev.reduced_phys_bits
+    )
+    machine = machine .. ",confidential-guest-support=vore-sev"
+  end
+
+  vm:arg("-machine", machine)
+
+  local cpu_model = cpu.model
+  if cpu_model == "host" and not instance.kvm then
+    -- host only exists with KVM, max is the closest TCG has
+    cpu_model = "max"
+  end
+
+  if not is_x86(instance) then
+    -- The Hyper-V enlightenments are x86 only
+    local cpu_flags = cpu_model
+    for _, flag in ipairs(cpu.flags) do
+      cpu_flags = cpu_flags .. "," .. flag
+    end
+
+    vm:arg("-cpu", cpu_flags)
+    return vm
+  end
+
+  local cpu_flags = cpu_model .. ",hv-time,hv-relaxed,hv-vapic,hv-spinlocks=0x1fff,+topoext"
+  if instance.stealth or instance.hide_kvm then
+    -- hide_kvm is on unless turned off, kvm=off hides the KVM signature, and the vendor id replaces
+    -- "Microsoft Hv", which is what Nvidia drivers (Code 43) and anti-cheats look for
+    cpu_flags = cpu_flags .. ",kvm=off,hv-vendor-id=" .. (cpu.vendor_id or "whatever")
+  elseif cpu.vendor_id ~= nil then
+    cpu_flags = cpu_flags .. ",hv-vendor-id=" .. cpu.vendor_id
+  end
+
+  -- user flags go last, so they can override the ones above
+  for _, flag in ipairs(cpu.flags) do
+    cpu_flags = cpu_flags .. "," .. flag
+  end
+
+  vm:arg("-cpu", cpu_flags)
 
   return vm
 end)
@@ -184,6 +591,8 @@ function virtio_scsi_disk_gen(type)
       hd = hd .. ",rotation_rate=1"
     end
 
+    hd = hd .. bootindex(disk.boot_index) .. share_rw(disk)
+
     vm:arg("-device", hd)
 
     return vm
@@ -195,11 +604,24 @@ end
 ---@param device_type string
 ---@return fun(vm: VM, instance: Instance, idx: number, disk: Disk): VM
 function ide_disk_gen(name, device_type)
-  return function(vm, _, _, disk)
+  return function(vm, instance, _, disk)
     local drive_id = name .. vm:get_counter(name, 1)
 
     vm:arg("-drive", "file=" .. disk.path .. ",driver=" .. disk.disk_type .. ",if=none,id=" .. drive_id)
-    vm:arg("-device", device_type .. ",drive=" .. drive_id .. ",bus=ide." .. vm:get_counter("ide", 0))
+    if instance.machine_type == "virt" then
+      -- virt has no IDE controller, the drive goes on virtio-scsi instead (ide-cd becomes scsi-cd)
+      local scsi_pci = vm:get_device_id("virtio-scsi-pci")
+      if scsi_pci == nil then
+        scsi_pci = "scsi-pci"
+        vm:arg("-device", "virtio-scsi-pci,id=" .. scsi_pci)
+      end
+
+      local scsi_type = string.gsub(device_type, "^ide%-", "scsi-")
+      vm:arg("-device", scsi_type .. ",drive=" .. drive_id .. ",bus=" .. scsi_pci .. ".0" .. bootindex(disk.boot_index) .. share_rw(disk))
+      return vm
+    end
+
+    vm:arg("-device", device_type .. ",drive=" .. drive_id .. ",bus=ide." .. vm:get_counter("ide", 0) .. bootindex(disk.boot_index) .. share_rw(disk))
 
     return vm
   end
@@ -216,7 +638,7 @@ vore:register_disk_preset("nvme", "PCIe based NVMe drive", function(vm, _, _, di
 
   -- see https://blog.christophersmart.com/2019/12/18/kvm-guests-with-emulated-ssd-and-nvme-drives/
   vm:arg("-drive", "file=" .. disk.path .. ",driver=" .. disk.disk_type .. ",if=none,id=NVME" .. nvme_id)
-  vm:arg("-device", "nvme,drive=NVME" .. nvme_id .. ",serial=nvme-" .. nvme_id)
+  vm:arg("-device", "nvme,drive=NVME" .. nvme_id .. ",serial=nvme-" .. nvme_id .. bootindex(disk.boot_index) .. share_rw(disk))
 
   return vm
 end)
\ No newline at end of file
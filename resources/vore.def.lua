@@ -49,6 +49,8 @@ end
 ---@field preset string
 ---@field disk_type string
 ---@field path string
+---@field shared boolean
+---@field boot_index number|nil
 
 
 ---@class Cpu
@@ -57,19 +59,69 @@ end
 ---@field dies number
 ---@field cores number
 ---@field threads number
+---@field model string
+---@field flags string[]
+---@field vendor_id string|nil
+---@field pin number[]|nil
+---@field isolate boolean
+
+---@class Sev
+---@field enabled boolean
+---@field snp boolean
+---@field policy number
+---@field cbitpos number
+---@field reduced_phys_bits number
 
 ---@class Uefi
 ---@field enabled boolean
+---@field vars_path string
+
+---@class Resolution
+---@field width number
+---@field height number
+---@field refresh number
 
 ---@class LookingGlass
 ---@field enabled boolean
 ---@field mem_path string
 ---@field buffer_size number
+---@field resolutions Resolution[]
+---@field edid_path string|nil
 
 ---@class Scream
 ---@field enabled boolean
+---@field mode string
 ---@field mem_path string
 ---@field buffer_size number
+---@field interface string|nil
+---@field port number
+
+---@class NumaNode
+---@field cpus number
+---@field memory number
+---@field host_node number|nil
+
+---@class Net
+---@field model string
+---@field ipv4 boolean
+---@field ipv6 boolean
+---@field ipv6_prefix string|nil
+---@field ipv6_prefix_len number|nil
+---@field dns string|nil
+---@field ipv6_dns string|nil
+---@field dns_search string[]
+---@field tftp string|nil
+---@field bootfile string|nil
+---@field tftp_server_name string|nil
+---@field romfile string|nil
+---@field boot_index number|nil
+
+---@class Smbios
+---@field uuid string|nil
+---@field bios table<string, string>
+---@field system table<string, string>
+---@field baseboard table<string, string>
+---@field chassis table<string, string>
 
 ---@class Vfio
 ---@field device number|nil
@@ -78,28 +130,123 @@ end
 ---@field address string
 ---@field graphics boolean
 ---@field multifunction boolean
+---@field reserve boolean
+---@field release boolean
+---@field romfile string|nil
+---@field rombar boolean
+---@field physical_function string|nil
+---@field sriov_vf number
+---@field sriov_vfs number
+
+---@class Mdev
+---@field parent string
+---@field mdev_type string
+---@field uuid string
+---@field display boolean
+
+---@class Usb
+---@field vendor number|nil
+---@field product number|nil
+---@field bus number|nil
+---@field port string|nil
 
 ---@class Spice
 ---@field enabled boolean
 ---@field socket_path string
+---@field listen string
+---@field port number|nil
+---@field tls_port number|nil
+---@field x509_dir string|nil
+---@field x509_cert_file string|nil
+---@field x509_key_file string|nil
+---@field x509_cacert_file string|nil
+---@field password_path string|nil
+---@field image_compression string|nil
+---@field jpeg_wan_compression string|nil
+---@field zlib_glz_wan_compression string|nil
+---@field streaming_video string|nil
+---@field agent boolean
+
+---@class Seat
+---@field name string
+---@field looking_glass boolean
+---@field looking_glass_mem_path string
+---@field spice boolean
+---@field spice_socket_path string
+
+---@class Video
+---@field model string
+---@field vram number|nil
+---@field heads number
 
 ---@class Pulse
 ---@field enabled boolean
 
+---@class Jack
+---@field enabled boolean
+---@field server_name string|nil
+---@field client_name string|nil
+---@field connect_ports string|nil
+---@field input_connect_ports string|nil
+---@field user string
+---@field user_uid number
+
+---@class GuestActions
+---@field enabled boolean
+---@field socket_path string
+
+---@class Clipboard
+---@field enabled boolean
+---@field socket_path string
+
+---@class GuestAgent
+---@field enabled boolean
+---@field socket_path string
+
+---@class Tpm
+---@field enabled boolean
+---@field state_path string
+---@field socket_path string
+
+---@class Input
+---@field devices string[]
+---@field grab_toggle string|nil
+
 ---@class Instance
 ---@field name string
 ---@field kvm boolean
+---@field stealth boolean
+---@field hide_kvm boolean
+---@field timezone string|nil
 ---@field arch string
 ---@field memory number
+---@field balloon boolean
 ---@field chipset string
+---@field machine_type "q35"|"i440fx"|"microvm"|"virt"
 ---@field disks Disk[]
+---@field boot_order string[]
 ---@field cpu Cpu
 ---@field uefi Uefi
+---@field sev Sev
 ---@field vfio Vfio[]
+---@field mdev Mdev[]
+---@field pci_hotplug_slots number
+---@field usb Usb[]
+---@field numa NumaNode[]
+---@field net Net
+---@field smbios Smbios
 ---@field looking_glass LookingGlass
 ---@field scream Scream
 ---@field spice Spice
+---@field video Video
+---@field seats Seat[]
 ---@field pulse Pulse
+---@field jack Jack
+---@field guest_actions GuestActions
+---@field clipboard Clipboard
+---@field guest_agent GuestAgent
+---@field tpm Tpm
+---@field input Input
 
 ----
 ---Add a disk definition to the argument list
@@ -129,4 +276,10 @@ end
 ---@param target string The target path within the local working directory
 ---@param source_file string the source or template file
 function vore:get_file(target, source_file)
+end
+
+---Get the value for -rtc base= for a timezone (implemented in Rust)
+---@param timezone string utc, localtime or an IANA timezone like Europe/Amsterdam
+---@return string
+function vore:rtc_base(timezone)
 end
\ No newline at end of file
@@ -0,0 +1,116 @@
+#![cfg(feature = "host")]
+
+// Checks whether a block device given to a VM as disk is still used by the host
+//
+// A device (or one of its partitions) that's mounted, used as swap, or held by device mapper,
+// md or bcache, would be written by the host and the guest at the same time
+
+use std::fs::{read_dir, read_to_string};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & !0xff)
+}
+
+/// Device number of [path] if it's a block device
+fn block_device_number(path: &Path) -> Option<u64> {
+    let meta = std::fs::metadata(path).ok()?;
+    if meta.file_type().is_block_device() {
+        Some(meta.rdev())
+    } else {
+        None
+    }
+}
+
+/// sysfs directory of the device, and of each of its partitions, with their device numbers
+fn device_and_partitions(dev: u64) -> Vec<(PathBuf, String)> {
+    let sys = match std::fs::canonicalize(format!("/sys/dev/block/{}:{}", major(dev), minor(dev))) {
+        Ok(sys) => sys,
+        Err(_) => return vec![],
+    };
+
+    let mut devices = vec![(sys.clone(), format!("{}:{}", major(dev), minor(dev)))];
+    if let Ok(entries) = read_dir(&sys) {
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            if !path.join("partition").exists() {
+                continue;
+            }
+
+            if let Ok(number) = read_to_string(path.join("dev")) {
+                devices.push((path, number.trim().to_string()));
+            }
+        }
+    }
+
+    devices
+}
+
+fn device_name(sys: &Path) -> String {
+    sys.file_name()
+        .map_or_else(String::new, |x| x.to_string_lossy().to_string())
+}
+
+/// Sources in [table] (/proc/mounts or /proc/swaps) that are one of [numbers], with the rest of their line
+fn matching_sources(table: &str, numbers: &[String], skip_header: bool) -> Vec<(String, String)> {
+    read_to_string(table)
+        .unwrap_or_default()
+        .lines()
+        .skip(if skip_header { 1 } else { 0 })
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let target = fields.next().unwrap_or_default();
+            let dev = block_device_number(Path::new(source))?;
+            if numbers.contains(&format!("{}:{}", major(dev), minor(dev))) {
+                Some((source.to_string(), target.to_string()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// What on the host uses the block device at [path] or one of its partitions, empty if nothing does
+pub fn block_device_users(path: &str) -> Vec<String> {
+    let dev = match block_device_number(Path::new(path)) {
+        Some(dev) => dev,
+        None => return vec![],
+    };
+
+    let devices = device_and_partitions(dev);
+    let numbers = devices.iter().map(|(_, x)| x.clone()).collect::<Vec<_>>();
+    let mut users = vec![];
+
+    for (source, target) in matching_sources("/proc/mounts", &numbers, false) {
+        users.push(format!("{} is mounted at {}", source, target));
+    }
+
+    for (source, _) in matching_sources("/proc/swaps", &numbers, true) {
+        users.push(format!("{} is used as swap", source));
+    }
+
+    for (sys, _) in &devices {
+        let holders = read_dir(sys.join("holders"))
+            .map(|x| {
+                x.filter_map(|x| x.ok())
+                    .map(|x| x.file_name().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if !holders.is_empty() {
+            users.push(format!(
+                "{} is held by {}",
+                device_name(sys),
+                holders.join(", ")
+            ));
+        }
+    }
+
+    users
+}
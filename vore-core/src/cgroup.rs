@@ -0,0 +1,233 @@
+#![cfg(feature = "host")]
+
+// Every VM gets its own cgroup (v2) at /sys/fs/cgroup/vore/<name>, which QEMU is started in,
+// so the configured resource limits apply to the whole QEMU process
+
+use crate::{format_cpu_list, ResourceLimits};
+use anyhow::Context;
+use std::ffi::CString;
+use std::fs::{create_dir_all, read_to_string, remove_dir, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const VORE_CGROUP: &str = "/sys/fs/cgroup/vore";
+
+/// Period used for cpu.max, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+#[derive(Debug)]
+pub struct VmCgroup {
+    path: PathBuf,
+}
+
+impl VmCgroup {
+    /// Create the cgroup for VM [name], apply [limits] to it, and confine it to [cpus] if given
+    ///
+    /// Returns None if cgroup v2 isn't available, and no limits are set
+    pub fn create(
+        name: &str,
+        limits: &ResourceLimits,
+        cpus: Option<&[usize]>,
+    ) -> Result<Option<VmCgroup>, anyhow::Error> {
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            if limits.is_empty() {
+                log::debug!(
+                    "cgroup v2 isn't mounted on {}, not creating a cgroup for {}",
+                    CGROUP_ROOT,
+                    name
+                );
+                return Ok(None);
+            }
+
+            anyhow::bail!(
+                "Resource limits are set for {}, but cgroup v2 isn't mounted on {}",
+                name,
+                CGROUP_ROOT
+            );
+        }
+
+        let mut controllers = vec![];
+        if limits.cpu_quota.is_some() {
+            controllers.push("cpu");
+        }
+
+        if limits.memory_max.is_some() {
+            controllers.push("memory");
+        }
+
+        if limits.io_weight.is_some() {
+            controllers.push("io");
+        }
+
+        create_dir_all(VORE_CGROUP).with_context(|| format!("Failed to create {}", VORE_CGROUP))?;
+        enable_controllers(Path::new(CGROUP_ROOT), &controllers)?;
+        enable_controllers(Path::new(VORE_CGROUP), &controllers)?;
+
+        let cgroup = VmCgroup {
+            path: Path::new(VORE_CGROUP).join(name),
+        };
+        create_dir_all(&cgroup.path)
+            .with_context(|| format!("Failed to create cgroup {:?}", cgroup.path))?;
+
+        // Limits that aren't set are reset, in case they were set for a previous run
+        cgroup.write_if_exists(
+            "cpu.max",
+            &match limits.cpu_quota {
+                Some(percent) => format!("{} {}", percent as u64 * CPU_PERIOD / 100, CPU_PERIOD),
+                None => format!("max {}", CPU_PERIOD),
+            },
+        )?;
+        cgroup.write_if_exists(
+            "memory.max",
+            &limits
+                .memory_max
+                .map_or("max".to_string(), |x| (x * 1024 * 1024).to_string()),
+        )?;
+        cgroup.write_if_exists(
+            "io.weight",
+            &format!("default {}", limits.io_weight.unwrap_or(100)),
+        )?;
+
+        // The cpuset only adds to the affinity set on the threads, so failing to use it isn't fatal
+        if let Some(cpus) = cpus {
+            let res = enable_controllers(Path::new(CGROUP_ROOT), &["cpuset"])
+                .and_then(|_| enable_controllers(Path::new(VORE_CGROUP), &["cpuset"]))
+                .and_then(|_| cgroup.write_if_exists("cpuset.cpus", &cpu_list_string(cpus)));
+            if let Err(err) = res {
+                log::warn!(
+                    "Failed to confine {} to its CPU's with a cpuset, only its threads will be pinned: {:?}",
+                    name,
+                    err
+                );
+            }
+        } else {
+            // Empty means all CPU's of the parent
+            cgroup.write_if_exists("cpuset.cpus", "\n")?;
+        }
+
+        Ok(Some(cgroup))
+    }
+
+    /// The cgroup of VM [name], if it exists
+    pub fn open(name: &str) -> Option<VmCgroup> {
+        let path = Path::new(VORE_CGROUP).join(name);
+        if path.is_dir() {
+            Some(VmCgroup { path })
+        } else {
+            None
+        }
+    }
+
+    fn write_if_exists(&self, file: &str, value: &str) -> Result<(), anyhow::Error> {
+        let path = self.path.join(file);
+        if !path.exists() {
+            return Ok(());
+        }
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut x| x.write_all(value.as_bytes()))
+            .with_context(|| format!("Failed to write {} to {:?}", value, path))
+    }
+
+    /// Confine the cgroup to [cpus], e.g. after the VM got pinned to other CPU's
+    pub fn set_cpus(&self, cpus: &[usize]) -> Result<(), anyhow::Error> {
+        self.write_if_exists("cpuset.cpus", &cpu_list_string(cpus))
+    }
+
+    /// Make [command] move itself into this cgroup before it executes
+    ///
+    /// Doing this in the child means everything QEMU allocates is accounted to the cgroup
+    pub fn apply(&self, command: &mut Command) -> Result<(), anyhow::Error> {
+        let procs = CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())?;
+        unsafe {
+            command.pre_exec(move || join_cgroup(&procs));
+        }
+
+        Ok(())
+    }
+
+    /// Remove the cgroup, only possible once QEMU is gone
+    pub fn remove(&self) -> Result<(), io::Error> {
+        remove_dir(&self.path)
+    }
+}
+
+/// Move the calling process into the cgroup, this runs between fork and exec so it sticks to plain syscalls
+fn join_cgroup(procs: &CString) -> Result<(), io::Error> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Writing 0 moves the writing process
+        let res = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+        if res < 0 {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Format CPU id's the way cpuset.cpus expects them, e.g. 0-3,8
+fn cpu_list_string(cpus: &[usize]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort_unstable();
+    cpus.dedup();
+    format_cpu_list(&cpus)
+}
+
+fn enable_controllers(path: &Path, controllers: &[&str]) -> Result<(), anyhow::Error> {
+    let available = read_to_string(path.join("cgroup.controllers"))?;
+    let enabled = read_to_string(path.join("cgroup.subtree_control"))?;
+    for controller in controllers {
+        if enabled.split_whitespace().any(|x| x == *controller) {
+            continue;
+        }
+
+        if !available.split_whitespace().any(|x| x == *controller) {
+            anyhow::bail!(
+                "cgroup controller {} isn't available in {:?}, can't apply resource limits",
+                controller,
+                path
+            );
+        }
+
+        OpenOptions::new()
+            .append(true)
+            .open(path.join("cgroup.subtree_control"))
+            .and_then(|mut x| x.write_all(format!("+{}", controller).as_bytes()))
+            .with_context(|| {
+                format!(
+                    "Failed to enable cgroup controller {} in {:?}",
+                    controller, path
+                )
+            })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cgroup::cpu_list_string;
+
+    #[test]
+    fn test_cpu_list_string() {
+        assert_eq!(cpu_list_string(&[]), "");
+        assert_eq!(cpu_list_string(&[5]), "5");
+        assert_eq!(cpu_list_string(&[0, 1, 2, 3, 8]), "0-3,8");
+        // Pinned CPU's come in the order of the vCPU's, with emulator threads possibly sharing one
+        assert_eq!(cpu_list_string(&[9, 3, 2, 8, 3, 12]), "2-3,8-9,12");
+    }
+}
@@ -6,6 +6,8 @@ pub struct Cpu {
     pub package: usize,
     pub die: usize,
     pub core: usize,
+    /// NUMA node, 0 on hosts without NUMA
+    pub node: usize,
     pub layer_0: Option<usize>,
     pub layer_1: Option<usize>,
     pub layer_2: Option<usize>,
@@ -47,6 +49,17 @@ impl CpuList {
         CPU_LIST.get_adjacent(amount)
     }
 
+    pub fn for_nodes(nodes: &[(usize, Option<usize>)]) -> Option<Vec<Cpu>> {
+        CPU_LIST.get_for_nodes(nodes)
+    }
+
+    /// The CPU's with [ids], in the order of [ids], None if the host lacks any of them
+    pub fn by_ids(ids: &[usize]) -> Option<Vec<Cpu>> {
+        ids.iter()
+            .map(|id| CPU_LIST.list.iter().find(|x| x.id == *id).copied())
+            .collect()
+    }
+
     pub fn len(&self) -> usize {
         self.list.len()
     }
@@ -62,6 +75,37 @@ impl CpuList {
             Some(&self.list[..amount])
         }
     }
+
+    /// Pick CPU's for every (amount, host node) in [nodes], nodes without host node get
+    /// whatever is left, every CPU is only used once
+    ///
+    /// The CPU's are returned in the order of [nodes]
+    pub fn get_for_nodes(&self, nodes: &[(usize, Option<usize>)]) -> Option<Vec<Cpu>> {
+        let mut used = vec![false; self.len()];
+        let mut picked: Vec<Vec<Cpu>> = vec![vec![]; nodes.len()];
+
+        // Bound nodes go first, so unbound nodes don't take the CPU's they need
+        let bound = nodes.iter().enumerate().filter(|(_, x)| x.1.is_some());
+        let unbound = nodes.iter().enumerate().filter(|(_, x)| x.1.is_none());
+        for (i, (amount, host_node)) in bound.chain(unbound) {
+            for (cpu, used) in self.list.iter().zip(used.iter_mut()) {
+                if picked[i].len() == *amount {
+                    break;
+                }
+
+                if !*used && host_node.map_or(true, |x| x == cpu.node) {
+                    *used = true;
+                    picked[i].push(*cpu);
+                }
+            }
+
+            if picked[i].len() < *amount {
+                return None;
+            }
+        }
+
+        Some(picked.into_iter().flatten().collect())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -75,8 +119,18 @@ impl CpuListOwned {}
 mod linux {
     use crate::cpu_list::Cpu;
     use std::fs::read_to_string;
+    use std::path::Path;
     use std::str::FromStr;
 
+    /// The NUMA node of a CPU is only visible as a node<N> link in its directory
+    fn read_node(cpu_dir: &Path) -> Option<usize> {
+        std::fs::read_dir(cpu_dir).ok()?.find_map(|entry| {
+            let name = entry.ok()?.file_name();
+            let node = name.to_str()?.strip_prefix("node")?;
+            usize::from_str(node).ok()
+        })
+    }
+
     pub fn get_cpus() -> Vec<Cpu> {
         let cpu = std::fs::read_dir("/sys/devices/system/cpu")
             .expect("Failed to read /sys/devices/system/cpu, no /sys mounted?");
@@ -99,6 +153,7 @@ mod linux {
                     package: read_id("topology/physical_package_id").unwrap(),
                     die: read_id("topology/die_id").unwrap(),
                     core: read_id("topology/core_id").unwrap(),
+                    node: read_node(&topology).unwrap_or(0),
                     layer_0: read_id("cache/index0/id"),
                     layer_1: read_id("cache/index1/id"),
                     layer_2: read_id("cache/index2/id"),
@@ -115,4 +170,61 @@ mod linux {
 
         cpus
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::cpu_list::{Cpu, CpuList};
+
+    /// A host with 2 NUMA nodes of [per_node] CPU's, ordered like get_cpus orders them
+    fn two_node_host(per_node: usize) -> CpuList {
+        let list = (0..per_node * 2)
+            .map(|id| Cpu {
+                id,
+                package: id / per_node,
+                die: 0,
+                core: id % per_node,
+                node: id / per_node,
+                layer_0: None,
+                layer_1: None,
+                layer_2: None,
+                layer_3: None,
+            })
+            .collect::<Vec<_>>();
+        CpuList {
+            list: Box::leak(list.into_boxed_slice()),
+        }
+    }
+
+    fn ids(cpus: Option<Vec<Cpu>>) -> Option<Vec<usize>> {
+        cpus.map(|x| x.into_iter().map(|x| x.id).collect())
+    }
+
+    #[test]
+    fn test_get_for_nodes() {
+        let host = two_node_host(4);
+        assert_eq!(
+            ids(host.get_for_nodes(&[(2, Some(1)), (2, Some(0))])),
+            Some(vec![4, 5, 0, 1])
+        );
+
+        // The bound node picks first, even though it comes last
+        assert_eq!(
+            ids(host.get_for_nodes(&[(4, None), (3, Some(0))])),
+            Some(vec![3, 4, 5, 6, 0, 1, 2])
+        );
+
+        // Every CPU is only handed out once
+        assert_eq!(
+            ids(host.get_for_nodes(&[(4, Some(0)), (4, None)])),
+            Some(vec![0, 1, 2, 3, 4, 5, 6, 7])
+        );
+    }
+
+    #[test]
+    fn test_get_for_nodes_not_enough() {
+        let host = two_node_host(4);
+        assert_eq!(ids(host.get_for_nodes(&[(5, Some(0))])), None);
+        assert_eq!(ids(host.get_for_nodes(&[(1, Some(2))])), None);
+        assert_eq!(ids(host.get_for_nodes(&[(4, Some(1)), (5, None)])), None);
+    }
+}
@@ -0,0 +1,123 @@
+// Creating empty disk images with qemu-img, for `vore disk create` and disks with create-if-missing
+
+use anyhow::Context;
+use std::path::Path;
+use std::process::Command;
+
+/// Formats images can be created in
+pub const DISK_IMAGE_FORMATS: &[&str] = &["qcow2", "raw"];
+
+/// Preallocation modes qemu-img knows, metadata only for qcow2
+pub const PREALLOCATION_MODES: &[&str] = &["off", "metadata", "falloc", "full"];
+
+/// Preallocation modes that allocate the whole image up front, which can take minutes
+pub const SLOW_PREALLOCATION_MODES: &[&str] = &["falloc", "full"];
+
+/// Check [format] and [preallocation] can be used together
+pub fn check_disk_image_options(
+    format: &str,
+    preallocation: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    if !DISK_IMAGE_FORMATS.contains(&format) {
+        anyhow::bail!(
+            "Can't create {} images, only {}",
+            format,
+            DISK_IMAGE_FORMATS.join(" or ")
+        );
+    }
+
+    match preallocation {
+        Some(mode) if !PREALLOCATION_MODES.contains(&mode) => anyhow::bail!(
+            "Unknown preallocation mode {}, should be one of {}",
+            mode,
+            PREALLOCATION_MODES.join(", ")
+        ),
+        Some("metadata") if format != "qcow2" => {
+            anyhow::bail!("Only qcow2 images can have their metadata preallocated")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn qemu_img_create_args(
+    path: &str,
+    format: &str,
+    size: u64,
+    preallocation: Option<&str>,
+) -> Result<Vec<String>, anyhow::Error> {
+    check_disk_image_options(format, preallocation)?;
+    if size == 0 {
+        anyhow::bail!("Disk image {} needs a size", path);
+    }
+
+    let mut args = vec!["create".to_string(), "-f".to_string(), format.to_string()];
+    if let Some(mode) = preallocation {
+        args.push("-o".to_string());
+        args.push(format!("preallocation={}", mode));
+    }
+
+    args.push(path.to_string());
+    args.push(format!("{}M", size));
+    Ok(args)
+}
+
+/// Create an empty [format] image of [size] MiB at [path], which may not exist yet
+pub fn create_disk_image(
+    path: &str,
+    format: &str,
+    size: u64,
+    preallocation: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let args = qemu_img_create_args(path, format, size, preallocation)?;
+    if Path::new(path).exists() {
+        anyhow::bail!("{} already exists, not overwriting it", path);
+    }
+
+    if let Some(parent) = Path::new(path)
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {:?}", parent))?;
+    }
+
+    let output = Command::new("qemu-img")
+        .args(&args)
+        .output()
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        // Don't leave a half written image behind, full preallocation can fail halfway
+        let _ = std::fs::remove_file(path);
+        anyhow::bail!(
+            "qemu-img failed to create {}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk_image::qemu_img_create_args;
+
+    #[test]
+    fn test_create_args() {
+        assert_eq!(
+            qemu_img_create_args("/vm/win10.qcow2", "qcow2", 200 * 1024, Some("metadata")).unwrap(),
+            vec![
+                "create",
+                "-f",
+                "qcow2",
+                "-o",
+                "preallocation=metadata",
+                "/vm/win10.qcow2",
+                "204800M"
+            ]
+        );
+        assert!(qemu_img_create_args("/vm/data.img", "raw", 1024, Some("metadata")).is_err());
+        assert!(qemu_img_create_args("/vm/data.vdi", "vdi", 1024, None).is_err());
+        assert!(qemu_img_create_args("/vm/data.img", "raw", 0, None).is_err());
+    }
+}
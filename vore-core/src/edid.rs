@@ -0,0 +1,311 @@
+// EDID generator for looking-glass.resolutions
+//
+// Builds an EDID 1.4 base block with a detailed timing descriptor per resolution, the first one
+// being the preferred mode, more than 3 resolutions spill over in a CTA-861 extension block.
+// Timings use CVT reduced blanking, which every GPU driver accepts for digital outputs
+//
+// see VESA E-EDID 1.4 and VESA CVT 1.2
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+const BLOCK_SIZE: usize = 128;
+const DESCRIPTOR_SIZE: usize = 18;
+/// Detailed timings that fit in a CTA-861 extension block without data blocks
+const EXTENSION_TIMINGS: usize = (BLOCK_SIZE - 5) / DESCRIPTOR_SIZE;
+const MAX_RESOLUTIONS: usize = 3 + EXTENSION_TIMINGS;
+
+// CVT reduced blanking constants
+const RB_H_BLANK: u64 = 160;
+const RB_H_SYNC: u64 = 32;
+const RB_H_FRONT_PORCH: u64 = 48;
+const RB_V_FRONT_PORCH: u64 = 3;
+const RB_MIN_V_BACK_PORCH: u64 = 6;
+const RB_MIN_V_BLANK_US: u64 = 460;
+
+/// Physical size reported to the guest, in mm, some drivers use it to pick a DPI
+const IMAGE_WIDTH_MM: u64 = 600;
+const IMAGE_HEIGHT_MM: u64 = 340;
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Resolution {
+    pub width: u64,
+    pub height: u64,
+    pub refresh: u64,
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}@{}", self.width, self.height, self.refresh)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    /// Parses WIDTHxHEIGHT@REFRESH, the refresh rate defaults to 60
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '@');
+        let size = parts.next().unwrap_or_default();
+        let refresh = parts
+            .next()
+            .map_or(Ok(60), |x| u64::from_str(x.trim_end_matches("Hz")))
+            .with_context(|| format!("Invalid refresh rate in resolution {}", s))?;
+
+        let mut size = size.splitn(2, 'x');
+        let (width, height) = match (size.next(), size.next()) {
+            (Some(width), Some(height)) => (width, height),
+            _ => anyhow::bail!("Resolution {} should look like 2560x1440@120", s),
+        };
+        let resolution = Resolution {
+            width: u64::from_str(width)
+                .with_context(|| format!("Invalid width in resolution {}", s))?,
+            height: u64::from_str(height)
+                .with_context(|| format!("Invalid height in resolution {}", s))?,
+            refresh,
+        };
+
+        if resolution.width == 0 || resolution.height == 0 || resolution.refresh == 0 {
+            anyhow::bail!("Resolution {} can't have a zero in it", s);
+        }
+
+        Ok(resolution)
+    }
+}
+
+impl Resolution {
+    pub fn pixels(&self) -> u64 {
+        self.width * self.height
+    }
+
+    fn vsync_width(&self) -> u64 {
+        let (w, h) = (self.width, self.height);
+        kiam::when! {
+            w * 3 == h * 4 => 4,
+            w * 9 == h * 16 => 5,
+            w * 10 == h * 16 => 6,
+            w * 4 == h * 5 || w * 9 == h * 15 => 7,
+            _ => 10,
+        }
+    }
+
+    /// Detailed timing descriptor of this resolution with CVT reduced blanking
+    fn detailed_timing(&self) -> Result<[u8; DESCRIPTOR_SIZE], anyhow::Error> {
+        let h_active = self.width;
+        let v_active = self.height;
+        let v_sync = self.vsync_width();
+
+        // Estimated line period in ns, from which the lines needed for the minimal blanking time follow
+        let frame_ns = 1_000_000_000 / self.refresh;
+        let line_ns = frame_ns
+            .checked_sub(RB_MIN_V_BLANK_US * 1000)
+            .map(|x| x / v_active)
+            .filter(|x| *x > 0)
+            .with_context(|| format!("Refresh rate of {} is too high", self))?;
+        let v_blank = ((RB_MIN_V_BLANK_US * 1000) / line_ns + 1)
+            .max(RB_V_FRONT_PORCH + v_sync + RB_MIN_V_BACK_PORCH);
+        let h_blank = RB_H_BLANK;
+
+        // In units of 10 kHz
+        let clock = (h_active + h_blank) * (v_active + v_blank) * self.refresh / 10_000;
+        if clock > u16::MAX as u64 {
+            anyhow::bail!(
+                "{} needs a pixel clock of {} MHz, an EDID can describe up to 655 MHz",
+                self,
+                clock / 100
+            );
+        }
+
+        if h_active > 0xFFF || v_active > 0xFFF {
+            anyhow::bail!("{} is too large for an EDID", self);
+        }
+
+        let mut dtd = [0u8; DESCRIPTOR_SIZE];
+        dtd[0..2].copy_from_slice(&(clock as u16).to_le_bytes());
+        dtd[2] = h_active as u8;
+        dtd[3] = h_blank as u8;
+        dtd[4] = (((h_active >> 8) << 4) | (h_blank >> 8)) as u8;
+        dtd[5] = v_active as u8;
+        dtd[6] = v_blank as u8;
+        dtd[7] = (((v_active >> 8) << 4) | (v_blank >> 8)) as u8;
+        dtd[8] = RB_H_FRONT_PORCH as u8;
+        dtd[9] = RB_H_SYNC as u8;
+        dtd[10] = ((RB_V_FRONT_PORCH << 4) | v_sync) as u8;
+        dtd[11] = 0;
+        dtd[12] = IMAGE_WIDTH_MM as u8;
+        dtd[13] = IMAGE_HEIGHT_MM as u8;
+        dtd[14] = (((IMAGE_WIDTH_MM >> 8) << 4) | (IMAGE_HEIGHT_MM >> 8)) as u8;
+        // Digital separate sync, hsync positive, vsync negative, as CVT reduced blanking wants
+        dtd[17] = 0x1A;
+
+        Ok(dtd)
+    }
+}
+
+fn checksum(block: &mut [u8]) {
+    let sum = block[..BLOCK_SIZE - 1]
+        .iter()
+        .fold(0u8, |sum, x| sum.wrapping_add(*x));
+    block[BLOCK_SIZE - 1] = 0u8.wrapping_sub(sum);
+}
+
+fn monitor_name(name: &str) -> [u8; DESCRIPTOR_SIZE] {
+    let mut descriptor = [0u8; DESCRIPTOR_SIZE];
+    descriptor[3] = 0xFC;
+    let text = &mut descriptor[5..];
+    text.iter_mut().for_each(|x| *x = b' ');
+    let name = name.as_bytes();
+    let len = name.len().min(text.len() - 1);
+    text[..len].copy_from_slice(&name[..len]);
+    text[len] = b'\n';
+    descriptor
+}
+
+/// Dummy descriptor for unused descriptor slots
+fn dummy_descriptor() -> [u8; DESCRIPTOR_SIZE] {
+    let mut descriptor = [0u8; DESCRIPTOR_SIZE];
+    descriptor[3] = 0x10;
+    descriptor
+}
+
+/// EDID exposing exactly [resolutions], the first one being the preferred mode
+pub fn build_edid(resolutions: &[Resolution], bit_depth: u64) -> Result<Vec<u8>, anyhow::Error> {
+    if resolutions.is_empty() {
+        anyhow::bail!("An EDID needs at least one resolution");
+    }
+
+    if resolutions.len() > MAX_RESOLUTIONS {
+        anyhow::bail!("An EDID can hold at most {} resolutions", MAX_RESOLUTIONS);
+    }
+
+    let timings = resolutions
+        .iter()
+        .map(|x| x.detailed_timing())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut base = [0u8; BLOCK_SIZE];
+    base[0..8].copy_from_slice(&HEADER);
+    // Manufacturer "VOR", 3 letters of 5 bits
+    let manufacturer =
+        ((b'V' - b'@') as u16) << 10 | ((b'O' - b'@') as u16) << 5 | (b'R' - b'@') as u16;
+    base[8..10].copy_from_slice(&manufacturer.to_be_bytes());
+    base[10..12].copy_from_slice(&1u16.to_le_bytes());
+    // Model year 2021
+    base[16] = 0xFF;
+    base[17] = 31;
+    base[18] = 1;
+    base[19] = 4;
+    // Digital input over DisplayPort, with the bit depth LG transfers
+    base[20] = 0x80
+        | 0x05
+        | match bit_depth {
+            6 => 0x10,
+            10 => 0x30,
+            12 => 0x40,
+            _ => 0x20,
+        };
+    base[21] = (IMAGE_WIDTH_MM / 10) as u8;
+    base[22] = (IMAGE_HEIGHT_MM / 10) as u8;
+    // Gamma 2.2
+    base[23] = 120;
+    // sRGB, the preferred timing is the native mode
+    base[24] = 0x06;
+    // sRGB chromaticity
+    base[25..35].copy_from_slice(&[0xEE, 0x91, 0xA3, 0x54, 0x4C, 0x99, 0x26, 0x0F, 0x50, 0x54]);
+    // No established or standard timings, only the detailed ones
+    base[38..54].iter_mut().for_each(|x| *x = 0x01);
+
+    let (first, rest) = timings.split_at(timings.len().min(3));
+    let mut descriptors = first.to_vec();
+    while descriptors.len() < 3 {
+        descriptors.push(dummy_descriptor());
+    }
+    descriptors.push(monitor_name("vore"));
+    for (i, descriptor) in descriptors.iter().enumerate() {
+        let offset = 54 + i * DESCRIPTOR_SIZE;
+        base[offset..offset + DESCRIPTOR_SIZE].copy_from_slice(descriptor);
+    }
+
+    base[126] = if rest.is_empty() { 0 } else { 1 };
+    checksum(&mut base);
+
+    let mut edid = base.to_vec();
+    if !rest.is_empty() {
+        let mut extension = [0u8; BLOCK_SIZE];
+        extension[0] = 0x02;
+        extension[1] = 0x03;
+        // No data blocks, the detailed timings start right after the header
+        extension[2] = 4;
+        for (i, descriptor) in rest.iter().enumerate() {
+            let offset = 4 + i * DESCRIPTOR_SIZE;
+            extension[offset..offset + DESCRIPTOR_SIZE].copy_from_slice(descriptor);
+        }
+
+        checksum(&mut extension);
+        edid.extend_from_slice(&extension);
+    }
+
+    Ok(edid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolutions(list: &[&str]) -> Vec<Resolution> {
+        list.iter()
+            .map(|x| Resolution::from_str(x).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_resolution() {
+        let resolution = Resolution::from_str("2560x1440@120").unwrap();
+        assert_eq!(resolution.width, 2560);
+        assert_eq!(resolution.height, 1440);
+        assert_eq!(resolution.refresh, 120);
+        assert_eq!(Resolution::from_str("1920x1080").unwrap().refresh, 60);
+        assert!(Resolution::from_str("1920").is_err());
+        assert!(Resolution::from_str("0x1080@60").is_err());
+    }
+
+    #[test]
+    fn test_edid_checksums() {
+        let edid = build_edid(
+            &resolutions(&["2560x1440@120", "1920x1080@60", "1280x720", "3440x1440@100"]),
+            8,
+        )
+        .unwrap();
+        assert_eq!(edid.len(), 2 * BLOCK_SIZE);
+        assert_eq!(edid[..8], HEADER);
+        assert_eq!(edid[126], 1);
+        for block in edid.chunks(BLOCK_SIZE) {
+            assert_eq!(block.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)), 0);
+        }
+    }
+
+    #[test]
+    fn test_preferred_timing() {
+        let edid = build_edid(&resolutions(&["2560x1440@120"]), 10).unwrap();
+        assert_eq!(edid.len(), BLOCK_SIZE);
+        let dtd = &edid[54..72];
+        let h_active = dtd[2] as u64 | ((dtd[4] as u64 >> 4) << 8);
+        let v_active = dtd[5] as u64 | ((dtd[7] as u64 >> 4) << 8);
+        assert_eq!((h_active, v_active), (2560, 1440));
+
+        let h_total = h_active + (dtd[3] as u64 | ((dtd[4] as u64 & 0xF) << 8));
+        let v_total = v_active + (dtd[6] as u64 | ((dtd[7] as u64 & 0xF) << 8));
+        let clock = u16::from_le_bytes([dtd[0], dtd[1]]) as u64 * 10_000;
+        let refresh = clock as f64 / (h_total * v_total) as f64;
+        assert!((refresh - 120.0).abs() < 0.5, "refresh was {}", refresh);
+    }
+
+    #[test]
+    fn test_too_many_resolutions() {
+        let list = vec!["1920x1080@60"; MAX_RESOLUTIONS + 1];
+        assert!(build_edid(&resolutions(&list), 8).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::LegacyKeys;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -12,6 +13,18 @@ pub struct GlobalConfig {
     pub vore: GlobalVoreConfig,
     pub qemu: GlobalQemuConfig,
     pub uefi: HashMap<String, GlobalUefiConfig>,
+    #[serde(default)]
+    pub metrics: GlobalMetricsConfig,
+    #[serde(default)]
+    pub vfio: GlobalVfioConfig,
+    #[serde(default, rename(deserialize = "host-shutdown"))]
+    pub host_shutdown: GlobalHostShutdownConfig,
+    #[serde(default)]
+    pub failover: GlobalFailoverConfig,
+    #[serde(default, rename(deserialize = "guest-agent"))]
+    pub guest_agent: GlobalGuestAgentConfig,
+    #[serde(default)]
+    pub compat: GlobalCompatConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -21,6 +34,9 @@ pub struct GlobalVoreConfig {
     pub group: Option<String>,
     #[serde(default)]
     pub unix_group_id: Option<libc::gid_t>,
+    /// What to do with definitions that use renamed keys
+    #[serde(default)]
+    pub legacy_config_keys: LegacyKeys,
 }
 
 impl GlobalVoreConfig {
@@ -65,8 +81,18 @@ impl GlobalVoreConfig {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
 pub struct GlobalQemuConfig {
     pub script: String,
+    /// QEMU to run per guest architecture, qemu-system-<arch> from PATH for the ones not in it
+    #[serde(default)]
+    pub binaries: HashMap<String, String>,
+    /// User QEMU drops to once it opened everything, nobody if not set
+    #[serde(default)]
+    pub run_as: Option<String>,
+    /// Groups that user has to be in, e.g. kvm, input or render for devices QEMU opens later
+    #[serde(default)]
+    pub groups: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -76,6 +102,83 @@ pub struct GlobalUefiConfig {
     pub boot_code: String,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GlobalMetricsConfig {
+    /// Address the prometheus metrics endpoint should listen on, disabled if not set
+    #[serde(default)]
+    pub listen: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GlobalCompatConfig {
+    /// Socket the virsh-like JSON-RPC calls are served on, disabled if not set
+    #[serde(default)]
+    pub listen: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct GlobalVfioConfig {
+    /// Give devices bound to vfio-pci that no loaded VM uses back to their host driver when vored starts
+    #[serde(default)]
+    pub auto_recover: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct GlobalHostShutdownConfig {
+    /// Shut down the running VMs when logind announces the host shuts down or reboots
+    pub enabled: bool,
+    /// Seconds the guests get to shut down, before QEMU is quit
+    pub timeout: u64,
+}
+
+impl Default for GlobalHostShutdownConfig {
+    fn default() -> Self {
+        GlobalHostShutdownConfig {
+            enabled: true,
+            timeout: 120,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct GlobalFailoverConfig {
+    /// Lease file the vored managing the VMs keeps renewing, only one vored runs at a time if set
+    pub lease_file: Option<String>,
+    /// Seconds without renewal after which the lease is up for grabs by a vored started with --standby
+    pub lease_timeout: u64,
+    /// Start the machines that were running on a peer that went away here,
+    /// only safe if the peer is sure to be down, and its QEMU's with it
+    pub restart_peer_machines: bool,
+}
+
+impl Default for GlobalFailoverConfig {
+    fn default() -> Self {
+        GlobalFailoverConfig {
+            lease_file: None,
+            lease_timeout: 60,
+            restart_peer_machines: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+pub struct GlobalGuestAgentConfig {
+    /// ISO with the guest agent and virtio drivers for Windows, attached for guest-agent.install-media
+    pub virtio_win_iso: String,
+}
+
+impl Default for GlobalGuestAgentConfig {
+    fn default() -> Self {
+        GlobalGuestAgentConfig {
+            virtio_win_iso: "/usr/share/virtio-win/virtio-win.iso".to_string(),
+        }
+    }
+}
+
 impl GlobalConfig {
     pub fn load(toml: &str) -> Result<GlobalConfig, anyhow::Error> {
         toml::from_str(toml).context("Failed to parse toml for global config")
@@ -0,0 +1,83 @@
+#![cfg(feature = "host")]
+
+// Line based channel on a virtio-serial port, the guest writes the name of an action,
+// and gets "ok" or "error <message>" back once it has been handled
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+
+/// Name of the port as it shows up in the guest, e.g. /dev/virtio-ports/<name>
+pub const GUEST_ACTIONS_PORT: &str = "me.eater.vore.actions";
+
+/// Requests longer than this are dropped, names of actions should be short anyway
+const MAX_REQUEST_LENGTH: usize = 1024;
+
+#[derive(Debug)]
+pub struct GuestActionChannel {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+}
+
+impl GuestActionChannel {
+    pub fn connect(path: &str) -> Result<GuestActionChannel, io::Error> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(GuestActionChannel {
+            stream,
+            buffer: vec![],
+        })
+    }
+
+    pub fn stream(&self) -> &UnixStream {
+        &self.stream
+    }
+
+    /// Read all complete requests, returns false as first item if the channel was closed
+    pub fn read_requests(&mut self) -> Result<(bool, Vec<String>), io::Error> {
+        let mut still_open = true;
+        loop {
+            let mut buffer = [0u8; 1024];
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    still_open = false;
+                    break;
+                }
+                Ok(amount) => self.buffer.extend_from_slice(&buffer[..amount]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut requests = vec![];
+        while let Some(idx) = self.buffer.iter().position(|x| *x == b'\n') {
+            let line = self.buffer.drain(..=idx).collect::<Vec<_>>();
+            let request = String::from_utf8_lossy(&line).trim().to_string();
+            if !request.is_empty() {
+                requests.push(request);
+            }
+        }
+
+        if self.buffer.len() > MAX_REQUEST_LENGTH {
+            log::warn!("Guest sent a too long action request, dropping it");
+            self.buffer.clear();
+        }
+
+        Ok((still_open, requests))
+    }
+
+    pub fn reply(&mut self, result: &Result<(), anyhow::Error>) -> Result<(), io::Error> {
+        let line = match result {
+            Ok(()) => "ok\n".to_string(),
+            Err(err) => format!("error {}\n", format!("{:#}", err).replace('\n', " ")),
+        };
+
+        // The guest may not be reading, don't let that block the daemon
+        self.stream.set_nonblocking(false)?;
+        self.stream
+            .set_write_timeout(Some(std::time::Duration::from_secs(1)))?;
+        let res = self.stream.write_all(line.as_bytes());
+        self.stream.set_nonblocking(true)?;
+        res
+    }
+}
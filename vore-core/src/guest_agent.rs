@@ -0,0 +1,301 @@
+#![cfg(feature = "host")]
+
+// Time of day and timezone of the guest, set through the RTC when it boots and the QEMU guest agent once it runs
+//
+// Only the few guest agent commands vored needs are implemented, see qga/qapi-schema.json in QEMU for all of them
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use std::ffi::OsString;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the port qemu-ga in the guest looks for
+pub const GUEST_AGENT_PORT: &str = "org.qemu.guest_agent.0";
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+extern "C" {
+    // Not in the libc crate, localtime_r doesn't pick up changes to TZ without it
+    fn tzset();
+}
+
+/// The IANA name of [timezone], localtime is resolved to the timezone of the host
+pub fn timezone_name(timezone: &str) -> Result<String, anyhow::Error> {
+    match timezone {
+        "utc" => Ok("UTC".to_string()),
+        "localtime" => {
+            // /etc/localtime is a link into the zoneinfo directory on about every distribution
+            let target = std::fs::read_link("/etc/localtime")
+                .context("Can't find the timezone of the host, /etc/localtime isn't a link")?;
+            let target = target.to_string_lossy();
+            target
+                .find("zoneinfo/")
+                .map(|x| target[x + "zoneinfo/".len()..].to_string())
+                .with_context(|| {
+                    format!(
+                        "Can't find the timezone of the host, /etc/localtime links to {}",
+                        target
+                    )
+                })
+        }
+        timezone => {
+            if !Path::new(ZONEINFO).join(timezone).is_file() {
+                anyhow::bail!("Unknown timezone {}, it's not in {}", timezone, ZONEINFO);
+            }
+
+            Ok(timezone.to_string())
+        }
+    }
+}
+
+/// Value for -rtc base= of QEMU, with an IANA timezone the RTC starts at the current time in that timezone
+pub fn rtc_base(timezone: &str) -> Result<String, anyhow::Error> {
+    if timezone == "utc" || timezone == "localtime" {
+        return Ok(timezone.to_string());
+    }
+
+    let timezone = timezone_name(timezone)?;
+    let previous = std::env::var_os("TZ");
+    std::env::set_var("TZ", OsString::from(format!(":{}", timezone)));
+    let tm = unsafe {
+        tzset();
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        let res = libc::localtime_r(&now, &mut tm);
+
+        match previous {
+            Some(previous) => std::env::set_var("TZ", previous),
+            None => std::env::remove_var("TZ"),
+        }
+        tzset();
+
+        if res.is_null() {
+            anyhow::bail!("Failed to get the current time in {}", timezone);
+        }
+
+        tm
+    };
+
+    Ok(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    ))
+}
+
+#[derive(Debug)]
+pub struct GuestAgent {
+    reader: BufReader<UnixStream>,
+}
+
+impl GuestAgent {
+    /// Connect to the guest agent socket at [path], every read waits at most [timeout]
+    pub fn connect(path: &str, timeout: Duration) -> Result<GuestAgent, io::Error> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(GuestAgent {
+            reader: BufReader::new(stream),
+        })
+    }
+
+    /// Wait at most [timeout] for every read and write from now on, for commands that take longer
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        let stream = self.reader.get_ref();
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))
+    }
+
+    /// Check if the agent in the guest runs, and skip whatever it still had to say to earlier connections
+    ///
+    /// Returns false if it didn't answer in time
+    pub fn sync(&mut self) -> Result<bool, anyhow::Error> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u32;
+
+        // 0xFF resets the parser of the agent, in case an earlier command was only partially written,
+        // the delimited sync answers with a 0xFF in front of its result
+        let mut command = vec![0xFF];
+        command.extend_from_slice(
+            json!({ "execute": "guest-sync-delimited", "arguments": { "id": id } })
+                .to_string()
+                .as_bytes(),
+        );
+        command.push(b'\n');
+        if !self.write(&command)? {
+            return Ok(false);
+        }
+
+        loop {
+            let mut skipped = vec![];
+            match self.reader.read_until(0xFF, &mut skipped) {
+                Ok(0) => return Ok(false),
+                Ok(_) if skipped.last() == Some(&0xFF) => {}
+                Ok(_) => continue,
+                Err(err) if is_timeout(&err) => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+
+            match self.read()? {
+                Some(answer) if answer.get("return") == Some(&json!(id)) => return Ok(true),
+                Some(_) => continue,
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Run [command] in the agent, this should only be used after [sync] succeeded
+    pub fn execute(&mut self, command: &str, arguments: Value) -> Result<Value, anyhow::Error> {
+        let mut line = json!({ "execute": command, "arguments": arguments }).to_string();
+        line.push('\n');
+        if !self.write(line.as_bytes())? {
+            anyhow::bail!("Guest agent didn't accept {} in time", command);
+        }
+
+        let answer = self
+            .read()?
+            .with_context(|| format!("Guest agent didn't answer {} in time", command))?;
+        if let Some(error) = answer.get("error") {
+            anyhow::bail!(
+                "Guest agent failed {}: {}",
+                command,
+                error
+                    .get("desc")
+                    .and_then(|x| x.as_str())
+                    .unwrap_or("unknown error")
+            );
+        }
+
+        Ok(answer.get("return").cloned().unwrap_or(Value::Null))
+    }
+
+    /// Set the clock of the guest to the time of the host
+    pub fn set_time(&mut self) -> Result<(), anyhow::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        self.execute("guest-set-time", json!({ "time": now }))?;
+        Ok(())
+    }
+
+    /// Start [path] with [args] in the guest, without waiting for it to finish
+    pub fn exec(&mut self, path: &str, args: &[&str]) -> Result<(), anyhow::Error> {
+        self.execute("guest-exec", json!({ "path": path, "arg": args }))?;
+        Ok(())
+    }
+
+    /// Start [path] with [args] in the guest and keep its output, returns the pid to pass to [exec_status]
+    pub fn exec_captured(&mut self, path: &str, args: &[&str]) -> Result<i64, anyhow::Error> {
+        self.execute(
+            "guest-exec",
+            json!({ "path": path, "arg": args, "capture-output": true }),
+        )?
+        .get("pid")
+        .and_then(|x| x.as_i64())
+        .context("Guest agent didn't return the pid of the started process")
+    }
+
+    /// Exit code and output of the process started with [exec_captured], None while it still runs
+    pub fn exec_status(&mut self, pid: i64) -> Result<Option<(i64, String)>, anyhow::Error> {
+        let status = self.execute("guest-exec-status", json!({ "pid": pid }))?;
+        if status.get("exited").and_then(|x| x.as_bool()) != Some(true) {
+            return Ok(None);
+        }
+
+        let output = status
+            .get("out-data")
+            .and_then(|x| x.as_str())
+            .map_or_else(|| Ok(vec![]), decode_base64)?;
+        Ok(Some((
+            status
+                .get("exitcode")
+                .and_then(|x| x.as_i64())
+                .unwrap_or(-1),
+            String::from_utf8_lossy(&output).to_string(),
+        )))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<bool, anyhow::Error> {
+        match self.reader.get_mut().write_all(data) {
+            Ok(_) => Ok(true),
+            Err(err) if is_timeout(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Read the next answer, None if nothing came in time
+    fn read(&mut self) -> Result<Option<Value>, anyhow::Error> {
+        loop {
+            let mut line = vec![];
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                Err(err) if is_timeout(&err) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+
+            // Skip garbage left over from commands the agent got halfway
+            line.retain(|x| *x != 0xFF);
+            if let Ok(answer) = serde_json::from_slice::<Value>(&line) {
+                return Ok(Some(answer));
+            }
+        }
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+/// The agent sends the output of processes base64 encoded
+fn decode_base64(data: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut output = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for char in data
+        .bytes()
+        .filter(|x| !x.is_ascii_whitespace() && *x != b'=')
+    {
+        let value = match char {
+            b'A'..=b'Z' => char - b'A',
+            b'a'..=b'z' => char - b'a' + 26,
+            b'0'..=b'9' => char - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => anyhow::bail!("Guest agent sent invalid base64"),
+        };
+
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guest_agent::decode_base64;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Mzg0MHgyMTYwDQo=").unwrap(), b"3840x2160\r\n");
+        assert_eq!(decode_base64("YWI=").unwrap(), b"ab");
+        assert!(decode_base64("a?b").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+#![cfg(feature = "host")]
+
+// Small ISO with a script that installs the guest agent and the virtio drivers in a Windows guest
+//
+// Both come from the virtio-win ISO (https://github.com/virtio-win/virtio-win-pkg-scripts), which
+// is attached next to it, the script looks for it on every drive letter
+
+use anyhow::Context;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Volume label of the ISO, which is how it shows up in Explorer
+const LABEL: &str = "VORE_TOOLS";
+
+const AUTORUN: &str = "[autorun]\r\nopen=install-vore-tools.cmd\r\nlabel=vore tools\r\n";
+
+const INSTALL_SCRIPT: &str = "@echo off\r
+rem Installs the virtio drivers and the QEMU guest agent from the virtio-win CD\r
+for %%d in (D E F G H I J K L M N O P Q R S T U V W X Y Z) do (\r
+  if exist %%d:\\guest-agent\\qemu-ga-x86_64.msi (\r
+    msiexec /i %%d:\\virtio-win-gt-x64.msi /qn /norestart\r
+    msiexec /i %%d:\\guest-agent\\qemu-ga-x86_64.msi /qn /norestart\r
+    echo Installed the virtio drivers and the guest agent\r
+    exit /b 0\r
+  )\r
+)\r
+echo The virtio-win CD isn't attached\r
+exit /b 1\r
+";
+
+/// Tools that can make an ISO, all taking mkisofs arguments after the given ones
+const ISO_TOOLS: &[(&str, &[&str])] = &[
+    ("xorriso", &["-as", "mkisofs"]),
+    ("genisoimage", &[]),
+    ("mkisofs", &[]),
+];
+
+/// Write the guest tools ISO to [path], if it isn't there yet
+pub fn build_guest_tools_iso<P: AsRef<Path>>(path: P) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    if path.is_file() {
+        return Ok(());
+    }
+
+    let staging = path.with_extension("staging");
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging).with_context(|| format!("Failed to create {:?}", staging))?;
+    std::fs::write(staging.join("autorun.inf"), AUTORUN)?;
+    std::fs::write(staging.join("install-vore-tools.cmd"), INSTALL_SCRIPT)?;
+
+    let mut result = Err(anyhow::anyhow!(
+        "No tool to make an ISO with found, install xorriso, genisoimage or mkisofs"
+    ));
+    for (tool, args) in ISO_TOOLS {
+        let status = Command::new(tool)
+            .args(*args)
+            .args(&["-quiet", "-J", "-R", "-V", LABEL, "-o"])
+            .arg(path)
+            .arg(&staging)
+            .stdout(Stdio::null())
+            .status();
+        result = match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(anyhow::anyhow!("{} failed ({})", tool, status)),
+            Err(_) => continue,
+        };
+        break;
+    }
+
+    let _ = std::fs::remove_dir_all(&staging);
+    result.with_context(|| format!("Failed to make guest tools ISO {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guest_tools::INSTALL_SCRIPT;
+
+    #[test]
+    fn test_script_line_endings() {
+        // cmd.exe misreads labels and blocks in scripts with only LF line endings
+        assert!(INSTALL_SCRIPT
+            .split('\n')
+            .filter(|x| !x.is_empty())
+            .all(|x| x.ends_with('\r')));
+    }
+}
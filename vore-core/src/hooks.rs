@@ -0,0 +1,159 @@
+#![cfg(feature = "host")]
+
+// Running the hook scripts of a VM, vored waits for them, so a hook that hangs would hang the
+// daemon with it if it didn't get a deadline
+//
+// Hooks run in their own process group, so the commands they started are killed with them
+
+use crate::rpc::{ErrorCode, RpcError};
+use anyhow::Context;
+use std::ffi::OsStr;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Run [script] for [hook] with [envs], killing it if it takes longer than [timeout]
+pub fn run_hook_script(
+    hook: &str,
+    script: &str,
+    envs: &[(&str, &OsStr)],
+    timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut command = Command::new(script);
+    command.envs(envs.iter().copied());
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to run {} hook ({})", hook, script))?;
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+
+            let _ = child.wait();
+            return Err(RpcError::new(
+                ErrorCode::Timeout,
+                format!(
+                    "{} hook ({}) didn't finish within {} seconds, killed it",
+                    hook,
+                    script,
+                    timeout.as_secs()
+                ),
+            )
+            .into());
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    if !status.success() {
+        anyhow::bail!("{} hook ({}) failed with {}", hook, script, status);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hooks::run_hook_script;
+    use crate::rpc::{ErrorCode, RpcError};
+    use std::ffi::OsStr;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    fn write_script(dir: &Path, name: &str, body: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// If [pid] is still there, and not a zombie waiting to be reaped
+    fn is_running(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid)).map_or(false, |x| {
+            x.rsplit(')')
+                .next()
+                .map(|x| x.trim_start())
+                .map_or(false, |x| !x.starts_with('Z'))
+        })
+    }
+
+    fn scripts_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vore-hooks-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_hook_env_and_status() {
+        let dir = scripts_dir("status");
+        let out = dir.join("out");
+        let script = write_script(
+            &dir,
+            "hook.sh",
+            &format!("echo \"$VORE_HOOK $VORE_VM_NAME\" > {}", out.display()),
+        );
+        run_hook_script(
+            "pre-start",
+            &script,
+            &[
+                ("VORE_HOOK", OsStr::new("pre-start")),
+                ("VORE_VM_NAME", OsStr::new("win10")),
+            ],
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "pre-start win10\n");
+
+        let failing = write_script(&dir, "failing.sh", "exit 3");
+        let err = run_hook_script("post-stop", &failing, &[], Duration::from_secs(5)).unwrap_err();
+        assert!(err.to_string().contains("post-stop hook"), "{}", err);
+        assert!(run_hook_script("post-stop", "/nonexistent", &[], Duration::from_secs(5)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hook_timeout() {
+        let dir = scripts_dir("timeout");
+        let pid_file = dir.join("sleep.pid");
+        // The sleep it started has to go too, not only the script
+        let script = write_script(
+            &dir,
+            "hang.sh",
+            &format!("sleep 60 &\necho $! > {}\nwait", pid_file.display()),
+        );
+
+        let started = Instant::now();
+        let err = run_hook_script("pre-start", &script, &[], Duration::from_secs(1)).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            err.downcast_ref::<RpcError>().map(|x| x.code),
+            Some(ErrorCode::Timeout)
+        );
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while is_running(pid.trim()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        assert!(!is_running(pid.trim()), "sleep {} survived", pid.trim());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{read_dir, read_to_string, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct HostCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What was found, or how to fix it if not ok
+    pub message: String,
+}
+
+/// If /dev/kvm can be opened, which QEMU needs for -enable-kvm
+pub fn kvm_available() -> Result<(), io::Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .map(|_| ())
+}
+
+/// Check if the host has the features needed to run VM's (with passthrough)
+pub fn host_checks() -> Vec<HostCheck> {
+    let mut checks = vec![];
+
+    checks.push(
+        match kvm_available() {
+            Ok(_) => HostCheck {
+                name: "KVM",
+                ok: true,
+                message: "available".to_string(),
+            },
+            Err(err) => HostCheck {
+                name: "KVM",
+                ok: false,
+                message: format!("/dev/kvm can't be opened ({}), VM's with kvm enabled won't start, make sure virtualization is enabled in the BIOS and the kvm module is loaded", err),
+            },
+        },
+    );
+
+    let iommu_groups = read_dir("/sys/kernel/iommu_groups").map_or(0, |x| x.count());
+    checks.push(HostCheck {
+        name: "IOMMU",
+        ok: iommu_groups > 0,
+        message: if iommu_groups > 0 {
+            format!("enabled ({} groups)", iommu_groups)
+        } else {
+            "disabled, vfio passthrough won't work, add intel_iommu=on or amd_iommu=on to the kernel command line".to_string()
+        },
+    });
+
+    let vfio = Path::new("/sys/bus/pci/drivers/vfio-pci").exists();
+    checks.push(HostCheck {
+        name: "vfio-pci",
+        ok: vfio,
+        message: if vfio {
+            "loaded".to_string()
+        } else {
+            "not loaded, vfio passthrough won't work, run `modprobe vfio-pci`".to_string()
+        },
+    });
+
+    checks
+}
+
+/// If kvm_amd has SEV enabled, or SEV-SNP with [snp]
+pub fn sev_enabled(snp: bool) -> bool {
+    let parameter = if snp { "sev_snp" } else { "sev" };
+    read_to_string(format!("/sys/module/kvm_amd/parameters/{}", parameter))
+        .map_or(false, |x| matches!(x.trim(), "Y" | "1"))
+}
+
+/// Position of the SEV encryption bit and the physical address bits it costs, as (cbitpos, reduced-phys-bits)
+#[cfg(target_arch = "x86_64")]
+pub fn sev_cbitpos() -> Option<(u32, u32)> {
+    use std::arch::x86_64::{__cpuid, __get_cpuid_max};
+
+    // Leaf 0x8000001F is AMD's memory encryption leaf, EAX bit 1 is SEV
+    let (max, _) = __get_cpuid_max(0x8000_0000);
+    if max < 0x8000_001F {
+        return None;
+    }
+
+    let leaf = __cpuid(0x8000_001F);
+    if leaf.eax & 0b10 == 0 {
+        return None;
+    }
+
+    Some((leaf.ebx & 0x3f, (leaf.ebx >> 6) & 0x3f))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn sev_cbitpos() -> Option<(u32, u32)> {
+    None
+}
+
+/// Something on the host a VM needs before it's auto-started, e.g. a bridge or an imported storage pool
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HostRequirement {
+    /// Network bridge with this name exists
+    Bridge(String),
+    /// File or directory exists
+    Path(String),
+    /// Something is mounted on this path
+    Mount(String),
+    /// systemd unit is active
+    Service(String),
+}
+
+impl HostRequirement {
+    pub fn is_met(&self) -> bool {
+        match self {
+            HostRequirement::Bridge(name) => {
+                Path::new("/sys/class/net").join(name).join("bridge").is_dir()
+            }
+            HostRequirement::Path(path) => Path::new(path).exists(),
+            HostRequirement::Mount(path) => read_to_string("/proc/self/mountinfo")
+                .map_or(false, |x| {
+                    x.lines()
+                        .filter_map(|x| x.split(' ').nth(4))
+                        .any(|x| Path::new(&x.replace("\\040", " ")) == Path::new(path))
+                }),
+            HostRequirement::Service(unit) => Command::new("systemctl")
+                .args(&["is-active", "--quiet", unit])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_or(false, |x| x.success()),
+        }
+    }
+}
+
+impl FromStr for HostRequirement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("").to_string();
+        if value.is_empty() {
+            anyhow::bail!(
+                "Host requirement '{}' should look like bridge:<name>, path:<path>, mount:<path> or service:<unit>",
+                s
+            );
+        }
+
+        if (kind == "path" || kind == "mount") && !value.starts_with('/') {
+            anyhow::bail!("Host requirement '{}' should have an absolute path", s);
+        }
+
+        Ok(match kind {
+            "bridge" => HostRequirement::Bridge(value),
+            "path" => HostRequirement::Path(value),
+            "mount" => HostRequirement::Mount(value),
+            "service" => HostRequirement::Service(value),
+            _ => anyhow::bail!(
+                "Unknown host requirement '{}', should be bridge, path, mount or service",
+                kind
+            ),
+        })
+    }
+}
+
+impl Display for HostRequirement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HostRequirement::Bridge(name) => write!(f, "bridge:{}", name),
+            HostRequirement::Path(path) => write!(f, "path:{}", path),
+            HostRequirement::Mount(path) => write!(f, "mount:{}", path),
+            HostRequirement::Service(unit) => write!(f, "service:{}", unit),
+        }
+    }
+}
@@ -1,28 +1,149 @@
-use crate::utils::get_uid_by_username;
+use crate::utils::{get_gid_by_groupname, get_uid_by_username};
+use crate::HostRequirement;
+use crate::Resolution;
+use crate::{check_disk_image_options, SLOW_PREALLOCATION_MODES};
 use anyhow::{Context, Error};
 use config::{Config, File, FileFormat, Value};
 use serde::de::Visitor;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
 use std::str::FromStr;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct InstanceConfig {
     pub name: String,
     pub arch: String,
+    /// QEMU machine type, e.g. q35, pc or pc-q35-8.2
     pub chipset: String,
+    /// Family of [chipset]
+    #[serde(default)]
+    pub machine_type: MachineType,
     pub kvm: bool,
+    /// Emulate the guest with TCG when [kvm] is set but KVM isn't available, instead of not starting
+    #[serde(default)]
+    pub allow_tcg: bool,
+    /// Hide the hypervisor from the guest, and let KVM ignore MSR's it doesn't know while it runs
+    pub stealth: bool,
+    /// Hide the KVM signature from the guest, without the host side changes of [stealth].
+    /// On by default, Nvidia drivers refuse passed through GPU's otherwise (Code 43)
+    pub hide_kvm: bool,
+    /// utc, localtime or an IANA name, the RTC starts at the time in it and the guest agent sets it in the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
     pub auto_start: bool,
+    /// Seconds to wait after the previous auto-started VM before starting this one
+    pub auto_start_delay: u64,
+    /// VM's with a lower order are auto-started first
+    pub auto_start_order: i64,
+    /// Host requirements (see [HostRequirement]) that have to be met before the VM is auto-started
+    pub requires_host: Vec<String>,
+    /// Seconds auto-start waits for [requires_host], 0 to wait forever
+    pub requires_host_timeout: u64,
+    pub restart: RestartPolicy,
+    /// Keep a paused QEMU launched while the VM isn't running, so starting it only has to resume it
+    pub standby: bool,
+    /// VM's that can't run at the same time as this one, on top of the ones sharing a device or disk with it
+    pub conflicts: Vec<String>,
+    /// Leave the shared memory files and the SPICE socket behind when QEMU quits, for debugging
+    #[serde(default)]
+    pub keep_shm: bool,
+    /// Empty PCIe root ports PCI devices can be attached to while the VM runs
+    pub pci_hotplug_slots: u32,
+    /// How many times in a row the VM is restarted before giving up, 0 for no limit
+    pub restart_max_retries: u32,
+    /// Seconds to wait before the first restart, doubled for every following attempt
+    pub restart_backoff: u64,
     pub memory: u64,
+    /// Add a virtio-balloon device, so the memory of the guest can be changed while it runs
+    pub balloon: bool,
+    pub limits: ResourceLimits,
     pub cpu: CpuConfig,
     pub disks: Vec<DiskConfig>,
+    /// Devices the guest boots from, in order: diskN, cdrom (the first iso disk) or net
+    pub boot_order: Vec<String>,
     pub uefi: UefiConfig,
     pub vfio: Vec<VfioConfig>,
+    pub mdev: Vec<MdevConfig>,
+    pub usb: Vec<UsbConfig>,
+    /// Guest NUMA nodes, empty for a single node without host binding
+    pub numa: Vec<NumaNodeConfig>,
+    pub net: NetConfig,
+    pub smbios: SmbiosConfig,
     pub looking_glass: LookingGlassConfig,
     pub scream: ScreamConfig,
     pub pulse: PulseConfig,
+    pub jack: JackConfig,
     pub spice: SpiceConfig,
+    #[serde(default)]
+    pub video: VideoConfig,
+    #[serde(default)]
+    pub seats: Vec<SeatConfig>,
+    pub hooks: HooksConfig,
+    pub guest_actions: GuestActionsConfig,
+    pub clipboard: ClipboardConfig,
+    pub guest_agent: GuestAgentConfig,
+    #[serde(default)]
+    pub tpm: TpmConfig,
+    #[serde(default)]
+    pub sev: SevConfig,
+    pub health: HealthConfig,
+    pub sandbox: SandboxConfig,
+    pub input: InputConfig,
+    pub disk_space: DiskSpaceConfig,
+    /// Appended as-is to the QEMU command line the Lua script built, for what vore doesn't support
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    /// Environment variables set for QEMU, on top of the ones it inherits from vored
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// User QEMU drops to, qemu.run-as of vored.toml if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<String>,
+    /// Groups the user QEMU runs as has to be in, instead of qemu.groups of vored.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+    /// Renamed keys the definition still uses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecations: Vec<Deprecation>,
+}
+
+/// Keys that were renamed as (old, new), definitions using the old key keep working with a deprecation warning
+pub const KEY_ALIASES: &[(&str, &str)] = &[
+    ("scream.mem-path", "scream.path"),
+    ("looking-glass.mem-path", "looking-glass.path"),
+];
+
+/// Use of a key that was renamed
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct Deprecation {
+    pub key: String,
+    pub replacement: String,
+}
+
+impl Display for Deprecation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is deprecated, use {} instead",
+            self.key, self.replacement
+        )
+    }
+}
+
+/// How vored treats definitions that use renamed keys
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LegacyKeys {
+    /// Accept them without saying anything
+    Allow,
+    /// Accept them, but log and show a deprecation warning
+    #[default]
+    Warn,
+    /// Refuse to load the definition
+    Deny,
 }
 
 impl InstanceConfig {
@@ -31,16 +152,42 @@ impl InstanceConfig {
         Self::from_config(toml)
     }
 
-    pub fn from_config(config: Config) -> Result<InstanceConfig, anyhow::Error> {
-        let mut instance_config = InstanceConfig::default();
+    pub fn from_config(mut config: Config) -> Result<InstanceConfig, anyhow::Error> {
+        let mut instance_config = InstanceConfig {
+            deprecations: apply_key_aliases(&mut config)?,
+            ..Default::default()
+        };
         if let Ok(name) = config.get_str("machine.name") {
             instance_config.name = name
         }
 
+        if let Ok(arch) = config.get::<Value>("machine.arch") {
+            let arch = arch.into_str().context("machine.arch should be a string")?;
+            if !SUPPORTED_ARCHES.contains(&arch.as_str()) {
+                anyhow::bail!(
+                    "'{}' is not a supported machine.arch ({})",
+                    arch,
+                    SUPPORTED_ARCHES.join(", ")
+                );
+            }
+
+            // KVM only runs guests of the host's own architecture, the rest is emulated
+            instance_config.kvm = arch == std::env::consts::ARCH;
+            instance_config.machine_type = MachineType::default_for(&arch);
+            instance_config.chipset = instance_config.machine_type.to_string();
+            instance_config.arch = arch;
+        }
+
         if let Ok(kvm) = config.get::<Value>("machine.kvm") {
             instance_config.kvm = kvm.into_bool().context("machine.kvm should be a boolean")?;
         }
 
+        if let Ok(allow_tcg) = config.get::<Value>("machine.allow-tcg") {
+            instance_config.allow_tcg = allow_tcg
+                .into_bool()
+                .context("machine.allow-tcg should be a boolean")?;
+        }
+
         if let Ok(mem) = config.get::<Value>("machine.memory") {
             let mem = mem
                 .into_str()
@@ -48,10 +195,222 @@ impl InstanceConfig {
             instance_config.memory = parse_size(&mem)?;
         }
 
+        if let Ok(balloon) = config.get::<Value>("machine.balloon") {
+            instance_config.balloon = balloon
+                .into_bool()
+                .context("machine.balloon should be a boolean")?;
+        }
+
+        if let Ok(stealth) = config.get::<Value>("machine.stealth") {
+            instance_config.stealth = stealth
+                .into_bool()
+                .context("machine.stealth should be a boolean")?;
+        }
+
+        if let Ok(timezone) = config.get::<Value>("machine.timezone") {
+            let timezone = timezone
+                .into_str()
+                .context("machine.timezone should be a string")?;
+            if !is_valid_timezone(&timezone) {
+                anyhow::bail!(
+                    "machine.timezone should be utc, localtime or a timezone like Europe/Amsterdam, got {}",
+                    timezone
+                );
+            }
+
+            instance_config.timezone = Some(timezone);
+        }
+
+        if let Ok(hide_kvm) = config.get::<Value>("machine.hide-kvm") {
+            instance_config.hide_kvm = hide_kvm
+                .into_bool()
+                .context("machine.hide-kvm should be a boolean")?;
+        }
+
         if let Ok(auto_start) = config.get_bool("machine.auto-start") {
             instance_config.auto_start = auto_start;
         }
 
+        if let Ok(delay) = config.get::<Value>("machine.auto-start-delay") {
+            instance_config.auto_start_delay = delay
+                .into_int()
+                .ok()
+                .filter(|x| *x >= 0)
+                .context("machine.auto-start-delay should be a positive amount of seconds")?
+                as u64;
+        }
+
+        if let Ok(requires) = config.get::<Value>("machine.requires-host") {
+            instance_config.requires_host = requires
+                .into_array()
+                .context("machine.requires-host should be an array of strings")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("machine.requires-host should be an array of strings")?;
+
+            for requirement in &instance_config.requires_host {
+                HostRequirement::from_str(requirement).context("Invalid machine.requires-host")?;
+            }
+        }
+
+        if let Ok(timeout) = config.get::<Value>("machine.requires-host-timeout") {
+            instance_config.requires_host_timeout =
+                timeout.into_int().ok().filter(|x| *x >= 0).context(
+                    "machine.requires-host-timeout should be a positive amount of seconds",
+                )? as u64;
+        }
+
+        if let Ok(chipset) = config.get::<Value>("machine.chipset") {
+            let chipset = chipset
+                .into_str()
+                .context("machine.chipset should be a string")?;
+            instance_config.machine_type =
+                MachineType::from_chipset(&chipset).context("Invalid machine.chipset")?;
+            instance_config.chipset = match chipset.as_str() {
+                "i440fx" => "pc".to_string(),
+                _ => chipset,
+            };
+        }
+
+        if let Ok(slots) = config.get::<Value>("machine.pci-hotplug-slots") {
+            instance_config.pci_hotplug_slots = slots
+                .into_int()
+                .ok()
+                .filter(|x| *x >= 0 && *x <= 32)
+                .context("machine.pci-hotplug-slots should be a number between 0 and 32")?
+                as u32;
+        }
+
+        if let Ok(standby) = config.get::<Value>("machine.standby") {
+            instance_config.standby = standby
+                .into_bool()
+                .context("machine.standby should be a boolean")?;
+        }
+
+        if let Ok(keep_shm) = config.get::<Value>("machine.keep-shm") {
+            instance_config.keep_shm = keep_shm
+                .into_bool()
+                .context("machine.keep-shm should be a boolean")?;
+        }
+
+        if let Ok(conflicts) = config.get::<Value>("machine.conflicts") {
+            instance_config.conflicts = conflicts
+                .into_array()
+                .context("machine.conflicts should be an array of VM names")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("machine.conflicts should be an array of VM names")?;
+        }
+
+        if let Ok(extra_args) = config.get::<Value>("qemu.extra-args") {
+            instance_config.extra_args = extra_args
+                .into_array()
+                .context("qemu.extra-args should be an array of strings")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("qemu.extra-args should be an array of strings")?;
+        }
+
+        if let Ok(env) = config.get::<Value>("qemu.env") {
+            instance_config.env = env
+                .into_table()
+                .context("qemu.env should be a table of environment variables")?
+                .into_iter()
+                .map(|(name, value)| {
+                    value
+                        .into_str()
+                        .with_context(|| format!("qemu.env.{} should be a string", name))
+                        .map(|value| (name, value))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Ok(run_as) = config.get::<Value>("qemu.run-as") {
+            let run_as = run_as
+                .into_str()
+                .context("qemu.run-as should be a user name")?;
+            if run_as.is_empty() {
+                anyhow::bail!("qemu.run-as can't be empty");
+            }
+
+            instance_config.run_as = Some(run_as);
+        }
+
+        if let Ok(groups) = config.get::<Value>("qemu.groups") {
+            instance_config.groups = Some(
+                groups
+                    .into_array()
+                    .context("qemu.groups should be an array of group names")?
+                    .into_iter()
+                    .map(|x| x.into_str())
+                    .collect::<Result<_, _>>()
+                    .context("qemu.groups should be an array of group names")?,
+            );
+        }
+
+        if let Ok(restart) = config.get_str("machine.restart") {
+            instance_config.restart = RestartPolicy::from_str(&restart)?;
+        }
+
+        if let Ok(retries) = config.get::<Value>("machine.restart-max-retries") {
+            instance_config.restart_max_retries = retries
+                .into_int()
+                .ok()
+                .filter(|x| *x >= 0)
+                .context("machine.restart-max-retries should be a positive number")?
+                as u32;
+        }
+
+        if let Ok(backoff) = config.get::<Value>("machine.restart-backoff") {
+            instance_config.restart_backoff = backoff
+                .into_int()
+                .ok()
+                .filter(|x| *x >= 0)
+                .context("machine.restart-backoff should be a positive amount of seconds")?
+                as u64;
+        }
+
+        if let Ok(quota) = config.get::<Value>("machine.cpu-quota") {
+            let quota = quota
+                .into_str()
+                .context("machine.cpu-quota should be a percentage")?;
+            instance_config.limits.cpu_quota = Some(
+                u32::from_str(quota.trim_end_matches('%'))
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .with_context(|| {
+                        format!("machine.cpu-quota should be a percentage, got '{}'", quota)
+                    })?,
+            );
+        }
+
+        if let Ok(memory_max) = config.get::<Value>("machine.memory-max") {
+            let memory_max = memory_max
+                .into_str()
+                .context("machine.memory-max should be a string or number")?;
+            instance_config.limits.memory_max = Some(parse_size(&memory_max)?);
+        }
+
+        if let Ok(weight) = config.get::<Value>("machine.io-weight") {
+            instance_config.limits.io_weight = Some(
+                weight
+                    .into_int()
+                    .ok()
+                    .filter(|x| (1..=10000).contains(x))
+                    .context("machine.io-weight should be a number between 1 and 10000")?
+                    as u32,
+            );
+        }
+
+        if let Ok(order) = config.get::<Value>("machine.auto-start-order") {
+            instance_config.auto_start_order = order
+                .into_int()
+                .context("machine.auto-start-order should be a number")?;
+        }
+
         if let Ok(cpu) = config.get_table("cpu") {
             instance_config.cpu.apply_table(cpu)?
         }
@@ -66,6 +425,26 @@ impl InstanceConfig {
             }
         }
 
+        if let Ok(order) = config.get::<Value>("machine.boot-order") {
+            instance_config.boot_order = order
+                .into_array()
+                .context("machine.boot-order should be an array of strings")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("machine.boot-order should be an array of strings")?;
+
+            for (i, device) in instance_config.boot_order.iter().enumerate() {
+                if instance_config.boot_order[..i].contains(device) {
+                    anyhow::bail!("{} is in machine.boot-order more than once", device);
+                }
+
+                instance_config
+                    .check_boot_device(device)
+                    .context("Invalid machine.boot-order")?;
+            }
+        }
+
         if let Ok(uefi) = config.get_table("uefi") {
             instance_config.uefi.apply_table(uefi)?;
         }
@@ -80,6 +459,67 @@ impl InstanceConfig {
             }
         }
 
+        if let Ok(mdev) = config.get::<Value>("mdev") {
+            let arr = mdev.into_array().context("mdev should be an array")?;
+            for (i, device) in arr.into_iter().enumerate() {
+                let table = device
+                    .into_table()
+                    .with_context(|| format!("mdev[{}] should be a table", i))?;
+                instance_config.mdev.push(
+                    MdevConfig::from_table(table)
+                        .with_context(|| format!("Failed to parse mdev[{}]", i))?,
+                );
+            }
+        }
+
+        if let Ok(usb) = config.get::<Value>("usb") {
+            let arr = usb.into_array().context("usb should be an array")?;
+            for (i, device) in arr.into_iter().enumerate() {
+                let table = device
+                    .into_table()
+                    .with_context(|| format!("usb[{}] should be a table", i))?;
+                instance_config.usb.push(
+                    UsbConfig::from_table(table)
+                        .with_context(|| format!("Failed to parse usb[{}]", i))?,
+                );
+            }
+        }
+
+        if let Ok(numa) = config.get::<Value>("numa") {
+            let arr = numa.into_array().context("numa should be an array")?;
+            for (i, node) in arr.into_iter().enumerate() {
+                let table = node
+                    .into_table()
+                    .with_context(|| format!("numa[{}] should be a table", i))?;
+                instance_config.numa.push(
+                    NumaNodeConfig::from_table(table)
+                        .with_context(|| format!("Failed to parse numa[{}]", i))?,
+                );
+            }
+
+            instance_config.check_numa()?;
+        }
+
+        if let Ok(seats) = config.get::<Value>("seat") {
+            let arr = seats.into_array().context("seat should be an array")?;
+            for (i, seat) in arr.into_iter().enumerate() {
+                let table = seat
+                    .into_table()
+                    .with_context(|| format!("seat[{}] should be a table", i))?;
+                let seat = SeatConfig::from_table(table)
+                    .with_context(|| format!("Failed to parse seat[{}]", i))?;
+                if instance_config.seats.iter().any(|x| x.name == seat.name) {
+                    anyhow::bail!(
+                        "seat[{}] has the same name as another seat, '{}'",
+                        i,
+                        seat.name
+                    );
+                }
+
+                instance_config.seats.push(seat);
+            }
+        }
+
         instance_config.looking_glass =
             LookingGlassConfig::from_table(config.get_table("looking-glass").unwrap_or_default())?;
         instance_config.scream =
@@ -89,6 +529,42 @@ impl InstanceConfig {
 
         instance_config.pulse =
             PulseConfig::from_table(config.get_table("pulse").unwrap_or_default())?;
+        instance_config.jack =
+            JackConfig::from_table(config.get_table("jack").unwrap_or_default())?;
+
+        instance_config.hooks =
+            HooksConfig::from_table(config.get_table("hooks").unwrap_or_default())?;
+
+        instance_config.guest_actions =
+            GuestActionsConfig::from_table(config.get_table("guest-actions").unwrap_or_default())?;
+
+        instance_config.clipboard =
+            ClipboardConfig::from_table(config.get_table("clipboard").unwrap_or_default())?;
+
+        instance_config.net = NetConfig::from_table(config.get_table("net").unwrap_or_default())?;
+        instance_config.smbios =
+            SmbiosConfig::from_table(config.get_table("smbios").unwrap_or_default())?;
+
+        instance_config.guest_agent =
+            GuestAgentConfig::from_table(config.get_table("guest-agent").unwrap_or_default())?;
+
+        instance_config.tpm = TpmConfig::from_table(config.get_table("tpm").unwrap_or_default())?;
+        instance_config.sev = SevConfig::from_table(config.get_table("sev").unwrap_or_default())?;
+
+        instance_config.health =
+            HealthConfig::from_table(config.get_table("health").unwrap_or_default())?;
+
+        instance_config.sandbox =
+            SandboxConfig::from_table(config.get_table("sandbox").unwrap_or_default())?;
+
+        instance_config.input =
+            InputConfig::from_table(config.get_table("input").unwrap_or_default())?;
+
+        instance_config.disk_space =
+            DiskSpaceConfig::from_table(config.get_table("disk-space").unwrap_or_default())?;
+
+        instance_config.video =
+            VideoConfig::from_table(config.get_table("video").unwrap_or_default())?;
 
         if let Ok(features) = config.get::<Vec<String>>("machine.features") {
             for feature in features {
@@ -98,13 +574,361 @@ impl InstanceConfig {
                     "scream" => instance_config.scream.enabled = true,
                     "uefi" => instance_config.uefi.enabled = true,
                     "pulse" => instance_config.pulse.enabled = true,
+                    "jack" => instance_config.jack.enabled = true,
+                    "guest-actions" => instance_config.guest_actions.enabled = true,
+                    "clipboard" => instance_config.clipboard.enabled = true,
+                    "guest-agent" => instance_config.guest_agent.enabled = true,
+                    "tpm" => instance_config.tpm.enabled = true,
+                    "sev" => instance_config.sev.enabled = true,
+                    "health" => instance_config.health.enabled = true,
+                    "sandbox" => instance_config.sandbox.enabled = true,
                     _ => {}
                 }
             }
         }
 
+        instance_config.check_machine_type()?;
+
+        if instance_config.jack.enabled && instance_config.pulse.enabled {
+            anyhow::bail!(
+                "jack can't be used together with pulse, the guest gets one audio device"
+            );
+        }
+
+        if instance_config.video.model.is_vga() && instance_config.vfio.iter().any(|x| x.graphics) {
+            anyhow::bail!(
+                "video.model = \"{}\" can't be used with a [[vfio]] device with graphics, both would be the VGA of the guest, use virtio-gpu or none",
+                instance_config.video.model
+            );
+        }
+
+        for seat in &instance_config.seats {
+            if seat.looking_glass && !instance_config.looking_glass.enabled {
+                anyhow::bail!(
+                    "seat '{}' has looking-glass, but looking-glass isn't enabled for the VM",
+                    seat.name
+                );
+            }
+
+            if seat.spice && (!instance_config.spice.enabled || instance_config.spice.is_tcp()) {
+                anyhow::bail!(
+                    "seat '{}' has spice, which needs spice to be enabled on a socket, not on TCP",
+                    seat.name
+                );
+            }
+        }
+
+        if instance_config.sev.enabled && !instance_config.uefi.enabled {
+            anyhow::bail!("sev needs uefi, only OVMF can boot an encrypted guest");
+        }
+
+        if instance_config.clipboard.enabled && instance_config.spice.enabled {
+            anyhow::bail!("clipboard can't be used together with spice, SPICE clients already share the clipboard");
+        }
+
+        if instance_config.spice.enabled
+            && instance_config.spice.is_tcp()
+            && instance_config.sandbox.enabled
+            && instance_config.sandbox.network
+        {
+            anyhow::bail!("spice can't listen on TCP with sandbox.network, nothing outside the sandbox can reach it");
+        }
+
         Ok(instance_config)
     }
+
+    /// Check that everything the VM has fits on its machine type
+    fn check_machine_type(&self) -> Result<(), anyhow::Error> {
+        let machine_type = self.machine_type;
+        let x86 = self.arch == "x86_64";
+        if x86 == (machine_type == MachineType::Virt) {
+            anyhow::bail!(
+                "machine.chipset {} can't be used for {} guests",
+                self.chipset,
+                self.arch
+            );
+        }
+
+        if self.kvm && self.arch != std::env::consts::ARCH {
+            anyhow::bail!(
+                "machine.kvm can only be used for {} guests on this host, {} guests are emulated",
+                std::env::consts::ARCH,
+                self.arch
+            );
+        }
+
+        if !x86 {
+            let unsupported = [
+                ("sev", self.sev.enabled),
+                ("machine.stealth", self.stealth),
+            ];
+            if let Some((name, _)) = unsupported.iter().find(|(_, used)| *used) {
+                anyhow::bail!("{} can only be used for x86_64 guests", name);
+            }
+        }
+        if self.pci_hotplug_slots > 0 && !machine_type.has_pcie() {
+            anyhow::bail!(
+                "machine.pci-hotplug-slots needs PCIe root ports, which {} doesn't have, only q35 does",
+                machine_type
+            );
+        }
+
+        let ide = self
+            .disks
+            .iter()
+            .filter(|x| x.preset == "iso" || x.preset == "ide")
+            .count();
+        if machine_type == MachineType::I440fx && ide > 2 {
+            anyhow::bail!(
+                "i440fx has room for 2 IDE (iso or ide) disks in vore's layout, {} are defined",
+                ide
+            );
+        }
+
+        if machine_type != MachineType::Microvm {
+            return Ok(());
+        }
+
+        // microvm has no PCI bus, no firmware but qboot and no legacy devices
+        let unsupported = [
+            ("[[vfio]]", !self.vfio.is_empty()),
+            ("[[mdev]]", !self.mdev.is_empty()),
+            ("[[usb]]", !self.usb.is_empty()),
+            ("uefi", self.uefi.enabled),
+            ("looking-glass", self.looking_glass.enabled),
+            (
+                "scream over ivshmem",
+                self.scream.enabled && self.scream.mode == ScreamMode::Ivshmem,
+            ),
+            ("tpm", self.tpm.enabled),
+            ("iso and ide disks", ide > 0),
+            (
+                "video.model other than none",
+                !matches!(self.video.model, VideoModel::Default | VideoModel::None),
+            ),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, used)| *used) {
+            anyhow::bail!("{} can't be used with microvm", name);
+        }
+
+        Ok(())
+    }
+
+    /// Name of the [uefi.<name>] firmware in the global config for this guest, OVMF is the default
+    pub fn uefi_firmware(&self) -> &str {
+        if self.arch == "x86_64" {
+            "default"
+        } else {
+            &self.arch
+        }
+    }
+
+    /// Check if [device] is something the guest can boot from
+    pub fn check_boot_device(&self, device: &str) -> Result<(), anyhow::Error> {
+        match device {
+            "net" => Ok(()),
+            "cdrom" if self.disks.iter().any(|x| x.preset == "iso") => Ok(()),
+            "cdrom" => anyhow::bail!("Can't boot from cdrom, there's no disk with the iso preset"),
+            device => match device
+                .strip_prefix("disk")
+                .and_then(|x| x.parse::<usize>().ok())
+            {
+                Some(index) if index < self.disks.len() => Ok(()),
+                Some(_) => anyhow::bail!(
+                    "Can't boot from {}, there are only {} disks",
+                    device,
+                    self.disks.len()
+                ),
+                None => anyhow::bail!(
+                    "Unknown boot device {}, should be diskN, cdrom or net",
+                    device
+                ),
+            },
+        }
+    }
+
+    /// Set the boot indexes of the devices in machine.boot-order, with [first] booted before them
+    ///
+    /// Without a boot order the disks follow [first] in the order they're defined,
+    /// and if neither is given no boot indexes are set at all
+    pub fn apply_boot_order(&mut self, first: Option<&str>) -> Result<(), anyhow::Error> {
+        let mut order = vec![];
+        if let Some(first) = first {
+            self.check_boot_device(first)?;
+            order.push(first.to_string());
+            if self.boot_order.is_empty() {
+                order.extend((0..self.disks.len()).map(|x| format!("disk{}", x)));
+            }
+        }
+
+        order.extend(self.boot_order.iter().cloned());
+
+        let mut index = 0;
+        for device in order {
+            let target = match device.as_str() {
+                "net" => Some(&mut self.net.boot_index),
+                "cdrom" => self
+                    .disks
+                    .iter_mut()
+                    .find(|x| x.preset == "iso")
+                    .map(|x| &mut x.boot_index),
+                device => device
+                    .strip_prefix("disk")
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .and_then(|x| self.disks.get_mut(x))
+                    .map(|x| &mut x.boot_index),
+            };
+
+            // The same device can be in the order twice, e.g. as cdrom and disk1
+            if let Some(target) = target.filter(|x| x.is_none()) {
+                *target = Some(index);
+                index += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attach [cdroms] as read-only CD-ROM drives, and set the boot indexes like [apply_boot_order]
+    ///
+    /// The attached CD-ROMs boot after the devices in the boot order, unless one of them is booted [first] as cdrom
+    pub fn apply_boot(
+        &mut self,
+        cdroms: &[String],
+        first: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let configured = self.disks.len();
+        self.disks.extend(cdroms.iter().map(|path| DiskConfig {
+            disk_type: "raw".to_string(),
+            preset: "iso".to_string(),
+            path: path.clone(),
+            read_only: true,
+            shared: false,
+            create_if_missing: false,
+            size: None,
+            preallocation: None,
+            boot_index: None,
+        }));
+
+        self.apply_boot_order(first)?;
+
+        // With strict boot, devices without a boot index can't be booted at all
+        let next = self
+            .disks
+            .iter()
+            .filter_map(|x| x.boot_index)
+            .chain(self.net.boot_index)
+            .max();
+        if let Some(mut next) = next {
+            for disk in self.disks[configured..]
+                .iter_mut()
+                .filter(|x| x.boot_index.is_none())
+            {
+                next += 1;
+                disk.boot_index = Some(next);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The effective config as TOML, with the names of the fields instead of the definition keys
+    pub fn to_toml(&self) -> Result<String, anyhow::Error> {
+        // TOML has no null, and going through a Value puts the plain values before the tables
+        let mut json = serde_json::to_value(self)?;
+        strip_nulls(&mut json);
+        let value = toml::Value::try_from(json)?;
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    /// Why this VM can't run at the same time as [other], if it can't
+    ///
+    /// Either of them may declare the other in machine.conflicts, besides that they conflict when they
+    /// pass through the same PCI or mediated device, or use the same disk without both reading it only
+    /// or both sharing it
+    pub fn conflict_with(&self, other: &InstanceConfig) -> Option<String> {
+        if self.conflicts.contains(&other.name) || other.conflicts.contains(&self.name) {
+            return Some("they're marked as conflicting in machine.conflicts".to_string());
+        }
+
+        if let Some(vfio) = self
+            .vfio
+            .iter()
+            .find(|x| other.vfio.iter().any(|y| x.device_key() == y.device_key()))
+        {
+            return Some(format!("both pass through PCI device {}", vfio.address));
+        }
+
+        if let Some(mdev) = self
+            .mdev
+            .iter()
+            .find(|x| x.uuid.is_some() && other.mdev.iter().any(|y| x.uuid == y.uuid))
+        {
+            return Some(format!(
+                "both use mediated device {}",
+                mdev.uuid.as_deref().unwrap_or_default()
+            ));
+        }
+
+        let disk_path = |disk: &DiskConfig| {
+            std::fs::canonicalize(&disk.path)
+                .unwrap_or_else(|_| Path::new(&disk.path).to_path_buf())
+        };
+        if let Some(disk) = self.disks.iter().find(|x| {
+            other
+                .disks
+                .iter()
+                .filter(|y| !((x.read_only && y.read_only) || (x.shared && y.shared)))
+                .any(|y| disk_path(x) == disk_path(y))
+        }) {
+            return Some(format!("both use disk {}", disk.path));
+        }
+
+        None
+    }
+
+    /// Make sure the NUMA nodes add up to the vCPU's and memory of the VM,
+    /// nodes without memory get an equal share of what's left
+    fn check_numa(&mut self) -> Result<(), anyhow::Error> {
+        let cpus = self.numa.iter().map(|x| x.cpus).sum::<u64>();
+        if cpus != self.cpu.amount {
+            anyhow::bail!(
+                "The NUMA nodes have {} vCPU's together, but the VM has {}",
+                cpus,
+                self.cpu.amount
+            );
+        }
+
+        let assigned = self.numa.iter().filter_map(|x| x.memory).sum::<u64>();
+        let unassigned = self.numa.iter().filter(|x| x.memory.is_none()).count() as u64;
+        if assigned > self.memory || (unassigned == 0 && assigned != self.memory) {
+            anyhow::bail!(
+                "The NUMA nodes have {}M of memory together, but the VM has {}M",
+                assigned,
+                self.memory
+            );
+        }
+
+        if unassigned > 0 {
+            let share = (self.memory - assigned) / unassigned;
+            if share == 0 {
+                anyhow::bail!("There's no memory left for the NUMA nodes that don't specify any");
+            }
+
+            // The last node gets what's left after rounding
+            let mut left = self.memory - assigned;
+            let last = self.numa.iter().rposition(|x| x.memory.is_none());
+            for (i, node) in self.numa.iter_mut().enumerate() {
+                if node.memory.is_none() {
+                    let memory = if Some(i) == last { left } else { share };
+                    node.memory = Some(memory);
+                    left -= memory;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for InstanceConfig {
@@ -112,23 +936,92 @@ impl Default for InstanceConfig {
         InstanceConfig {
             name: "vore".to_string(),
             arch: std::env::consts::ARCH.to_string(),
-            chipset: "q35".to_string(),
+            chipset: MachineType::default_for(std::env::consts::ARCH).to_string(),
+            machine_type: MachineType::default_for(std::env::consts::ARCH),
             kvm: true,
+            allow_tcg: false,
+            stealth: false,
+            hide_kvm: true,
+            timezone: None,
             auto_start: false,
-            // 2 GB
-            memory: 2 * 1024 * 1024 * 1024,
+            auto_start_delay: 0,
+            auto_start_order: 0,
+            requires_host: vec![],
+            requires_host_timeout: 300,
+            pci_hotplug_slots: 0,
+            restart: RestartPolicy::Never,
+            standby: false,
+            conflicts: vec![],
+            keep_shm: false,
+            restart_max_retries: 3,
+            restart_backoff: 5,
+            // 2 GB, in MiB like machine.memory
+            memory: 2 * 1024,
+            balloon: true,
+            limits: Default::default(),
             cpu: Default::default(),
             disks: vec![],
+            boot_order: vec![],
             uefi: Default::default(),
             vfio: vec![],
+            mdev: vec![],
+            usb: vec![],
+            numa: vec![],
             looking_glass: Default::default(),
             scream: Default::default(),
             pulse: Default::default(),
+            jack: Default::default(),
             spice: Default::default(),
+            video: Default::default(),
+            seats: vec![],
+            hooks: Default::default(),
+            guest_actions: Default::default(),
+            clipboard: Default::default(),
+            guest_agent: Default::default(),
+            tpm: Default::default(),
+            sev: Default::default(),
+            net: Default::default(),
+            smbios: Default::default(),
+            health: Default::default(),
+            sandbox: Default::default(),
+            input: Default::default(),
+            disk_space: Default::default(),
+            extra_args: vec![],
+            env: BTreeMap::new(),
+            run_as: None,
+            groups: None,
+            deprecations: vec![],
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Only when QEMU exits without being asked to
+    OnFailure,
+    /// Also when the guest shuts down by itself
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "never" => RestartPolicy::Never,
+            "on-failure" => RestartPolicy::OnFailure,
+            "always" => RestartPolicy::Always,
+            _ => anyhow::bail!(
+                "'{}' is not a valid restart policy (never, on-failure or always)",
+                s
+            ),
+        })
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct CpuConfig {
     pub amount: u64,
@@ -136,6 +1029,18 @@ pub struct CpuConfig {
     pub threads: u64,
     pub dies: u64,
     pub sockets: u64,
+    /// QEMU CPU model, e.g. "host" or "EPYC-v3"
+    pub model: String,
+    /// Extra CPU flags passed after the model, e.g. "+topoext", "-hypervisor" or "pmu=off"
+    pub flags: Vec<String>,
+    /// Hyper-V vendor id the guest sees instead of "Microsoft Hv", at most 12 characters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor_id: Option<String>,
+    /// Host CPU's the vCPU's are pinned to, in the order of the vCPU's, picked automatically if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin: Option<Vec<usize>>,
+    /// Keep all other tasks of the host off the CPU's the vCPU's are pinned to while the VM runs
+    pub isolate: bool,
 }
 
 impl Default for CpuConfig {
@@ -146,6 +1051,11 @@ impl Default for CpuConfig {
             threads: 2,
             dies: 1,
             sockets: 1,
+            model: "host".to_string(),
+            flags: vec![],
+            vendor_id: None,
+            pin: None,
+            isolate: false,
         }
     }
 }
@@ -195,6 +1105,78 @@ impl CpuConfig {
             self.sockets = sockets;
         }
 
+        if let Some(model) = table.get("model").cloned() {
+            self.model = model.into_str().context("cpu.model should be a string")?;
+            if self.model.is_empty() || self.model.contains(',') {
+                anyhow::bail!("cpu.model should be a single QEMU CPU model, e.g. \"host\"");
+            }
+        }
+
+        if let Some(flags) = table.get("flags").cloned() {
+            self.flags = flags
+                .into_array()
+                .context("cpu.flags should be an array of strings")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("cpu.flags should be an array of strings")?;
+
+            if let Some(flag) = self.flags.iter().find(|x| x.is_empty() || x.contains(',')) {
+                anyhow::bail!(
+                    "cpu.flags should contain one flag per entry, got '{}'",
+                    flag
+                );
+            }
+        }
+
+        if let Some(vendor_id) = table.get("vendor-id").cloned() {
+            let vendor_id = vendor_id
+                .into_str()
+                .context("cpu.vendor-id should be a string")?;
+            if vendor_id.is_empty()
+                || vendor_id.len() > 12
+                || !vendor_id.chars().all(|x| x.is_ascii_graphic() && x != ',')
+            {
+                anyhow::bail!(
+                    "cpu.vendor-id should be 1 to 12 ASCII characters without spaces or commas, got '{}'",
+                    vendor_id
+                );
+            }
+
+            self.vendor_id = Some(vendor_id);
+        }
+
+        if let Some(pin) = table.get("pin").cloned() {
+            // `vore describe` shows the list as an array, so both are accepted
+            let pin = match pin.clone().into_array() {
+                Ok(arr) => arr
+                    .into_iter()
+                    .map(|x| {
+                        x.into_int()
+                            .ok()
+                            .filter(|x| !x.is_negative())
+                            .map(|x| x as usize)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .context(
+                        "cpu.pin should be a CPU list like \"8-15\" or an array of CPU id's",
+                    )?,
+                Err(_) => parse_cpu_list(
+                    &pin.into_str()
+                        .context("cpu.pin should be a CPU list like \"8-15\"")?,
+                )
+                .context("Failed to parse cpu.pin")?,
+            };
+
+            self.pin = Some(pin);
+        }
+
+        if let Some(isolate) = table.get("isolate").cloned() {
+            self.isolate = isolate
+                .into_bool()
+                .context("cpu.isolate should be a boolean")?;
+        }
+
         if !table.contains_key("amount") {
             self.amount = self.sockets * self.dies * self.cores * self.threads;
         } else if table
@@ -212,34 +1194,162 @@ impl CpuConfig {
             self.cores = self.amount;
         }
 
+        if let Some(pin) = &self.pin {
+            self.check_pin(pin)?;
+        }
+
         Ok(())
     }
-}
 
-fn parse_size(orig_input: &str) -> Result<u64, anyhow::Error> {
-    let input = orig_input.to_string().to_lowercase().replace(" ", "");
-    let mut input = input.strip_suffix("b").unwrap_or(&input);
-    let mut modifier: u64 = 1;
+    /// Make sure [pin] has a distinct host CPU for every vCPU
+    pub fn check_pin(&self, pin: &[usize]) -> Result<(), anyhow::Error> {
+        if pin.len() as u64 != self.amount {
+            anyhow::bail!(
+                "{} CPU's are given to pin to, but the VM has {} vCPU's",
+                pin.len(),
+                self.amount
+            );
+        }
 
-    if input.chars().last().unwrap_or('_').is_alphabetic() {
-        modifier = match input.chars().last().unwrap() {
-            'k' => {
-                return Err(anyhow::Error::msg(
-                    "size can only be specified in megabytes or larger",
-                ));
-            }
-            'm' => 1,
-            'g' => 1024,
-            't' => 1024 * 1024,
-            _ => {
-                return Err(anyhow::Error::msg(format!(
-                    "'{}' is not a valid size",
-                    orig_input
-                )));
-            }
-        };
+        if let Some(cpu) = pin
+            .iter()
+            .enumerate()
+            .find(|(i, x)| pin[..*i].contains(x))
+            .map(|(_, x)| x)
+        {
+            anyhow::bail!("CPU {} is given more than once to pin to", cpu);
+        }
 
-        input = &input[..input.len() - 1];
+        Ok(())
+    }
+}
+
+/// Parse a CPU list like "8-15" or "0-3,8,10-11" into CPU id's, in the order given
+pub fn parse_cpu_list(input: &str) -> Result<Vec<usize>, anyhow::Error> {
+    let mut cpus = vec![];
+    for part in input.split(',').map(|x| x.trim()) {
+        let mut range = part.splitn(2, '-');
+        let start = range.next().unwrap_or("");
+        let start = usize::from_str(start)
+            .with_context(|| format!("'{}' is not a valid CPU list", input))?;
+        let end = match range.next() {
+            Some(end) => usize::from_str(end)
+                .with_context(|| format!("'{}' is not a valid CPU list", input))?,
+            None => start,
+        };
+
+        if end < start {
+            anyhow::bail!("'{}' is not a valid CPU list, {} is reversed", input, part);
+        }
+
+        cpus.extend(start..=end);
+    }
+
+    Ok(cpus)
+}
+
+/// Format CPU id's as a CPU list like "8-15", runs of ascending id's are joined into a range
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *cpu => *end = *cpu,
+            _ => ranges.push((*cpu, *cpu)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Set [key] in [table] of the definition [toml] to the string [value], the other keys are kept as is
+///
+/// Comments and formatting of the definition don't survive this
+pub fn set_definition_value(
+    toml: &str,
+    table: &str,
+    key: &str,
+    value: &str,
+) -> Result<String, anyhow::Error> {
+    let mut definition = toml::from_str::<toml::Value>(toml).context("Invalid VM definition")?;
+    let root = definition
+        .as_table_mut()
+        .context("VM definition should be a table")?;
+    let table = root
+        .entry(table.to_string())
+        .or_insert_with(|| toml::Value::Table(Default::default()))
+        .as_table_mut()
+        .with_context(|| format!("{} in the VM definition should be a table", table))?;
+    table.insert(key.to_string(), toml::Value::String(value.to_string()));
+
+    Ok(toml::to_string_pretty(&definition)?)
+}
+
+fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let nulls = map
+                .iter()
+                .filter(|(_, x)| x.is_null())
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in nulls {
+                map.remove(&key);
+            }
+
+            map.values_mut().for_each(strip_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// If [timezone] is utc, localtime or looks like an IANA timezone name
+fn is_valid_timezone(timezone: &str) -> bool {
+    timezone == "utc"
+        || timezone == "localtime"
+        || (timezone
+            .split('/')
+            .all(|x| !x.is_empty() && x != "." && x != "..")
+            && timezone
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || "/_-+".contains(x)))
+}
+
+/// Parse a size like "12G" or "512M" into MiB
+pub fn parse_size(orig_input: &str) -> Result<u64, anyhow::Error> {
+    let input = orig_input.to_string().to_lowercase().replace(" ", "");
+    let mut input = input.strip_suffix("b").unwrap_or(&input);
+    let mut modifier: u64 = 1;
+
+    if input.chars().last().unwrap_or('_').is_alphabetic() {
+        modifier = match input.chars().last().unwrap() {
+            'k' => {
+                return Err(anyhow::Error::msg(
+                    "size can only be specified in megabytes or larger",
+                ));
+            }
+            'm' => 1,
+            'g' => 1024,
+            't' => 1024 * 1024,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "'{}' is not a valid size",
+                    orig_input
+                )));
+            }
+        };
+
+        input = &input[..input.len() - 1];
     }
 
     if input.is_empty() {
@@ -257,11 +1367,17 @@ fn parse_size(orig_input: &str) -> Result<u64, anyhow::Error> {
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct UefiConfig {
     pub enabled: bool,
+    /// Where the UEFI variables of the VM are kept, empty for uefi/OVMF_VARS.fd in the working dir
+    #[serde(default)]
+    pub vars_path: String,
 }
 
 impl Default for UefiConfig {
     fn default() -> Self {
-        UefiConfig { enabled: false }
+        UefiConfig {
+            enabled: false,
+            vars_path: "".to_string(),
+        }
     }
 }
 
@@ -276,6 +1392,20 @@ impl UefiConfig {
             self.enabled = enabled
         }
 
+        if let Some(vars_path) = table
+            .get("vars-path")
+            .cloned()
+            .map(|x| x.into_str().context("uefi.vars-path should be a string"))
+            .transpose()?
+        {
+            // QEMU would take everything after it as options of the pflash drive
+            if vars_path.contains(',') {
+                anyhow::bail!("uefi.vars-path can't contain a comma");
+            }
+
+            self.vars_path = vars_path;
+        }
+
         Ok(())
     }
 }
@@ -283,8 +1413,42 @@ impl UefiConfig {
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ScreamConfig {
     pub enabled: bool,
+    #[serde(default)]
+    pub mode: ScreamMode,
     pub mem_path: String,
     pub buffer_size: u64,
+    /// Host interface the receiver listens on in network mode, all of them if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// UDP port the guest sends to in network mode
+    #[serde(default = "default_scream_port")]
+    pub port: u16,
+}
+
+fn default_scream_port() -> u16 {
+    4010
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScreamMode {
+    /// Audio goes through an IVSHMEM shared memory device
+    #[default]
+    Ivshmem,
+    /// Audio is sent over the NIC of the guest as UDP, no shared memory needed
+    Network,
+}
+
+impl FromStr for ScreamMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ivshmem" => ScreamMode::Ivshmem,
+            "network" => ScreamMode::Network,
+            _ => anyhow::bail!("'{}' is not a valid scream mode (ivshmem or network)", s),
+        })
+    }
 }
 
 impl ScreamConfig {
@@ -294,7 +1458,7 @@ impl ScreamConfig {
             cfg.enabled = enabled.into_bool()?;
         }
 
-        if let Some(mem_path) = table.get("mem-path").cloned() {
+        if let Some(mem_path) = table.get("path").cloned() {
             cfg.mem_path = mem_path.into_str()?;
         }
 
@@ -302,6 +1466,34 @@ impl ScreamConfig {
             cfg.buffer_size = buffer_size.into_int()? as u64;
         }
 
+        if let Some(mode) = table.get("mode").cloned() {
+            cfg.mode = ScreamMode::from_str(
+                &mode
+                    .into_str()
+                    .context("scream.mode should be ivshmem or network")?,
+            )?;
+        }
+
+        if let Some(interface) = table.get("interface").cloned() {
+            cfg.interface = Some(
+                interface
+                    .into_str()
+                    .context("scream.interface should be a string")?,
+            );
+        }
+
+        if let Some(port) = table.get("port").cloned() {
+            cfg.port = port
+                .into_int()
+                .ok()
+                .filter(|x| *x > 0 && *x <= u16::MAX as i64)
+                .context("scream.port should be a port number")? as u16;
+        }
+
+        if cfg.mode == ScreamMode::Network && !cfg.mem_path.is_empty() {
+            anyhow::bail!("scream.path can't be used with scream.mode = \"network\"");
+        }
+
         Ok(cfg)
     }
 }
@@ -310,8 +1502,11 @@ impl Default for ScreamConfig {
     fn default() -> Self {
         ScreamConfig {
             enabled: false,
+            mode: ScreamMode::Ivshmem,
             mem_path: "".to_string(),
             buffer_size: 2097152,
+            interface: None,
+            port: default_scream_port(),
         }
     }
 }
@@ -324,6 +1519,18 @@ pub struct LookingGlassConfig {
     pub width: u64,
     pub height: u64,
     pub bit_depth: u64,
+    /// Modes the guest is offered through a generated EDID, the first one is preferred
+    #[serde(default)]
+    pub resolutions: Vec<Resolution>,
+    /// Where the generated EDID is written, set on prepare if there are resolutions
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub edid_path: String,
+    /// Ask the guest agent for the resolution of the guest while it runs, by running a query in the guest
+    #[serde(default)]
+    pub watch_resolution: bool,
+    /// Make the shared memory big enough for the resolution the guest switched to on the next boot
+    #[serde(default)]
+    pub grow: bool,
 }
 
 impl Default for LookingGlassConfig {
@@ -335,6 +1542,10 @@ impl Default for LookingGlassConfig {
             width: 1920,
             height: 1080,
             bit_depth: 8,
+            resolutions: vec![],
+            edid_path: "".to_string(),
+            watch_resolution: false,
+            grow: false,
         }
     }
 }
@@ -379,10 +1590,42 @@ impl LookingGlassConfig {
             cfg.enabled = enabled.into_bool()?;
         }
 
-        if let Some(mem_path) = table.get("mem-path").cloned() {
+        if let Some(mem_path) = table.get("path").cloned() {
             cfg.mem_path = mem_path.into_str()?;
         }
 
+        if let Some(watch_resolution) = table.get("watch-resolution").cloned() {
+            cfg.watch_resolution = watch_resolution
+                .into_bool()
+                .context("looking-glass.watch-resolution should be a boolean")?;
+        }
+
+        if let Some(grow) = table.get("grow").cloned() {
+            cfg.grow = grow
+                .into_bool()
+                .context("looking-glass.grow should be a boolean")?;
+        }
+
+        if cfg.grow && !cfg.watch_resolution {
+            anyhow::bail!("looking-glass.grow needs watch-resolution, to know what to grow to");
+        }
+
+        if let Some(resolutions) = table.get("resolutions").cloned() {
+            cfg.resolutions = resolutions
+                .into_array()
+                .context("looking-glass.resolutions should be a list")?
+                .into_iter()
+                .map(|x| {
+                    x.into_str()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|x| Resolution::from_str(&x))
+                })
+                .collect::<Result<_, _>>()
+                .context(
+                    "looking-glass.resolutions should be a list of resolutions like 2560x1440@120",
+                )?;
+        }
+
         match (table.get("buffer-size").cloned(), table.get("width").cloned(), table.get("height").cloned()) {
             (Some(buffer_size), None, None) => {
                 cfg.set_buffer_size(buffer_size.into_int()? as u64);
@@ -399,6 +1642,12 @@ impl LookingGlassConfig {
             }
 
             (None, None, None) => {
+                // The buffer has to fit the largest mode the guest can pick
+                if let Some(largest) = cfg.resolutions.iter().max_by_key(|x| x.pixels()) {
+                    cfg.width = largest.width;
+                    cfg.height = largest.height;
+                }
+
                 cfg.calc_buffer_size_from_screen()
             }
 
@@ -415,6 +1664,21 @@ pub struct DiskConfig {
     pub preset: String,
     pub path: String,
     pub read_only: bool,
+    /// Let other VM's use this disk at the same time, for raw disks with a cluster filesystem on them
+    #[serde(default)]
+    pub shared: bool,
+    /// Create the image with qemu-img on prepare if it doesn't exist, [size] is then required
+    #[serde(default)]
+    pub create_if_missing: bool,
+    /// Size in MiB of the image when it's created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// qemu-img preallocation mode of the image when it's created (off or metadata)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preallocation: Option<String>,
+    /// Set from the boot order right before the QEMU command is built, never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_index: Option<u32>,
 }
 
 impl DiskConfig {
@@ -450,17 +1714,318 @@ impl DiskConfig {
             .context("Failed to read read-only as boolean from config")?
             .unwrap_or(false);
 
+        let shared = table
+            .get("shared")
+            .cloned()
+            .map(|x| x.into_bool())
+            .transpose()
+            .context("Failed to read shared as boolean from config")?
+            .unwrap_or(false);
+        if shared && disk_type != "raw" {
+            anyhow::bail!(
+                "Disk {} is shared, but only raw disks can be, a {} image can't be written by more than one VM",
+                path,
+                disk_type
+            );
+        }
+
+        let create_if_missing = table
+            .get("create-if-missing")
+            .cloned()
+            .map(|x| x.into_bool())
+            .transpose()
+            .context("Failed to read create-if-missing as boolean from config")?
+            .unwrap_or(false);
+
+        let size = table
+            .get("size")
+            .cloned()
+            .map(|x| {
+                x.into_str()
+                    .context("disk.size should be a string or number")
+                    .and_then(|x| parse_size(&x))
+            })
+            .transpose()?;
+
+        let preallocation = table
+            .get("preallocation")
+            .cloned()
+            .map(|x| x.into_str())
+            .transpose()
+            .context("disk.preallocation should be a string")?;
+
+        if create_if_missing {
+            if size.is_none() {
+                anyhow::bail!("Disk {} has create-if-missing set, but no size", path);
+            }
+
+            if path.starts_with("/dev") {
+                anyhow::bail!("Disk {} is a device, it can't be created", path);
+            }
+
+            check_disk_image_options(&disk_type, preallocation.as_deref())
+                .with_context(|| format!("Disk {} can't be created", path))?;
+
+            // vored creates it while it prepares the VM, and can't wait that long
+            if let Some(mode) = preallocation
+                .as_deref()
+                .filter(|x| SLOW_PREALLOCATION_MODES.contains(x))
+            {
+                anyhow::bail!(
+                    "Disk {} can't be created with {} preallocation on prepare, create it with `vore disk create` instead",
+                    path,
+                    mode
+                );
+            }
+        }
+
         let disk = DiskConfig {
             disk_type,
             preset,
             path,
             read_only,
+            shared,
+            create_if_missing,
+            size,
+            preallocation,
+            boot_index: None,
         };
 
         Ok(disk)
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NumaNodeConfig {
+    /// Amount of vCPU's in this node, vCPU's are assigned to the nodes in order
+    pub cpus: u64,
+    /// Memory of this node in MiB, always set after parsing
+    pub memory: Option<u64>,
+    /// Host NUMA node the memory and vCPU's of this node are bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_node: Option<usize>,
+}
+
+impl NumaNodeConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<NumaNodeConfig, anyhow::Error> {
+        let cpus = table
+            .get("cpus")
+            .cloned()
+            .context("Every NUMA node needs an amount of cpus")?
+            .into_int()
+            .ok()
+            .filter(|x| *x > 0)
+            .context("cpus should be a positive number")? as u64;
+
+        let memory = table
+            .get("memory")
+            .cloned()
+            .map(|x| {
+                x.into_str()
+                    .context("memory should be a string or number")
+                    .and_then(|x| parse_size(&x))
+            })
+            .transpose()?;
+
+        let host_node = table
+            .get("host-node")
+            .cloned()
+            .map(|x| {
+                x.into_int()
+                    .ok()
+                    .filter(|x| *x >= 0)
+                    .context("host-node should be the number of a host NUMA node")
+            })
+            .transpose()?
+            .map(|x| x as usize);
+
+        Ok(NumaNodeConfig {
+            cpus,
+            memory,
+            host_node,
+        })
+    }
+}
+
+/// USB device of the host passed through with usb-host, selected by its ids or where it's plugged in
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UsbConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bus: Option<u32>,
+    /// Port on the bus, with the ports of the hubs in between separated by dots, e.g. 2.1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+}
+
+impl UsbConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<UsbConfig, anyhow::Error> {
+        let id = |name: &str| -> Result<Option<u16>, anyhow::Error> {
+            table
+                .get(name)
+                .cloned()
+                .map(|x| {
+                    x.into_int()
+                        .ok()
+                        .filter(|x| *x >= 0 && *x <= 0xffff)
+                        .map(|x| x as u16)
+                        .with_context(|| {
+                            format!("usb.{} should be an id between 0 and 0xffff", name)
+                        })
+                })
+                .transpose()
+        };
+
+        let mut cfg = UsbConfig {
+            vendor: id("vendor")?,
+            product: id("product")?,
+            ..Default::default()
+        };
+
+        if let Some(bus) = table.get("bus").cloned() {
+            cfg.bus = Some(
+                bus.into_int()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .context("usb.bus should be a bus number, starting at 1")?
+                    as u32,
+            );
+        }
+
+        if let Some(port) = table.get("port").cloned() {
+            let port = port.into_str().context("usb.port should be a string")?;
+            UsbConfig::check_port(&port)?;
+            cfg.port = Some(port);
+        }
+
+        match (&cfg.vendor, &cfg.product, &cfg.bus, &cfg.port) {
+            (Some(_), Some(_), None, None) | (None, None, Some(_), Some(_)) => Ok(cfg),
+            _ => anyhow::bail!(
+                "USB devices need either vendor and product or bus and port to be set"
+            ),
+        }
+    }
+
+    fn check_port(port: &str) -> Result<(), anyhow::Error> {
+        if port.is_empty()
+            || port
+                .split('.')
+                .any(|x| x.is_empty() || !x.chars().all(|x| x.is_ascii_digit()))
+        {
+            anyhow::bail!(
+                "usb.port should be the port numbers separated by dots, e.g. \"2.1\", got '{}'",
+                port
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Name of the device in /sys/bus/usb/devices, e.g. 1-2.1
+    pub fn find_device(&self) -> Result<String, anyhow::Error> {
+        if let (Some(bus), Some(port)) = (self.bus, &self.port) {
+            let name = format!("{}-{}", bus, port);
+            if !Path::new("/sys/bus/usb/devices").join(&name).exists() {
+                anyhow::bail!(
+                    "There's no USB device plugged into port {} of bus {}",
+                    port,
+                    bus
+                );
+            }
+
+            return Ok(name);
+        }
+
+        let (vendor, product) = (self.vendor.unwrap_or(0), self.product.unwrap_or(0));
+        let read_id = |device: &Path, file: &str| {
+            std::fs::read_to_string(device.join(file))
+                .ok()
+                .and_then(|x| u16::from_str_radix(x.trim(), 16).ok())
+        };
+
+        let mut found = vec![];
+        for entry in std::fs::read_dir("/sys/bus/usb/devices")? {
+            let path = entry?.path();
+            if read_id(&path, "idVendor") == Some(vendor)
+                && read_id(&path, "idProduct") == Some(product)
+            {
+                found.push(path.file_name().unwrap().to_string_lossy().to_string());
+            }
+        }
+
+        found.sort();
+        match found.len() {
+            0 => anyhow::bail!(
+                "Can't find a USB device with vendor id {:04x} and product id {:04x}",
+                vendor,
+                product
+            ),
+            1 => Ok(found.remove(0)),
+            _ => anyhow::bail!(
+                "There are {} USB devices with vendor id {:04x} and product id {:04x} ({}), set bus and port instead",
+                found.len(),
+                vendor,
+                product,
+                found.join(", ")
+            ),
+        }
+    }
+}
+
+/// Parses the same notation as it's displayed in, vendor:product (in hex) or bus-port
+impl FromStr for UsbConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        if let (Some(vendor), Some(product)) = (parts.next(), parts.next()) {
+            let id = |x: &str| {
+                u16::from_str_radix(x, 16)
+                    .with_context(|| format!("'{}' isn't a hexadecimal USB id", x))
+            };
+
+            return Ok(UsbConfig {
+                vendor: Some(id(vendor)?),
+                product: Some(id(product)?),
+                ..Default::default()
+            });
+        }
+
+        let mut parts = s.splitn(2, '-');
+        if let (Some(bus), Some(port)) = (parts.next(), parts.next()) {
+            let bus = bus
+                .parse::<u32>()
+                .ok()
+                .filter(|x| *x > 0)
+                .with_context(|| format!("'{}' isn't a USB bus number", bus))?;
+            UsbConfig::check_port(port)?;
+            return Ok(UsbConfig {
+                bus: Some(bus),
+                port: Some(port.to_string()),
+                ..Default::default()
+            });
+        }
+
+        anyhow::bail!(
+            "'{}' isn't a USB device, use vendor:product (e.g. 046d:c52b) or bus-port (e.g. 1-2.1)",
+            s
+        )
+    }
+}
+
+impl Display for UsbConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.vendor, self.product, self.bus, &self.port) {
+            (Some(vendor), Some(product), _, _) => write!(f, "{:04x}:{:04x}", vendor, product),
+            (_, _, Some(bus), Some(port)) => write!(f, "{}-{}", bus, port),
+            _ => write!(f, "unknown"),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct VfioConfig {
     pub address: PciAddress,
@@ -469,7 +2034,34 @@ pub struct VfioConfig {
     pub index: u32,
     pub graphics: bool,
     pub multifunction: bool,
+    /// Bind the device to vfio-pci when vored starts and this VM is saved, so no host driver
+    /// claims it first, off by default as the host keeps using the device till the VM is prepared
     pub reserve: bool,
+    /// Give the device back to its host driver when QEMU of this VM goes away
+    pub release: bool,
+    /// vBIOS to expose to the guest instead of the ROM of the device, e.g. a patched dump for a GPU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub romfile: Option<String>,
+    /// Map the ROM in the BAR of the device, turned off for devices whose ROM breaks the guest
+    pub rombar: bool,
+    /// Set when an SR-IOV virtual function of this device should be passed through instead,
+    /// [address] will point to the virtual function once prepared
+    pub physical_function: Option<PciAddress>,
+    /// Which virtual function to pass through, starting at 1
+    pub sriov_vf: u32,
+    /// Amount of virtual functions to create on the physical function
+    pub sriov_vfs: u32,
+}
+
+impl VfioConfig {
+    /// The device this passes through, a virtual function is known by its physical function,
+    /// as [address] only points to it once prepared
+    fn device_key(&self) -> (PciAddress, u32) {
+        (
+            self.physical_function.unwrap_or(self.address),
+            self.sriov_vf,
+        )
+    }
 }
 
 pub fn read_pci_ids(addr: &PciAddress) -> Result<(u32, u32), anyhow::Error> {
@@ -602,6 +2194,12 @@ impl VfioConfig {
             graphics: false,
             multifunction: false,
             reserve: false,
+            release: false,
+            romfile: None,
+            rombar: true,
+            physical_function: None,
+            sriov_vf: 0,
+            sriov_vfs: 0,
         };
 
         if let Some(graphics) = table.get("graphics").cloned() {
@@ -613,7 +2211,119 @@ impl VfioConfig {
         }
 
         if let Some(reserve) = table.get("reserve").cloned() {
-            cfg.reserve = reserve.into_bool()?;
+            cfg.reserve = reserve
+                .into_bool()
+                .context("vfio.reserve should be a boolean")?;
+        }
+
+        if let Some(release) = table.get("release").cloned() {
+            cfg.release = release.into_bool()?;
+        }
+
+        if let Some(romfile) = table.get("romfile").cloned() {
+            let romfile = romfile
+                .into_str()
+                .context("vfio.romfile should be a path")?;
+            if romfile.is_empty() || romfile.contains(',') {
+                anyhow::bail!(
+                    "vfio.romfile can't be empty or contain a comma, got '{}'",
+                    romfile
+                );
+            }
+
+            cfg.romfile = Some(romfile);
+        }
+
+        if let Some(rombar) = table.get("rombar").cloned() {
+            cfg.rombar = rombar
+                .into_bool()
+                .context("vfio.rombar should be a boolean")?;
+        }
+
+        if let Some(sriov_vf) = table.get("sriov-vf").cloned() {
+            cfg.sriov_vf = sriov_vf.into_int()? as u32;
+            if cfg.sriov_vf == 0 {
+                anyhow::bail!("vfio.sriov-vf starts counting at 1");
+            }
+
+            cfg.physical_function = Some(cfg.address);
+            cfg.sriov_vfs = table
+                .get("sriov-vfs")
+                .cloned()
+                .map(|x| x.into_int().map(|x| x as u32))
+                .transpose()?
+                .unwrap_or(cfg.sriov_vf);
+
+            if cfg.sriov_vfs < cfg.sriov_vf {
+                anyhow::bail!(
+                    "vfio.sriov-vf is {}, but only {} virtual functions would be created (vfio.sriov-vfs)",
+                    cfg.sriov_vf,
+                    cfg.sriov_vfs
+                );
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Mediated device created on a parent device (Intel GVT-g, NVIDIA vGPU) and passed through with vfio-pci
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MdevConfig {
+    pub parent: PciAddress,
+    /// One of the mdev_supported_types of the parent, e.g. i915-GVTg_V5_4
+    pub mdev_type: String,
+    /// A new one is picked on prepare if not set
+    pub uuid: Option<String>,
+    /// Let QEMU show what the guest renders on it (display=on)
+    pub display: bool,
+}
+
+impl MdevConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<MdevConfig, anyhow::Error> {
+        let parent = table
+            .get("parent")
+            .cloned()
+            .context("mdev.parent should be set to the PCI address of the parent device")?
+            .into_str()
+            .context("mdev.parent should be a PCI address")?;
+        let mdev_type = table
+            .get("type")
+            .cloned()
+            .context("mdev.type should be set, see mdev_supported_types of the parent device")?
+            .into_str()
+            .context("mdev.type should be a string")?;
+        if mdev_type.is_empty() || mdev_type.contains('/') {
+            anyhow::bail!("mdev.type should be the name of a mediated device type");
+        }
+
+        let mut cfg = MdevConfig {
+            parent: PciAddress::from_str(&parent)
+                .with_context(|| format!("mdev.parent '{}' isn't a PCI address", parent))?,
+            mdev_type,
+            uuid: None,
+            display: false,
+        };
+
+        if let Some(uuid) = table.get("uuid").cloned() {
+            let uuid = uuid
+                .into_str()
+                .context("mdev.uuid should be a string")?
+                .to_lowercase();
+            let groups = uuid.split('-').map(str::len).collect::<Vec<_>>();
+            if groups != [8, 4, 4, 4, 12]
+                || !uuid.chars().all(|x| x == '-' || x.is_ascii_hexdigit())
+            {
+                anyhow::bail!("mdev.uuid should be a UUID, got '{}'", uuid);
+            }
+
+            cfg.uuid = Some(uuid);
+        }
+
+        if let Some(display) = table.get("display").cloned() {
+            cfg.display = display
+                .into_bool()
+                .context("mdev.display should be a boolean")?;
         }
 
         Ok(cfg)
@@ -663,29 +2373,1456 @@ impl PulseConfig {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
-pub struct SpiceConfig {
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JackConfig {
     pub enabled: bool,
-    pub socket_path: String,
+    /// JACK server to connect to, the default server if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Name of the JACK clients QEMU registers, vore-<name> if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    /// Regex of the ports the output of the guest is connected to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_ports: Option<String>,
+    /// Regex of the ports connected to the input of the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_connect_ports: Option<String>,
+    /// User whose JACK (or PipeWire) session QEMU connects to
+    pub user: String,
+    pub user_uid: u32,
 }
 
-impl SpiceConfig {
-    pub fn from_table(table: HashMap<String, Value>) -> Result<SpiceConfig, anyhow::Error> {
-        let mut cfg = SpiceConfig {
+impl Default for JackConfig {
+    fn default() -> Self {
+        JackConfig {
             enabled: false,
-            socket_path: "".to_string(),
-        };
+            server_name: None,
+            client_name: None,
+            connect_ports: None,
+            input_connect_ports: None,
+            user: "#1000".to_string(),
+            user_uid: 1000,
+        }
+    }
+}
+
+impl JackConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<JackConfig, anyhow::Error> {
+        let mut cfg = JackConfig::default();
 
         if let Some(enabled) = table.get("enabled").cloned() {
-            cfg.enabled = enabled.into_bool()?;
+            cfg.enabled = enabled
+                .into_bool()
+                .context("jack.enabled should be a boolean")?;
         }
 
-        if let Some(socket_path) = table.get("socket-path").cloned() {
-            cfg.socket_path = socket_path.into_str()?;
+        for (key, target) in [
+            ("server-name", &mut cfg.server_name),
+            ("client-name", &mut cfg.client_name),
+            ("connect-ports", &mut cfg.connect_ports),
+            ("input-connect-ports", &mut cfg.input_connect_ports),
+        ] {
+            if let Some(value) = table.get(key).cloned() {
+                let value = value
+                    .into_str()
+                    .with_context(|| format!("jack.{} should be a string", key))?;
+                if value.is_empty() {
+                    anyhow::bail!("jack.{} can't be empty", key);
+                }
+
+                *target = Some(value);
+            }
         }
 
-        Ok(cfg)
-    }
+        if let Some(user) = table.get("user").cloned() {
+            cfg.user = user.into_str().context("jack.user should be a string")?;
+        }
+
+        if let Some(number) = cfg.user.strip_prefix('#') {
+            cfg.user_uid = u32::from_str(number)
+                .with_context(|| format!("Couldn't parse {} as number (for jack.user)", number))?;
+        } else {
+            cfg.user_uid = get_uid_by_username(&cfg.user)?;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Display endpoints of a physical seat, so multiple seats on the host can each attach to the VM
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SeatConfig {
+    pub name: String,
+    /// Owner of the endpoints of this seat, they keep the owner they were created with if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_gid: Option<u32>,
+    /// Permissions of the endpoints of this seat
+    pub mode: u32,
+    /// If this seat gets its own looking glass shared memory
+    pub looking_glass: bool,
+    /// /dev/shm/vore/<name>/looking-glass-<seat> if not set
+    pub looking_glass_mem_path: String,
+    /// If this seat gets its own socket to the SPICE server
+    pub spice: bool,
+    /// /var/lib/vore/instance/<name>/spice-<seat>.sock if not set
+    pub spice_socket_path: String,
+}
+
+impl SeatConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<SeatConfig, anyhow::Error> {
+        let name = table
+            .get("name")
+            .cloned()
+            .context("seat.name is required")?
+            .into_str()
+            .context("seat.name should be a string")?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+        {
+            anyhow::bail!(
+                "seat.name should only have letters, numbers, dashes and underscores, not '{}'",
+                name
+            );
+        }
+
+        let mut cfg = SeatConfig {
+            name,
+            user: None,
+            user_uid: None,
+            group: None,
+            group_gid: None,
+            mode: 0o660,
+            looking_glass: false,
+            looking_glass_mem_path: "".to_string(),
+            spice: false,
+            spice_socket_path: "".to_string(),
+        };
+
+        if let Some(user) = table.get("user").cloned() {
+            let user = user.into_str().context("seat.user should be a string")?;
+            cfg.user_uid = Some(if let Some(number) = user.strip_prefix('#') {
+                u32::from_str(number).with_context(|| {
+                    format!("Couldn't parse {} as number (for seat.user)", number)
+                })?
+            } else {
+                get_uid_by_username(&user)?
+            });
+            cfg.user = Some(user);
+        }
+
+        if let Some(group) = table.get("group").cloned() {
+            let group = group.into_str().context("seat.group should be a string")?;
+            cfg.group_gid = Some(if let Some(number) = group.strip_prefix('#') {
+                u32::from_str(number).with_context(|| {
+                    format!("Couldn't parse {} as number (for seat.group)", number)
+                })?
+            } else {
+                get_gid_by_groupname(&group)?
+            });
+            cfg.group = Some(group);
+        }
+
+        if let Some(mode) = table.get("mode").cloned() {
+            let mode = mode.into_str().context("seat.mode should be a string")?;
+            cfg.mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|x| *x <= 0o777)
+                .with_context(|| {
+                    format!(
+                        "seat.mode should be octal permissions, e.g. \"0660\", not '{}'",
+                        mode
+                    )
+                })?;
+        }
+
+        if let Some(looking_glass) = table.get("looking-glass").cloned() {
+            cfg.looking_glass = looking_glass
+                .into_bool()
+                .context("seat.looking-glass should be a boolean")?;
+        }
+
+        if let Some(mem_path) = table.get("looking-glass-path").cloned() {
+            cfg.looking_glass_mem_path = mem_path
+                .into_str()
+                .context("seat.looking-glass-path should be a string")?;
+        }
+
+        if let Some(spice) = table.get("spice").cloned() {
+            cfg.spice = spice
+                .into_bool()
+                .context("seat.spice should be a boolean")?;
+        }
+
+        if let Some(socket_path) = table.get("spice-socket-path").cloned() {
+            cfg.spice_socket_path = socket_path
+                .into_str()
+                .context("seat.spice-socket-path should be a string")?;
+        }
+
+        if !cfg.looking_glass && !cfg.spice {
+            anyhow::bail!(
+                "seat '{}' should have looking-glass or spice, or it has nothing to attach to",
+                cfg.name
+            );
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Guest architectures there's a qemu-system-<arch> for that vore knows how to set up
+pub const SUPPORTED_ARCHES: &[&str] = &["x86_64", "aarch64", "riscv64"];
+
+/// Family of QEMU machine types, which decides what buses and devices the guest can have
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MachineType {
+    /// ICH9 with PCIe
+    #[default]
+    Q35,
+    /// Older PIIX3 with plain PCI, called pc by QEMU
+    I440fx,
+    /// Minimal virtio-mmio machine, without PCI
+    Microvm,
+    /// Generic PCIe machine of aarch64 and riscv64
+    Virt,
+}
+
+impl MachineType {
+    /// The family of [chipset], which may also be a versioned machine type like pc-q35-8.2
+    pub fn from_chipset(chipset: &str) -> Result<MachineType, anyhow::Error> {
+        Ok(match chipset {
+            "q35" => MachineType::Q35,
+            "pc" | "i440fx" => MachineType::I440fx,
+            "microvm" => MachineType::Microvm,
+            "virt" => MachineType::Virt,
+            _ if chipset.starts_with("pc-q35-") => MachineType::Q35,
+            _ if chipset.starts_with("virt-") => MachineType::Virt,
+            _ if chipset.starts_with("pc-i440fx-") => MachineType::I440fx,
+            _ => anyhow::bail!(
+                "'{}' is not a supported machine type (q35, pc/i440fx, microvm or virt, optionally versioned like pc-q35-8.2)",
+                chipset
+            ),
+        })
+    }
+
+    /// What guests of [arch] get if they don't pick a machine.chipset
+    pub fn default_for(arch: &str) -> MachineType {
+        match arch {
+            "x86_64" => MachineType::Q35,
+            _ => MachineType::Virt,
+        }
+    }
+
+    pub fn has_pcie(&self) -> bool {
+        matches!(self, MachineType::Q35 | MachineType::Virt)
+    }
+}
+
+impl Display for MachineType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MachineType::Q35 => "q35",
+            MachineType::I440fx => "i440fx",
+            MachineType::Microvm => "microvm",
+            MachineType::Virt => "virt",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VideoModel {
+    /// QEMU's standard VGA, unless a [[vfio]] device has graphics
+    #[default]
+    Default,
+    /// No display device at all
+    None,
+    Std,
+    Qxl,
+    VirtioGpu,
+    VirtioVga,
+}
+
+impl VideoModel {
+    /// If the device is the VGA of the guest, of which there can only be one
+    pub fn is_vga(&self) -> bool {
+        matches!(
+            self,
+            VideoModel::Std | VideoModel::Qxl | VideoModel::VirtioVga
+        )
+    }
+}
+
+impl Display for VideoModel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VideoModel::Default => "default",
+            VideoModel::None => "none",
+            VideoModel::Std => "std",
+            VideoModel::Qxl => "qxl",
+            VideoModel::VirtioGpu => "virtio-gpu",
+            VideoModel::VirtioVga => "virtio-vga",
+        })
+    }
+}
+
+impl FromStr for VideoModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => VideoModel::Default,
+            "none" => VideoModel::None,
+            "std" => VideoModel::Std,
+            "qxl" => VideoModel::Qxl,
+            "virtio-gpu" => VideoModel::VirtioGpu,
+            "virtio-vga" => VideoModel::VirtioVga,
+            _ => anyhow::bail!(
+                "'{}' is not a valid video model (default, none, std, qxl, virtio-gpu or virtio-vga)",
+                s
+            ),
+        })
+    }
+}
+
+/// Emulated display device of the guest
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VideoConfig {
+    pub model: VideoModel,
+    /// Video memory in MiB, only for std and qxl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vram: Option<u64>,
+    /// Amount of displays, only for qxl, virtio-gpu and virtio-vga
+    pub heads: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            model: VideoModel::Default,
+            vram: None,
+            heads: 1,
+        }
+    }
+}
+
+impl VideoConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<VideoConfig, anyhow::Error> {
+        let mut cfg = VideoConfig::default();
+
+        if let Some(model) = table.get("model").cloned() {
+            cfg.model =
+                VideoModel::from_str(&model.into_str().context("video.model should be a string")?)?;
+        }
+
+        if let Some(vram) = table.get("vram").cloned() {
+            let vram = vram
+                .into_int()
+                .ok()
+                .filter(|x| *x > 0 && (*x as u64).is_power_of_two())
+                .context("video.vram should be a power of 2 in MiB, e.g. 64")?
+                as u64;
+            if !matches!(cfg.model, VideoModel::Std | VideoModel::Qxl) {
+                anyhow::bail!(
+                    "video.vram can only be set for std and qxl, not {}",
+                    cfg.model
+                );
+            }
+
+            cfg.vram = Some(vram);
+        }
+
+        if let Some(heads) = table.get("heads").cloned() {
+            cfg.heads = heads
+                .into_int()
+                .ok()
+                .filter(|x| *x >= 1 && *x <= 16)
+                .context("video.heads should be a number from 1 to 16")?
+                as u32;
+            if cfg.heads > 1
+                && !matches!(
+                    cfg.model,
+                    VideoModel::Qxl | VideoModel::VirtioGpu | VideoModel::VirtioVga
+                )
+            {
+                anyhow::bail!(
+                    "video.heads can only be more than 1 for qxl, virtio-gpu and virtio-vga, not {}",
+                    cfg.model
+                );
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SpiceConfig {
+    pub enabled: bool,
+    pub socket_path: String,
+    /// Address to listen on for TCP, only used if [port] or [tls_port] is set
+    #[serde(default = "default_spice_listen")]
+    pub listen: String,
+    /// TCP port for unencrypted connections, SPICE listens on the unix socket if neither port is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_port: Option<u16>,
+    /// Directory with ca-cert.pem, server-cert.pem and server-key.pem
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x509_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x509_cert_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x509_key_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x509_cacert_file: Option<String>,
+    /// Never sent to clients or Lua, QEMU reads it from [password_path]
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Set when the VM is prepared, if there's a password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jpeg_wan_compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zlib_glz_wan_compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streaming_video: Option<String>,
+    /// Add a port for spice-vdagent in the guest, for the clipboard and resizing the display with the client
+    #[serde(default)]
+    pub agent: bool,
+}
+
+fn default_spice_listen() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl Default for SpiceConfig {
+    fn default() -> Self {
+        SpiceConfig {
+            enabled: false,
+            socket_path: "".to_string(),
+            listen: default_spice_listen(),
+            port: None,
+            tls_port: None,
+            x509_dir: None,
+            x509_cert_file: None,
+            x509_key_file: None,
+            x509_cacert_file: None,
+            password: None,
+            password_path: None,
+            image_compression: None,
+            jpeg_wan_compression: None,
+            zlib_glz_wan_compression: None,
+            streaming_video: None,
+            agent: false,
+        }
+    }
+}
+
+fn spice_option(
+    table: &HashMap<String, Value>,
+    key: &str,
+    allowed: &[&str],
+) -> Result<Option<String>, anyhow::Error> {
+    let value = match table.get(key).cloned() {
+        Some(value) => value
+            .into_str()
+            .with_context(|| format!("spice.{} should be a string", key))?,
+        None => return Ok(None),
+    };
+
+    if !allowed.contains(&value.as_str()) {
+        anyhow::bail!(
+            "spice.{} should be one of {}, got '{}'",
+            key,
+            allowed.join(", "),
+            value
+        );
+    }
+
+    Ok(Some(value))
+}
+
+impl SpiceConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<SpiceConfig, anyhow::Error> {
+        let mut cfg = SpiceConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(socket_path) = table.get("socket-path").cloned() {
+            cfg.socket_path = socket_path.into_str()?;
+        }
+
+        if let Some(listen) = table.get("listen").cloned() {
+            cfg.listen = listen
+                .into_str()
+                .context("spice.listen should be an address")?;
+        }
+
+        for (key, port) in [("port", &mut cfg.port), ("tls-port", &mut cfg.tls_port)] {
+            if let Some(value) = table.get(key).cloned() {
+                *port = Some(
+                    value
+                        .into_int()
+                        .ok()
+                        .filter(|x| *x > 0 && *x <= u16::MAX as i64)
+                        .with_context(|| format!("spice.{} should be a port number", key))?
+                        as u16,
+                );
+            }
+        }
+
+        for (key, path) in [
+            ("x509-dir", &mut cfg.x509_dir),
+            ("x509-cert-file", &mut cfg.x509_cert_file),
+            ("x509-key-file", &mut cfg.x509_key_file),
+            ("x509-cacert-file", &mut cfg.x509_cacert_file),
+            ("password", &mut cfg.password),
+        ] {
+            if let Some(value) = table.get(key).cloned() {
+                *path = Some(
+                    value
+                        .into_str()
+                        .with_context(|| format!("spice.{} should be a string", key))?,
+                );
+            }
+        }
+
+        cfg.image_compression = spice_option(
+            &table,
+            "image-compression",
+            &["auto_glz", "auto_lz", "quic", "glz", "lz", "off"],
+        )?;
+        cfg.jpeg_wan_compression =
+            spice_option(&table, "jpeg-wan-compression", &["auto", "never", "always"])?;
+        cfg.zlib_glz_wan_compression = spice_option(
+            &table,
+            "zlib-glz-wan-compression",
+            &["auto", "never", "always"],
+        )?;
+        cfg.streaming_video = spice_option(&table, "streaming-video", &["off", "all", "filter"])?;
+
+        if let Some(agent) = table.get("agent").cloned() {
+            cfg.agent = agent
+                .into_bool()
+                .context("spice.agent should be a boolean")?;
+        }
+
+        let has_tls_files = cfg.x509_dir.is_some()
+            || cfg.x509_cert_file.is_some()
+            || cfg.x509_key_file.is_some()
+            || cfg.x509_cacert_file.is_some();
+        if cfg.tls_port.is_some() && !has_tls_files {
+            anyhow::bail!("spice.tls-port needs spice.x509-dir, or the x509 files set");
+        }
+
+        if cfg.port.is_some() && cfg.port == cfg.tls_port {
+            anyhow::bail!("spice.port and spice.tls-port can't be the same port");
+        }
+
+        Ok(cfg)
+    }
+
+    /// If SPICE listens on TCP instead of the unix socket
+    pub fn is_tcp(&self) -> bool {
+        self.port.is_some() || self.tls_port.is_some()
+    }
+
+    /// Where SPICE clients connect to, e.g. with remote-viewer
+    pub fn uri(&self) -> String {
+        let host = if self.listen.contains(':') {
+            format!("[{}]", self.listen)
+        } else {
+            self.listen.clone()
+        };
+
+        match (self.port, self.tls_port) {
+            (None, None) => format!("spice+unix://{}", self.socket_path),
+            (Some(port), None) => format!("spice://{}:{}", host, port),
+            (None, Some(tls_port)) => format!("spice://{}?tls-port={}", host, tls_port),
+            (Some(port), Some(tls_port)) => {
+                format!("spice://{}:{}?tls-port={}", host, port, tls_port)
+            }
+        }
+    }
+}
+
+/// Clipboard sharing with the guest's spice-vdagent, for when there's no SPICE client
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ClipboardConfig {
+    pub enabled: bool,
+    pub socket_path: String,
+}
+
+impl ClipboardConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<ClipboardConfig, anyhow::Error> {
+        let mut cfg = ClipboardConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(socket_path) = table.get("socket-path").cloned() {
+            cfg.socket_path = socket_path.into_str()?;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// User-mode (slirp) network of the guest
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NetConfig {
+    /// QEMU NIC model, e1000e is what QEMU adds by default on q35
+    pub model: String,
+    pub ipv4: bool,
+    pub ipv6: bool,
+    /// Address of the IPv6 network the guest gets, e.g. "fd00::"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_prefix_len: Option<u8>,
+    /// Address the guest sees the DNS server at, it forwards to the resolver of the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_dns: Option<String>,
+    /// Search domains handed out over DHCP
+    pub dns_search: Vec<String>,
+    /// Host directory the built-in TFTP server serves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tftp: Option<String>,
+    /// File or URL handed out over DHCP for network boot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootfile: Option<String>,
+    /// TFTP server name handed out over DHCP (option 66), for booting from a netboot server on the network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tftp_server_name: Option<String>,
+    /// Option ROM of the NIC, e.g. an iPXE build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub romfile: Option<String>,
+    /// Set from the boot order right before the QEMU command is built, never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_index: Option<u32>,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig {
+            model: "e1000e".to_string(),
+            ipv4: true,
+            ipv6: true,
+            ipv6_prefix: None,
+            ipv6_prefix_len: None,
+            dns: None,
+            ipv6_dns: None,
+            dns_search: vec![],
+            tftp: None,
+            bootfile: None,
+            tftp_server_name: None,
+            romfile: None,
+            boot_index: None,
+        }
+    }
+}
+
+impl NetConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<NetConfig, anyhow::Error> {
+        let mut cfg = NetConfig::default();
+
+        if let Some(model) = table.get("model").cloned() {
+            cfg.model = model.into_str()?;
+            if cfg.model.is_empty() || cfg.model.contains(',') {
+                anyhow::bail!("net.model should be a QEMU NIC model, e.g. \"virtio-net-pci\"");
+            }
+        }
+
+        if let Some(ipv4) = table.get("ipv4").cloned() {
+            cfg.ipv4 = ipv4.into_bool().context("net.ipv4 should be a boolean")?;
+        }
+
+        if let Some(ipv6) = table.get("ipv6").cloned() {
+            cfg.ipv6 = ipv6.into_bool().context("net.ipv6 should be a boolean")?;
+        }
+
+        if !cfg.ipv4 && !cfg.ipv6 {
+            anyhow::bail!("net.ipv4 and net.ipv6 can't both be disabled");
+        }
+
+        if let Some(prefix) = table.get("ipv6-prefix").cloned() {
+            let prefix = prefix.into_str()?;
+            let mut parts = prefix.splitn(2, '/');
+            let address = parts.next().unwrap_or_default();
+            let len = parts.next();
+
+            address.parse::<Ipv6Addr>().with_context(|| {
+                format!(
+                    "net.ipv6-prefix should be an IPv6 network like fd00::/64, got {}",
+                    prefix
+                )
+            })?;
+            cfg.ipv6_prefix_len = len
+                .map(|x| x.parse::<u8>().ok().filter(|x| (1..=126).contains(x)))
+                .map(|x| {
+                    x.with_context(|| {
+                        format!(
+                            "The length of net.ipv6-prefix should be between 1 and 126, got {}",
+                            prefix
+                        )
+                    })
+                })
+                .transpose()?;
+            cfg.ipv6_prefix = Some(address.to_string());
+        }
+
+        if let Some(dns) = table.get("dns").cloned() {
+            let dns = dns.into_str()?;
+            dns.parse::<Ipv4Addr>()
+                .with_context(|| format!("net.dns should be an IPv4 address, got {}", dns))?;
+            cfg.dns = Some(dns);
+        }
+
+        if let Some(dns) = table.get("ipv6-dns").cloned() {
+            let dns = dns.into_str()?;
+            dns.parse::<Ipv6Addr>()
+                .with_context(|| format!("net.ipv6-dns should be an IPv6 address, got {}", dns))?;
+            cfg.ipv6_dns = Some(dns);
+        }
+
+        if let Some(search) = table.get("dns-search").cloned() {
+            cfg.dns_search = search
+                .into_array()
+                .context("net.dns-search should be an array of strings")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("net.dns-search should be an array of strings")?;
+
+            if let Some(domain) = cfg
+                .dns_search
+                .iter()
+                .find(|x| x.is_empty() || x.contains(',') || x.contains(char::is_whitespace))
+            {
+                anyhow::bail!(
+                    "net.dns-search should contain one domain per entry, got '{}'",
+                    domain
+                );
+            }
+        }
+
+        for (key, target) in [
+            ("tftp", &mut cfg.tftp),
+            ("bootfile", &mut cfg.bootfile),
+            ("tftp-server-name", &mut cfg.tftp_server_name),
+            ("romfile", &mut cfg.romfile),
+        ] {
+            if let Some(value) = table.get(key).cloned() {
+                let value = value
+                    .into_str()
+                    .with_context(|| format!("net.{} should be a string", key))?;
+                if value.is_empty() || value.contains(',') {
+                    anyhow::bail!(
+                        "net.{} can't be empty or contain a comma, got '{}'",
+                        key,
+                        value
+                    );
+                }
+
+                *target = Some(value);
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// DMI data the guest sees, e.g. to keep software that's licensed against it working after moving it into a VM
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct SmbiosConfig {
+    /// System UUID, QEMU makes one up if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// SMBIOS type 0
+    pub bios: BTreeMap<String, String>,
+    /// SMBIOS type 1
+    pub system: BTreeMap<String, String>,
+    /// SMBIOS type 2
+    pub baseboard: BTreeMap<String, String>,
+    /// SMBIOS type 3
+    pub chassis: BTreeMap<String, String>,
+}
+
+const SMBIOS_BIOS_FIELDS: &[&str] = &["vendor", "version", "date", "release"];
+const SMBIOS_SYSTEM_FIELDS: &[&str] = &[
+    "manufacturer",
+    "product",
+    "version",
+    "serial",
+    "sku",
+    "family",
+];
+const SMBIOS_BASEBOARD_FIELDS: &[&str] = &[
+    "manufacturer",
+    "product",
+    "version",
+    "serial",
+    "asset",
+    "location",
+];
+const SMBIOS_CHASSIS_FIELDS: &[&str] = &["manufacturer", "version", "serial", "asset", "sku"];
+
+impl SmbiosConfig {
+    /// The system fields and uuid are set on [smbios] itself, the other types have their own table
+    pub fn from_table(mut table: HashMap<String, Value>) -> Result<SmbiosConfig, anyhow::Error> {
+        let mut cfg = SmbiosConfig::default();
+
+        if let Some(uuid) = table.remove("uuid") {
+            let uuid = uuid.into_str()?;
+            if !is_valid_uuid(&uuid) {
+                anyhow::bail!(
+                    "smbios.uuid should be a UUID like 4c4c4544-0042-3510-8052-b4c04f4e3132, got {}",
+                    uuid
+                );
+            }
+
+            cfg.uuid = Some(uuid);
+        }
+
+        for (name, fields, target) in [
+            ("bios", SMBIOS_BIOS_FIELDS, &mut cfg.bios),
+            ("baseboard", SMBIOS_BASEBOARD_FIELDS, &mut cfg.baseboard),
+            ("chassis", SMBIOS_CHASSIS_FIELDS, &mut cfg.chassis),
+        ] {
+            if let Some(section) = table.remove(name) {
+                let section = section
+                    .into_table()
+                    .with_context(|| format!("smbios.{} should be a table", name))?;
+                *target = smbios_fields(&format!("smbios.{}", name), section, fields)?;
+            }
+        }
+
+        cfg.system = smbios_fields("smbios", table, SMBIOS_SYSTEM_FIELDS)?;
+
+        if let Some(date) = cfg.bios.get("date") {
+            let parts = date.split('/').collect::<Vec<_>>();
+            if parts.len() != 3
+                || parts
+                    .iter()
+                    .any(|x| x.is_empty() || !x.chars().all(|x| x.is_ascii_digit()))
+            {
+                anyhow::bail!("smbios.bios.date should look like 12/31/2020, got {}", date);
+            }
+        }
+
+        if let Some(release) = cfg.bios.get("release") {
+            let parts = release.split('.').collect::<Vec<_>>();
+            if parts.len() != 2 || parts.iter().any(|x| x.parse::<u8>().is_err()) {
+                anyhow::bail!("smbios.bios.release should look like 1.2, got {}", release);
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+fn smbios_fields(
+    prefix: &str,
+    table: HashMap<String, Value>,
+    allowed: &[&str],
+) -> Result<BTreeMap<String, String>, anyhow::Error> {
+    let mut fields = BTreeMap::new();
+    for (key, value) in table {
+        if !allowed.contains(&key.as_str()) {
+            anyhow::bail!(
+                "Unknown field {}.{}, it can have {}",
+                prefix,
+                key,
+                allowed.join(", ")
+            );
+        }
+
+        let value = value
+            .into_str()
+            .with_context(|| format!("{}.{} should be a string", prefix, key))?;
+        if value.is_empty() {
+            anyhow::bail!("{}.{} can't be empty", prefix, key);
+        }
+
+        fields.insert(key, value);
+    }
+
+    Ok(fields)
+}
+
+/// Move the values of renamed keys to their new key, returns which were used
+fn apply_key_aliases(config: &mut Config) -> Result<Vec<Deprecation>, anyhow::Error> {
+    let mut deprecations = vec![];
+    for (old, new) in KEY_ALIASES {
+        let value = match config.get::<Value>(old) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+
+        if config.get::<Value>(new).is_ok() {
+            anyhow::bail!(
+                "{} and {} are the same, {} is deprecated and should be removed",
+                old,
+                new,
+                old
+            );
+        }
+
+        config.set(new, value)?;
+        deprecations.push(Deprecation {
+            key: old.to_string(),
+            replacement: new.to_string(),
+        });
+    }
+
+    Ok(deprecations)
+}
+
+fn is_valid_uuid(uuid: &str) -> bool {
+    let parts = uuid.split('-').map(|x| x.len()).collect::<Vec<_>>();
+    parts == [8, 4, 4, 4, 12] && uuid.chars().all(|x| x == '-' || x.is_ascii_hexdigit())
+}
+
+/// Socket for qemu-ga in the guest, used to set its clock and timezone
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GuestAgentConfig {
+    pub enabled: bool,
+    pub socket_path: String,
+    /// Attach the virtio-win ISO and a script that installs the guest agent and drivers from it
+    #[serde(default)]
+    pub install_media: bool,
+    /// CD-ROM's attached for install_media, set on prepare
+    #[serde(default)]
+    pub install_media_paths: Vec<String>,
+}
+
+impl GuestAgentConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<GuestAgentConfig, anyhow::Error> {
+        let mut cfg = GuestAgentConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(socket_path) = table.get("socket-path").cloned() {
+            cfg.socket_path = socket_path.into_str()?;
+        }
+
+        if let Some(install_media) = table.get("install-media").cloned() {
+            cfg.install_media = install_media
+                .into_bool()
+                .context("guest-agent.install-media should be a boolean")?;
+            // Installing the agent without a channel to talk to it would be pointless
+            cfg.enabled |= cfg.install_media;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// TPM 2.0 emulated by a swtpm process vored runs next to QEMU
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TpmConfig {
+    pub enabled: bool,
+    /// Where swtpm keeps the state of the TPM, /var/lib/vore/instance/<name>/tpm if not set
+    pub state_path: String,
+    /// Control socket of swtpm QEMU connects to, set on prepare
+    pub socket_path: String,
+}
+
+impl TpmConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<TpmConfig, anyhow::Error> {
+        let mut cfg = TpmConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled
+                .into_bool()
+                .context("tpm.enabled should be a boolean")?;
+        }
+
+        if let Some(state_path) = table.get("state-path").cloned() {
+            cfg.state_path = state_path
+                .into_str()
+                .context("tpm.state-path should be a string")?;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Memory encryption of the guest with AMD SEV or SEV-SNP
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct SevConfig {
+    pub enabled: bool,
+    /// Use SEV-SNP instead of plain SEV
+    pub snp: bool,
+    /// Guest policy, 0x1 (no debugging) for SEV and 0x30000 for SEV-SNP if not set
+    pub policy: Option<u64>,
+    /// Position of the encryption bit in the page tables, read from the CPU on prepare if not set
+    pub cbitpos: Option<u32>,
+    /// Physical address bits lost to the encryption, read from the CPU on prepare if not set
+    pub reduced_phys_bits: Option<u32>,
+}
+
+impl SevConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<SevConfig, anyhow::Error> {
+        let mut cfg = SevConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled
+                .into_bool()
+                .context("sev.enabled should be a boolean")?;
+        }
+
+        if let Some(snp) = table.get("snp").cloned() {
+            cfg.snp = snp.into_bool().context("sev.snp should be a boolean")?;
+        }
+
+        if let Some(policy) = table.get("policy").cloned() {
+            cfg.policy = Some(
+                policy
+                    .into_int()
+                    .ok()
+                    .filter(|x| *x >= 0)
+                    .context("sev.policy should be a positive number, e.g. 0x30000")?
+                    as u64,
+            );
+        }
+
+        if let Some(cbitpos) = table.get("cbitpos").cloned() {
+            cfg.cbitpos = Some(
+                cbitpos
+                    .into_int()
+                    .ok()
+                    .filter(|x| *x >= 32 && *x < 64)
+                    .context("sev.cbitpos should be a number from 32 to 63")?
+                    as u32,
+            );
+        }
+
+        if let Some(bits) = table.get("reduced-phys-bits").cloned() {
+            cfg.reduced_phys_bits = Some(
+                bits.into_int()
+                    .ok()
+                    .filter(|x| *x >= 1 && *x <= 63)
+                    .context("sev.reduced-phys-bits should be a number from 1 to 63")?
+                    as u32,
+            );
+        }
+
+        Ok(cfg)
+    }
+
+    /// The policy the guest is launched with
+    pub fn effective_policy(&self) -> u64 {
+        self.policy.unwrap_or(if self.snp { 0x30000 } else { 0x1 })
+    }
+}
+
+/// Probe that takes screenshots of the guest, to catch it hanging with a busy CPU (e.g. boot loops)
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    /// Seconds between screenshots
+    pub interval: u64,
+    /// Seconds the screen has to stay the same while the CPU is busy before the VM counts as stalled
+    pub stall_after: u64,
+    /// Percentage of all vCPU's that counts as busy
+    pub cpu_threshold: u32,
+    /// Kill a stalled VM, so the restart policy can start it again
+    pub restart: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            enabled: false,
+            interval: 30,
+            stall_after: 600,
+            cpu_threshold: 90,
+            restart: true,
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<HealthConfig, anyhow::Error> {
+        let mut cfg = HealthConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(interval) = get_positive_number_from_table(&table, "interval", "health")? {
+            cfg.interval = interval.max(1);
+        }
+
+        if let Some(stall_after) = get_positive_number_from_table(&table, "stall-after", "health")?
+        {
+            cfg.stall_after = stall_after;
+        }
+
+        if let Some(threshold) = get_positive_number_from_table(&table, "cpu-threshold", "health")?
+        {
+            if threshold > 100 {
+                anyhow::bail!("health.cpu-threshold should be a percentage between 0 and 100");
+            }
+
+            cfg.cpu_threshold = threshold as u32;
+        }
+
+        if let Some(restart) = table.get("restart").cloned() {
+            cfg.restart = restart.into_bool()?;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Mount (and network) namespace QEMU is launched in, with only the paths the VM needs mounted
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    /// Also give QEMU its own network namespace, which cuts user-mode networking off from the host
+    pub network: bool,
+    /// Extra paths QEMU can write to
+    pub paths: Vec<String>,
+    /// Extra paths QEMU can only read
+    pub read_only_paths: Vec<String>,
+}
+
+impl SandboxConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<SandboxConfig, anyhow::Error> {
+        let mut cfg = SandboxConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(network) = table.get("network").cloned() {
+            cfg.network = network.into_bool()?;
+        }
+
+        cfg.paths = sandbox_paths(&table, "paths")?;
+        cfg.read_only_paths = sandbox_paths(&table, "read-only-paths")?;
+
+        Ok(cfg)
+    }
+}
+
+fn sandbox_paths(table: &HashMap<String, Value>, key: &str) -> Result<Vec<String>, anyhow::Error> {
+    let paths: Vec<String> = match table.get(key).cloned() {
+        Some(paths) => paths
+            .into_array()
+            .with_context(|| format!("sandbox.{} should be an array of strings", key))?
+            .into_iter()
+            .map(|x| x.into_str())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("sandbox.{} should be an array of strings", key))?,
+        None => return Ok(vec![]),
+    };
+
+    if let Some(path) = paths.iter().find(|x| !x.starts_with('/')) {
+        anyhow::bail!(
+            "sandbox.{} should only contain absolute paths, got '{}'",
+            key,
+            path
+        );
+    }
+
+    Ok(paths)
+}
+
+/// What to do when the filesystems of the VM run low on space while it runs
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LowDiskSpaceAction {
+    /// Log it and run the low-disk-space hook
+    #[default]
+    Warn,
+    /// Also pause the VM, it's resumed once there's enough space again
+    Pause,
+}
+
+impl FromStr for LowDiskSpaceAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "warn" => LowDiskSpaceAction::Warn,
+            "pause" => LowDiskSpaceAction::Pause,
+            _ => anyhow::bail!("'{}' is not a valid disk-space.on-low (warn or pause)", s),
+        })
+    }
+}
+
+/// Free space needed on the filesystems of the working dir and qcow2 images, which grow while the VM runs
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DiskSpaceConfig {
+    /// In MiB, 0 to not check at all
+    pub min_free: u64,
+    pub on_low: LowDiskSpaceAction,
+}
+
+impl DiskSpaceConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<DiskSpaceConfig, anyhow::Error> {
+        let mut cfg = DiskSpaceConfig::default();
+
+        if let Some(min_free) = table.get("min-free").cloned() {
+            cfg.min_free = parse_size(&min_free.into_str()?)
+                .context("disk-space.min-free should be a size, e.g. \"10G\"")?;
+        }
+
+        if let Some(on_low) = table.get("on-low").cloned() {
+            cfg.on_low = LowDiskSpaceAction::from_str(&on_low.into_str()?)?;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Keys QEMU can switch the grab of evdev devices between the host and guest with
+const GRAB_TOGGLES: &[&str] = &[
+    "ctrl-ctrl",
+    "alt-alt",
+    "shift-shift",
+    "meta-meta",
+    "scrolllock",
+    "ctrl-scrolllock",
+];
+
+/// evdev devices of the host (/dev/input/event*) passed through to the guest
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct InputConfig {
+    pub devices: Vec<String>,
+    /// Keys to switch the devices between host and guest with, without it the guest has them for as long as it runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grab_toggle: Option<String>,
+}
+
+impl InputConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<InputConfig, anyhow::Error> {
+        let mut cfg = InputConfig::default();
+
+        if let Some(devices) = table.get("devices").cloned() {
+            cfg.devices = devices
+                .into_array()
+                .context("input.devices should be an array of paths")?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<_, _>>()
+                .context("input.devices should be an array of paths")?;
+        }
+
+        if let Some(device) = cfg.devices.iter().find(|x| !x.starts_with('/')) {
+            anyhow::bail!(
+                "input.devices should only contain absolute paths (e.g. /dev/input/by-id/...-event-kbd), got '{}'",
+                device
+            );
+        }
+
+        if let Some(grab_toggle) = table.get("grab-toggle").cloned() {
+            let grab_toggle = grab_toggle.into_str()?;
+            if !GRAB_TOGGLES.contains(&grab_toggle.as_str()) {
+                anyhow::bail!(
+                    "input.grab-toggle should be one of {}, got '{}'",
+                    GRAB_TOGGLES.join(", "),
+                    grab_toggle
+                );
+            }
+
+            cfg.grab_toggle = Some(grab_toggle);
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Limits applied to the cgroup QEMU runs in
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// Percentage of a single CPU, 200 allows 2 full CPU's
+    pub cpu_quota: Option<u32>,
+    /// In MiB
+    pub memory_max: Option<u64>,
+    /// Between 1 and 10000, the kernel default is 100
+    pub io_weight: Option<u32>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.cpu_quota.is_none() && self.memory_max.is_none() && self.io_weight.is_none()
+    }
+}
+
+/// Seconds a hook gets before it's killed
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 30;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HooksConfig {
+    pub pre_start: Option<String>,
+    pub post_start: Option<String>,
+    pub pre_stop: Option<String>,
+    pub post_stop: Option<String>,
+    pub on_crash: Option<String>,
+    /// Ran when the filesystems of the VM run low on space, see disk-space.min-free
+    pub low_disk_space: Option<String>,
+    /// Seconds vored waits for a hook before killing it
+    pub timeout: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            pre_start: None,
+            post_start: None,
+            pre_stop: None,
+            post_stop: None,
+            on_crash: None,
+            low_disk_space: None,
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+impl HooksConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<HooksConfig, anyhow::Error> {
+        let get = |key: &str| -> Result<Option<String>, anyhow::Error> {
+            table
+                .get(key)
+                .cloned()
+                .map(|x| x.into_str())
+                .transpose()
+                .with_context(|| format!("hooks.{} should be a path", key))
+        };
+
+        Ok(HooksConfig {
+            pre_start: get("pre-start")?,
+            post_start: get("post-start")?,
+            pre_stop: get("pre-stop")?,
+            post_stop: get("post-stop")?,
+            on_crash: get("on-crash")?,
+            low_disk_space: get("low-disk-space")?,
+            timeout: table
+                .get("timeout")
+                .cloned()
+                .map(|x| {
+                    x.into_int()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .context("hooks.timeout should be a positive amount of seconds")
+                })
+                .transpose()?
+                .map_or(DEFAULT_HOOK_TIMEOUT, |x| x as u64),
+        })
+    }
+
+    pub fn get(&self, hook: &str) -> Option<&str> {
+        match hook {
+            "pre-start" => self.pre_start.as_deref(),
+            "post-start" => self.post_start.as_deref(),
+            "pre-stop" => self.pre_stop.as_deref(),
+            "post-stop" => self.post_stop.as_deref(),
+            "on-crash" => self.on_crash.as_deref(),
+            "low-disk-space" => self.low_disk_space.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GuestActionsConfig {
+    pub enabled: bool,
+    pub socket_path: String,
+    /// Actions the guest is allowed to request, by name
+    pub actions: HashMap<String, GuestAction>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GuestAction {
+    /// Start the VM with this name
+    Start(String),
+    /// Stop the VM with this name
+    Stop(String),
+    /// Run this command on the host
+    Command(Vec<String>),
+}
+
+impl GuestActionsConfig {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<GuestActionsConfig, anyhow::Error> {
+        let mut cfg = GuestActionsConfig::default();
+
+        if let Some(enabled) = table.get("enabled").cloned() {
+            cfg.enabled = enabled.into_bool()?;
+        }
+
+        if let Some(socket_path) = table.get("socket-path").cloned() {
+            cfg.socket_path = socket_path.into_str()?;
+        }
+
+        if let Some(actions) = table.get("actions").cloned() {
+            let actions = actions
+                .into_table()
+                .context("guest-actions.actions should be a table")?;
+            for (name, action) in actions {
+                let action = GuestAction::from_table(action.into_table().with_context(|| {
+                    format!("guest-actions.actions.{} should be a table", name)
+                })?)
+                .with_context(|| format!("Invalid guest action {}", name))?;
+                cfg.actions.insert(name, action);
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+impl GuestAction {
+    pub fn from_table(table: HashMap<String, Value>) -> Result<GuestAction, anyhow::Error> {
+        if let Some(vm) = table.get("start").cloned() {
+            return Ok(GuestAction::Start(vm.into_str()?));
+        }
+
+        if let Some(vm) = table.get("stop").cloned() {
+            return Ok(GuestAction::Stop(vm.into_str()?));
+        }
+
+        if let Some(command) = table.get("command").cloned() {
+            let command = command
+                .into_array()?
+                .into_iter()
+                .map(|x| x.into_str())
+                .collect::<Result<Vec<_>, _>>()?;
+            if command.is_empty() {
+                anyhow::bail!("command can't be empty");
+            }
+
+            return Ok(GuestAction::Command(command));
+        }
+
+        anyhow::bail!("Expected one of start, stop or command")
+    }
 }
 
 #[derive(Default, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -737,7 +3874,7 @@ impl Serialize for PciAddress {
 }
 
 impl PciAddress {
-    fn to_pci_string(&self) -> String {
+    pub(crate) fn to_pci_string(self) -> String {
         format!(
             "{:04x}:{:02x}:{:02x}.{:x}",
             self.domain, self.bus, self.slot, self.func
@@ -806,9 +3943,850 @@ impl FromStr for PciAddress {
 
 #[cfg(test)]
 mod tests {
-    use crate::PciAddress;
+    use crate::{
+        format_cpu_list, set_definition_value, Deprecation, HostRequirement, InstanceConfig,
+        LowDiskSpaceAction, MachineType, PciAddress, ScreamMode, UsbConfig, VideoModel,
+        DEFAULT_HOOK_TIMEOUT,
+    };
     use std::str::FromStr;
 
+    #[test]
+    fn test_auto_start_options() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+name = "test"
+auto-start = true
+auto-start-delay = 30
+auto-start-order = -1
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert!(config.auto_start);
+        assert_eq!(config.auto_start_delay, 30);
+        assert_eq!(config.auto_start_order, -1);
+        assert!(InstanceConfig::from_toml("[machine]\nauto-start-delay = -5").is_err());
+    }
+
+    #[test]
+    fn test_requires_host() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+requires-host = ["bridge:br0", "mount:/tank/images", "service:ceph.target"]
+requires-host-timeout = 60
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.requires_host.len(), 3);
+        assert_eq!(config.requires_host_timeout, 60);
+        assert_eq!(
+            HostRequirement::from_str("mount:/tank/images").unwrap(),
+            HostRequirement::Mount("/tank/images".to_string())
+        );
+        assert!(HostRequirement::from_str("path:/").unwrap().is_met());
+        assert!(HostRequirement::from_str("mount:/proc").unwrap().is_met());
+        assert!(InstanceConfig::from_toml("[machine]\nrequires-host = [\"disk:sda\"]").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nrequires-host = [\"path:tank\"]").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nrequires-host = [\"bridge:\"]").is_err());
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+cpu-quota = "250%"
+memory-max = "9G"
+io-weight = 50
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.limits.cpu_quota, Some(250));
+        assert_eq!(config.limits.memory_max, Some(9 * 1024));
+        assert_eq!(config.limits.io_weight, Some(50));
+        assert!(InstanceConfig::from_toml("[machine]\ncpu-quota = \"0%\"").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nio-weight = 0").is_err());
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+features = ["sandbox"]
+
+[sandbox]
+network = true
+paths = ["/srv/vm"]
+read-only-paths = ["/srv/iso"]
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert!(config.sandbox.enabled);
+        assert!(config.sandbox.network);
+        assert_eq!(config.sandbox.paths, vec!["/srv/vm".to_string()]);
+        assert_eq!(config.sandbox.read_only_paths, vec!["/srv/iso".to_string()]);
+        assert!(!InstanceConfig::from_toml("").unwrap().sandbox.enabled);
+        assert!(InstanceConfig::from_toml("[sandbox]\npaths = [\"vm\"]").is_err());
+    }
+
+    #[test]
+    fn test_extra_args() {
+        let config = InstanceConfig::from_toml(
+            "[qemu]\nextra-args = [\"-device\", \"virtio-rng-pci\"]",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(config.extra_args, vec!["-device", "virtio-rng-pci"]);
+        assert!(config.to_toml().unwrap().contains("virtio-rng-pci"));
+        assert!(InstanceConfig::from_toml("[qemu]\nextra-args = \"-nodefaults\"").is_err());
+    }
+
+    #[test]
+    fn test_env() {
+        let config = InstanceConfig::from_toml(
+            "[qemu.env]\nSDL_VIDEODRIVER = \"wayland\"\nmesa_glthread = true",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(config.env.get("SDL_VIDEODRIVER").unwrap(), "wayland");
+        assert_eq!(config.env.get("mesa_glthread").unwrap(), "true");
+        assert!(InstanceConfig::from_toml("[qemu]\nenv = [\"A=b\"]").is_err());
+    }
+
+    #[test]
+    fn test_run_as() {
+        let config = InstanceConfig::from_toml(
+            "[qemu]\nrun-as = \"vore-qemu\"\ngroups = [\"kvm\", \"render\"]",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(config.run_as.as_deref(), Some("vore-qemu"));
+        assert_eq!(config.groups, Some(vec!["kvm".to_string(), "render".to_string()]));
+        assert_eq!(InstanceConfig::from_toml("").unwrap().groups, None);
+        assert!(InstanceConfig::from_toml("[qemu]\nrun-as = \"\"").is_err());
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+name = "test"
+features = ["guest-actions"]
+
+[guest-actions.actions.game]
+start = "game"
+
+[[numa]]
+cpus = 4
+"#,
+        )
+        .expect("Failed to parse config");
+
+        let toml = config.to_toml().expect("Failed to write config as TOML");
+        assert!(toml.contains("name = 'test'"));
+        assert!(toml.contains("[guest_actions.actions.game]"));
+    }
+
+    #[test]
+    fn test_cpu_model_and_flags() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[cpu]
+amount = 4
+model = "EPYC-v3"
+flags = ["+topoext", "-hypervisor"]
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.cpu.model, "EPYC-v3");
+        assert_eq!(config.cpu.flags, vec!["+topoext", "-hypervisor"]);
+        assert_eq!(config.cpu.cores, 2);
+        assert!(InstanceConfig::from_toml("[cpu]\nflags = [\"+a,+b\"]").is_err());
+    }
+
+    #[test]
+    fn test_cpu_pin() {
+        let config = InstanceConfig::from_toml("[cpu]\namount = 4\npin = \"8-9,2,3\"")
+            .expect("Failed to parse config");
+        assert_eq!(config.cpu.pin, Some(vec![8, 9, 2, 3]));
+        assert_eq!(format_cpu_list(&[8, 9, 2, 3]), "8-9,2-3");
+
+        let config = InstanceConfig::from_toml("[cpu]\namount = 2\npin = [5, 4]")
+            .expect("Failed to parse config");
+        assert_eq!(config.cpu.pin, Some(vec![5, 4]));
+
+        assert!(InstanceConfig::from_toml("[cpu]\namount = 4\npin = \"0-2\"").is_err());
+        assert!(InstanceConfig::from_toml("[cpu]\namount = 2\npin = \"1,1\"").is_err());
+        assert!(InstanceConfig::from_toml("[cpu]\namount = 2\npin = \"3-2\"").is_err());
+
+        let toml = set_definition_value(
+            "[machine]\nname = \"test\"\n[cpu]\namount = 2\n",
+            "cpu",
+            "pin",
+            "4-5",
+        )
+        .expect("Failed to update definition");
+        let config = InstanceConfig::from_toml(&toml).expect("Failed to parse updated definition");
+        assert_eq!(config.name, "test");
+        assert_eq!(config.cpu.pin, Some(vec![4, 5]));
+    }
+
+    #[test]
+    fn test_hide_kvm() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+hide-kvm = false
+
+[cpu]
+vendor-id = "1234567890ab"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert!(!config.hide_kvm);
+        assert!(!config.stealth);
+        assert!(InstanceConfig::from_toml("").unwrap().hide_kvm);
+        assert_eq!(config.cpu.vendor_id.as_deref(), Some("1234567890ab"));
+        assert!(InstanceConfig::from_toml("[cpu]\nvendor-id = \"1234567890abc\"").is_err());
+        assert!(InstanceConfig::from_toml("[cpu]\nvendor-id = \"a,b\"").is_err());
+    }
+
+    #[test]
+    fn test_net() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[net]
+ipv4 = false
+ipv6-prefix = "fd42::/48"
+ipv6-dns = "fd42::3"
+dns-search = ["lab.example", "example"]
+bootfile = "http://netboot.lab/boot.ipxe"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert!(!config.net.ipv4);
+        assert_eq!(config.net.ipv6_prefix.as_deref(), Some("fd42::"));
+        assert_eq!(config.net.ipv6_prefix_len, Some(48));
+        assert_eq!(config.net.dns_search, vec!["lab.example", "example"]);
+        assert_eq!(
+            config.net.bootfile.as_deref(),
+            Some("http://netboot.lab/boot.ipxe")
+        );
+        assert!(config.net.tftp.is_none());
+        assert_eq!(InstanceConfig::from_toml("").unwrap().net.model, "e1000e");
+        assert!(InstanceConfig::from_toml("[net]\ndns = \"fd42::3\"").is_err());
+        assert!(InstanceConfig::from_toml("[net]\nipv6-prefix = \"fd42::/200\"").is_err());
+        assert!(InstanceConfig::from_toml("[net]\nipv4 = false\nipv6 = false").is_err());
+        assert!(InstanceConfig::from_toml("[net]\ntftp = \"/srv/a,b\"").is_err());
+    }
+
+    #[test]
+    fn test_smbios() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[smbios]
+uuid = "4c4c4544-0042-3510-8052-b4c04f4e3132"
+manufacturer = "Dell Inc."
+product = "OptiPlex 7050"
+serial = "ABC1234"
+
+[smbios.bios]
+vendor = "Dell Inc."
+date = "06/25/2021"
+release = "1.23"
+
+[smbios.baseboard]
+serial = "/ABC1234/CN7016377M0123/"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(
+            config.smbios.uuid.as_deref(),
+            Some("4c4c4544-0042-3510-8052-b4c04f4e3132")
+        );
+        assert_eq!(
+            config.smbios.system.get("product").map(|x| x.as_str()),
+            Some("OptiPlex 7050")
+        );
+        assert_eq!(config.smbios.bios.len(), 3);
+        assert_eq!(config.smbios.baseboard.len(), 1);
+        assert!(config.smbios.chassis.is_empty());
+        assert!(InstanceConfig::from_toml("[smbios]\nuuid = \"not-a-uuid\"").is_err());
+        assert!(InstanceConfig::from_toml("[smbios]\nvendor = \"Dell\"").is_err());
+        assert!(InstanceConfig::from_toml("[smbios.bios]\ndate = \"2021-06-25\"").is_err());
+    }
+
+    #[test]
+    fn test_boot_order() {
+        let mut config = InstanceConfig::from_toml(
+            r#"
+[machine]
+boot-order = ["disk0", "net"]
+
+[[disk]]
+path = "/dev/sda"
+preset = "ssd"
+
+[[disk]]
+path = "/tmp/install.iso"
+preset = "iso"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.boot_order, vec!["disk0", "net"]);
+        config.clone().apply_boot_order(Some("disk9")).unwrap_err();
+
+        config.apply_boot_order(Some("cdrom")).unwrap();
+        assert_eq!(config.disks[1].boot_index, Some(0));
+        assert_eq!(config.disks[0].boot_index, Some(1));
+        assert_eq!(config.net.boot_index, Some(2));
+
+        let mut attached = InstanceConfig::from_toml("[machine]\nboot-order = [\"net\"]").unwrap();
+        attached
+            .apply_boot(&["/tmp/a.iso".to_string(), "/tmp/b.iso".to_string()], None)
+            .unwrap();
+        assert_eq!(attached.net.boot_index, Some(0));
+        assert_eq!(attached.disks[0].boot_index, Some(1));
+        assert_eq!(attached.disks[1].boot_index, Some(2));
+        assert!(attached.disks[1].read_only);
+
+        let mut attached = InstanceConfig::from_toml("").unwrap();
+        attached
+            .apply_boot(&["/tmp/a.iso".to_string()], Some("cdrom"))
+            .unwrap();
+        assert_eq!(attached.disks[0].boot_index, Some(0));
+
+        assert!(InstanceConfig::from_toml("[machine]\nboot-order = [\"cdrom\"]").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nboot-order = [\"net\", \"net\"]").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nboot-order = [\"floppy\"]").is_err());
+    }
+
+    #[test]
+    fn test_timezone() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+timezone = "Europe/Amsterdam"
+features = ["guest-agent"]
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.timezone.as_deref(), Some("Europe/Amsterdam"));
+        assert!(config.guest_agent.enabled);
+        assert!(!config.guest_agent.install_media);
+        assert!(InstanceConfig::from_toml("[machine]\ntimezone = \"utc\"").is_ok());
+        assert!(InstanceConfig::from_toml("[machine]\ntimezone = \"../../etc/passwd\"").is_err());
+        assert!(InstanceConfig::from_toml("[machine]\ntimezone = \"/etc/localtime\"").is_err());
+    }
+
+    #[test]
+    fn test_numa_nodes() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[machine]
+memory = "10G"
+
+[cpu]
+amount = 8
+
+[[numa]]
+cpus = 6
+memory = "6G"
+host-node = 1
+
+[[numa]]
+cpus = 2
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.numa.len(), 2);
+        assert_eq!(config.numa[0].host_node, Some(1));
+        assert_eq!(config.numa[1].memory, Some(4 * 1024));
+        assert!(InstanceConfig::from_toml(
+            "[machine]\nmemory = \"4G\"\n[cpu]\namount = 4\n[[numa]]\ncpus = 2"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_usb() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[[usb]]
+vendor = 0x046d
+product = 0xc52b
+
+[[usb]]
+bus = 1
+port = "2.1"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.usb.len(), 2);
+        assert_eq!(config.usb[0].to_string(), "046d:c52b");
+        assert_eq!(config.usb[1].to_string(), "1-2.1");
+        for device in ["046d:c52b", "1-2.1", "3-4"] {
+            assert_eq!(UsbConfig::from_str(device).unwrap().to_string(), device);
+        }
+        assert!(UsbConfig::from_str("046d").is_err());
+        assert!(UsbConfig::from_str("0-1").is_err());
+        assert!(UsbConfig::from_str("1-2.").is_err());
+        assert!(InstanceConfig::from_toml("[[usb]]\nvendor = 0x046d").is_err());
+        assert!(InstanceConfig::from_toml("[[usb]]\nbus = 1\nport = \"2.\"").is_err());
+        assert!(InstanceConfig::from_toml(
+            "[[usb]]\nvendor = 1\nproduct = 2\nbus = 1\nport = \"1\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_mdev() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[[mdev]]
+parent = "0000:00:02.0"
+type = "i915-GVTg_V5_4"
+display = true
+
+[[mdev]]
+parent = "00:02.0"
+type = "i915-GVTg_V5_8"
+uuid = "A297DB4A-F4C2-11E6-90F6-D3B88D6C9525"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.mdev.len(), 2);
+        assert_eq!(config.mdev[0].parent, config.mdev[1].parent);
+        assert_eq!(config.mdev[0].mdev_type, "i915-GVTg_V5_4");
+        assert!(config.mdev[0].display);
+        assert_eq!(config.mdev[0].uuid, None);
+        assert_eq!(
+            config.mdev[1].uuid.as_deref(),
+            Some("a297db4a-f4c2-11e6-90f6-d3b88d6c9525")
+        );
+        assert!(InstanceConfig::from_toml("[[mdev]]\nparent = \"00:02.0\"").is_err());
+        assert!(InstanceConfig::from_toml(
+            "[[mdev]]\nparent = \"00:02.0\"\ntype = \"x\"\nuuid = \"1234\""
+        )
+        .is_err());
+    }
+
+    /// Addresses of the PCI devices of this host, vfio entries are checked against the host
+    fn host_pci_addresses() -> Vec<String> {
+        let mut addresses = std::fs::read_dir("/sys/bus/pci/devices")
+            .expect("Failed to list PCI devices")
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let config = |toml: &str| InstanceConfig::from_toml(toml).expect("Failed to parse config");
+        // Once without the domain, the same device should still be found
+        let address = &host_pci_addresses()[0];
+        let gaming = config(&format!(
+            "[machine]\nname = \"gaming\"\nconflicts = [\"work\"]\n[[vfio]]\naddr = \"{}\"",
+            address.trim_start_matches("0000:")
+        ));
+        let work = config("[machine]\nname = \"work\"");
+        let linux = config(&format!(
+            "[machine]\nname = \"linux\"\n[[vfio]]\naddr = \"{}\"",
+            address
+        ));
+        let nas = config("[machine]\nname = \"nas\"");
+
+        assert!(gaming.conflict_with(&work).is_some());
+        assert!(work.conflict_with(&gaming).is_some());
+        assert!(gaming.conflict_with(&linux).is_some());
+        assert!(gaming.conflict_with(&nas).is_none());
+        assert!(work.conflict_with(&linux).is_none());
+
+        let disk = |name: &str, shared: bool| {
+            config(&format!(
+                "[machine]\nname = \"{}\"\n[[disk]]\npreset = \"ssd\"\npath = \"/dev/vore-test\"\ntype = \"raw\"\nshared = {}",
+                name, shared
+            ))
+        };
+        assert!(disk("a", false).conflict_with(&disk("b", true)).is_some());
+        assert!(disk("a", true).conflict_with(&disk("b", true)).is_none());
+        assert!(InstanceConfig::from_toml(
+            "[[disk]]\npreset = \"ssd\"\npath = \"/tmp/a.qcow2\"\nshared = true"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_create_if_missing() {
+        let disk = |extra: &str| {
+            InstanceConfig::from_toml(&format!(
+                "[[disk]]\npreset = \"ssd\"\npath = \"/vm/a.qcow2\"\ncreate-if-missing = true\n{}",
+                extra
+            ))
+        };
+        let config = disk("size = \"200G\"\npreallocation = \"metadata\"").unwrap();
+        assert!(config.disks[0].create_if_missing);
+        assert_eq!(config.disks[0].size, Some(200 * 1024));
+        assert_eq!(config.disks[0].preallocation.as_deref(), Some("metadata"));
+        assert!(disk("").is_err());
+        assert!(disk("size = \"20G\"\npreallocation = \"lots\"").is_err());
+        // Too slow for vored to wait for
+        assert!(disk("size = \"20G\"\npreallocation = \"full\"").is_err());
+        assert!(disk("size = \"20G\"\npreallocation = \"falloc\"").is_err());
+    }
+
+    #[test]
+    fn test_vfio_reserve() {
+        let addresses = host_pci_addresses();
+        let config = InstanceConfig::from_toml(&format!(
+            "[[vfio]]\naddr = \"{}\"\nreserve = true\n[[vfio]]\naddr = \"{}\"",
+            addresses[0], addresses[1]
+        ))
+        .expect("Failed to parse config");
+
+        assert!(config.vfio[0].reserve);
+        assert!(!config.vfio[1].reserve);
+        assert!(config.vfio[1].rombar);
+        assert_eq!(config.vfio[1].romfile, None);
+        assert!(InstanceConfig::from_toml(&format!(
+            "[[vfio]]\naddr = \"{}\"\nreserve = \"maybe\"",
+            addresses[0]
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_spice_tcp() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[spice]
+enabled = true
+listen = "::"
+port = 5900
+tls-port = 5901
+x509-dir = "/etc/pki/vore"
+password = "hunter2"
+image-compression = "quic"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert!(config.spice.is_tcp());
+        assert_eq!(config.spice.uri(), "spice://[::]:5900?tls-port=5901");
+        assert_eq!(config.spice.image_compression.as_deref(), Some("quic"));
+        assert!(!serde_json::to_string(&config).unwrap().contains("hunter2"));
+
+        assert!(InstanceConfig::from_toml("[spice]\ntls-port = 5901").is_err());
+        assert!(InstanceConfig::from_toml("[spice]\nstreaming-video = \"some\"").is_err());
+        assert!(InstanceConfig::from_toml(
+            "[spice]\nenabled = true\nport = 5900\n[sandbox]\nenabled = true\nnetwork = true"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_key_aliases() {
+        let config =
+            InstanceConfig::from_toml("[scream]\nenabled = true\nmem-path = \"/dev/shm/scream\"")
+                .expect("Failed to parse config");
+        assert_eq!(config.scream.mem_path, "/dev/shm/scream");
+        assert_eq!(
+            config.deprecations,
+            vec![Deprecation {
+                key: "scream.mem-path".to_string(),
+                replacement: "scream.path".to_string(),
+            }]
+        );
+
+        let config = InstanceConfig::from_toml("[looking-glass]\npath = \"/dev/kvmfr0\"")
+            .expect("Failed to parse config");
+        assert_eq!(config.looking_glass.mem_path, "/dev/kvmfr0");
+        assert!(config.deprecations.is_empty());
+
+        assert!(InstanceConfig::from_toml("[scream]\nmem-path = \"/a\"\npath = \"/b\"").is_err());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let config = InstanceConfig::from_toml(
+            "[hooks]\npre-start = \"/etc/vore/hooks/hugepages-on.sh\"\ntimeout = 120",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(
+            config.hooks.get("pre-start"),
+            Some("/etc/vore/hooks/hugepages-on.sh")
+        );
+        assert_eq!(config.hooks.timeout, 120);
+        assert_eq!(
+            InstanceConfig::from_toml("").unwrap().hooks.timeout,
+            DEFAULT_HOOK_TIMEOUT
+        );
+        assert!(InstanceConfig::from_toml("[hooks]\ntimeout = 0").is_err());
+    }
+
+    #[test]
+    fn test_tpm() {
+        let config = InstanceConfig::from_toml("machine.features = [\"tpm\", \"uefi\"]")
+            .expect("Failed to parse config");
+        assert!(config.tpm.enabled);
+        assert!(config.tpm.state_path.is_empty());
+
+        let config =
+            InstanceConfig::from_toml("[tpm]\nenabled = true\nstate-path = \"/srv/vm/tpm\"")
+                .expect("Failed to parse config");
+        assert_eq!(config.tpm.state_path, "/srv/vm/tpm");
+        assert!(InstanceConfig::from_toml("[tpm]\nenabled = \"yes please\"").is_err());
+    }
+
+    #[test]
+    fn test_uefi_vars_path() {
+        let config = InstanceConfig::from_toml("machine.features = [\"uefi\"]")
+            .expect("Failed to parse config");
+        assert!(config.uefi.vars_path.is_empty());
+
+        let config = InstanceConfig::from_toml(
+            "[uefi]\nenabled = true\nvars-path = \"/srv/vm/OVMF_VARS.fd\"",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(config.uefi.vars_path, "/srv/vm/OVMF_VARS.fd");
+        assert!(InstanceConfig::from_toml("[uefi]\nvars-path = \"/a,b\"").is_err());
+    }
+
+    #[test]
+    fn test_machine_type() {
+        let config = InstanceConfig::from_toml("").expect("Failed to parse config");
+        assert_eq!(config.machine_type, MachineType::Q35);
+
+        let config = InstanceConfig::from_toml("[machine]\nchipset = \"i440fx\"")
+            .expect("Failed to parse config");
+        assert_eq!(config.chipset, "pc");
+        assert_eq!(config.machine_type, MachineType::I440fx);
+
+        let config = InstanceConfig::from_toml("[machine]\nchipset = \"pc-q35-8.2\"")
+            .expect("Failed to parse config");
+        assert_eq!(config.machine_type, MachineType::Q35);
+
+        assert!(InstanceConfig::from_toml("[machine]\nchipset = \"virt\"").is_err());
+        assert!(
+            InstanceConfig::from_toml("[machine]\nchipset = \"pc\"\npci-hotplug-slots = 2")
+                .is_err()
+        );
+        assert!(InstanceConfig::from_toml(
+            "[machine]\nchipset = \"microvm\"\nfeatures = [\"uefi\"]"
+        )
+        .is_err());
+        assert!(InstanceConfig::from_toml("[machine]\nchipset = \"microvm\"").is_ok());
+        assert!(InstanceConfig::from_toml("[machine]\nallow-tcg = true")
+            .expect("Failed to parse config")
+            .allow_tcg);
+        let config = InstanceConfig::from_toml("[machine]\narch = \"aarch64\"")
+            .expect("Failed to parse config");
+        assert_eq!(config.chipset, "virt");
+        assert_eq!(config.uefi_firmware(), "aarch64");
+        assert_eq!(config.kvm, std::env::consts::ARCH == "aarch64");
+        assert!(!config.allow_tcg);
+        assert!(InstanceConfig::from_toml("[machine]\narch = \"mips\"").is_err());
+        assert!(
+            InstanceConfig::from_toml("[machine]\narch = \"riscv64\"\nchipset = \"q35\"").is_err()
+        );
+    }
+
+    #[test]
+    fn test_sev() {
+        let config = InstanceConfig::from_toml("machine.features = [\"sev\", \"uefi\"]")
+            .expect("Failed to parse config");
+        assert!(config.sev.enabled);
+        assert_eq!(config.sev.effective_policy(), 0x1);
+        assert_eq!(config.sev.cbitpos, None);
+
+        let config = InstanceConfig::from_toml(
+            "machine.features = [\"uefi\"]\n[sev]\nenabled = true\nsnp = true\ncbitpos = 51",
+        )
+        .expect("Failed to parse config");
+        assert_eq!(config.sev.effective_policy(), 0x30000);
+        assert_eq!(config.sev.cbitpos, Some(51));
+
+        assert!(InstanceConfig::from_toml("machine.features = [\"sev\"]").is_err());
+        assert!(InstanceConfig::from_toml("[sev]\ncbitpos = 12").is_err());
+    }
+
+    #[test]
+    fn test_seats() {
+        let config = InstanceConfig::from_toml(
+            r##"
+machine.features = ["looking-glass", "spice"]
+
+[[seat]]
+name = "left"
+user = "#1001"
+mode = "0640"
+looking-glass = true
+spice = true
+
+[[seat]]
+name = "right"
+group = "#1002"
+looking-glass = true
+"##,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.seats.len(), 2);
+        assert_eq!(config.seats[0].user_uid, Some(1001));
+        assert_eq!(config.seats[0].mode, 0o640);
+        assert_eq!(config.seats[1].group_gid, Some(1002));
+        assert_eq!(config.seats[1].mode, 0o660);
+        assert!(!config.seats[1].spice);
+
+        assert!(
+            InstanceConfig::from_toml("[[seat]]\nname = \"left\"\nlooking-glass = true").is_err()
+        );
+        assert!(InstanceConfig::from_toml(
+            "machine.features = [\"spice\"]\n[[seat]]\nname = \"a\"\nspice = true\n[[seat]]\nname = \"a\"\nspice = true"
+        )
+        .is_err());
+        assert!(InstanceConfig::from_toml(
+            "[spice]\nenabled = true\nport = 5900\n[[seat]]\nname = \"left\"\nspice = true"
+        )
+        .is_err());
+        assert!(InstanceConfig::from_toml("[[seat]]\nname = \"left\"").is_err());
+    }
+
+    #[test]
+    fn test_video() {
+        let config = InstanceConfig::from_toml("[video]\nmodel = \"qxl\"\nvram = 64\nheads = 2")
+            .expect("Failed to parse config");
+        assert_eq!(config.video.model, VideoModel::Qxl);
+        assert_eq!(config.video.vram, Some(64));
+        assert_eq!(config.video.heads, 2);
+
+        let address = &host_pci_addresses()[0];
+        let config = InstanceConfig::from_toml(&format!(
+            "[video]\nmodel = \"virtio-gpu\"\n[[vfio]]\naddr = \"{}\"\ngraphics = true",
+            address
+        ))
+        .expect("Failed to parse config");
+        assert!(!config.video.model.is_vga());
+
+        assert!(InstanceConfig::from_toml("[video]\nmodel = \"cirrus\"").is_err());
+        assert!(InstanceConfig::from_toml("[video]\nmodel = \"virtio-gpu\"\nvram = 64").is_err());
+        assert!(InstanceConfig::from_toml("[video]\nmodel = \"std\"\nheads = 2").is_err());
+        assert!(InstanceConfig::from_toml(&format!(
+            "[video]\nmodel = \"std\"\n[[vfio]]\naddr = \"{}\"\ngraphics = true",
+            address
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_scream_network() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[scream]
+enabled = true
+mode = "network"
+interface = "lo"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.scream.mode, ScreamMode::Network);
+        assert_eq!(config.scream.interface.as_deref(), Some("lo"));
+        assert_eq!(config.scream.port, 4010);
+        assert!(InstanceConfig::from_toml("[scream]\nmode = \"udp\"").is_err());
+        assert!(InstanceConfig::from_toml(
+            "[scream]\nmode = \"network\"\nmem-path = \"/dev/shm/scream\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_looking_glass_resolutions() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[looking-glass]
+enabled = true
+resolutions = ["1920x1080@144", "3440x1440@100", "2560x1440"]
+"#,
+        )
+        .expect("Failed to parse config");
+
+        let looking_glass = config.looking_glass;
+        assert_eq!(looking_glass.resolutions.len(), 3);
+        assert_eq!(looking_glass.resolutions[2].refresh, 60);
+        assert_eq!((looking_glass.width, looking_glass.height), (3440, 1440));
+        assert!(InstanceConfig::from_toml(
+            "[looking-glass]\nenabled = true\nresolutions = [\"1440p\"]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_looking_glass_watch_resolution() {
+        let config = InstanceConfig::from_toml("[looking-glass]\nenabled = true")
+            .expect("Failed to parse config");
+        assert!(!config.looking_glass.watch_resolution);
+
+        let config = InstanceConfig::from_toml(
+            "[looking-glass]\nenabled = true\nwatch-resolution = true\ngrow = true",
+        )
+        .expect("Failed to parse config");
+        assert!(config.looking_glass.watch_resolution);
+        assert!(config.looking_glass.grow);
+        assert!(InstanceConfig::from_toml("[looking-glass]\nenabled = true\ngrow = true").is_err());
+    }
+
+    #[test]
+    fn test_input() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[input]
+devices = ["/dev/input/by-id/usb-keyboard-event-kbd", "/dev/input/event3"]
+grab-toggle = "ctrl-ctrl"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.input.devices.len(), 2);
+        assert_eq!(config.input.grab_toggle.as_deref(), Some("ctrl-ctrl"));
+        assert!(InstanceConfig::from_toml("[input]\ndevices = [\"event3\"]").is_err());
+        assert!(InstanceConfig::from_toml("[input]\ngrab-toggle = \"f12\"").is_err());
+    }
+
+    #[test]
+    fn test_disk_space() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[disk-space]
+min-free = "10G"
+on-low = "pause"
+"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(config.disk_space.min_free, 10 * 1024);
+        assert_eq!(config.disk_space.on_low, LowDiskSpaceAction::Pause);
+        assert_eq!(
+            InstanceConfig::from_toml("").unwrap().disk_space.min_free,
+            0
+        );
+        assert!(InstanceConfig::from_toml("[disk-space]\non-low = \"stop\"").is_err());
+    }
+
     #[test]
     fn test_input_and_output_are_same() {
         assert_eq!(
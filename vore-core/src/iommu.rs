@@ -0,0 +1,223 @@
+// IOMMU groups of the host, VFIO can only give a device to a VM if no other device in its group
+// is used by the host, since the IOMMU can't isolate devices in the same group from each other
+//
+// Also used by `vore pci list`, which reads sysfs itself, so this doesn't need the host feature
+
+use crate::PciAddress;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs::{read_dir, read_link, read_to_string};
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+/// Where distributions put the PCI ID database, first one found is used
+const PCI_IDS_PATHS: &[&str] = &[
+    "/usr/share/hwdata/pci.ids",
+    "/usr/share/misc/pci.ids",
+    "/usr/share/pci.ids",
+];
+
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor: u32,
+    pub device: u32,
+    /// Class, subclass and programming interface, e.g. 0x030000 for a VGA controller
+    pub class: u32,
+    pub driver: Option<String>,
+}
+
+impl PciDevice {
+    pub fn read(address: PciAddress) -> Result<PciDevice, anyhow::Error> {
+        let read_hex = |name: &str| {
+            let path = format!("/sys/bus/pci/devices/{:#}/{}", address, name);
+            read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path))
+                .and_then(|x| {
+                    u32::from_str_radix(x.trim().trim_start_matches("0x"), 16)
+                        .with_context(|| format!("Failed to parse {} ({:?})", path, x))
+                })
+        };
+
+        Ok(PciDevice {
+            address,
+            vendor: read_hex("vendor")?,
+            device: read_hex("device")?,
+            class: read_hex("class")?,
+            driver: read_link(format!("/sys/bus/pci/devices/{:#}/driver", address))
+                .ok()
+                .and_then(|x| x.file_name().map(|x| x.to_string_lossy().to_string())),
+        })
+    }
+
+    /// Bridges stay with the host, VFIO doesn't need them to be unbound
+    pub fn is_bridge(&self) -> bool {
+        self.class >> 16 == 0x06
+    }
+
+    /// If this device doesn't keep the rest of its group from being used with VFIO
+    pub fn is_vfio_viable(&self) -> bool {
+        self.is_bridge()
+            || matches!(
+                self.driver.as_deref(),
+                None | Some("vfio-pci") | Some("pci-stub")
+            )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IommuGroup {
+    pub id: u32,
+    pub devices: Vec<PciDevice>,
+}
+
+impl IommuGroup {
+    pub fn read(id: u32) -> Result<IommuGroup, anyhow::Error> {
+        let path = format!("/sys/kernel/iommu_groups/{}/devices", id);
+        let mut devices = vec![];
+        for entry in read_dir(&path).with_context(|| format!("Failed to read {}", path))? {
+            let name = entry?.file_name();
+            let address = PciAddress::from_str(&name.to_string_lossy())?;
+            devices.push(PciDevice::read(address)?);
+        }
+
+        devices.sort_by_key(|x| x.address);
+        Ok(IommuGroup { id, devices })
+    }
+}
+
+/// IOMMU group [address] is in, None if the IOMMU is disabled
+pub fn iommu_group_of(address: &PciAddress) -> Result<Option<u32>, anyhow::Error> {
+    let link_path = format!("/sys/bus/pci/devices/{:#}/iommu_group", address);
+    match read_link(&link_path) {
+        Ok(link) => link
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| u32::from_str(x).ok())
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("{} doesn't point to an IOMMU group", link_path)),
+
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// All IOMMU groups of the host, ordered by id, empty if the IOMMU is disabled
+pub fn iommu_groups() -> Result<Vec<IommuGroup>, anyhow::Error> {
+    let mut ids = vec![];
+    match read_dir("/sys/kernel/iommu_groups") {
+        Ok(entries) => {
+            for entry in entries {
+                if let Ok(id) = u32::from_str(&entry?.file_name().to_string_lossy()) {
+                    ids.push(id);
+                }
+            }
+        }
+
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+        Err(err) => return Err(err.into()),
+    }
+
+    ids.sort_unstable();
+    ids.into_iter().map(IommuGroup::read).collect()
+}
+
+/// Check that every device in the IOMMU group of [address] can be used with VFIO
+///
+/// Devices in [ours] are skipped, they're passed through as well and get bound to vfio-pci on prepare
+pub fn check_iommu_group(address: &PciAddress, ours: &[PciAddress]) -> Result<(), anyhow::Error> {
+    let id = match iommu_group_of(address)? {
+        Some(id) => id,
+        None => anyhow::bail!(
+            "PCI device {} isn't in an IOMMU group, make sure the IOMMU is enabled (intel_iommu=on or amd_iommu=on)",
+            address
+        ),
+    };
+
+    let group = IommuGroup::read(id)?;
+    let offenders = group
+        .devices
+        .iter()
+        .filter(|x| x.address != *address && !ours.contains(&x.address) && !x.is_vfio_viable())
+        .map(|x| {
+            format!(
+                "{} ({})",
+                x.address,
+                x.driver.as_deref().unwrap_or("no driver")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if !offenders.is_empty() {
+        anyhow::bail!(
+            "PCI device {} is in IOMMU group {} together with {}, which are still used by the host, add them as [[vfio]] devices as well or unbind them (see `vore pci list`)",
+            address,
+            id,
+            offenders.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Names of PCI vendors and devices, from the pci.ids database of the host
+#[derive(Clone, Debug, Default)]
+pub struct PciIds {
+    vendors: HashMap<u32, (String, HashMap<u32, String>)>,
+}
+
+impl PciIds {
+    /// Empty if the host doesn't have a pci.ids
+    pub fn load() -> PciIds {
+        PCI_IDS_PATHS
+            .iter()
+            .find_map(|x| read_to_string(x).ok())
+            .map_or_else(PciIds::default, |x| PciIds::parse(&x))
+    }
+
+    pub fn parse(contents: &str) -> PciIds {
+        let mut vendors = HashMap::new();
+        let mut vendor = None;
+        for line in contents.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            // Subsystems, not needed here
+            if line.starts_with("\t\t") {
+                continue;
+            }
+
+            let mut parts = line.trim_start().splitn(2, "  ");
+            let id = parts.next().and_then(|x| u32::from_str_radix(x, 16).ok());
+            let name = parts.next().unwrap_or("").trim().to_string();
+
+            if !line.starts_with('\t') {
+                // The device classes at the end of the file start with "C ", and don't parse as an id
+                vendor = id;
+                if let Some(id) = id {
+                    vendors.insert(id, (name, HashMap::new()));
+                }
+            } else if let (Some(vendor), Some(id)) = (vendor, id) {
+                if let Some((_, devices)) = vendors.get_mut(&vendor) {
+                    devices.insert(id, name);
+                }
+            }
+        }
+
+        PciIds { vendors }
+    }
+
+    pub fn vendor(&self, vendor: u32) -> Option<&str> {
+        self.vendors.get(&vendor).map(|(name, _)| name.as_str())
+    }
+
+    pub fn device(&self, vendor: u32, device: u32) -> Option<&str> {
+        self.vendors
+            .get(&vendor)
+            .and_then(|(_, devices)| devices.get(&device))
+            .map(|x| x.as_str())
+    }
+}
@@ -0,0 +1,168 @@
+#![cfg(feature = "host")]
+
+// Host side of cpu.isolate: while the VM runs, every other task of the host is kept off the CPU's
+// its vCPU's are pinned to, by narrowing AllowedCPUs of the systemd slices, close to what isolcpus
+// does without changing the kernel command line
+//
+// The slices are shared between all isolating VM's, the host gets every CPU none of them use,
+// and their original AllowedCPUs back once the last one stops
+
+use crate::cpu_list::get_cpus;
+use crate::format_cpu_list;
+use anyhow::Context;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Everything running on the host that isn't a VM, QEMU runs in its own cgroup outside of these
+const SLICES: &[&str] = &["system.slice", "user.slice", "init.scope"];
+
+lazy_static! {
+    static ref ISOLATION: Mutex<Isolation> = Mutex::new(Isolation::default());
+}
+
+#[derive(Debug, Default)]
+struct Isolation {
+    /// CPU's taken from the host, by VM name
+    vms: HashMap<String, Vec<usize>>,
+    /// AllowedCPUs of every slice before the first VM was isolated, empty means all CPU's
+    original: HashMap<String, String>,
+}
+
+impl Isolation {
+    fn isolated(&self) -> Vec<usize> {
+        let mut cpus = self.vms.values().flatten().copied().collect::<Vec<_>>();
+        cpus.sort_unstable();
+        cpus.dedup();
+        cpus
+    }
+
+    /// Give the slices every CPU that no VM isolated, or their original AllowedCPUs if none did
+    fn apply(&mut self) -> Result<(), anyhow::Error> {
+        let isolated = self.isolated();
+        if isolated.is_empty() {
+            for (slice, original) in self.original.drain() {
+                match set_allowed_cpus(&slice, &original) {
+                    Ok(_) => log::info!(
+                        "Restored AllowedCPUs of {} to {}, no VM isolates CPU's anymore",
+                        slice,
+                        if original.is_empty() {
+                            "all"
+                        } else {
+                            original.as_str()
+                        }
+                    ),
+                    Err(err) => log::warn!("Failed to restore {}: {:?}", slice, err),
+                }
+            }
+
+            return Ok(());
+        }
+
+        let host = get_cpus()
+            .into_iter()
+            .map(|x| x.id)
+            .filter(|x| !isolated.contains(x))
+            .collect::<Vec<_>>();
+        if host.is_empty() {
+            anyhow::bail!(
+                "Isolating CPU's {} would leave no CPU for the host",
+                format_cpu_list(&isolated)
+            );
+        }
+
+        let host = format_cpu_list(&host);
+        for slice in SLICES {
+            if !self.original.contains_key(*slice) {
+                self.original
+                    .insert(slice.to_string(), allowed_cpus(slice)?);
+            }
+
+            set_allowed_cpus(slice, &host)?;
+        }
+
+        log::info!(
+            "Host tasks are limited to CPU's {}, {} are isolated",
+            host,
+            format_cpu_list(&isolated)
+        );
+        Ok(())
+    }
+}
+
+fn allowed_cpus(slice: &str) -> Result<String, anyhow::Error> {
+    let output = Command::new("systemctl")
+        .args(&["show", "--property=AllowedCPUs", "--value", slice])
+        .stderr(Stdio::null())
+        .output()
+        .context("Failed to run systemctl")?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to read AllowedCPUs of {}", slice);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn set_allowed_cpus(slice: &str, cpus: &str) -> Result<(), anyhow::Error> {
+    let status = Command::new("systemctl")
+        .args(&[
+            "set-property",
+            "--runtime",
+            slice,
+            &format!("AllowedCPUs={}", cpus),
+        ])
+        .stdout(Stdio::null())
+        .status()
+        .context("Failed to run systemctl")?;
+    if !status.success() {
+        anyhow::bail!("Failed to set AllowedCPUs={} on {}", cpus, slice);
+    }
+
+    Ok(())
+}
+
+/// Keep all host tasks off [cpus] while [vm] runs
+pub fn isolate_cpus(vm: &str, cpus: &[usize]) -> Result<(), anyhow::Error> {
+    let mut isolation = ISOLATION.lock().unwrap();
+    isolation.vms.insert(vm.to_string(), cpus.to_vec());
+    let res = isolation.apply();
+    if res.is_err() {
+        isolation.vms.remove(vm);
+        if let Err(err) = isolation.apply() {
+            log::warn!("Failed to undo CPU isolation of {}: {:?}", vm, err);
+        }
+    }
+
+    res
+}
+
+/// Register [cpus] isolated by a previous vored for [vm], so they're still given back once it stops
+///
+/// The original AllowedCPUs are lost with the previous vored, so all CPU's are given back
+pub fn adopt_isolated_cpus(vm: &str, cpus: &[usize]) {
+    let mut isolation = ISOLATION.lock().unwrap();
+    isolation.vms.insert(vm.to_string(), cpus.to_vec());
+    for slice in SLICES {
+        isolation
+            .original
+            .entry(slice.to_string())
+            .or_default();
+    }
+}
+
+/// Give the CPU's [vm] isolated back to the host, unless another VM isolates them as well
+pub fn release_isolated_cpus(vm: &str) {
+    let mut isolation = ISOLATION.lock().unwrap();
+    if isolation.vms.remove(vm).is_none() {
+        return;
+    }
+
+    if let Err(err) = isolation.apply() {
+        log::warn!(
+            "Failed to give the CPU's isolated for {} back to the host: {:?}",
+            vm,
+            err
+        );
+    }
+}
@@ -0,0 +1,425 @@
+#![cfg(feature = "host")]
+
+// cyclictest-style wake up latency measurement
+//
+// A thread sleeps till an absolute point in time, touches a working set and records how late it
+// was done, the worst case of that is roughly what a vCPU thread on the same CPU will suffer. The
+// working set is in normal pages, or in hugepages to show what backing the guest with them gains.
+//
+// The vCPU threads of a running VM are measured as they are, from the time they spent waiting on
+// the run queue of their CPU, which the kernel keeps in their schedstat
+
+use crate::rpc::LatencyResult;
+use libc::{
+    clock_gettime, clock_nanosleep, cpu_set_t, sched_param, sched_setaffinity, sched_setscheduler,
+    timespec, CLOCK_MONOTONIC, CPU_SET, SCHED_FIFO, TIMER_ABSTIME,
+};
+use std::time::{Duration, Instant};
+use std::{io, mem, thread};
+
+/// Size of the working set touched after every wake up, 8 hugepages of 2 MiB
+const WORKING_SET_SIZE: usize = 16 * 1024 * 1024;
+/// Pages of the working set touched after every wake up
+const WORKING_SET_TOUCHES: usize = 256;
+/// How often the schedstat of the vCPU threads is read
+const SCHEDSTAT_INTERVAL: Duration = Duration::from_millis(10);
+
+fn now() -> timespec {
+    unsafe {
+        let mut ts = mem::zeroed::<timespec>();
+        clock_gettime(CLOCK_MONOTONIC, &mut ts);
+        ts
+    }
+}
+
+fn to_nanos(ts: &timespec) -> i64 {
+    ts.tv_sec * 1_000_000_000 + ts.tv_nsec
+}
+
+fn from_nanos(nanos: i64) -> timespec {
+    timespec {
+        tv_sec: (nanos / 1_000_000_000) as _,
+        tv_nsec: (nanos % 1_000_000_000) as _,
+    }
+}
+
+/// Anonymous memory the measuring thread touches, unmapped when dropped
+struct WorkingSet {
+    ptr: *mut u8,
+}
+
+impl WorkingSet {
+    fn new(hugepages: bool) -> Result<WorkingSet, io::Error> {
+        let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE;
+        if hugepages {
+            flags |= libc::MAP_HUGETLB;
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                WORKING_SET_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            if hugepages {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("No free hugepages for the working set ({})", err),
+                ));
+            }
+
+            return Err(err);
+        }
+
+        Ok(WorkingSet {
+            ptr: ptr as *mut u8,
+        })
+    }
+
+    fn touch(&mut self) {
+        let stride = WORKING_SET_SIZE / WORKING_SET_TOUCHES;
+        for i in 0..WORKING_SET_TOUCHES {
+            unsafe {
+                let byte = self.ptr.add(i * stride);
+                byte.write_volatile(byte.read_volatile().wrapping_add(1));
+            }
+        }
+    }
+}
+
+impl Drop for WorkingSet {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, WORKING_SET_SIZE);
+        }
+    }
+}
+
+fn measure(
+    cpu: Option<usize>,
+    fifo: bool,
+    hugepages: bool,
+    duration: Duration,
+    interval: Duration,
+) -> Result<(u64, u64, u64, u64), io::Error> {
+    unsafe {
+        if let Some(cpu) = cpu {
+            let mut set = mem::zeroed::<cpu_set_t>();
+            CPU_SET(cpu, &mut set);
+            if sched_setaffinity(0, mem::size_of::<cpu_set_t>(), &set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if fifo {
+            let param = sched_param { sched_priority: 1 };
+            if sched_setscheduler(0, SCHED_FIFO, &param) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    let mut working_set = WorkingSet::new(hugepages)?;
+    let interval = interval.as_nanos() as i64;
+    let end = to_nanos(&now()) + duration.as_nanos() as i64;
+    let mut next = to_nanos(&now()) + interval;
+    let (mut samples, mut min, mut max, mut total) = (0u64, u64::MAX, 0u64, 0u64);
+
+    while next < end {
+        let target = from_nanos(next);
+        unsafe {
+            clock_nanosleep(
+                CLOCK_MONOTONIC,
+                TIMER_ABSTIME,
+                &target,
+                std::ptr::null_mut(),
+            );
+        }
+
+        working_set.touch();
+        let latency = (to_nanos(&now()) - next).max(0) as u64;
+        samples += 1;
+        min = min.min(latency);
+        max = max.max(latency);
+        total += latency;
+        next += interval;
+    }
+
+    if samples == 0 {
+        return Ok((0, 0, 0, 0));
+    }
+
+    Ok((samples, min, total / samples, max))
+}
+
+fn latency_result(
+    mode: &str,
+    cpu: Option<usize>,
+    result: Result<(u64, u64, u64, u64), io::Error>,
+) -> LatencyResult {
+    let mut latency = LatencyResult {
+        mode: mode.to_string(),
+        cpu,
+        samples: 0,
+        min_ns: 0,
+        avg_ns: 0,
+        max_ns: 0,
+        error: None,
+    };
+
+    match result {
+        Ok((samples, min, avg, max)) => {
+            latency.samples = samples;
+            latency.min_ns = min;
+            latency.avg_ns = avg;
+            latency.max_ns = max;
+        }
+        Err(err) => latency.error = Some(err.to_string()),
+    }
+
+    latency
+}
+
+/// Measure the wake up latency on the given CPU's in parallel
+///
+/// [pinned] decides if the measuring threads are pinned to their CPU, [fifo] if they run with realtime priority
+/// and [hugepages] if their working set is in hugepages
+pub fn measure_latency(
+    mode: &str,
+    cpus: &[usize],
+    pinned: bool,
+    fifo: bool,
+    hugepages: bool,
+    duration: Duration,
+) -> Vec<LatencyResult> {
+    let handles = cpus
+        .iter()
+        .map(|cpu| {
+            let cpu = *cpu;
+            let handle = thread::spawn(move || {
+                measure(
+                    Some(cpu).filter(|_| pinned),
+                    fifo,
+                    hugepages,
+                    duration,
+                    Duration::from_micros(1000),
+                )
+            });
+
+            (cpu, handle)
+        })
+        .collect::<Vec<_>>();
+
+    handles
+        .into_iter()
+        .map(|(cpu, handle)| {
+            let result = handle.join().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Latency measurement thread panicked",
+                ))
+            });
+
+            latency_result(mode, Some(cpu).filter(|_| pinned), result)
+        })
+        .collect()
+}
+
+/// Time spent waiting on the run queue in ns, and the amount of times the thread got a CPU
+fn parse_schedstat(schedstat: &str) -> Option<(u64, u64)> {
+    let mut fields = schedstat.split_whitespace().skip(1);
+    let wait = fields.next()?.parse().ok()?;
+    let timeslices = fields.next()?.parse().ok()?;
+    Some((wait, timeslices))
+}
+
+fn read_schedstat(pid: u32, tid: usize) -> Result<(u64, u64), io::Error> {
+    let path = format!("/proc/{}/task/{}/schedstat", pid, tid);
+    let schedstat = std::fs::read_to_string(&path)?;
+    parse_schedstat(&schedstat).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Can't parse {} ({:?})", path, schedstat.trim()),
+        )
+    })
+}
+
+/// Latency of every (wait, timeslices) reading in [readings] to the next, as measured for [measure]
+fn run_queue_latency(readings: &[(u64, u64)]) -> (u64, u64, u64, u64) {
+    let (mut samples, mut min, mut max) = (0u64, u64::MAX, 0u64);
+    for pair in readings.windows(2) {
+        let wait = pair[1].0.saturating_sub(pair[0].0);
+        let timeslices = pair[1].1.saturating_sub(pair[0].1);
+        if timeslices == 0 {
+            continue;
+        }
+
+        let latency = wait / timeslices;
+        samples += 1;
+        min = min.min(latency);
+        max = max.max(latency);
+    }
+
+    if samples == 0 {
+        return (0, 0, 0, 0);
+    }
+
+    let (first, last) = (readings[0], readings[readings.len() - 1]);
+    let avg = (last.0 - first.0) / (last.1 - first.1).max(1);
+    (samples, min, avg, max)
+}
+
+/// Measure how long the vCPU [threads] of QEMU [pid] wait for their CPU once they're runnable,
+/// as (thread id, host CPU it's pinned to)
+pub fn measure_vcpu_threads(
+    pid: u32,
+    threads: &[(usize, Option<usize>)],
+    duration: Duration,
+) -> Vec<LatencyResult> {
+    let mut readings = threads.iter().map(|_| Ok(vec![])).collect::<Vec<_>>();
+    let end = Instant::now() + duration;
+    loop {
+        for ((tid, _), thread_readings) in threads.iter().zip(readings.iter_mut()) {
+            if let Ok(list) = thread_readings {
+                match read_schedstat(pid, *tid) {
+                    Ok(reading) => list.push(reading),
+                    Err(err) => *thread_readings = Err(err),
+                }
+            }
+        }
+
+        if Instant::now() >= end {
+            break;
+        }
+
+        thread::sleep(SCHEDSTAT_INTERVAL);
+    }
+
+    threads
+        .iter()
+        .zip(readings)
+        .map(|((_, cpu), readings)| {
+            latency_result("vcpu", *cpu, readings.map(|x| run_queue_latency(&x)))
+        })
+        .collect()
+}
+
+/// Everything `vore bench` measures for a VM, taken from it up front so the measurements
+/// can run on their own thread
+#[derive(Clone, Debug)]
+pub struct BenchPlan {
+    /// Host CPU's the vCPU's are pinned to
+    pub cpus: Vec<usize>,
+    /// Pid of QEMU, if it's running
+    pub pid: Option<u32>,
+    /// vCPU threads of QEMU, with the host CPU they're pinned to
+    pub vcpu_threads: Vec<(usize, Option<usize>)>,
+}
+
+impl BenchPlan {
+    /// Measure the vCPU threads as they are, then the CPU's they're pinned to unpinned, pinned,
+    /// pinned with realtime priority and with hugepages on top, to show what each step gains
+    ///
+    /// This takes up to 5 times [duration]
+    pub fn run(&self, duration: Duration) -> Vec<LatencyResult> {
+        let mut results = match self.pid {
+            Some(pid) => measure_vcpu_threads(pid, &self.vcpu_threads, duration),
+            None => vec![latency_result(
+                "vcpu",
+                None,
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "QEMU isn't running, only the host CPU's are measured",
+                )),
+            )],
+        };
+
+        let cpus = &self.cpus;
+        results.extend(measure_latency(
+            "unpinned", cpus, false, false, false, duration,
+        ));
+        results.extend(measure_latency(
+            "pinned", cpus, true, false, false, duration,
+        ));
+        results.extend(measure_latency(
+            "pinned-fifo",
+            cpus,
+            true,
+            true,
+            false,
+            duration,
+        ));
+        results.extend(measure_latency(
+            "pinned-fifo-hugepages",
+            cpus,
+            true,
+            true,
+            true,
+            duration,
+        ));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::latency::{
+        measure_latency, measure_vcpu_threads, parse_schedstat, run_queue_latency,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_schedstat() {
+        assert_eq!(
+            parse_schedstat("1266536433 54325671 8071\n"),
+            Some((54325671, 8071))
+        );
+        assert_eq!(parse_schedstat("1266536433"), None);
+    }
+
+    #[test]
+    fn test_run_queue_latency() {
+        // 3 intervals, the second without getting the CPU at all
+        let readings = [(1000, 10), (3000, 12), (3000, 12), (9000, 14)];
+        assert_eq!(run_queue_latency(&readings), (2, 1000, 2000, 3000));
+        assert_eq!(run_queue_latency(&[(1000, 10)]), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_measure_latency() {
+        let results = measure_latency(
+            "pinned",
+            &[0],
+            true,
+            false,
+            false,
+            Duration::from_millis(50),
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].error, None);
+        assert_eq!(results[0].cpu, Some(0));
+        assert!(results[0].samples > 0);
+        assert!(results[0].min_ns <= results[0].avg_ns && results[0].avg_ns <= results[0].max_ns);
+    }
+
+    #[test]
+    fn test_measure_vcpu_threads() {
+        // This test thread stands in for a vCPU
+        let pid = std::process::id();
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as usize;
+        let results =
+            measure_vcpu_threads(pid, &[(tid, Some(3)), (0, None)], Duration::from_millis(50));
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].mode, "vcpu");
+        assert_eq!(results[0].cpu, Some(3));
+        assert_eq!(results[0].error, None);
+        assert!(results[1].error.is_some());
+    }
+}
@@ -1,17 +1,84 @@
+mod block_device;
+mod cgroup;
 pub mod consts;
 mod cpu_list;
+mod disk_image;
+mod edid;
 mod global_config;
+mod guest_actions;
+mod guest_agent;
+mod guest_tools;
+mod hooks;
+mod host_checks;
 mod instance_config;
+mod iommu;
+mod isolation;
+mod latency;
+mod lint;
+mod log_context;
+mod looking_glass;
+mod mdev;
 mod qemu;
 pub mod rpc;
+mod sandbox;
+mod snapshot;
+mod socket_forward;
+mod sriov;
+mod stealth;
+mod test_support;
+mod uefi_vars;
 pub mod utils;
+mod vdagent;
+mod vfio_recovery;
 mod virtual_machine;
 mod virtual_machine_info;
 
+#[cfg(feature = "host")]
+pub use block_device::*;
+#[cfg(feature = "host")]
+pub use cgroup::*;
+pub use disk_image::*;
+pub use edid::*;
 pub use global_config::*;
+pub use host_checks::*;
 pub use instance_config::*;
+pub use iommu::*;
+pub use lint::*;
+pub use log_context::with_log_context;
 pub use qemu::QemuCommandBuilder;
 #[cfg(feature = "host")]
+pub use guest_actions::*;
+#[cfg(feature = "host")]
+pub use guest_agent::*;
+#[cfg(feature = "host")]
+pub use guest_tools::*;
+#[cfg(feature = "host")]
+pub use isolation::*;
+#[cfg(feature = "host")]
+pub use latency::*;
+#[cfg(feature = "host")]
+pub use looking_glass::*;
+#[cfg(feature = "host")]
+pub use mdev::*;
+#[cfg(feature = "host")]
+pub use sandbox::*;
+#[cfg(feature = "host")]
+pub use snapshot::*;
+#[cfg(feature = "host")]
+pub use socket_forward::*;
+#[cfg(feature = "host")]
+pub use sriov::*;
+#[cfg(feature = "host")]
+pub use stealth::*;
+#[cfg(any(test, feature = "test-support"))]
+pub use test_support::*;
+#[cfg(feature = "host")]
+pub use uefi_vars::*;
+#[cfg(feature = "host")]
+pub use vdagent::*;
+#[cfg(feature = "host")]
+pub use vfio_recovery::*;
+#[cfg(feature = "host")]
 pub use virtual_machine::*;
 pub use virtual_machine_info::*;
 
@@ -23,5 +90,10 @@ pub fn init_logging() {
         builder.filter_level(LevelFilter::Debug);
     }
     builder.parse_filters(&std::env::var("RUST_LOG").unwrap_or_else(|_| "".to_string()));
-    builder.init();
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(log_context::ContextLogger {
+        inner: Box::new(logger),
+    }))
+    .expect("Failed to set logger");
 }
@@ -0,0 +1,257 @@
+// Checks for configurations that are valid, but most likely not what the user wants
+//
+// Some of these look at the host (sysfs, procfs), so they only make sense when run on
+// the machine that will run the VM
+
+use crate::{InstanceConfig, ScreamMode, VideoModel};
+use std::fs::read_to_string;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+/// Kernel parameters that release the boot framebuffer so the GPU can be handed to a VM
+const CONSOLE_HANDOVER_PARAMS: &[&str] = &[
+    "efifb:off",
+    "vesafb:off",
+    "simplefb:off",
+    "initcall_blacklist=sysfb_init",
+];
+
+/// Returns a list of warnings about the given config
+pub fn lint(config: &InstanceConfig) -> Vec<String> {
+    let mut warnings = config
+        .deprecations
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+
+    if config.scream.enabled && !config.pulse.enabled && !config.jack.enabled {
+        warnings.push(
+            "scream is enabled, but there's no audio backend, enable pulse or jack to hear anything"
+                .to_string(),
+        );
+    }
+
+    if config.scream.enabled && config.scream.mode == ScreamMode::Network && !config.net.ipv4 {
+        warnings.push(
+            "scream is in network mode, but net.ipv4 is disabled, the guest can't send any audio"
+                .to_string(),
+        );
+    }
+
+    let spice = &config.spice;
+    if spice.enabled
+        && spice.port.is_some()
+        && spice.password.is_none()
+        && !["127.0.0.1", "::1", "localhost"].contains(&spice.listen.as_str())
+    {
+        warnings.push(format!(
+            "spice listens on {} without a password, anyone who can reach it controls the VM",
+            spice.listen
+        ));
+    }
+
+    if config.video.model == VideoModel::None && config.health.enabled {
+        warnings.push(
+            "video.model is none, but health is enabled, there's no display to take screenshots of"
+                .to_string(),
+        );
+    }
+
+    if config.tpm.enabled && !config.uefi.enabled {
+        warnings
+            .push("tpm is enabled, but uefi isn't, Windows 11 needs both to install".to_string());
+    }
+
+    if config.sev.enabled && config.looking_glass.enabled {
+        warnings.push(
+            "looking-glass is enabled together with sev, the guest can't share its encrypted memory with the host"
+                .to_string(),
+        );
+    }
+
+    if config.looking_glass.enabled && !config.spice.enabled {
+        warnings.push(
+            "looking-glass is enabled, but spice is disabled, looking-glass will have no way to pass input"
+                .to_string(),
+        );
+    }
+
+    if config.looking_glass.enabled && !config.vfio.iter().any(|x| x.graphics) {
+        warnings
+            .push("looking-glass is enabled, but no vfio device is marked as graphics".to_string());
+    }
+
+    if config.looking_glass.enabled {
+        for resolution in &config.looking_glass.resolutions {
+            let mut needed = config.looking_glass.clone();
+            needed.width = resolution.width;
+            needed.height = resolution.height;
+            needed.calc_buffer_size_from_screen();
+            if needed.buffer_size > config.looking_glass.buffer_size {
+                warnings.push(format!(
+                    "looking-glass resolution {} doesn't fit in its shared memory of {} MiB, looking-glass will fail when the guest switches to it",
+                    resolution,
+                    config.looking_glass.buffer_size / MIB
+                ));
+            }
+        }
+    }
+
+    let cmdline = read_to_string("/proc/cmdline").unwrap_or_default();
+    for vfio in config.vfio.iter().filter(|x| x.graphics) {
+        let boot_vga = read_to_string(format!(
+            "/sys/bus/pci/devices/{}/boot_vga",
+            vfio.address.to_pci_string()
+        ))
+        .map_or(false, |x| x.trim() == "1");
+
+        if boot_vga
+            && !CONSOLE_HANDOVER_PARAMS
+                .iter()
+                .any(|param| cmdline.contains(param))
+        {
+            warnings.push(format!(
+                "vfio device {} is the boot GPU, but the host console isn't released from it (add e.g. video=efifb:off to the kernel command line)",
+                vfio.address
+            ));
+        }
+    }
+
+    if config.standby {
+        for vfio in config.vfio.iter().filter(|x| x.release) {
+            warnings.push(format!(
+                "vfio device {} is given back to the host when QEMU goes away, but standby launches QEMU again right after",
+                vfio.address
+            ));
+        }
+    }
+
+    // Memory is configured in MiB
+    let memory = config.memory * MIB;
+    if memory > 16 * GIB {
+        let reserved = hugepages_reserved();
+        if reserved < memory {
+            warnings.push(format!(
+                "VM has {} GiB of memory, but only {} GiB of hugepages are reserved",
+                memory / GIB,
+                reserved / GIB
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Amount of bytes reserved as hugepages on this host
+fn hugepages_reserved() -> u64 {
+    parse_hugepages_reserved(&read_to_string("/proc/meminfo").unwrap_or_default())
+}
+
+fn parse_hugepages_reserved(meminfo: &str) -> u64 {
+    let mut total = 0;
+    let mut size = 0;
+    for line in meminfo.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next().map(str::parse::<u64>)) {
+            (Some("HugePages_Total:"), Some(Ok(value))) => total = value,
+            // Given in kB
+            (Some("Hugepagesize:"), Some(Ok(value))) => size = value * 1024,
+            _ => {}
+        }
+    }
+
+    total * size
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lint::{hugepages_reserved, lint, parse_hugepages_reserved, GIB};
+    use crate::InstanceConfig;
+
+    fn lint_toml(toml: &str) -> Vec<String> {
+        lint(&InstanceConfig::from_toml(toml).expect("Failed to parse config"))
+    }
+
+    fn assert_warns(toml: &str, warning: &str) {
+        let warnings = lint_toml(toml);
+        assert!(
+            warnings.iter().any(|x| x.contains(warning)),
+            "{:?} doesn't warn about {}",
+            warnings,
+            warning
+        );
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(lint_toml(""), Vec::<String>::new());
+        assert_eq!(
+            lint_toml("[machine]\nmemory = \"16G\""),
+            Vec::<String>::new()
+        );
+        let hugepages = lint_toml("[machine]\nmemory = \"64G\"")
+            .iter()
+            .any(|x| x.contains("VM has 64 GiB of memory"));
+        assert_eq!(hugepages, hugepages_reserved() < 64 * GIB);
+
+        assert_warns("[scream]\nenabled = true", "there's no audio backend");
+        assert!(lint_toml("[scream]\nenabled = true\n[pulse]\nenabled = true").is_empty());
+        assert_warns(
+            "[spice]\nenabled = true\nlisten = \"0.0.0.0\"\nport = 5900",
+            "spice listens on 0.0.0.0 without a password",
+        );
+        assert!(lint_toml("[spice]\nenabled = true\nport = 5900").is_empty());
+        assert_warns(
+            "[video]\nmodel = \"none\"\n[health]\nenabled = true",
+            "no display to take screenshots of",
+        );
+        assert_warns("[tpm]\nenabled = true", "Windows 11 needs both");
+        assert!(lint_toml("machine.features = [\"tpm\", \"uefi\"]").is_empty());
+        assert_warns(
+            "[looking-glass]\nenabled = true",
+            "spice is disabled, looking-glass will have no way to pass input",
+        );
+        assert_warns(
+            "[looking-glass]\nenabled = true",
+            "no vfio device is marked as graphics",
+        );
+    }
+
+    #[test]
+    fn test_lint_deprecations() {
+        assert_warns(
+            "[scream]\nenabled = true\nmem-path = \"/dev/shm/scream\"\n[pulse]\nenabled = true",
+            "scream.mem-path",
+        );
+    }
+
+    #[test]
+    fn test_lint_looking_glass_resolutions() {
+        // 64 MiB fits 2560x1440, but not 3840x2160
+        let toml = "[looking-glass]\nenabled = true\nbuffer-size = 33554432\nresolutions = [\"2560x1440\", \"3840x2160@60\"]";
+        let warnings = lint_toml(toml)
+            .into_iter()
+            .filter(|x| x.contains("doesn't fit in its shared memory"))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("3840x2160"));
+        assert!(warnings[0].contains("64 MiB"));
+    }
+
+    #[test]
+    fn test_parse_hugepages_reserved() {
+        let meminfo = "MemTotal:       65765432 kB
+HugePages_Total:      16
+HugePages_Free:       16
+Hugepagesize:    1048576 kB
+Hugetlb:        16777216 kB
+";
+        assert_eq!(parse_hugepages_reserved(meminfo), 16 * GIB);
+        assert_eq!(
+            parse_hugepages_reserved("HugePages_Total:       0\nHugepagesize:       2048 kB\n"),
+            0
+        );
+        assert_eq!(parse_hugepages_reserved(""), 0);
+    }
+}
@@ -0,0 +1,65 @@
+// Context put in front of every log record of the current thread
+//
+// vored sets it to the id of the RPC request it's handling, so everything that's logged while
+// handling it can be matched to the client that sent it
+
+use log::{Log, Metadata, Record};
+use std::cell::RefCell;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Run [f] with [context] in front of everything it logs
+pub fn with_log_context<T, F: FnOnce() -> T>(context: &str, f: F) -> T {
+    let previous = CONTEXT.with(|x| x.replace(Some(context.to_string())));
+    let result = f();
+    CONTEXT.with(|x| *x.borrow_mut() = previous);
+    result
+}
+
+/// Logger that passes records on to [inner], with the context of the thread in front
+pub(crate) struct ContextLogger {
+    pub(crate) inner: Box<dyn Log>,
+}
+
+impl Log for ContextLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        CONTEXT.with(|context| match context.borrow().as_deref() {
+            Some(context) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", context, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        })
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_context::{with_log_context, CONTEXT};
+
+    #[test]
+    fn test_context_is_restored() {
+        with_log_context("outer", || {
+            with_log_context("inner", || {
+                CONTEXT.with(|x| assert_eq!(x.borrow().as_deref(), Some("inner")));
+            });
+            CONTEXT.with(|x| assert_eq!(x.borrow().as_deref(), Some("outer")));
+        });
+        CONTEXT.with(|x| assert_eq!(*x.borrow(), None));
+    }
+}
@@ -0,0 +1,110 @@
+#![cfg(feature = "host")]
+
+// Session state of Looking Glass, read from the shared memory the guest's host application writes its
+// LGMP (Looking Glass Memory Protocol) header to, and from the processes on the host that mapped it
+//
+// Only the start of the header is read, which stayed the same over the LGMP versions: magic, version,
+// session id and a timestamp the host application keeps updating while it runs
+//
+// The shared memory doesn't tell the size of the frames, so with looking-glass.watch-resolution the
+// resolution of the guest is asked through the guest agent instead, to warn before the frames don't
+// fit anymore. spice-vdagent can't tell, its monitors config only goes from the client to the guest
+
+use std::convert::TryInto;
+use std::fs::{read_dir, read_to_string, File};
+use std::io::Read;
+use std::path::Path;
+
+const LGMP_MAGIC: &[u8; 4] = b"LGMP";
+const HEADER_SIZE: usize = 16;
+
+/// Start of the LGMP header in the shared memory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LgmpHeader {
+    pub version: u32,
+    /// Changes every time the host application (re)starts
+    pub session_id: u32,
+    pub timestamp: u32,
+}
+
+/// The LGMP header in [path], None if the host application never initialized it
+pub fn read_lgmp_header<P: AsRef<Path>>(path: P) -> Option<LgmpHeader> {
+    let mut header = [0u8; HEADER_SIZE];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[..4] != LGMP_MAGIC {
+        return None;
+    }
+
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    Some(LgmpHeader {
+        version: read_u32(4),
+        session_id: read_u32(8),
+        timestamp: read_u32(12),
+    })
+}
+
+/// Program run in the guest through the guest agent, printing the resolution of every display adapter
+///
+/// qemu-ga runs as a service, which only sees its own session, but WMI reports the adapters' modes
+pub const GUEST_RESOLUTION_COMMAND: (&str, &[&str]) = (
+    "powershell.exe",
+    &[
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        "Get-CimInstance Win32_VideoController | ForEach-Object { \"$($_.CurrentHorizontalResolution)x$($_.CurrentVerticalResolution)\" }",
+    ],
+);
+
+/// Largest resolution printed by [GUEST_RESOLUTION_COMMAND], as (width, height)
+///
+/// Adapters without a display print just an x, those are skipped
+pub fn parse_guest_resolution(output: &str) -> Option<(u64, u64)> {
+    output
+        .lines()
+        .filter_map(|x| {
+            let mut parts = x.trim().splitn(2, 'x');
+            let width = parts.next()?.parse::<u64>().ok()?;
+            let height = parts.next()?.parse::<u64>().ok()?;
+            Some((width, height))
+        })
+        .max_by_key(|(width, height)| width * height)
+}
+
+/// Pids of the processes that mapped [path], other than [exclude] (QEMU)
+pub fn looking_glass_clients<P: AsRef<Path>>(path: P, exclude: u32) -> Vec<u32> {
+    let suffix = format!(" {}", path.as_ref().to_string_lossy());
+    let entries = match read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut clients = entries
+        .filter_map(|x| x.ok())
+        .filter_map(|x| x.file_name().to_str().and_then(|x| x.parse::<u32>().ok()))
+        .filter(|pid| *pid != exclude)
+        .filter(|pid| {
+            // The path is the last field of a mapping, it may contain spaces itself
+            read_to_string(format!("/proc/{}/maps", pid))
+                .map_or(false, |maps| maps.lines().any(|x| x.ends_with(&suffix)))
+        })
+        .collect::<Vec<_>>();
+    clients.sort_unstable();
+    clients
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::looking_glass::parse_guest_resolution;
+
+    #[test]
+    fn test_parse_guest_resolution() {
+        assert_eq!(
+            parse_guest_resolution("x\r\n1920x1080\r\n3840x2160\r\n"),
+            Some((3840, 2160))
+        );
+        assert_eq!(parse_guest_resolution("x\r\n"), None);
+        assert_eq!(parse_guest_resolution(""), None);
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "host")]
+
+// Mediated devices (mdev), slices of a device the host driver hands out, like Intel GVT-g or NVIDIA vGPU
+//
+// They're created on the parent device via sysfs when a VM is prepared, and removed again once
+// QEMU of the VM goes away
+
+use crate::PciAddress;
+use anyhow::Context;
+use std::fs::{read_dir, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+fn types_dir(parent: &PciAddress) -> PathBuf {
+    PathBuf::from(format!(
+        "/sys/bus/pci/devices/{:#}/mdev_supported_types",
+        parent
+    ))
+}
+
+fn write_value(path: &Path, value: &str) -> Result<(), anyhow::Error> {
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut x| x.write_all(format!("{}\n", value).as_bytes()))
+        .with_context(|| format!("Failed to write {} to {:?}", value, path))
+}
+
+/// Path QEMU gets the mediated device by, with vfio-pci,sysfsdev=
+pub fn mdev_path(uuid: &str) -> String {
+    format!("/sys/bus/mdev/devices/{}", uuid)
+}
+
+pub fn mdev_exists(uuid: &str) -> bool {
+    Path::new(&mdev_path(uuid)).exists()
+}
+
+/// A new random UUID for a mediated device
+pub fn new_mdev_uuid() -> Result<String, anyhow::Error> {
+    Ok(read_to_string("/proc/sys/kernel/random/uuid")
+        .context("Failed to generate a UUID for a mediated device")?
+        .trim()
+        .to_string())
+}
+
+/// Check if [parent] can create another mediated device of [mdev_type]
+pub fn check_mdev_type(parent: &PciAddress, mdev_type: &str) -> Result<(), anyhow::Error> {
+    let types_dir = types_dir(parent);
+    if !types_dir.is_dir() {
+        anyhow::bail!(
+            "PCI device {} doesn't support mediated devices, make sure its driver has it enabled (e.g. i915.enable_gvt=1 and the kvmgt module for Intel GVT-g)",
+            parent
+        );
+    }
+
+    let type_dir = types_dir.join(mdev_type);
+    if !type_dir.is_dir() {
+        let mut types = read_dir(&types_dir)?
+            .filter_map(|x| x.ok())
+            .map(|x| x.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        types.sort();
+        anyhow::bail!(
+            "PCI device {} doesn't support mediated device type {}, it supports: {}",
+            parent,
+            mdev_type,
+            types.join(", ")
+        );
+    }
+
+    let available = read_to_string(type_dir.join("available_instances"))
+        .ok()
+        .and_then(|x| u32::from_str(x.trim()).ok());
+    if available == Some(0) {
+        anyhow::bail!(
+            "PCI device {} can't create another mediated device of type {}, all are in use",
+            parent,
+            mdev_type
+        );
+    }
+
+    Ok(())
+}
+
+/// Create mediated device [uuid] of [mdev_type] on [parent]
+pub fn create_mdev(parent: &PciAddress, mdev_type: &str, uuid: &str) -> Result<(), anyhow::Error> {
+    check_mdev_type(parent, mdev_type)?;
+    write_value(&types_dir(parent).join(mdev_type).join("create"), uuid)
+        .with_context(|| format!("Failed to create mediated device {} on {}", uuid, parent))
+}
+
+pub fn remove_mdev(uuid: &str) -> Result<(), anyhow::Error> {
+    write_value(&Path::new(&mdev_path(uuid)).join("remove"), "1")
+}
@@ -1,7 +1,8 @@
 #![cfg(feature = "host")]
 
 use crate::consts::VORE_CONFIG;
-use crate::{GlobalConfig, InstanceConfig};
+use crate::rpc::{QemuId, QemuIdMap};
+use crate::{rtc_base, GlobalConfig, InstanceConfig};
 use anyhow::Context;
 use mlua::prelude::LuaError;
 use mlua::{
@@ -20,31 +21,42 @@ struct VirtualMachine {
     args: Vec<String>,
     bus_ids: HashMap<String, usize>,
     devices: HashMap<String, String>,
-    device: bool,
+    /// Every id given on the command line so far
+    ids: Vec<QemuId>,
+    /// Option of which the value is the next argument, if it's one that can have an id
+    option: Option<String>,
 }
 
+/// Options of which the ids are kept, the value of all but -drive starts with the driver or type
+const ID_OPTIONS: &[&str] = &["-device", "-object", "-chardev", "-netdev", "-drive"];
+
 impl UserData for VirtualMachine {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method_mut("arg", |_, this, args: MultiValue| {
             for item in args.iter() {
                 if let Value::String(item) = item {
                     let item = item.to_str()?.to_string();
-                    if this.device {
-                        let mut items = item.split(',');
-                        if let Some(_type) = items.next() {
-                            for item in items {
-                                if let Some(id) = item.strip_prefix("id=") {
-                                    this.devices.insert(_type.to_string(), id.to_string());
-                                    break;
-                                }
+                    if let Some(option) = this.option.take() {
+                        let driver = item
+                            .split(',')
+                            .next()
+                            .filter(|x| !x.contains('='))
+                            .unwrap_or_default();
+                        if let Some(id) = item.split(',').find_map(|x| x.strip_prefix("id=")) {
+                            if option == "-device" {
+                                this.devices.insert(driver.to_string(), id.to_string());
                             }
-                        }
 
-                        this.device = false;
+                            this.ids.push(QemuId {
+                                kind: option.trim_start_matches('-').to_string(),
+                                id: id.to_string(),
+                                driver: driver.to_string(),
+                            });
+                        }
                     }
 
-                    if item == "-device" {
-                        this.device = true;
+                    if ID_OPTIONS.contains(&item.as_str()) {
+                        this.option = Some(item.clone());
                     }
 
                     this.args.push(item)
@@ -180,6 +192,10 @@ impl UserData for VoreLuaWeakStorage {
             path_str.to_lua(lua)
         });
 
+        methods.add_method("rtc_base", |_, _, timezone: String| {
+            rtc_base(&timezone).map_err(LuaError::external)
+        });
+
         methods.add_method(
             "add_disk",
             |lua,
@@ -233,6 +249,8 @@ pub struct QemuCommandBuilder {
     lua: Lua,
     script: String,
     storage: VoreLuaStorage,
+    /// User QEMU runs as for VM's that don't set qemu.run-as
+    run_as: String,
 }
 
 impl QemuCommandBuilder {
@@ -251,6 +269,11 @@ impl QemuCommandBuilder {
                 format!("Failed to load lua qemu command build script ({:?})", lua)
             })?,
             storage: VoreLuaStorage::new(working_dir),
+            run_as: global
+                .qemu
+                .run_as
+                .clone()
+                .unwrap_or_else(|| "nobody".to_string()),
         };
 
         builder.init(global)?;
@@ -299,6 +322,14 @@ impl QemuCommandBuilder {
     }
 
     pub fn build(self, config: &InstanceConfig) -> Result<Vec<String>, anyhow::Error> {
+        self.build_with_ids(config).map(|(cmd, _)| cmd)
+    }
+
+    /// Build the command line, together with the ids the script gave everything on it
+    pub fn build_with_ids(
+        self,
+        config: &InstanceConfig,
+    ) -> Result<(Vec<String>, QemuIdMap), anyhow::Error> {
         self.lua
             .load(&self.script)
             .eval::<()>()
@@ -336,7 +367,7 @@ impl QemuCommandBuilder {
             "timestamp=on".into(),
             // Drop privileges as soon as possible
             "-runas".into(),
-            "nobody".into(),
+            config.run_as.as_ref().unwrap_or(&self.run_as).clone(),
         ];
 
         let working_dir = working_dir
@@ -355,10 +386,24 @@ impl QemuCommandBuilder {
         cmd.push("chardev=charmonitor,id=monitor,mode=control".to_string());
 
         cmd.append(&mut vm_instance.args);
+        cmd.extend(config.extra_args.iter().cloned());
+
+        let mut ids = vec![QemuId {
+            kind: "chardev".to_string(),
+            id: "charmonitor".to_string(),
+            driver: "socket".to_string(),
+        }];
+        ids.append(&mut vm_instance.ids);
 
         self.clean_up()?;
 
-        Ok(cmd)
+        Ok((
+            cmd,
+            QemuIdMap {
+                ids,
+                counters: vm_instance.bus_ids.into_iter().collect(),
+            },
+        ))
     }
 
     pub fn clean_up(self) -> anyhow::Result<()> {
@@ -1,8 +1,11 @@
 use crate::rpc::{Request, Response};
-use crate::VirtualMachineInfo;
+use crate::{
+    InstanceConfig, PciAddress, VirtualMachineInfo, VirtualMachineState, VirtualMachineStats,
+};
 use paste::paste;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
 
 macro_rules! define_requests {
     ($($name:ident($req:tt, $resp:tt))+) => {
@@ -57,12 +60,210 @@ impl Response for AllResponses {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartStep {
+    /// Checking the VM and binding its VFIO devices to vfio-pci
+    Prepare,
+    /// Spawning QEMU, or taking over its standby QEMU
+    Launch,
+    /// Waiting for the QMP socket of QEMU and its handshake
+    Connect,
+    /// Letting the vCPU's run
+    Resume,
+}
+
+/// Step of a start in progress, sent to clients that asked for it before the answer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StartProgress {
+    pub step: StartStep,
+    pub message: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DiskPreset {
     pub name: String,
     pub description: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UefiBootEntry {
+    pub id: u16,
+    pub description: String,
+    pub active: bool,
+    /// Position in BootOrder, if it's in there at all
+    pub position: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BootRecord {
+    /// Unix timestamp
+    pub started: u64,
+    /// Unix timestamp, None while it's still running
+    pub stopped: Option<u64>,
+    /// How the VM went down, e.g. stopped or crashed
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Artifact {
+    /// Path relative to the working directory of the VM
+    pub path: String,
+    pub size: u64,
+    /// Last modification as unix timestamp
+    pub modified: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LatencyResult {
+    pub mode: String,
+    /// CPU the measurement was pinned to, if any
+    pub cpu: Option<usize>,
+    pub samples: u64,
+    pub min_ns: u64,
+    pub avg_ns: u64,
+    pub max_ns: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Removable drive of a VM, with the medium that's in it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CdromDrive {
+    /// ISO in the drive, None if it's empty
+    pub path: Option<String>,
+    pub tray_open: bool,
+    /// The guest locked the tray, ejecting forces it open anyway
+    pub locked: bool,
+}
+
+/// USB device passed through to a running VM
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsbDevice {
+    /// Id of the usb-host device in QEMU
+    pub id: String,
+    /// vendor:product or bus-port, like it's given to attach
+    pub device: String,
+    /// Attached while the VM runs, instead of coming from its config
+    pub hotplugged: bool,
+}
+
+/// Root port of a running VM PCI devices can be attached to, see machine.pci-hotplug-slots
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PciSlot {
+    /// Counted from 1
+    pub slot: u32,
+    pub address: Option<PciAddress>,
+    /// Detach was requested, but the guest didn't let go of it yet
+    pub detaching: bool,
+}
+
+/// Id of a device, object or backend on the command line of QEMU
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct QemuId {
+    /// device, object, chardev, netdev or drive, after the option it was given with
+    pub kind: String,
+    pub id: String,
+    /// Driver, type or backend, e.g. vfio-pci or memory-backend-file, empty for drives
+    pub driver: String,
+}
+
+/// Ids the QEMU script gave everything it added, in the order they are on the command line
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct QemuIdMap {
+    pub ids: Vec<QemuId>,
+    /// Buses and counters handed out by get_next_bus and get_counter, with the last value
+    pub counters: BTreeMap<String, usize>,
+}
+
+impl QemuIdMap {
+    pub fn get(&self, kind: &str, id: &str) -> Option<&QemuId> {
+        self.ids.iter().find(|x| x.kind == kind && x.id == id)
+    }
+}
+
+/// PCI device vore bound to vfio-pci
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VfioBinding {
+    pub address: PciAddress,
+    /// Driver it was bound to before, empty if it had none
+    pub driver: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A VM definition was loaded
+    Loaded,
+    /// A VM definition was unloaded
+    Unloaded,
+    /// A VM changed state
+    State,
+    /// Something about a running VM needs attention, e.g. its looking-glass shared memory is too small
+    Warning,
+}
+
+impl EventKind {
+    pub fn from_name(name: &str) -> Result<EventKind, anyhow::Error> {
+        Ok(match name {
+            "loaded" => EventKind::Loaded,
+            "unloaded" => EventKind::Unloaded,
+            "state" => EventKind::State,
+            "warning" => EventKind::Warning,
+            _ => anyhow::bail!(
+                "'{}' is not an event type (loaded, unloaded, state or warning)",
+                name
+            ),
+        })
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EventKind::Loaded => "loaded",
+            EventKind::Unloaded => "unloaded",
+            EventKind::State => "state",
+            EventKind::Warning => "warning",
+        })
+    }
+}
+
+/// Something that happened to a VM, sent to the connections that subscribed to it after the answer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VmEvent {
+    pub kind: EventKind,
+    pub name: String,
+    /// State of the VM after the event, None once it's unloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<VirtualMachineState>,
+    /// What the warning is about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Snapshots of all disks of a VM taken at the same moment, reverted together
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotGroup {
+    /// Name of the group, and of the internal snapshot in every image
+    pub name: String,
+    /// Unix time it was taken at
+    pub created: u64,
+    pub disks: Vec<String>,
+    /// Taken while QEMU ran, in one QMP transaction
+    pub live: bool,
+    /// The filesystems of the guest were frozen through the guest agent while it was taken
+    pub frozen: bool,
+}
+
+/// What a subscriber gets after the answer of its subscribe call
+#[derive(Clone, Debug)]
+pub enum EventMessage {
+    Event(VmEvent),
+    /// Events that vored dropped, because the subscriber didn't read them fast enough
+    Lost(u64),
+}
+
 define_requests! {
     Info({}, {
         pub name: String,
@@ -89,12 +290,24 @@ define_requests! {
         pub name: String,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         pub cdroms: Vec<String>,
-    }, {})
+        /// Only check what would fail, without changing anything
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub dry_run: bool,
+    }, {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub problems: Vec<String>,
+    })
 
     Start({
         pub name: String,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         pub cdroms: Vec<String>,
+        /// Device to boot from first, only for this start
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub boot: Option<String>,
+        /// Send a progress message for every step before the answer
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub progress: bool,
     }, {})
 
     Stop({
@@ -109,7 +322,231 @@ define_requests! {
         pub name: String,
     }, {})
 
+    Wait({
+        pub name: String,
+        pub state: VirtualMachineState,
+        /// Timeout in seconds, waits indefinitely if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub timeout: Option<u64>,
+    }, {
+        pub state: VirtualMachineState,
+    })
+
+    Subscribe({
+        /// Only send events of these VM's, all if empty
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub names: Vec<String>,
+        /// Only send events of these kinds, all if empty
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub kinds: Vec<EventKind>,
+    }, {})
+
     DiskPresets({}, {
         pub presets: Vec<DiskPreset>
     })
+
+    Stats({
+        pub name: String,
+    }, {
+        pub stats: VirtualMachineStats,
+    })
+
+    Bench({
+        pub name: String,
+        /// Duration of every measurement in seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub duration: Option<u64>,
+    }, {
+        pub results: Vec<LatencyResult>
+    })
+
+    ListArtifacts({
+        pub name: String,
+    }, {
+        pub artifacts: Vec<Artifact>
+    })
+
+    FetchArtifact({
+        pub name: String,
+        pub path: String,
+    }, {
+        pub data: Vec<u8>
+    })
+
+    History({
+        pub name: String,
+    }, {
+        /// Seconds this VM has been running in total, including the current boot
+        pub total_uptime: u64,
+        /// Most recent last
+        pub boots: Vec<BootRecord>,
+    })
+
+    Clipboard({
+        pub name: String,
+        /// Text to put on the clipboard of the guest, only the current clipboard is returned if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub set: Option<String>,
+    }, {
+        /// Last text copied in the guest
+        pub text: Option<String>,
+    })
+
+    Describe({
+        pub name: String,
+    }, {
+        /// Config the VM is currently loaded with
+        pub config: InstanceConfig,
+        /// Contents of the saved definition of the VM, if it was saved
+        pub stored: Option<String>,
+    })
+
+    Memory({
+        pub name: String,
+        /// Memory the guest should use in MiB, only the current size is returned if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub set: Option<u64>,
+    }, {
+        /// Memory the VM was started with in MiB
+        pub memory: u64,
+        /// Current size of the balloon in bytes, if the VM is running and has a balloon device
+        pub balloon: Option<u64>,
+    })
+
+    Release({
+        pub name: String,
+    }, {
+        /// Devices that were given back to their host driver
+        pub devices: Vec<PciAddress>,
+    })
+
+    VfioRecover({
+        /// Only list the devices that would be released
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub dry_run: bool,
+    }, {
+        /// Devices bound to vfio-pci by vore that no loaded VM uses
+        pub devices: Vec<VfioBinding>,
+    })
+
+    Cdrom({
+        pub name: String,
+        /// CD-ROM drive to change, counted from 0
+        #[serde(default)]
+        pub drive: usize,
+        /// ISO to insert into the drive, replacing the one in it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub insert: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub eject: bool,
+    }, {
+        pub drives: Vec<CdromDrive>,
+    })
+
+    Usb({
+        pub name: String,
+        /// USB device to pass through, as vendor:product or bus-port
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub attach: Option<String>,
+        /// USB device to unplug, by its id, vendor:product or bus-port
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub detach: Option<String>,
+    }, {
+        pub devices: Vec<UsbDevice>,
+    })
+
+    Pci({
+        pub name: String,
+        /// PCI device of the host to bind to vfio-pci and attach to a free slot
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub attach: Option<PciAddress>,
+        /// PCI device to unplug, it's given back to its host driver once the guest let go of it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub detach: Option<PciAddress>,
+        /// Also rebind devices of which the driver is blacklisted from automatic rebinding
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub force: bool,
+    }, {
+        pub slots: Vec<PciSlot>,
+    })
+
+    Repin({
+        pub name: String,
+        /// Host CPU's to pin the vCPU's to, in the order of the vCPU's, only the current pinning is returned if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub cpus: Option<Vec<usize>>,
+        /// Also store the pinning in the saved definition, so it's used after vored restarts
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub save: bool,
+    }, {
+        /// Host CPU's the vCPU's are pinned to, None if there are more vCPU's than host CPU's
+        pub cpus: Option<Vec<usize>>,
+    })
+
+    Fsfreeze({
+        pub name: String,
+        /// Freeze the filesystems of the guest, they're thawed again after this many seconds at the latest
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub freeze: Option<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub thaw: bool,
+    }, {
+        /// "frozen" or "thawed", as the guest agent reports it
+        pub status: String,
+        /// Filesystems frozen or thawed by this call
+        pub filesystems: u64,
+    })
+
+    QemuIds({
+        pub name: String,
+    }, {
+        pub map: QemuIdMap,
+    })
+
+    UefiBootEntries({
+        pub name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub order: Option<Vec<u16>>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub delete: Vec<u16>,
+    }, {
+        pub entries: Vec<UefiBootEntry>
+    })
+
+    UefiVarsPath({
+        pub name: String,
+    }, {
+        pub path: String,
+        /// The store is only created when the VM is first started
+        pub exists: bool,
+    })
+
+    UefiReset({
+        pub name: String,
+    }, {
+        pub path: String,
+        /// Where the previous store was moved to, if there was one
+        pub backup: Option<String>,
+    })
+
+    Snapshot({
+        pub name: String,
+        /// Snapshot every disk as a group with this name
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub create: Option<String>,
+        /// Put every disk of this group back, the VM has to be stopped
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub revert: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub delete: Option<String>,
+    }, {
+        pub snapshots: Vec<SnapshotGroup>,
+    })
+
+    Clean({
+        pub name: String,
+    }, {
+        /// Sockets and shared memory files that were removed
+        pub removed: Vec<String>,
+    })
 }
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Machine readable reason a call failed, sent along with the human readable message
+///
+/// Codes are never renamed or reused, new ones may be added, those are read as [ErrorCode::Other]
+/// by older clients
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// Anything that doesn't have a more specific code
+    #[default]
+    Other,
+    /// detail: name
+    VmNotFound,
+    /// The given definition couldn't be parsed
+    InvalidConfig,
+    /// detail: address, driver
+    VfioNotBound,
+    /// detail: address
+    VfioBindFailed,
+    Unimplemented,
+    /// The VM can't run while another one does, detail: name, reason
+    Conflict,
+    /// The VM isn't in a state this can be done in (e.g. not running), detail: name
+    InvalidState,
+    /// Gave up waiting for something, e.g. the VM to reach a state
+    Timeout,
+    PermissionDenied,
+    /// Only used by the CLI, when it can't connect to vored at all
+    DaemonUnreachable,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Other => "other",
+            ErrorCode::VmNotFound => "vm_not_found",
+            ErrorCode::InvalidConfig => "invalid_config",
+            ErrorCode::VfioNotBound => "vfio_not_bound",
+            ErrorCode::VfioBindFailed => "vfio_bind_failed",
+            ErrorCode::Unimplemented => "unimplemented",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::InvalidState => "invalid_state",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::DaemonUnreachable => "daemon_unreachable",
+        }
+    }
+
+    /// Exit code the CLI uses for this error, so scripts can tell them apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorCode::Other => 1,
+            ErrorCode::VmNotFound => 2,
+            ErrorCode::InvalidConfig => 3,
+            ErrorCode::VfioNotBound => 4,
+            ErrorCode::VfioBindFailed => 5,
+            ErrorCode::Unimplemented => 6,
+            ErrorCode::Conflict => 7,
+            ErrorCode::InvalidState => 8,
+            ErrorCode::Timeout => 9,
+            ErrorCode::PermissionDenied => 10,
+            ErrorCode::DaemonUnreachable => 11,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "vm_not_found" => ErrorCode::VmNotFound,
+            "invalid_config" => ErrorCode::InvalidConfig,
+            "vfio_not_bound" => ErrorCode::VfioNotBound,
+            "vfio_bind_failed" => ErrorCode::VfioBindFailed,
+            "unimplemented" => ErrorCode::Unimplemented,
+            "conflict" => ErrorCode::Conflict,
+            "invalid_state" => ErrorCode::InvalidState,
+            "timeout" => ErrorCode::Timeout,
+            "permission_denied" => ErrorCode::PermissionDenied,
+            "daemon_unreachable" => ErrorCode::DaemonUnreachable,
+            _ => ErrorCode::Other,
+        })
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        Ok(ErrorCode::from_str(&code).unwrap())
+    }
+}
+
+/// Error with a code, errors returned from handling a call that have one of these in their
+/// chain are answered with its code and detail
+#[derive(Clone, Debug)]
+pub struct RpcError {
+    pub code: ErrorCode,
+    pub detail: HashMap<String, String>,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> RpcError {
+        RpcError {
+            code,
+            detail: HashMap::new(),
+            message: message.into(),
+        }
+    }
+
+    pub fn with_detail<V: ToString>(mut self, key: &str, value: V) -> RpcError {
+        self.detail.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn vm_not_found(name: &str) -> RpcError {
+        RpcError::new(
+            ErrorCode::VmNotFound,
+            format!("No machine with the name {} exists", name),
+        )
+        .with_detail("name", name)
+    }
+
+    pub fn not_running(name: &str) -> RpcError {
+        RpcError::new(
+            ErrorCode::InvalidState,
+            format!("VM {} isn't running", name),
+        )
+        .with_detail("name", name)
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::{CommandCenter, ErrorCode, InfoRequest, InfoResponse, RpcError};
+    use anyhow::Context;
+
+    #[test]
+    fn test_code_survives_context() {
+        let command = CommandCenter::default()
+            .write_command(InfoRequest {})
+            .map(|(_, json)| CommandCenter::read_command(&json).unwrap())
+            .unwrap();
+        let err: Result<InfoResponse, anyhow::Error> =
+            Err(RpcError::vm_not_found("test")).context("Failed to start");
+        let answer = CommandCenter::write_answer(&command, err).unwrap();
+
+        let err = CommandCenter::read_answer::<InfoRequest>(&answer).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::VmNotFound));
+    }
+
+    #[test]
+    fn test_unknown_code_is_other() {
+        let code: ErrorCode = serde_json::from_str("\"from_the_future\"").unwrap();
+        assert_eq!(code, ErrorCode::Other);
+    }
+}
@@ -1,7 +1,9 @@
 mod calls;
+mod error;
 mod serde;
 mod traits;
 
 pub use calls::*;
+pub use error::*;
 pub use crate::rpc::serde::*;
 pub use traits::*;
\ No newline at end of file
@@ -1,4 +1,5 @@
-use crate::rpc::{Command, Request, Answer, AnswerResult, AnswerError, Response};
+use crate::rpc::{Command, Request, Answer, AnswerResult, AnswerError, Response, RpcError, ErrorCode, StartProgress, StartResponse, SubscribeResponse, VmEvent, EventMessage};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fmt;
 use std::error::Error;
@@ -10,9 +11,20 @@ pub struct CommandCenter {
 
 impl CommandCenter {
     pub fn write_command<R: Request>(&mut self, request: R) -> Result<(u64, String), anyhow::Error> {
+        self.write_command_with(request, false)
+    }
+
+    /// Like write_command, but the daemon will still execute it if we disconnect before it ran
+    pub fn write_detached_command<R: Request>(&mut self, request: R) -> Result<(u64, String), anyhow::Error> {
+        self.write_command_with(request, true)
+    }
+
+    fn write_command_with<R: Request>(&mut self, request: R, detach: bool) -> Result<(u64, String), anyhow::Error> {
         let command = Command {
             id: self.id,
+            detach,
             data: request.into_enum(),
+            request_id: None,
         };
 
         self.id += 1;
@@ -27,9 +39,15 @@ impl CommandCenter {
             id: request.id,
             data: match answer {
                 Ok(data) => AnswerResult::Ok(data),
-                Err(err) => AnswerResult::Error(AnswerError {
-                    error: format!("{:?}", err)
-                })
+                Err(err) => {
+                    let rpc_error = err.chain().find_map(|x| x.downcast_ref::<RpcError>());
+                    AnswerResult::Error(AnswerError {
+                        error: format!("{:?}", err),
+                        code: rpc_error.map_or(ErrorCode::Other, |x| x.code),
+                        detail: rpc_error.map_or_else(HashMap::new, |x| x.detail.clone()),
+                        request_id: request.request_id.clone(),
+                    })
+                }
             },
         };
 
@@ -38,6 +56,51 @@ impl CommandCenter {
         Ok(str)
     }
 
+    /// Progress of [request] that's still running, the answer follows later
+    pub fn write_progress(request: &Command, progress: StartProgress) -> Result<String, anyhow::Error> {
+        let answer = Answer::<StartResponse> {
+            id: request.id,
+            data: AnswerResult::Progress(progress),
+        };
+
+        let mut str = serde_json::to_string(&answer)?;
+        str.push('\n');
+        Ok(str)
+    }
+
+    /// Event for the subscribe call with [id]
+    pub fn write_event(id: u64, event: VmEvent) -> Result<String, anyhow::Error> {
+        CommandCenter::write_event_message(id, AnswerResult::Event(event))
+    }
+
+    /// Marker for the [count] events the subscribe call with [id] lost
+    pub fn write_lost(id: u64, count: u64) -> Result<String, anyhow::Error> {
+        CommandCenter::write_event_message(id, AnswerResult::Lost { count })
+    }
+
+    fn write_event_message(id: u64, data: AnswerResult<SubscribeResponse>) -> Result<String, anyhow::Error> {
+        let mut str = serde_json::to_string(&Answer { id, data })?;
+        str.push('\n');
+        Ok(str)
+    }
+
+    /// The event in [answer], None if it's something else
+    pub fn read_event(answer: &str) -> Option<(u64, EventMessage)> {
+        match serde_json::from_str::<Answer<SubscribeResponse>>(answer) {
+            Ok(Answer { id, data: AnswerResult::Event(event) }) => Some((id, EventMessage::Event(event))),
+            Ok(Answer { id, data: AnswerResult::Lost { count } }) => Some((id, EventMessage::Lost(count))),
+            _ => None,
+        }
+    }
+
+    /// The progress in [answer], if it isn't the actual answer
+    pub fn read_progress(answer: &str) -> Option<(u64, StartProgress)> {
+        match serde_json::from_str::<Answer<StartResponse>>(answer) {
+            Ok(Answer { id, data: AnswerResult::Progress(progress) }) => Some((id, progress)),
+            _ => None,
+        }
+    }
+
     pub fn read_command(request: &str) -> Result<Command, anyhow::Error> {
         serde_json::from_str(request).map_err(From::from)
     }
@@ -48,7 +111,9 @@ impl CommandCenter {
 
         match answer_obj.data {
             AnswerResult::Error(err) => Err(CommandError::AnswerError(answer_obj.id, err)),
-            AnswerResult::Ok(data) => Ok((answer_obj.id, data))
+            AnswerResult::Ok(data) => Ok((answer_obj.id, data)),
+            AnswerResult::Progress(_) => Err(CommandError::InternalError(anyhow::anyhow!("Got progress instead of an answer"))),
+            AnswerResult::Event(_) | AnswerResult::Lost { .. } => Err(CommandError::InternalError(anyhow::anyhow!("Got an event instead of an answer"))),
         }
     }
 }
@@ -59,15 +124,44 @@ pub enum CommandError {
     InternalError(anyhow::Error),
 }
 
+impl CommandError {
+    /// Code of the error the daemon answered with, None if the call failed on our side
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            CommandError::AnswerError(_, err) => Some(err.code),
+            CommandError::InternalError(_) => None
+        }
+    }
+}
+
 impl Display for CommandError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            CommandError::AnswerError(idx, err) => {
-                write!(f, "{}\n(rpc call {})", err.error, idx)
-            }
+            CommandError::AnswerError(idx, err) => match &err.request_id {
+                Some(request_id) => write!(f, "{}\n(rpc call {}, logged by vored as [{}])", err.error, idx, request_id),
+                None => write!(f, "{}\n(rpc call {})", err.error, idx),
+            },
             CommandError::InternalError(err) => err.fmt(f)
         }
     }
 }
 
-impl Error for CommandError {}
\ No newline at end of file
+impl Error for CommandError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::{CommandCenter, StopRequest};
+
+    #[test]
+    fn test_detached_command() {
+        let mut center = CommandCenter::default();
+        let (_, json) = center.write_command(StopRequest { name: "win10".to_string() }).unwrap();
+        assert!(!json.contains("detach"));
+        assert!(!CommandCenter::read_command(&json).unwrap().detach);
+
+        let (id, json) = center.write_detached_command(StopRequest { name: "win10".to_string() }).unwrap();
+        let command = CommandCenter::read_command(&json).unwrap();
+        assert!(command.detach);
+        assert_eq!(command.id, id);
+    }
+}
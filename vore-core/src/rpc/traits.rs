@@ -1,13 +1,20 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Debug;
-use crate::rpc::{AllRequests, AllResponses};
+use crate::rpc::{AllRequests, AllResponses, ErrorCode, StartProgress, VmEvent};
 use serde::de::DeserializeOwned;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Command {
     pub id: u64,
+    /// Keep executing this command even if the connection that sent it is closed before it ran
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detach: bool,
     #[serde(flatten)]
     pub data: AllRequests,
+    /// Set by the daemon, to tell the commands of every connection apart in its log
+    #[serde(skip)]
+    pub request_id: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,11 +30,43 @@ pub enum AnswerResult<R: Response> {
     Error(AnswerError),
     #[serde(bound = "R: Response")]
     Ok(R),
+    /// Not the answer yet, only sent for calls that ask for progress
+    Progress(StartProgress),
+    /// Sent after the answer of a subscribe call, for every event that matches it
+    Event(VmEvent),
+    /// Events that were dropped in front of the next one, since the subscriber fell behind
+    Lost { count: u64 },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnswerError {
     pub(crate) error: String,
+    #[serde(default)]
+    pub(crate) code: ErrorCode,
+    /// Machine readable details, which keys are set depends on the code
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) detail: HashMap<String, String>,
+    /// What the daemon logged the failed command as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) request_id: Option<String>,
+}
+
+impl AnswerError {
+    pub fn message(&self) -> &str {
+        &self.error
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn detail(&self, key: &str) -> Option<&str> {
+        self.detail.get(key).map(String::as_str)
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
 }
 
 pub trait Request: Serialize + DeserializeOwned + Clone + Debug {
@@ -0,0 +1,406 @@
+#![cfg(feature = "host")]
+
+// Mount (and optionally network) namespace QEMU is launched in, with an empty root that only has the
+// system directories of the host (read-only) and the paths the VM needs mounted into it
+//
+// /dev, /proc and /sys are read-only too, device nodes can still be opened for writing on a read-only
+// mount, and the mounts below them (e.g. /dev/shm, /dev/hugepages and /dev/pts) stay writable
+//
+// Everything is worked out before forking, the child only does plain syscalls between fork and exec
+
+use anyhow::Context;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Needed to run QEMU at all, mounted read-only
+const SYSTEM_PATHS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+/// Kernel interfaces QEMU needs for KVM, VFIO, hugepages and shared memory, mounted read-only with
+/// everything below them
+const KERNEL_PATHS: &[&str] = &["/dev", "/proc", "/sys"];
+
+/// Directory in the new root the old root is moved to, before it's unmounted
+const OLD_ROOT: &str = ".old-root";
+
+#[derive(Debug)]
+enum Step {
+    Mkdir(CString),
+    Touch(CString),
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    Tmpfs(CString),
+    Bind {
+        source: CString,
+        target: CString,
+        recursive: bool,
+        read_only: bool,
+    },
+}
+
+#[derive(Debug)]
+struct Mount {
+    path: PathBuf,
+    writable: bool,
+    recursive: bool,
+}
+
+#[derive(Debug)]
+pub struct Sandbox {
+    /// Directory the new root is built in, it's only mounted in the namespace of QEMU
+    root: PathBuf,
+    network: bool,
+    mounts: Vec<Mount>,
+}
+
+impl Sandbox {
+    pub fn new<P: AsRef<Path>>(root: P, network: bool) -> Sandbox {
+        let mut sandbox = Sandbox {
+            root: root.as_ref().to_path_buf(),
+            network,
+            mounts: vec![],
+        };
+
+        for path in SYSTEM_PATHS {
+            sandbox.add(path, false);
+        }
+
+        for path in KERNEL_PATHS {
+            sandbox.mounts.push(Mount {
+                path: PathBuf::from(path),
+                writable: false,
+                recursive: true,
+            });
+        }
+
+        sandbox
+    }
+
+    /// Make [path] available to QEMU, paths that don't exist are skipped
+    pub fn add<P: AsRef<Path>>(&mut self, path: P, writable: bool) {
+        self.mounts.push(Mount {
+            path: path.as_ref().to_path_buf(),
+            writable,
+            recursive: false,
+        });
+    }
+
+    /// Make [command] enter the sandbox before it executes
+    pub fn apply(&self, command: &mut Command) -> Result<(), anyhow::Error> {
+        std::fs::create_dir_all(&self.root)
+            .with_context(|| format!("Failed to create sandbox root {:?}", self.root))?;
+
+        let root = cstring(&self.root)?;
+        let old_root = CString::new(OLD_ROOT)?;
+        let steps = self.steps()?;
+        let mut flags = libc::CLONE_NEWNS;
+        if self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+
+        unsafe {
+            command.pre_exec(move || enter_sandbox(flags, &root, &old_root, &steps));
+        }
+
+        Ok(())
+    }
+
+    fn steps(&self) -> Result<Vec<Step>, anyhow::Error> {
+        let mut mounts = self
+            .mounts
+            .iter()
+            .filter(|x| x.path.is_absolute())
+            .collect::<Vec<_>>();
+        // Parents first, so paths below them are mounted on top
+        mounts.sort_by_key(|x| x.path.components().count());
+
+        let mut steps = vec![];
+        let mut created = HashSet::new();
+        let mut mounted: Vec<&Mount> = vec![];
+        for mount in mounts {
+            let covered = mounted
+                .iter()
+                .any(|x| mount.path.starts_with(&x.path) && (x.writable || !mount.writable));
+            if covered {
+                continue;
+            }
+
+            let meta = match std::fs::symlink_metadata(&mount.path) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+
+            let target = self.root.join(mount.path.strip_prefix("/")?);
+            self.create_parents(&target, &mut created, &mut steps)?;
+
+            // /lib is a link to usr/lib on most distributions, which should stay a link
+            if meta.file_type().is_symlink()
+                && SYSTEM_PATHS.iter().any(|x| mount.path == Path::new(x))
+            {
+                steps.push(Step::Symlink {
+                    target: cstring(&std::fs::read_link(&mount.path)?)?,
+                    link: cstring(&target)?,
+                });
+                continue;
+            }
+
+            let source = std::fs::canonicalize(&mount.path)
+                .with_context(|| format!("Failed to resolve {:?} for the sandbox", mount.path))?;
+            if source.is_dir() {
+                if created.insert(target.clone()) {
+                    steps.push(Step::Mkdir(cstring(&target)?));
+                }
+            } else {
+                steps.push(Step::Touch(cstring(&target)?));
+            }
+
+            steps.push(Step::Bind {
+                source: cstring(&source)?,
+                target: cstring(&target)?,
+                recursive: mount.recursive,
+                read_only: !mount.writable,
+            });
+            mounted.push(mount);
+        }
+
+        let tmp = self.root.join("tmp");
+        self.create_parents(&tmp, &mut created, &mut steps)?;
+        steps.push(Step::Mkdir(cstring(&tmp)?));
+        steps.push(Step::Tmpfs(cstring(&tmp)?));
+        steps.push(Step::Mkdir(cstring(&self.root.join(OLD_ROOT))?));
+
+        Ok(steps)
+    }
+
+    fn create_parents(
+        &self,
+        target: &Path,
+        created: &mut HashSet<PathBuf>,
+        steps: &mut Vec<Step>,
+    ) -> Result<(), anyhow::Error> {
+        let mut parents = target
+            .ancestors()
+            .skip(1)
+            .take_while(|x| *x != self.root)
+            .collect::<Vec<_>>();
+        parents.reverse();
+
+        for parent in parents {
+            if created.insert(parent.to_path_buf()) {
+                steps.push(Step::Mkdir(cstring(parent)?));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn cstring(path: &Path) -> Result<CString, anyhow::Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("{:?} can't be used in the sandbox", path))
+}
+
+fn check(res: libc::c_int) -> Result<(), io::Error> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn exists(path: &CString) -> bool {
+    unsafe { libc::access(path.as_ptr(), libc::F_OK) == 0 }
+}
+
+/// Move the calling process into its own namespaces and the new root, this runs between fork and exec
+fn enter_sandbox(
+    flags: libc::c_int,
+    root: &CString,
+    old_root: &CString,
+    steps: &[Step],
+) -> Result<(), io::Error> {
+    let none = std::ptr::null();
+    unsafe {
+        check(libc::unshare(flags))?;
+        // Nothing mounted from here on should show up on the host
+        check(libc::mount(
+            none,
+            b"/\0".as_ptr() as *const libc::c_char,
+            none,
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ))?;
+        check(libc::mount(
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            root.as_ptr(),
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            libc::MS_NOSUID | libc::MS_NODEV,
+            b"mode=0755\0".as_ptr() as *const libc::c_void,
+        ))?;
+
+        for step in steps {
+            match step {
+                Step::Mkdir(path) => {
+                    if !exists(path) {
+                        check(libc::mkdir(path.as_ptr(), 0o755))?;
+                    }
+                }
+                Step::Touch(path) => {
+                    if !exists(path) {
+                        let fd = libc::open(
+                            path.as_ptr(),
+                            libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                            0o644,
+                        );
+                        check(fd)?;
+                        libc::close(fd);
+                    }
+                }
+                Step::Symlink { target, link } => {
+                    check(libc::symlink(target.as_ptr(), link.as_ptr()))?
+                }
+                Step::Tmpfs(path) => check(libc::mount(
+                    b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    path.as_ptr(),
+                    b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    b"mode=1777\0".as_ptr() as *const libc::c_void,
+                ))?,
+                Step::Bind {
+                    source,
+                    target,
+                    recursive,
+                    read_only,
+                } => {
+                    let rec = if *recursive { libc::MS_REC } else { 0 };
+                    check(libc::mount(
+                        source.as_ptr(),
+                        target.as_ptr(),
+                        none,
+                        libc::MS_BIND | rec,
+                        std::ptr::null(),
+                    ))?;
+
+                    // Read-only can only be set by remounting the bind mount
+                    if *read_only {
+                        check(libc::mount(
+                            none,
+                            target.as_ptr(),
+                            none,
+                            libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+                            std::ptr::null(),
+                        ))?;
+                    }
+                }
+            }
+        }
+
+        check(libc::chdir(root.as_ptr()))?;
+        check(libc::syscall(
+            libc::SYS_pivot_root,
+            b".\0".as_ptr() as *const libc::c_char,
+            old_root.as_ptr(),
+        ) as libc::c_int)?;
+        check(libc::chdir(b"/\0".as_ptr() as *const libc::c_char))?;
+        check(libc::umount2(old_root.as_ptr(), libc::MNT_DETACH))?;
+        check(libc::rmdir(old_root.as_ptr()))?;
+
+        check(libc::mount(
+            none,
+            b"/\0".as_ptr() as *const libc::c_char,
+            none,
+            libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+            std::ptr::null(),
+        ))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sandbox::{Sandbox, Step};
+    use std::ffi::CString;
+    use std::path::Path;
+
+    /// (source, recursive, read_only) of every bind mount in [steps]
+    fn binds(steps: &[Step]) -> Vec<(String, bool, bool)> {
+        steps
+            .iter()
+            .filter_map(|x| match x {
+                Step::Bind {
+                    source,
+                    recursive,
+                    read_only,
+                    ..
+                } => Some((source.to_str().unwrap().to_string(), *recursive, *read_only)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn path_string(path: &Path) -> String {
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_steps() {
+        let dir = std::env::temp_dir().join(format!("vore-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("vm/disks")).unwrap();
+        std::fs::write(dir.join("vm/disks/win10.img"), b"").unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        let root = dir.join("root");
+
+        let mut sandbox = Sandbox::new(&root, false);
+        sandbox.add(dir.join("vm"), false);
+        sandbox.add(dir.join("vm/disks/win10.img"), true);
+        // Already available through the writable disk image
+        sandbox.add(dir.join("vm/disks/win10.img"), false);
+        sandbox.add(dir.join("vm/missing"), true);
+        sandbox.add("relative", true);
+        sandbox.add("/dev/shm", true);
+        let steps = sandbox.steps().unwrap();
+        let binds = binds(&steps);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for path in &["/dev", "/proc", "/sys"] {
+            assert!(binds.contains(&(path.to_string(), true, true)), "{}", path);
+        }
+
+        assert!(binds.contains(&("/dev/shm".to_string(), false, false)));
+        assert!(binds.contains(&(path_string(&dir.join("vm")), false, true)));
+        assert!(binds.contains(&(path_string(&dir.join("vm/disks/win10.img")), false, false)));
+        assert!(!binds
+            .iter()
+            .any(|x| x.0.ends_with("missing") || x.0.ends_with("relative")));
+        assert_eq!(
+            binds.iter().filter(|x| x.0.ends_with("win10.img")).count(),
+            1
+        );
+
+        // Directories are created before they're mounted on, files touched
+        let position = |wanted: &Step| {
+            steps.iter().position(|x| match (x, wanted) {
+                (Step::Mkdir(a), Step::Mkdir(b)) | (Step::Touch(a), Step::Touch(b)) => a == b,
+                _ => false,
+            })
+        };
+        let disks = CString::new(path_string(
+            &root.join(dir.join("vm/disks").strip_prefix("/").unwrap()),
+        ))
+        .unwrap();
+        let image = CString::new(path_string(
+            &root.join(dir.join("vm/disks/win10.img").strip_prefix("/").unwrap()),
+        ))
+        .unwrap();
+        assert!(position(&Step::Mkdir(disks)).unwrap() < position(&Step::Touch(image)).unwrap());
+        assert!(
+            matches!(steps.last(), Some(Step::Mkdir(x)) if x.to_str().unwrap().ends_with(".old-root"))
+        );
+    }
+}
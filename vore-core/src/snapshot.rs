@@ -0,0 +1,143 @@
+#![cfg(feature = "host")]
+
+// Internal qcow2 snapshots of all disks of a VM at once, as a snapshot group
+//
+// While QEMU runs every disk is snapshotted in a single QMP transaction, so they're from the same
+// point in time or none is taken, while it doesn't they're taken with qemu-img. Which disks were in
+// a group is stored in the working dir, so reverting puts back all of them or nothing
+
+use crate::rpc::SnapshotGroup;
+use crate::InstanceConfig;
+use anyhow::Context;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// Disk images that are snapshotted, every disk the guest can write to
+///
+/// They all have to be qcow2, a group without some of them wouldn't be consistent
+pub fn snapshot_disks(config: &InstanceConfig) -> Result<Vec<String>, anyhow::Error> {
+    let disks = config
+        .disks
+        .iter()
+        .filter(|x| !x.read_only && x.preset != "iso")
+        .collect::<Vec<_>>();
+    if let Some(disk) = disks.iter().find(|x| x.disk_type != "qcow2") {
+        anyhow::bail!(
+            "Disk {} is {}, only qcow2 images can be snapshotted",
+            disk.path,
+            disk.disk_type
+        );
+    }
+
+    if disks.is_empty() {
+        anyhow::bail!("VM {} has no disks to snapshot", config.name);
+    }
+
+    Ok(disks.into_iter().map(|x| x.path.clone()).collect())
+}
+
+/// Names of the internal snapshots in the image at [path]
+pub fn image_snapshots(path: &str) -> Result<Vec<String>, anyhow::Error> {
+    let output = Command::new("qemu-img")
+        .args(&["info", "--output=json", "-U", path])
+        .output()
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "qemu-img info {} failed: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let info = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+        .with_context(|| format!("qemu-img info {} returned invalid JSON", path))?;
+    Ok(info
+        .get("snapshots")
+        .and_then(|x| x.as_array())
+        .map_or_else(Vec::new, |snapshots| {
+            snapshots
+                .iter()
+                .filter_map(|x| x.get("name").and_then(|x| x.as_str()))
+                .map(|x| x.to_string())
+                .collect()
+        }))
+}
+
+/// Create (-c), apply (-a) or delete (-d) the internal snapshot [name] of the image at [path],
+/// which nothing may have opened
+pub fn qemu_img_snapshot(action: &str, name: &str, path: &str) -> Result<(), anyhow::Error> {
+    let output = Command::new("qemu-img")
+        .args(&["snapshot", action, name, path])
+        .output()
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "qemu-img snapshot {} {} {} failed: {}",
+            action,
+            name,
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn load_snapshot_groups<P: AsRef<Path>>(path: P) -> Result<Vec<SnapshotGroup>, anyhow::Error> {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(groups) => serde_json::from_str(&groups)
+            .with_context(|| format!("Snapshot groups in {:?} are corrupt", path)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {:?}", path)),
+    }
+}
+
+pub fn save_snapshot_groups<P: AsRef<Path>>(
+    path: P,
+    groups: &[SnapshotGroup],
+) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::write(path, serde_json::to_string(groups)?)
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshot::snapshot_disks;
+    use crate::InstanceConfig;
+
+    #[test]
+    fn test_snapshot_disks() {
+        let config = InstanceConfig::from_toml(
+            r#"
+[[disk]]
+path = "/var/lib/vore/root.qcow2"
+preset = "ssd"
+
+[[disk]]
+path = "/var/lib/vore/data.qcow2"
+preset = "hdd"
+
+[[disk]]
+path = "/var/lib/vore/install.iso"
+preset = "iso"
+"#,
+        )
+        .expect("Failed to parse config");
+        assert_eq!(
+            snapshot_disks(&config).unwrap(),
+            vec!["/var/lib/vore/root.qcow2", "/var/lib/vore/data.qcow2"]
+        );
+
+        let config = InstanceConfig::from_toml("[[disk]]\npath = \"/dev/sdb\"\npreset = \"ssd\"")
+            .expect("Failed to parse config");
+        assert!(snapshot_disks(&config).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+#![cfg(feature = "host")]
+
+// Extra sockets in front of a socket QEMU listens on
+//
+// QEMU only runs a single SPICE server, so to give every seat its own socket, with its own owner
+// and permissions, vore listens on them itself and passes every connection on to QEMU's socket
+
+use anyhow::Context;
+use std::io::{copy, ErrorKind};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Listens on [path] until dropped, and connects every client to [target]
+#[derive(Debug)]
+pub struct SocketForward {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SocketForward {
+    pub fn bind<P: AsRef<Path>, T: AsRef<Path>>(
+        path: P,
+        target: T,
+    ) -> Result<SocketForward, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+        let target = target.as_ref().to_path_buf();
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("Failed to remove old socket {:?}", path))
+            }
+            _ => {}
+        }
+
+        let listener =
+            UnixListener::bind(&path).with_context(|| format!("Failed to bind {:?}", path))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            for client in listener.incoming() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let res = client.and_then(|client| {
+                    let server = UnixStream::connect(&target)?;
+                    pipe(client.try_clone()?, server.try_clone()?);
+                    pipe(server, client);
+                    Ok(())
+                });
+                if let Err(err) = res {
+                    log::warn!("Failed to forward a connection to {:?}: {}", target, err);
+                }
+            }
+        });
+
+        Ok(SocketForward {
+            path,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Copy everything from [from] to [to], until either side closes
+fn pipe(mut from: UnixStream, mut to: UnixStream) {
+    std::thread::spawn(move || {
+        let _ = copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Both);
+        let _ = from.shutdown(Shutdown::Both);
+    });
+}
+
+impl Drop for SocketForward {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the thread up from accepting, so it sees it should stop
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::socket_forward::SocketForward;
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    #[test]
+    fn test_forward() {
+        let dir = std::env::temp_dir().join(format!("vore-forward-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = UnixListener::bind(dir.join("server.sock")).unwrap();
+        let forward = SocketForward::bind(dir.join("seat.sock"), dir.join("server.sock")).unwrap();
+
+        let mut client = UnixStream::connect(forward.path()).unwrap();
+        let (mut accepted, _) = server.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        accepted.write_all(b"pong").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        drop(forward);
+        assert!(!dir.join("seat.sock").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
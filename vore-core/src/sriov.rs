@@ -0,0 +1,170 @@
+#![cfg(feature = "host")]
+
+// SR-IOV virtual functions, used to pass a slice of an (Intel) GPU to a VM
+//
+// The virtual functions are created on the physical function via sysfs when a VM is prepared,
+// and removed again once the VM stops
+
+use crate::PciAddress;
+use anyhow::Context;
+use std::fs::{read_link, read_to_string, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+fn read_number(path: &str) -> Result<i64, anyhow::Error> {
+    let value = read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    i64::from_str(value.trim()).with_context(|| format!("Failed to parse {} ({:?})", path, value))
+}
+
+fn write_value(path: &str, value: &str) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path))?;
+    file.write_all(format!("{}\n", value).as_bytes())
+        .with_context(|| format!("Failed to write {} to {}", value, path))
+}
+
+fn driver_name(addr: &PciAddress) -> Option<String> {
+    read_link(format!("/sys/bus/pci/devices/{:#}/driver", addr))
+        .ok()
+        .and_then(|x| x.file_name().and_then(|x| x.to_str()).map(str::to_string))
+}
+
+/// Value of a kernel module parameter, None if the module doesn't have it
+fn module_param(module: &str, param: &str) -> Result<Option<i64>, anyhow::Error> {
+    let path = format!("/sys/module/{}/parameters/{}", module, param);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+
+    read_number(&path).map(Some)
+}
+
+/// Check if [pf] is able to provide [vfs] virtual functions with the current driver and its options
+pub fn check_sriov_driver(pf: &PciAddress, vfs: u32) -> Result<(), anyhow::Error> {
+    let total_vfs_path = format!("/sys/bus/pci/devices/{:#}/sriov_totalvfs", pf);
+    if !Path::new(&total_vfs_path).exists() {
+        anyhow::bail!(
+            "PCI device {} doesn't support SR-IOV, if this is an Intel GPU make sure it's bound to a driver with SR-IOV support",
+            pf
+        );
+    }
+
+    match driver_name(pf).as_deref() {
+        Some("i915") => {
+            // Virtual functions need GuC submission, which is bit 0 of enable_guc, -1 (auto) doesn't enable SR-IOV
+            match module_param("i915", "enable_guc")? {
+                Some(enable_guc) if enable_guc < 0 || enable_guc & 1 == 0 => anyhow::bail!(
+                    "PCI device {} is driven by i915 with enable_guc={}, SR-IOV needs i915.enable_guc=3 on the kernel command line",
+                    pf,
+                    enable_guc
+                ),
+                _ => {}
+            }
+
+            if module_param("i915", "max_vfs")? == Some(0) {
+                anyhow::bail!(
+                    "PCI device {} is driven by i915 with max_vfs=0, add e.g. i915.max_vfs=7 to the kernel command line",
+                    pf
+                );
+            }
+        }
+
+        Some("xe") => {
+            if module_param("xe", "max_vfs")? == Some(0) {
+                anyhow::bail!(
+                    "PCI device {} is driven by xe with max_vfs=0, add e.g. xe.max_vfs=7 to the kernel command line",
+                    pf
+                );
+            }
+        }
+
+        Some(_) => {}
+
+        None => anyhow::bail!(
+            "PCI device {} has no driver, the driver of the physical function is needed to create virtual functions",
+            pf
+        ),
+    }
+
+    let total_vfs = read_number(&total_vfs_path)?;
+    if total_vfs < vfs as i64 {
+        anyhow::bail!(
+            "PCI device {} can provide {} virtual functions, but {} are needed",
+            pf,
+            total_vfs,
+            vfs
+        );
+    }
+
+    Ok(())
+}
+
+/// Address of the [vf]th (starting at 1) virtual function of [pf], None if it doesn't exist (yet)
+pub fn sriov_vf_address(pf: &PciAddress, vf: u32) -> Result<Option<PciAddress>, anyhow::Error> {
+    let link_path = format!(
+        "/sys/bus/pci/devices/{:#}/virtfn{}",
+        pf,
+        vf.saturating_sub(1)
+    );
+    match read_link(&link_path) {
+        Ok(link) => {
+            let name = link
+                .file_name()
+                .and_then(|x| x.to_str())
+                .ok_or_else(|| anyhow::anyhow!("{} doesn't point to a PCI device", link_path))?;
+
+            Ok(Some(PciAddress::from_str(name)?))
+        }
+
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Make sure [pf] has at least [vfs] virtual functions, returns true if they were created by this call
+pub fn create_sriov_vfs(pf: &PciAddress, vfs: u32) -> Result<bool, anyhow::Error> {
+    let num_vfs_path = format!("/sys/bus/pci/devices/{:#}/sriov_numvfs", pf);
+    let current = read_number(&num_vfs_path)?;
+    if current >= vfs as i64 {
+        return Ok(false);
+    }
+
+    if current != 0 {
+        anyhow::bail!(
+            "PCI device {} already has {} virtual functions but {} are needed, changing the amount would remove the existing ones",
+            pf,
+            current,
+            vfs
+        );
+    }
+
+    // Don't let host drivers claim the new virtual functions, they're bound to vfio-pci afterwards
+    write_value(
+        &format!("/sys/bus/pci/devices/{:#}/sriov_drivers_autoprobe", pf),
+        "0",
+    )?;
+    write_value(&num_vfs_path, &vfs.to_string())?;
+
+    Ok(true)
+}
+
+/// Remove all virtual functions of [pf], unless a virtual function not in [ours] is in use by VFIO
+///
+/// Returns false if they were kept
+pub fn remove_sriov_vfs(pf: &PciAddress, ours: &[PciAddress]) -> Result<bool, anyhow::Error> {
+    let mut vf = 1;
+    while let Some(address) = sriov_vf_address(pf, vf)? {
+        if !ours.contains(&address) && driver_name(&address).as_deref() == Some("vfio-pci") {
+            return Ok(false);
+        }
+
+        vf += 1;
+    }
+
+    write_value(&format!("/sys/bus/pci/devices/{:#}/sriov_numvfs", pf), "0")?;
+    Ok(true)
+}
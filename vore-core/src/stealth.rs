@@ -0,0 +1,184 @@
+#![cfg(feature = "host")]
+
+// Host side of machine.stealth: KVM module parameters that keep guests (anti-cheats, older Nvidia drivers)
+// from crashing on MSR's KVM doesn't emulate
+//
+// These are host wide, so they're shared between all stealth VM's, and only restored once the last one stops
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+const IGNORE_MSRS: &str = "/sys/module/kvm/parameters/ignore_msrs";
+const REPORT_IGNORED_MSRS: &str = "/sys/module/kvm/parameters/report_ignored_msrs";
+
+const STEALTH_PARAMS: &[(&str, &str)] = &[
+    (IGNORE_MSRS, "Y"),
+    // Otherwise every ignored MSR access ends up in the kernel log
+    (REPORT_IGNORED_MSRS, "N"),
+];
+
+lazy_static! {
+    static ref APPLIED: Mutex<HashMap<String, AppliedParam>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+struct AppliedParam {
+    original: String,
+    /// Names of the VM's that need this parameter
+    users: Vec<String>,
+}
+
+/// A host setting that was changed for a VM
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct HostChange {
+    pub path: String,
+    /// Value before the first VM that needed it started
+    pub original: String,
+    pub value: String,
+}
+
+impl HostChange {
+    /// If the setting was already set before vore touched it
+    pub fn is_noop(&self) -> bool {
+        self.original == self.value
+    }
+}
+
+impl Display for HostChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.path, self.original, self.value)
+    }
+}
+
+fn read_param(path: &str) -> Result<String, anyhow::Error> {
+    Ok(read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path))?
+        .trim()
+        .to_string())
+}
+
+fn write_param(path: &str, value: &str) -> Result<(), anyhow::Error> {
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut x| x.write_all(value.as_bytes()))
+        .with_context(|| format!("Failed to write {} to {}", value, path))
+}
+
+/// Apply the host settings stealth VM [vm] needs, returns every setting involved,
+/// including those that were already set
+pub fn apply_stealth(vm: &str) -> Result<Vec<HostChange>, anyhow::Error> {
+    if !Path::new(IGNORE_MSRS).exists() {
+        anyhow::bail!(
+            "{} doesn't exist, stealth needs the kvm module to be loaded",
+            IGNORE_MSRS
+        );
+    }
+
+    let mut applied = APPLIED.lock().unwrap();
+    let mut changes = vec![];
+    for (path, value) in STEALTH_PARAMS {
+        // report_ignored_msrs doesn't exist on older kernels
+        if !Path::new(path).exists() {
+            continue;
+        }
+
+        if let Some(param) = applied.get_mut(*path) {
+            if !param.users.iter().any(|x| x == vm) {
+                param.users.push(vm.to_string());
+            }
+
+            changes.push(HostChange {
+                path: path.to_string(),
+                original: param.original.clone(),
+                value: value.to_string(),
+            });
+            continue;
+        }
+
+        let original = read_param(path)?;
+        if original != *value {
+            write_param(path, value)?;
+            log::info!(
+                "Changed {} from {} to {} for stealth VM {}",
+                path,
+                original,
+                value,
+                vm
+            );
+        }
+
+        applied.insert(
+            path.to_string(),
+            AppliedParam {
+                original: original.clone(),
+                users: vec![vm.to_string()],
+            },
+        );
+        changes.push(HostChange {
+            path: path.to_string(),
+            original,
+            value: value.to_string(),
+        });
+    }
+
+    Ok(changes)
+}
+
+/// Register [changes] made by a previous vored for [vm], so they're still restored once it stops
+pub fn adopt_stealth(vm: &str, changes: &[HostChange]) {
+    let mut applied = APPLIED.lock().unwrap();
+    for change in changes {
+        let param = applied
+            .entry(change.path.clone())
+            .or_insert_with(|| AppliedParam {
+                original: change.original.clone(),
+                users: vec![],
+            });
+
+        if !param.users.iter().any(|x| x == vm) {
+            param.users.push(vm.to_string());
+        }
+    }
+}
+
+/// Drop the settings [vm] needed, and restore those no other VM needs anymore
+pub fn restore_stealth(vm: &str) {
+    let mut applied = APPLIED.lock().unwrap();
+    let unused = applied
+        .iter_mut()
+        .filter_map(|(path, param)| {
+            param.users.retain(|x| x != vm);
+            if param.users.is_empty() {
+                Some(path.clone())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for path in unused {
+        let param = applied.remove(&path).unwrap();
+        match read_param(&path) {
+            Ok(current) if current == param.original => {}
+            Ok(current) => match write_param(&path, &param.original) {
+                Ok(_) => log::info!(
+                    "Restored {} from {} to {}, no stealth VM needs it anymore",
+                    path,
+                    current,
+                    param.original
+                ),
+                Err(err) => log::warn!("Failed to restore {}: {:?}", path, err),
+            },
+            Err(err) => log::warn!("Failed to restore {}: {:?}", path, err),
+        }
+    }
+}
@@ -0,0 +1,500 @@
+#![cfg(any(test, feature = "test-support"))]
+
+// A stand-in for QEMU, so starting, stopping and crashing VM's can be tested without KVM
+//
+// It has two halves: a fake qemu-system-x86_64, a shell script the test hands to the VM through
+// qemu.binaries, which only records how it was launched and stays around until it's told to exit,
+// and a QMP server in the test process that listens on the control socket of the VM in its place.
+// The script finds its way back through the control socket path on its command line, so it
+// writes everything next to it in the working directory of the VM
+
+use crate::utils::can_access;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MOCK_QEMU: &str = r#"#!/bin/sh
+# Fake qemu-system-x86_64, QMP is served by MockQemu in the test process
+for arg in "$@"; do
+  case "$arg" in
+    socket,id=charmonitor,path=*) socket="${arg#socket,id=charmonitor,path=}"; socket="${socket%%,*}" ;;
+  esac
+done
+
+if [ -z "$socket" ]; then
+  echo "mock qemu: no control socket given" >&2
+  exit 1
+fi
+
+dir="$(dirname "$socket")"
+# Told to exit by a previous launch
+rm -f "$dir/mock-qemu.exit"
+printf '%s\n' "$@" > "$dir/mock-qemu.args"
+echo $$ > "$dir/mock-qemu.pid"
+trap 'exit 143' TERM
+trap 'exit 130' INT
+while [ ! -e "$dir/mock-qemu.exit" ]; do sleep 0.05; done
+exit "$(cat "$dir/mock-qemu.exit")"
+"#;
+
+/// If VM's can be launched here, creating their cgroup needs root when cgroup v2 is mounted
+pub fn can_launch_vms() -> bool {
+    !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() || can_access("/sys/fs/cgroup", true)
+}
+
+/// If [pid] is still there, and not a zombie waiting to be reaped
+fn is_running(pid: i32) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid)).map_or(false, |x| {
+        x.rsplit(')')
+            .next()
+            .map_or(false, |x| !x.trim_start().starts_with('Z'))
+    })
+}
+
+/// Run state the mock reports in query-status
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MockRunState {
+    Paused,
+    Running,
+    Shutdown,
+}
+
+impl MockRunState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MockRunState::Paused => "paused",
+            MockRunState::Running => "running",
+            MockRunState::Shutdown => "shutdown",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    run_state: MockRunState,
+    /// Every command received, with its arguments
+    commands: Vec<(String, Value)>,
+    /// Answers for commands that don't just return {}
+    answers: Vec<(String, Value)>,
+    /// Write half of the current QMP connection, for events
+    stream: Option<UnixStream>,
+    stopped: bool,
+}
+
+/// The QMP server of a mock QEMU, for a VM with [working_dir] as working directory
+///
+/// Like QEMU with -S it starts paused, and like QEMU it only serves one client at a time.
+/// Dropping it makes the fake QEMU exit and removes [working_dir]
+pub struct MockQemu {
+    working_dir: PathBuf,
+    state: Arc<Mutex<MockState>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockQemu {
+    pub fn new<P: AsRef<Path>>(working_dir: P) -> Result<MockQemu, anyhow::Error> {
+        let working_dir = working_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&working_dir)?;
+        let binary = working_dir.join("qemu-system-x86_64");
+        std::fs::write(&binary, MOCK_QEMU)?;
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755))?;
+        for file in &[
+            "qemu.sock",
+            "mock-qemu.args",
+            "mock-qemu.pid",
+            "mock-qemu.exit",
+        ] {
+            let _ = std::fs::remove_file(working_dir.join(file));
+        }
+
+        let listener = UnixListener::bind(working_dir.join("qemu.sock"))?;
+        listener.set_nonblocking(true)?;
+        let state = Arc::new(Mutex::new(MockState {
+            run_state: MockRunState::Paused,
+            commands: vec![],
+            answers: vec![],
+            stream: None,
+            stopped: false,
+        }));
+
+        let thread_state = state.clone();
+        let exit_file = working_dir.join("mock-qemu.exit");
+        let thread = std::thread::spawn(move || serve(listener, thread_state, exit_file));
+
+        Ok(MockQemu {
+            working_dir,
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// The fake qemu-system-x86_64, to put in qemu.binaries
+    pub fn binary(&self) -> PathBuf {
+        self.working_dir.join("qemu-system-x86_64")
+    }
+
+    /// Answer [command] with [answer] (the value of "return") from now on
+    pub fn answer(&self, command: &str, answer: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .answers
+            .push((command.to_string(), answer));
+    }
+
+    /// Names of the commands received so far, in order
+    pub fn commands(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Arguments of the last [command] received
+    pub fn arguments(&self, command: &str) -> Option<Value> {
+        self.state
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .rev()
+            .find(|(name, _)| name == command)
+            .map(|(_, arguments)| arguments.clone())
+    }
+
+    pub fn run_state(&self) -> MockRunState {
+        self.state.lock().unwrap().run_state
+    }
+
+    /// Send [event] to the connected client, e.g. SHUTDOWN when the guest powers off by itself
+    pub fn emit(&self, event: &str, data: Value) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let stream = state
+            .stream
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Nothing is connected to the mock QEMU"))?;
+        write_event(stream, event, data)
+    }
+
+    /// Command line the fake QEMU was launched with, None if it wasn't launched yet
+    pub fn args(&self) -> Option<Vec<String>> {
+        std::fs::read_to_string(self.working_dir.join("mock-qemu.args"))
+            .ok()
+            .map(|x| x.lines().map(|x| x.to_string()).collect())
+    }
+
+    /// Pid of the fake QEMU, None if it wasn't launched yet
+    pub fn pid(&self) -> Option<i32> {
+        std::fs::read_to_string(self.working_dir.join("mock-qemu.pid"))
+            .ok()
+            .and_then(|x| x.trim().parse().ok())
+    }
+
+    /// Wait until the fake QEMU was launched
+    pub fn wait_for_launch(&self, timeout: Duration) -> Result<i32, anyhow::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(pid) = self.pid() {
+                return Ok(pid);
+            }
+
+            if Instant::now() > deadline {
+                anyhow::bail!("Mock QEMU wasn't launched within {:?}", timeout);
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Let the fake QEMU exit with [code] without saying anything over QMP, like a crash
+    ///
+    /// Like with a real QEMU, the control socket only closes once it's gone
+    pub fn crash(&self, code: i32) {
+        let _ = std::fs::write(self.working_dir.join("mock-qemu.exit"), code.to_string());
+        self.wait_for_exit();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(stream) = state.stream.take() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+
+        // The next one starts paused again
+        state.run_state = MockRunState::Paused;
+    }
+
+    /// Wait (a while) until the fake QEMU exited, if it was launched
+    fn wait_for_exit(&self) {
+        if let Some(pid) = self.pid() {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while is_running(pid) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+impl Drop for MockQemu {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().stopped = true;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        // Don't leave a fake QEMU behind when a test failed halfway, it has to see the exit file
+        // before the directory goes
+        let _ = std::fs::write(self.working_dir.join("mock-qemu.exit"), "0");
+        self.wait_for_exit();
+
+        let _ = std::fs::remove_dir_all(&self.working_dir);
+    }
+}
+
+fn write_event(stream: &mut UnixStream, event: &str, data: Value) -> Result<(), anyhow::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let event = json!({
+        "event": event,
+        "data": data,
+        "timestamp": { "seconds": now.as_secs(), "microseconds": now.subsec_micros() },
+    });
+    stream.write_all(format!("{}\r\n", event).as_bytes())?;
+    Ok(())
+}
+
+/// Version the mock says it is, in the greeting and query-version
+fn version() -> Value {
+    json!({
+        "qemu": { "micro": 0, "minor": 2, "major": 6 },
+        "package": "vore-mock",
+    })
+}
+
+fn serve(listener: UnixListener, state: Arc<Mutex<MockState>>, exit_file: PathBuf) {
+    loop {
+        if state.lock().unwrap().stopped {
+            return;
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+
+        if let Err(err) = serve_connection(stream, &state, &exit_file) {
+            log::debug!("Mock QEMU connection ended: {:?}", err);
+        }
+
+        state.lock().unwrap().stream = None;
+    }
+}
+
+fn serve_connection(
+    stream: UnixStream,
+    mock: &Arc<Mutex<MockState>>,
+    exit_file: &Path,
+) -> Result<(), anyhow::Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+    let mut writer = stream.try_clone()?;
+    mock.lock().unwrap().stream = Some(stream.try_clone()?);
+
+    let greeting = json!({ "QMP": { "version": version(), "capabilities": ["oob"] } });
+    writer.write_all(format!("{}\r\n", greeting).as_bytes())?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        if mock.lock().unwrap().stopped {
+            return Ok(());
+        }
+
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let request: Value = serde_json::from_str(line.trim())?;
+        line.clear();
+        let name = request["execute"].as_str().unwrap_or_default().to_string();
+        let arguments = request
+            .get("arguments")
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+
+        let mut state = mock.lock().unwrap();
+        state.commands.push((name.clone(), arguments));
+
+        let answer = state
+            .answers
+            .iter()
+            .rev()
+            .find(|(command, _)| *command == name)
+            .map(|(_, answer)| answer.clone());
+        let answer = match (name.as_str(), answer) {
+            (_, Some(answer)) => answer,
+            // What QMP::nop sends to see if QEMU's still there
+            ("query-version", None) => version(),
+            ("query-status", None) => json!({
+                "status": state.run_state.as_str(),
+                "singlestep": false,
+                "running": state.run_state == MockRunState::Running,
+            }),
+            _ => json!({}),
+        };
+
+        // Like QEMU, pausing and resuming happen before the answer, the guest shutting down after it
+        match name.as_str() {
+            "cont" if state.run_state != MockRunState::Running => {
+                state.run_state = MockRunState::Running;
+                write_event(&mut writer, "RESUME", json!({}))?;
+            }
+            "stop" if state.run_state == MockRunState::Running => {
+                state.run_state = MockRunState::Paused;
+                write_event(&mut writer, "STOP", json!({}))?;
+            }
+            _ => {}
+        }
+
+        let mut response = json!({ "return": answer });
+        if let Some(id) = request.get("id") {
+            response["id"] = id.clone();
+        }
+        writer.write_all(format!("{}\r\n", response).as_bytes())?;
+
+        match name.as_str() {
+            "system_powerdown" => {
+                // The guest takes a moment to shut down
+                drop(state);
+                std::thread::sleep(Duration::from_millis(50));
+                let mut state = mock.lock().unwrap();
+                write_event(&mut writer, "POWERDOWN", json!({}))?;
+                state.run_state = MockRunState::Shutdown;
+                write_event(
+                    &mut writer,
+                    "SHUTDOWN",
+                    json!({ "guest": true, "reason": "guest-shutdown" }),
+                )?;
+            }
+            "quit" => {
+                state.run_state = MockRunState::Paused;
+                std::fs::write(exit_file, "0")?;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{MockQemu, MockRunState};
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command};
+    use std::time::Duration;
+
+    fn working_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vore-test-{}-{}", std::process::id(), name))
+    }
+
+    fn launch(mock: &MockQemu, dir: &Path) -> Child {
+        Command::new(mock.binary())
+            .arg("-chardev")
+            .arg(format!(
+                "socket,id=charmonitor,path={}/qemu.sock,server=on,wait=off",
+                dir.to_str().unwrap()
+            ))
+            .arg("-S")
+            .spawn()
+            .expect("Failed to launch mock qemu")
+    }
+
+    fn read(reader: &mut BufReader<UnixStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn execute(stream: &mut UnixStream, command: &str) {
+        stream
+            .write_all(format!("{}\n", json!({ "execute": command })).as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_start_and_quit() {
+        let dir = working_dir("quit");
+        let mock = MockQemu::new(&dir).unwrap();
+        let mut child = launch(&mock, &dir);
+        assert_eq!(
+            mock.wait_for_launch(Duration::from_secs(5)).unwrap(),
+            child.id() as i32
+        );
+        assert!(mock.args().unwrap().contains(&"-S".to_string()));
+
+        let mut stream = UnixStream::connect(dir.join("qemu.sock")).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        assert!(read(&mut reader)["QMP"].is_object());
+
+        execute(&mut stream, "qmp_capabilities");
+        assert_eq!(read(&mut reader)["return"], json!({}));
+
+        execute(&mut stream, "cont");
+        assert_eq!(read(&mut reader)["event"], "RESUME");
+        assert_eq!(read(&mut reader)["return"], json!({}));
+        assert_eq!(mock.run_state(), MockRunState::Running);
+
+        execute(&mut stream, "quit");
+        assert_eq!(read(&mut reader)["return"], json!({}));
+        assert!(child.wait().unwrap().success());
+        assert_eq!(mock.commands(), vec!["qmp_capabilities", "cont", "quit"]);
+
+        drop(mock);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_powerdown_and_crash() {
+        let dir = working_dir("crash");
+        let mock = MockQemu::new(&dir).unwrap();
+        mock.answer("query-name", json!({ "name": "mock" }));
+        let mut child = launch(&mock, &dir);
+        mock.wait_for_launch(Duration::from_secs(5)).unwrap();
+
+        let mut stream = UnixStream::connect(dir.join("qemu.sock")).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        read(&mut reader);
+
+        execute(&mut stream, "query-name");
+        assert_eq!(read(&mut reader)["return"]["name"], "mock");
+
+        execute(&mut stream, "system_powerdown");
+        read(&mut reader);
+        assert_eq!(read(&mut reader)["event"], "POWERDOWN");
+        assert_eq!(read(&mut reader)["data"]["reason"], "guest-shutdown");
+
+        mock.crash(1);
+        assert_eq!(child.wait().unwrap().code(), Some(1));
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+}
@@ -0,0 +1,420 @@
+#![cfg(feature = "host")]
+
+// Minimal reader/writer for OVMF variable stores (OVMF_VARS.fd)
+//
+// The layout is a firmware volume header, followed by a variable store header,
+// followed by a list of variables. Every variable starts with 0x55AA and is
+// 4-byte aligned, unused space is 0xFF (erased flash)
+//
+// see edk2 MdeModulePkg/Include/Guid/VariableFormat.h
+
+use crate::rpc::UefiBootEntry;
+use anyhow::Context;
+use std::convert::TryInto;
+use std::path::Path;
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const VARIABLE_START_ID: u16 = 0x55AA;
+
+const VAR_ADDED: u8 = 0x3F;
+const VAR_DELETED: u8 = 0xFD;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xFE;
+
+const VARIABLE_STORE_HEADER_SIZE: usize = 28;
+const VARIABLE_HEADER_SIZE: usize = 32;
+const AUTHENTICATED_VARIABLE_HEADER_SIZE: usize = 60;
+
+// aaf32c78-947b-439a-a180-2e144ec37792
+const AUTHENTICATED_VARIABLE_GUID: [u8; 16] = [
+    0x78, 0x2c, 0xf3, 0xaa, 0x7b, 0x94, 0x9a, 0x43, 0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92,
+];
+
+// 8be4df61-93ca-11d2-aa0d-00e098032b8c
+const EFI_GLOBAL_VARIABLE_GUID: [u8; 16] = [
+    0x61, 0xdf, 0xe4, 0x8b, 0xca, 0x93, 0xd2, 0x11, 0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c,
+];
+
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+#[derive(Clone, Debug)]
+struct Variable {
+    offset: usize,
+    name: String,
+    guid: [u8; 16],
+    data_offset: usize,
+    data_size: usize,
+}
+
+#[derive(Debug)]
+pub struct VariableStore {
+    data: Vec<u8>,
+    authenticated: bool,
+    // End of the store, not of the used space
+    end: usize,
+    variables: Vec<Variable>,
+    // First free offset after the last variable
+    free: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn decode_utf16(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .take_while(|x| *x != 0)
+        .collect::<Vec<_>>();
+
+    String::from_utf16_lossy(&units)
+}
+
+fn encode_utf16(input: &str) -> Vec<u8> {
+    input
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+impl VariableStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<VariableStore, anyhow::Error> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read UEFI variable store {:?}", path))?;
+
+        VariableStore::parse(data)
+            .with_context(|| format!("Failed to parse UEFI variable store {:?}", path))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
+        let path = path.as_ref();
+        std::fs::write(path, &self.data)
+            .with_context(|| format!("Failed to write UEFI variable store {:?}", path))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<VariableStore, anyhow::Error> {
+        if data.len() < 56 || &data[40..44] != FV_SIGNATURE {
+            anyhow::bail!("File is not a firmware volume (no _FVH signature found)");
+        }
+
+        let header_length = read_u16(&data, 48) as usize;
+        if data.len() < header_length + VARIABLE_STORE_HEADER_SIZE {
+            anyhow::bail!("Firmware volume is too small to contain a variable store");
+        }
+
+        let authenticated = data[header_length..header_length + 16] == AUTHENTICATED_VARIABLE_GUID;
+        let store_size = read_u32(&data, header_length + 16) as usize;
+        let start = align4(header_length + VARIABLE_STORE_HEADER_SIZE);
+        let end = (header_length + store_size).min(data.len());
+        let header_size = if authenticated {
+            AUTHENTICATED_VARIABLE_HEADER_SIZE
+        } else {
+            VARIABLE_HEADER_SIZE
+        };
+
+        let mut variables = vec![];
+        let mut offset = start;
+        while offset + header_size <= end && read_u16(&data, offset) == VARIABLE_START_ID {
+            let state = data[offset + 2];
+            // NameSize, DataSize and VendorGuid are always the last fields of the header
+            let name_size = read_u32(&data, offset + header_size - 24) as usize;
+            let data_size = read_u32(&data, offset + header_size - 20) as usize;
+            let guid: [u8; 16] = data[offset + header_size - 16..offset + header_size]
+                .try_into()
+                .unwrap();
+
+            let name_offset = offset + header_size;
+            let data_offset = name_offset + name_size;
+            if data_offset + data_size > end {
+                anyhow::bail!(
+                    "Variable at offset {:#x} runs past the end of the store",
+                    offset
+                );
+            }
+
+            if state == VAR_ADDED || state == VAR_ADDED & VAR_IN_DELETED_TRANSITION {
+                variables.push(Variable {
+                    offset,
+                    name: decode_utf16(&data[name_offset..data_offset]),
+                    guid,
+                    data_offset,
+                    data_size,
+                });
+            }
+
+            offset = align4(data_offset + data_size);
+        }
+
+        Ok(VariableStore {
+            data,
+            authenticated,
+            end,
+            variables,
+            free: offset,
+        })
+    }
+
+    /// A variable can be in the store more than once when the firmware was interrupted while
+    /// updating it, the last copy is the current one
+    fn find(&self, name: &str) -> Option<&Variable> {
+        self.variables
+            .iter()
+            .rev()
+            .find(|x| x.guid == EFI_GLOBAL_VARIABLE_GUID && x.name == name)
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.find(name)
+            .map(|x| &self.data[x.data_offset..x.data_offset + x.data_size])
+    }
+
+    /// Delete every copy of a global variable, so an older one doesn't become the current one
+    fn delete(&mut self, name: &str) -> bool {
+        let (deleted, kept) = std::mem::take(&mut self.variables)
+            .into_iter()
+            .partition::<Vec<_>, _>(|x| x.guid == EFI_GLOBAL_VARIABLE_GUID && x.name == name);
+        self.variables = kept;
+
+        for var in &deleted {
+            self.data[var.offset + 2] &= VAR_DELETED;
+        }
+
+        !deleted.is_empty()
+    }
+
+    /// Replace a global variable, keeping the attributes of the old one
+    fn replace(&mut self, name: &str, value: &[u8]) -> Result<(), anyhow::Error> {
+        let old = self
+            .find(name)
+            .cloned()
+            .with_context(|| format!("No UEFI variable named {} found", name))?;
+
+        if old.data_size == value.len() {
+            self.data[old.data_offset..old.data_offset + value.len()].copy_from_slice(value);
+            return Ok(());
+        }
+
+        let header_size = if self.authenticated {
+            AUTHENTICATED_VARIABLE_HEADER_SIZE
+        } else {
+            VARIABLE_HEADER_SIZE
+        };
+
+        let name_bytes = encode_utf16(name);
+        let offset = self.free;
+        let data_offset = offset + header_size + name_bytes.len();
+        if data_offset + value.len() > self.end {
+            anyhow::bail!("Not enough free space left in the UEFI variable store");
+        }
+
+        let mut header = self.data[old.offset..old.offset + header_size].to_vec();
+        header[2] = VAR_ADDED;
+        header[header_size - 24..header_size - 20]
+            .copy_from_slice(&(name_bytes.len() as u32).to_le_bytes());
+        header[header_size - 20..header_size - 16]
+            .copy_from_slice(&(value.len() as u32).to_le_bytes());
+
+        self.data[offset..offset + header_size].copy_from_slice(&header);
+        self.data[offset + header_size..data_offset].copy_from_slice(&name_bytes);
+        self.data[data_offset..data_offset + value.len()].copy_from_slice(value);
+
+        self.delete(name);
+        self.variables.push(Variable {
+            offset,
+            name: name.to_string(),
+            guid: old.guid,
+            data_offset,
+            data_size: value.len(),
+        });
+        self.free = align4(data_offset + value.len());
+
+        Ok(())
+    }
+
+    pub fn boot_order(&self) -> Vec<u16> {
+        self.get("BootOrder")
+            .map(|x| {
+                x.chunks_exact(2)
+                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn set_boot_order(&mut self, order: &[u16]) -> Result<(), anyhow::Error> {
+        let entries = self.boot_entries();
+        for id in order {
+            if !entries.iter().any(|x| x.id == *id) {
+                anyhow::bail!("No boot entry Boot{:04X} exists", id);
+            }
+        }
+
+        let value = order
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        self.replace("BootOrder", &value)
+    }
+
+    pub fn delete_boot_entry(&mut self, id: u16) -> Result<(), anyhow::Error> {
+        if !self.delete(&format!("Boot{:04X}", id)) {
+            anyhow::bail!("No boot entry Boot{:04X} exists", id);
+        }
+
+        // Not through set_boot_order, BootOrder may already name entries that are gone, which the
+        // firmware skips
+        let order = self.boot_order();
+        if order.contains(&id) {
+            let value = order
+                .into_iter()
+                .filter(|x| *x != id)
+                .flat_map(|x| x.to_le_bytes().to_vec())
+                .collect::<Vec<_>>();
+            self.replace("BootOrder", &value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn boot_entries(&self) -> Vec<UefiBootEntry> {
+        let order = self.boot_order();
+        let mut entries = self
+            .variables
+            .iter()
+            .filter(|x| x.guid == EFI_GLOBAL_VARIABLE_GUID)
+            .filter_map(|x| {
+                let id = x.name.strip_prefix("Boot")?;
+                if id.len() != 4 {
+                    return None;
+                }
+
+                let id = u16::from_str_radix(id, 16).ok()?;
+                let data = &self.data[x.data_offset..x.data_offset + x.data_size];
+                if data.len() < 6 {
+                    return None;
+                }
+
+                Some(UefiBootEntry {
+                    id,
+                    description: decode_utf16(&data[6..]),
+                    active: read_u32(data, 0) & LOAD_OPTION_ACTIVE != 0,
+                    position: order.iter().position(|x| *x == id),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_by_key(|x| (x.position.is_none(), x.position, x.id));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_variable(data: &mut Vec<u8>, name: &str, value: &[u8]) {
+        let name = encode_utf16(name);
+        data.extend_from_slice(&VARIABLE_START_ID.to_le_bytes());
+        data.push(VAR_ADDED);
+        data.push(0);
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(&EFI_GLOBAL_VARIABLE_GUID);
+        data.extend_from_slice(&name);
+        data.extend_from_slice(value);
+        data.resize(align4(data.len()), 0xFF);
+    }
+
+    fn load_option(description: &str) -> Vec<u8> {
+        let mut option = LOAD_OPTION_ACTIVE.to_le_bytes().to_vec();
+        option.extend_from_slice(&0u16.to_le_bytes());
+        option.extend_from_slice(&encode_utf16(description));
+        option
+    }
+
+    fn store() -> VariableStore {
+        let mut data = vec![0u8; 72];
+        data[40..44].copy_from_slice(FV_SIGNATURE);
+        data[48..50].copy_from_slice(&72u16.to_le_bytes());
+        // Non-authenticated variable store of 4k
+        data.extend_from_slice(&[0u8; 16]);
+        data.extend_from_slice(&4096u32.to_le_bytes());
+        data.extend_from_slice(&[0x5a, 0xfe, 0, 0, 0, 0, 0, 0]);
+        push_variable(&mut data, "Boot0000", &load_option("UEFI Shell"));
+        push_variable(&mut data, "Boot0001", &load_option("Windows Boot Manager"));
+        push_variable(&mut data, "Boot0002", &load_option("Windows Boot Manager"));
+        push_variable(&mut data, "BootOrder", &[1, 0, 2, 0, 0, 0]);
+        data.resize(72 + 4096, 0xFF);
+
+        VariableStore::parse(data).expect("Failed to parse variable store")
+    }
+
+    #[test]
+    fn test_boot_entries_follow_boot_order() {
+        let store = store();
+        let entries = store.boot_entries();
+        assert_eq!(
+            entries.iter().map(|x| x.id).collect::<Vec<_>>(),
+            vec![1, 2, 0]
+        );
+        assert_eq!(entries[0].description, "Windows Boot Manager");
+    }
+
+    #[test]
+    fn test_delete_boot_entry_survives_reparse() {
+        let mut store = store();
+        store
+            .delete_boot_entry(2)
+            .expect("Failed to delete boot entry");
+        store.set_boot_order(&[0, 1]).expect("Failed to reorder");
+
+        let store = VariableStore::parse(store.data).expect("Failed to reparse");
+        assert_eq!(store.boot_order(), vec![0, 1]);
+        assert_eq!(store.boot_entries().len(), 2);
+    }
+
+    #[test]
+    fn test_delete_boot_entry_with_dangling_boot_order() {
+        let mut store = store();
+        // Boot0005 was deleted without taking it out of BootOrder
+        store
+            .replace("BootOrder", &[1, 0, 5, 0, 2, 0, 0, 0])
+            .expect("Failed to write BootOrder");
+        store
+            .delete_boot_entry(2)
+            .expect("Failed to delete boot entry");
+
+        let store = VariableStore::parse(store.data).expect("Failed to reparse");
+        assert_eq!(store.boot_order(), vec![1, 5, 0]);
+        assert_eq!(store.boot_entries().len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_variable() {
+        // Written again after the first one, without the firmware getting to delete the first
+        let VariableStore { mut data, free, .. } = store();
+        let mut duplicate = vec![];
+        push_variable(&mut duplicate, "BootOrder", &[2, 0, 0, 0]);
+        data[free..free + duplicate.len()].copy_from_slice(&duplicate);
+
+        let mut store = VariableStore::parse(data).expect("Failed to parse variable store");
+        assert_eq!(store.boot_order(), vec![2, 0]);
+
+        assert!(store.delete("BootOrder"));
+        assert_eq!(store.boot_order(), Vec::<u16>::new());
+        let store = VariableStore::parse(store.data).expect("Failed to reparse");
+        assert_eq!(store.boot_order(), Vec::<u16>::new());
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Context;
 use std::ffi::{CStr, CString};
+use std::path::PathBuf;
 
 pub fn get_username_by_uid(uid: u32) -> anyhow::Result<Option<String>> {
     unsafe {
@@ -30,3 +31,90 @@ pub fn get_uid_by_username(username: &str) -> anyhow::Result<u32> {
         Ok((*passwd).pw_uid)
     }
 }
+
+pub fn get_gid_by_groupname(group_name: &str) -> anyhow::Result<u32> {
+    unsafe {
+        let c_str = CString::new(group_name)?;
+        let group = libc::getgrnam(c_str.as_ptr());
+        if group.is_null() {
+            anyhow::bail!("No group found with the name {}", group_name);
+        }
+
+        Ok((*group).gr_gid)
+    }
+}
+
+/// Uid, primary gid and every group (including the primary one) of [username]
+pub fn get_user_ids(username: &str) -> anyhow::Result<(u32, u32, Vec<u32>)> {
+    unsafe {
+        let c_str = CString::new(username)?;
+        let passwd = libc::getpwnam(c_str.as_ptr());
+        if passwd.is_null() {
+            anyhow::bail!("No user found with the name {}", username);
+        }
+
+        let (uid, gid) = ((*passwd).pw_uid, (*passwd).pw_gid);
+        let mut groups = vec![0; 64];
+        loop {
+            let mut amount = groups.len() as libc::c_int;
+            if libc::getgrouplist(c_str.as_ptr(), gid, groups.as_mut_ptr(), &mut amount) >= 0 {
+                groups.truncate(amount as usize);
+                return Ok((uid, gid, groups));
+            }
+
+            // Tells how many there are when they didn't fit
+            groups.resize((amount as usize).max(groups.len() * 2), 0);
+        }
+    }
+}
+
+/// Name of this host, to tell apart the hosts sharing storage
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|x| x.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Where [name] is found in PATH, like which(1)
+pub fn find_in_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH")
+        .iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// If the current user can read (and [write] if given) the given path
+pub fn can_access(path: &str, write: bool) -> bool {
+    let c_str = match CString::new(path) {
+        Ok(c_str) => c_str,
+        Err(_) => return false,
+    };
+
+    let mode = if write {
+        libc::R_OK | libc::W_OK
+    } else {
+        libc::R_OK
+    };
+
+    unsafe { libc::access(c_str.as_ptr(), mode) == 0 }
+}
+
+/// If the current process is a member of the given group
+pub fn is_in_group(gid: u32) -> bool {
+    unsafe {
+        if libc::getegid() == gid {
+            return true;
+        }
+
+        let amount = libc::getgroups(0, std::ptr::null_mut());
+        if amount <= 0 {
+            return false;
+        }
+
+        let mut groups = vec![0; amount as usize];
+        let amount = libc::getgroups(amount, groups.as_mut_ptr());
+        groups.truncate(amount.max(0) as usize);
+        groups.contains(&gid)
+    }
+}
@@ -0,0 +1,367 @@
+#![cfg(feature = "host")]
+
+// Minimal peer for the guest's spice-vdagent, so vored can share the clipboard with the guest
+// without a SPICE server or client in between
+//
+// Only UTF-8 text is supported, messages and constants are those of spice-protocol's vd_agent.h
+
+use std::convert::TryInto;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Name of the port spice-vdagent in the guest looks for
+pub const VDAGENT_PORT: &str = "com.redhat.spice.0";
+
+const VDP_CLIENT_PORT: u32 = 1;
+const VD_AGENT_PROTOCOL: u32 = 1;
+
+const VD_AGENT_CLIPBOARD: u32 = 4;
+const VD_AGENT_ANNOUNCE_CAPABILITIES: u32 = 6;
+const VD_AGENT_CLIPBOARD_GRAB: u32 = 7;
+const VD_AGENT_CLIPBOARD_REQUEST: u32 = 8;
+const VD_AGENT_CLIPBOARD_RELEASE: u32 = 9;
+
+const VD_AGENT_CAP_CLIPBOARD_BY_DEMAND: u32 = 5;
+const VD_AGENT_CLIPBOARD_UTF8_TEXT: u32 = 1;
+
+const CHUNK_HEADER_SIZE: usize = 8;
+const MESSAGE_HEADER_SIZE: usize = 20;
+/// Max payload of a single chunk, the same as spice-server uses
+const MAX_CHUNK_DATA: usize = 2048;
+/// Anything bigger than this is not a clipboard we want to keep in memory
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct ClipboardChannel {
+    stream: UnixStream,
+    /// Bytes read from the port, which are chunks of messages
+    chunks: Vec<u8>,
+    /// Payload of the chunks, which are messages
+    messages: Vec<u8>,
+    /// Last text the guest copied
+    guest_text: Option<String>,
+    /// Text offered to the guest
+    host_text: Option<String>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+}
+
+impl ClipboardChannel {
+    pub fn connect(path: &str) -> Result<ClipboardChannel, io::Error> {
+        ClipboardChannel::new(UnixStream::connect(path)?)
+    }
+
+    fn new(stream: UnixStream) -> Result<ClipboardChannel, io::Error> {
+        stream.set_nonblocking(true)?;
+        let mut channel = ClipboardChannel {
+            stream,
+            chunks: vec![],
+            messages: vec![],
+            guest_text: None,
+            host_text: None,
+        };
+
+        // Ask the agent for its capabilities, it also learns ours from this
+        channel.announce_capabilities(true)?;
+        Ok(channel)
+    }
+
+    pub fn stream(&self) -> &UnixStream {
+        &self.stream
+    }
+
+    pub fn guest_text(&self) -> Option<&str> {
+        self.guest_text.as_deref()
+    }
+
+    /// Offer [text] as clipboard to the guest, the guest asks for it once something is pasted
+    pub fn set_host_text(&mut self, text: String) -> Result<(), io::Error> {
+        self.host_text = Some(text);
+        self.send(
+            VD_AGENT_CLIPBOARD_GRAB,
+            &VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes(),
+        )
+    }
+
+    fn announce_capabilities(&mut self, request: bool) -> Result<(), io::Error> {
+        let mut data = (request as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&(1u32 << VD_AGENT_CAP_CLIPBOARD_BY_DEMAND).to_le_bytes());
+        self.send(VD_AGENT_ANNOUNCE_CAPABILITIES, &data)
+    }
+
+    /// Handle everything the guest sent, returns false if the channel was closed
+    pub fn process(&mut self) -> Result<bool, io::Error> {
+        let mut still_open = true;
+        loop {
+            let mut buffer = [0u8; 4096];
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    still_open = false;
+                    break;
+                }
+                Ok(amount) => self.chunks.extend_from_slice(&buffer[..amount]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        while let Some(size) = read_u32(&self.chunks, 4) {
+            let size = size as usize;
+            if size > MAX_CHUNK_DATA {
+                log::warn!("Guest agent sent a {} bytes chunk, resetting channel", size);
+                self.chunks.clear();
+                self.messages.clear();
+                break;
+            }
+
+            if self.chunks.len() < CHUNK_HEADER_SIZE + size {
+                break;
+            }
+
+            let chunk = self
+                .chunks
+                .drain(..CHUNK_HEADER_SIZE + size)
+                .collect::<Vec<_>>();
+            self.messages.extend_from_slice(&chunk[CHUNK_HEADER_SIZE..]);
+        }
+
+        while let Some(size) = read_u32(&self.messages, 16) {
+            let size = size as usize;
+            if size > MAX_MESSAGE_SIZE {
+                log::warn!("Guest agent sent a {} bytes message, dropping it", size);
+                self.messages.clear();
+                break;
+            }
+
+            if self.messages.len() < MESSAGE_HEADER_SIZE + size {
+                break;
+            }
+
+            let message = self
+                .messages
+                .drain(..MESSAGE_HEADER_SIZE + size)
+                .collect::<Vec<_>>();
+            let kind = read_u32(&message, 4).unwrap();
+            self.handle_message(kind, &message[MESSAGE_HEADER_SIZE..])?;
+        }
+
+        Ok(still_open)
+    }
+
+    fn handle_message(&mut self, kind: u32, data: &[u8]) -> Result<(), io::Error> {
+        match kind {
+            // The guest asks for ours in return
+            VD_AGENT_ANNOUNCE_CAPABILITIES if read_u32(data, 0).unwrap_or(0) != 0 => {
+                self.announce_capabilities(false)?;
+            }
+
+            VD_AGENT_CLIPBOARD_GRAB => {
+                let has_text = (0..data.len() / 4)
+                    .filter_map(|i| read_u32(data, i * 4))
+                    .any(|x| x == VD_AGENT_CLIPBOARD_UTF8_TEXT);
+                if has_text {
+                    self.send(
+                        VD_AGENT_CLIPBOARD_REQUEST,
+                        &VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes(),
+                    )?;
+                }
+            }
+
+            VD_AGENT_CLIPBOARD if read_u32(data, 0) == Some(VD_AGENT_CLIPBOARD_UTF8_TEXT) => {
+                self.guest_text = Some(String::from_utf8_lossy(&data[4..]).to_string());
+            }
+
+            VD_AGENT_CLIPBOARD_REQUEST => {
+                // Always answer, the guest application pasting waits for it
+                let mut reply = VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes().to_vec();
+                if read_u32(data, 0) == Some(VD_AGENT_CLIPBOARD_UTF8_TEXT) {
+                    reply.extend_from_slice(self.host_text.as_deref().unwrap_or("").as_bytes());
+                }
+
+                self.send(VD_AGENT_CLIPBOARD, &reply)?;
+            }
+
+            VD_AGENT_CLIPBOARD_RELEASE => self.guest_text = None,
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, kind: u32, data: &[u8]) -> Result<(), io::Error> {
+        let bytes = encode_message(kind, data);
+
+        // Don't let a guest that isn't reading block the daemon
+        self.stream.set_nonblocking(false)?;
+        self.stream
+            .set_write_timeout(Some(Duration::from_secs(1)))?;
+        let res = self.stream.write_all(&bytes);
+        self.stream.set_nonblocking(true)?;
+        res
+    }
+}
+
+/// A message of [kind] with [data], split into the chunks that go over the port
+fn encode_message(kind: u32, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + data.len());
+    message.extend_from_slice(&VD_AGENT_PROTOCOL.to_le_bytes());
+    message.extend_from_slice(&kind.to_le_bytes());
+    message.extend_from_slice(&0u64.to_le_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    message.extend_from_slice(data);
+
+    let mut bytes = vec![];
+    for chunk in message.chunks(MAX_CHUNK_DATA) {
+        bytes.extend_from_slice(&VDP_CLIENT_PORT.to_le_bytes());
+        bytes.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(chunk);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vdagent::*;
+
+    /// The guest's end of the port, and the channel vored has on the other end
+    fn channel() -> (UnixStream, ClipboardChannel) {
+        let (guest, host) = UnixStream::pair().unwrap();
+        guest.set_nonblocking(true).unwrap();
+        let channel = ClipboardChannel::new(host).unwrap();
+        // Skip the capabilities the channel announced
+        read_messages(&guest);
+        (guest, channel)
+    }
+
+    /// Everything the channel sent to the guest, as (kind, data) messages
+    fn read_messages(mut guest: &UnixStream) -> Vec<(u32, Vec<u8>)> {
+        let mut bytes = vec![];
+        let mut buffer = [0u8; 4096];
+        loop {
+            match guest.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(amount) => bytes.extend_from_slice(&buffer[..amount]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        let mut data = vec![];
+        while !bytes.is_empty() {
+            let size = read_u32(&bytes, 4).unwrap() as usize;
+            assert!(size <= MAX_CHUNK_DATA);
+            data.extend(
+                bytes
+                    .drain(..CHUNK_HEADER_SIZE + size)
+                    .skip(CHUNK_HEADER_SIZE),
+            );
+        }
+
+        let mut messages = vec![];
+        while !data.is_empty() {
+            let size = read_u32(&data, 16).unwrap() as usize;
+            let message = data.drain(..MESSAGE_HEADER_SIZE + size).collect::<Vec<_>>();
+            messages.push((
+                read_u32(&message, 4).unwrap(),
+                message[MESSAGE_HEADER_SIZE..].to_vec(),
+            ));
+        }
+
+        messages
+    }
+
+    fn clipboard(text: &str) -> Vec<u8> {
+        let mut data = VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut guest, mut channel) = channel();
+        // Big enough to be split over several chunks
+        let text = "clipboard ".repeat(1000);
+        guest
+            .write_all(&encode_message(VD_AGENT_CLIPBOARD, &clipboard(&text)))
+            .unwrap();
+        assert!(channel.process().unwrap());
+        assert_eq!(channel.guest_text(), Some(text.as_str()));
+
+        channel.set_host_text(text.clone()).unwrap();
+        assert_eq!(
+            read_messages(&guest),
+            vec![(
+                VD_AGENT_CLIPBOARD_GRAB,
+                VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes().to_vec()
+            )]
+        );
+
+        guest
+            .write_all(&encode_message(
+                VD_AGENT_CLIPBOARD_REQUEST,
+                &VD_AGENT_CLIPBOARD_UTF8_TEXT.to_le_bytes(),
+            ))
+            .unwrap();
+        assert!(channel.process().unwrap());
+        assert_eq!(
+            read_messages(&guest),
+            vec![(VD_AGENT_CLIPBOARD, clipboard(&text))]
+        );
+
+        guest
+            .write_all(&encode_message(VD_AGENT_CLIPBOARD_RELEASE, &[]))
+            .unwrap();
+        assert!(channel.process().unwrap());
+        assert_eq!(channel.guest_text(), None);
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let (mut guest, mut channel) = channel();
+        let text = "x".repeat(3000);
+        let bytes = encode_message(VD_AGENT_CLIPBOARD, &clipboard(&text));
+
+        // Halfway a chunk header, halfway a chunk, and halfway the message nothing is handled yet
+        for part in &[
+            &bytes[..5],
+            &bytes[5..100],
+            &bytes[100..CHUNK_HEADER_SIZE + MAX_CHUNK_DATA + 2],
+        ] {
+            guest.write_all(part).unwrap();
+            assert!(channel.process().unwrap());
+            assert_eq!(channel.guest_text(), None);
+        }
+
+        guest
+            .write_all(&bytes[CHUNK_HEADER_SIZE + MAX_CHUNK_DATA + 2..])
+            .unwrap();
+        assert!(channel.process().unwrap());
+        assert_eq!(channel.guest_text(), Some(text.as_str()));
+
+        drop(guest);
+        assert!(!channel.process().unwrap());
+    }
+
+    #[test]
+    fn test_oversized_chunk_resets() {
+        let (mut guest, mut channel) = channel();
+        let mut bytes = VDP_CLIENT_PORT.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(MAX_CHUNK_DATA as u32 + 1).to_le_bytes());
+        guest.write_all(&bytes).unwrap();
+        assert!(channel.process().unwrap());
+
+        // The channel is usable again for the next message
+        guest
+            .write_all(&encode_message(VD_AGENT_CLIPBOARD, &clipboard("after")))
+            .unwrap();
+        assert!(channel.process().unwrap());
+        assert_eq!(channel.guest_text(), Some("after"));
+    }
+}
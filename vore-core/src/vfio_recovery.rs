@@ -0,0 +1,142 @@
+#![cfg(feature = "host")]
+
+// Bookkeeping of the PCI devices vore bound to vfio-pci, so devices that are left bound after vored
+// crashed (or the VM using them was removed) can be given back to their host driver
+//
+// The state file lives in VORE_DIRECTORY, since it has to outlive vored and every VM
+
+use crate::consts::VORE_DIRECTORY;
+use crate::rpc::VfioBinding;
+use crate::PciAddress;
+use anyhow::Context;
+use std::fs::{read_link, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+fn state_path() -> PathBuf {
+    PathBuf::from(VORE_DIRECTORY).join("vfio-bindings.json")
+}
+
+fn load_bindings() -> Result<Vec<VfioBinding>, anyhow::Error> {
+    match std::fs::read_to_string(state_path()) {
+        Ok(state) => {
+            serde_json::from_str(&state).with_context(|| format!("{:?} is corrupt", state_path()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn store_bindings(bindings: &[VfioBinding]) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(VORE_DIRECTORY)?;
+    std::fs::write(state_path(), serde_json::to_string(bindings)?)
+        .with_context(|| format!("Failed to write {:?}", state_path()))
+}
+
+fn current_driver(address: &PciAddress) -> Option<String> {
+    read_link(format!("/sys/bus/pci/devices/{:#}/driver", address))
+        .ok()
+        .and_then(|x| x.file_name().map(|x| x.to_string_lossy().to_string()))
+}
+
+fn driver_override(address: &PciAddress) -> Option<String> {
+    std::fs::read_to_string(format!(
+        "/sys/bus/pci/devices/{:#}/driver_override",
+        address
+    ))
+    .ok()
+    .map(|x| x.trim().to_string())
+}
+
+/// Remember that vore bound [address] to vfio-pci, while it was bound to [driver] (empty for none) before
+pub fn record_vfio_binding(address: PciAddress, driver: &str) {
+    let res = load_bindings().and_then(|mut bindings| {
+        // Keep the driver it had before vore touched it the first time
+        if bindings.iter().any(|x| x.address == address) {
+            return Ok(());
+        }
+
+        bindings.push(VfioBinding {
+            address,
+            driver: driver.to_string(),
+        });
+        store_bindings(&bindings)
+    });
+
+    if let Err(err) = res {
+        log::warn!(
+            "Failed to record that {} was bound to vfio-pci, it won't be recovered after a crash: {:?}",
+            address,
+            err
+        );
+    }
+}
+
+/// Devices vore bound to vfio-pci that are still bound to it, and aren't in [in_use]
+///
+/// Devices that aren't bound to vfio-pci by vore anymore are forgotten
+pub fn stale_vfio_bindings(in_use: &[PciAddress]) -> Result<Vec<VfioBinding>, anyhow::Error> {
+    let bindings = load_bindings()?;
+    let (bound, gone): (Vec<_>, Vec<_>) = bindings.into_iter().partition(|x| {
+        current_driver(&x.address).as_deref() == Some("vfio-pci")
+            && driver_override(&x.address).as_deref() == Some("vfio-pci")
+    });
+
+    if !gone.is_empty() {
+        store_bindings(&bound)?;
+    }
+
+    Ok(bound
+        .into_iter()
+        .filter(|x| !in_use.contains(&x.address))
+        .collect())
+}
+
+/// Give [address] back to its host driver, if vore bound it to vfio-pci
+///
+/// Returns false if vore didn't bind it, then it's left alone
+pub fn release_vfio_device(address: &PciAddress) -> Result<bool, anyhow::Error> {
+    match load_bindings()?.into_iter().find(|x| x.address == *address) {
+        Some(binding) => release_vfio_binding(&binding).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Unbind [binding] from vfio-pci, and let the kernel bind its host driver again
+pub fn release_vfio_binding(binding: &VfioBinding) -> Result<(), anyhow::Error> {
+    let address = format!("{:#}\n", binding.address).into_bytes();
+    let write = |path: String, data: &[u8]| {
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut x| x.write_all(data))
+            .with_context(|| format!("Failed to write to {}", path))
+    };
+
+    // An empty override lets the probe below pick the driver that matches the device
+    write(
+        format!("/sys/bus/pci/devices/{:#}/driver_override", binding.address),
+        b"\n",
+    )?;
+    if current_driver(&binding.address).is_some() {
+        write(
+            format!("/sys/bus/pci/devices/{:#}/driver/unbind", binding.address),
+            &address,
+        )?;
+    }
+    write("/sys/bus/pci/drivers_probe".to_string(), &address)?;
+
+    let driver = current_driver(&binding.address);
+    if !binding.driver.is_empty() && driver.as_deref() != Some(binding.driver.as_str()) {
+        log::warn!(
+            "{} was bound to {} before, but is now bound to {}",
+            binding.address,
+            binding.driver,
+            driver.as_deref().unwrap_or("no driver")
+        );
+    }
+
+    let mut bindings = load_bindings()?;
+    bindings.retain(|x| x.address != binding.address);
+    store_bindings(&bindings)
+}
@@ -1,30 +1,55 @@
 #![cfg(feature = "host")]
 
-use crate::cpu_list::CpuList;
+use crate::cpu_list::{Cpu, CpuList};
+use crate::hooks::run_hook_script;
+use crate::rpc::{
+    Artifact, BootRecord, CdromDrive, ErrorCode, PciSlot, QemuIdMap, RpcError, SnapshotGroup,
+    StartProgress, StartStep, UefiBootEntry, UsbDevice,
+};
+use crate::utils::{find_in_path, get_gid_by_groupname, get_user_ids, hostname};
 use crate::{
-    GlobalConfig, InstanceConfig, QemuCommandBuilder, VfioConfig, VirtualMachineInfo,
-    VirtualMachineState,
+    adopt_isolated_cpus, adopt_stealth, apply_stealth, block_device_users, build_edid,
+    build_guest_tools_iso, check_iommu_group, check_mdev_type, check_sriov_driver,
+    create_disk_image, create_mdev, create_sriov_vfs, image_snapshots, isolate_cpus, kvm_available,
+    load_snapshot_groups, looking_glass_clients, mdev_exists, new_mdev_uuid,
+    parse_guest_resolution, qemu_img_snapshot, read_lgmp_header, record_vfio_binding,
+    release_isolated_cpus, release_vfio_device, remove_mdev, remove_sriov_vfs, restore_stealth,
+    save_snapshot_groups, sev_cbitpos, sev_enabled, snapshot_disks, sriov_vf_address,
+    timezone_name, BenchPlan, BlockStats, ClipboardChannel, DiskConfig, DiskInfo, GlobalConfig,
+    GuestAction, GuestActionChannel, GuestAgent, HostChange, HostRequirement, InstanceConfig,
+    LgmpHeader, LookingGlassInfo, LowDiskSpaceAction, NetworkStats, PciAddress, QemuCommandBuilder,
+    ResourceUsage, RestartPolicy, RuntimeInfo, Sandbox, ScreamMode, SeatConfig, SocketForward,
+    UsbConfig, VariableStore, VfioConfig, VirtualMachineInfo, VirtualMachineState,
+    VirtualMachineStats, VmCgroup, GUEST_RESOLUTION_COMMAND,
 };
 use anyhow::{Context, Error};
 use beau_collector::BeauCollector;
 use libc::{cpu_set_t, sched_setaffinity, CPU_SET};
 use qapi::qmp::{Event, QMP};
 use qapi::Qmp;
-use qapi_qmp::QmpCommand;
+use qapi_qmp::{QmpCommand, RunState};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsStr;
 use std::fmt::{Debug, Formatter};
-use std::fs::{read_dir, read_link, OpenOptions};
+use std::fs::{read_dir, read_link, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::{BufReader, ErrorKind, Read, Write};
 use std::option::Option::Some;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
+use std::process::{Child, Command, ExitStatus};
 use std::result::Result::Ok;
 use std::slice::Iter;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::{Duration, Instant};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fmt, mem};
 
 #[derive(Debug)]
@@ -33,9 +58,147 @@ pub struct VirtualMachine {
     state: VirtualMachineState,
     config: InstanceConfig,
     global_config: GlobalConfig,
-    process: Option<Child>,
+    process: Option<QemuProcess>,
     control_socket: Option<ControlSocket>,
     quit_after_shutdown: bool,
+    started_at: Option<Instant>,
+    stop_requested: bool,
+    last_exit: Option<VirtualMachineExit>,
+    guest_actions: Option<GuestActionChannel>,
+    clipboard: Option<ClipboardChannel>,
+    /// swtpm process for tpm.enabled, next to QEMU
+    tpm: Option<QemuProcess>,
+    /// Sockets of the seats with spice, in front of the SPICE socket of QEMU
+    seat_forwards: Vec<SocketForward>,
+    /// Physical functions of which the SR-IOV virtual functions were created for this VM
+    sriov_created: Vec<PciAddress>,
+    /// UUID's of the mediated devices created for this VM
+    mdevs_created: Vec<String>,
+    cgroup: Option<VmCgroup>,
+    /// Host settings applied for machine.stealth
+    host_changes: Vec<HostChange>,
+    /// CPU's all other host tasks are kept off for cpu.isolate
+    isolated_cpus: Vec<usize>,
+    /// Set while [process] is a prelaunched QEMU that hasn't been started yet
+    standby: bool,
+    health: HealthProbe,
+    looking_glass: LookingGlassProbe,
+    disk_space: DiskSpaceProbe,
+    usage: UsageProbe,
+    image_info: ImageInfoProbe,
+    /// Set while the clock and timezone still have to be set through the guest agent
+    timezone_push: Option<TimezonePush>,
+    /// Set while the filesystems of the guest are frozen, until when they may stay frozen
+    frozen_until: Option<Instant>,
+    /// Device to boot from first, for the QEMU launched by the next start only
+    boot_once: Option<String>,
+    /// ISO's attached as CD-ROM drives on load, prepare or start, on top of the configured disks
+    cdroms: Vec<String>,
+    pci_hotplugged: Vec<HotpluggedPci>,
+    /// Ids the QEMU script gave the devices of the running QEMU
+    qemu_ids: QemuIdMap,
+    /// Set when VFIO devices went back to their host driver or mediated devices were removed,
+    /// so the next start prepares them again
+    vfio_released: bool,
+    /// Warnings for the event subscribers, taken by vored with [take_warnings]
+    warnings: Vec<String>,
+    /// Set when machine.kvm is turned off because KVM isn't available and machine.allow-tcg is set,
+    /// so the next prepare tries KVM again
+    kvm_fallback: bool,
+}
+
+#[derive(Debug)]
+struct TimezonePush {
+    since: Instant,
+    last_attempt: Option<Instant>,
+}
+
+/// State of the screenshot health probe, reset every time the VM starts
+#[derive(Debug, Default)]
+struct HealthProbe {
+    last_check: Option<Instant>,
+    /// CPU time of QEMU in milliseconds at the last check
+    last_cpu_time: u64,
+    screen_hash: Option<u64>,
+    /// Since when the screen stayed the same while the CPU was busy
+    stuck_since: Option<Instant>,
+    stalled: bool,
+    /// Set when no screenshots can be taken, e.g. because there's no emulated display
+    unavailable: bool,
+}
+
+/// State of the Looking Glass session, sampled every [LOOKING_GLASS_INTERVAL]
+#[derive(Debug, Default)]
+struct LookingGlassProbe {
+    last_check: Option<Instant>,
+    header: Option<LgmpHeader>,
+    info: Option<LookingGlassInfo>,
+    last_resolution_check: Option<Instant>,
+    /// Pid of the resolution query that still runs in the guest
+    resolution_pid: Option<i64>,
+    /// Largest resolution the guest reported, as (width, height)
+    resolution: Option<(u64, u64)>,
+    /// Size of the shared memory the running QEMU got, the config changes when it grows
+    buffer_size: Option<u64>,
+    buffer_too_small: bool,
+    /// Set once the guest agent can't tell the resolution, e.g. because the guest isn't Windows
+    resolution_unavailable: bool,
+}
+
+/// Free space on the filesystems of the VM, checked every [DISK_SPACE_INTERVAL] while it runs
+#[derive(Debug, Default)]
+struct DiskSpaceProbe {
+    last_check: Option<Instant>,
+    low: bool,
+    /// vore paused the VM because of it, and should resume it once there's space again
+    paused: bool,
+}
+
+/// Resource usage of QEMU, sampled every [USAGE_INTERVAL] while it runs
+#[derive(Debug, Default)]
+struct UsageProbe {
+    last_check: Option<Instant>,
+    /// CPU time in milliseconds and bytes read and written by QEMU at the last check
+    last_cpu_time: u64,
+    last_read: u64,
+    last_written: u64,
+    /// Only set from the second check on, rates need two samples
+    usage: Option<ResourceUsage>,
+}
+
+/// Disk images as qemu-img sees them, asked on a thread at most every [IMAGE_INFO_INTERVAL], as
+/// qemu-img can take its time on images on slow storage
+#[derive(Debug, Default)]
+struct ImageInfoProbe {
+    last_check: Option<Instant>,
+    disks: Vec<DiskInfo>,
+    pending: Option<JoinHandle<Vec<DiskInfo>>>,
+}
+
+/// PCI device attached to a hotplug slot while the VM runs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct HotpluggedPci {
+    slot: u32,
+    address: PciAddress,
+    #[serde(default)]
+    detaching: bool,
+}
+
+/// Why QEMU went away, if it wasn't on request of vore
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VirtualMachineExit {
+    Crashed,
+    Shutdown,
+}
+
+/// What was found when looking for a QEMU started by a previous vored
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reattach {
+    /// No QEMU was running
+    Nothing,
+    Reattached,
+    /// A QEMU was running on the given other host, whose vored went away
+    OtherHost(String),
 }
 
 struct ControlSocket {
@@ -54,13 +217,54 @@ impl Debug for ControlSocket {
 
 const AUTO_UNBIND_BLACKLIST: &[&str] = &["nvidia", "amdgpu"];
 
+/// Seconds
+const MAX_RESTART_BACKOFF: u64 = 300;
+
+/// Id of the xhci controller the USB devices are attached to, see qemu.lua
+const USB_CONTROLLER: &str = "vore-usb";
+/// Prefix of the ids of USB devices attached while the VM runs
+const USB_HOTPLUG_PREFIX: &str = "vore-usb-hotplug-";
+
+/// Ids of the root ports and devices for PCI hotplug, the slot is appended, see qemu.lua
+const PCI_HOTPLUG_PORT_PREFIX: &str = "vore-hotplug-";
+const PCI_HOTPLUG_DEVICE_PREFIX: &str = "vore-pci-";
+
+/// Time the start of a VM waits for the control socket of QEMU to come up
+pub const QMP_SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between checks of the free space on the filesystems of a running VM
+const DISK_SPACE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time between asking qemu-img about the disk images of a VM that doesn't run
+const IMAGE_INFO_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time between samples of the resource usage of QEMU
+const USAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time between samples of the Looking Glass shared memory
+const LOOKING_GLASS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time between asking the guest agent for the resolution of the guest, and picking up its answer
+const GUEST_RESOLUTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time between attempts to reach the guest agent
+const TIMEZONE_PUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Time after which vored stops waiting for the guest agent to come up
+const TIMEZONE_PUSH_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long freezing the filesystems of the guest may take, Windows asks every VSS writer first
+const FSFREEZE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the filesystems may stay frozen for a snapshot, if vored doesn't get to thaw them itself
+const SNAPSHOT_FREEZE_TIMEOUT: Duration = Duration::from_secs(60);
+
 impl VirtualMachine {
     pub fn new<P: AsRef<Path>>(
         config: InstanceConfig,
         global_config: &GlobalConfig,
         working_dir: P,
     ) -> VirtualMachine {
-        VirtualMachine {
+        let mut vm = VirtualMachine {
             working_dir: working_dir.as_ref().to_path_buf(),
             state: VirtualMachineState::Loaded,
             config,
@@ -68,7 +272,39 @@ impl VirtualMachine {
             process: None,
             control_socket: None,
             quit_after_shutdown: true,
-        }
+            started_at: None,
+            stop_requested: false,
+            last_exit: None,
+            guest_actions: None,
+            clipboard: None,
+            tpm: None,
+            seat_forwards: vec![],
+            sriov_created: vec![],
+            mdevs_created: vec![],
+            host_changes: vec![],
+            isolated_cpus: vec![],
+            standby: false,
+            health: Default::default(),
+            looking_glass: Default::default(),
+            disk_space: Default::default(),
+            usage: Default::default(),
+            image_info: Default::default(),
+            pci_hotplugged: vec![],
+            qemu_ids: Default::default(),
+            vfio_released: false,
+            warnings: vec![],
+            kvm_fallback: false,
+            timezone_push: None,
+            frozen_until: None,
+            boot_once: None,
+            cdroms: vec![],
+            cgroup: None,
+        };
+
+        vm.resolve_shm_paths();
+        // So the sizes of the images are known by the time anyone asks
+        vm.image_info();
+        vm
     }
 
     pub fn vfio_devices(&self) -> Iter<'_, VfioConfig> {
@@ -79,6 +315,10 @@ impl VirtualMachine {
         &self.config.name
     }
 
+    pub fn state(&self) -> VirtualMachineState {
+        self.state
+    }
+
     pub fn info(&self) -> VirtualMachineInfo {
         VirtualMachineInfo {
             name: self.name().to_string(),
@@ -86,60 +326,592 @@ impl VirtualMachine {
             config: self.config.clone(),
             state: self.state,
             quit_after_shutdown: self.quit_after_shutdown,
+            cdroms: self.cdroms.clone(),
+            runtime: self.process.as_ref().map(|process| RuntimeInfo {
+                pid: process.id(),
+                uptime: self.started_at.map_or(0, |x| x.elapsed().as_secs()),
+                spice_socket: if self.config.spice.enabled && self.config.spice.is_tcp() {
+                    Some(self.config.spice.uri())
+                } else if self.config.spice.enabled {
+                    Some(self.config.spice.socket_path.clone())
+                } else {
+                    None
+                },
+                standby: self.standby,
+                stalled: self.health.stalled,
+                looking_glass: self.looking_glass.info.clone(),
+                low_disk_space: self.disk_space.low,
+                host_changes: self
+                    .host_changes
+                    .iter()
+                    .filter(|x| !x.is_noop())
+                    .map(|x| x.to_string())
+                    .collect(),
+                isolated_cpus: self.isolated_cpus.clone(),
+                usage: self.usage.usage.clone(),
+            }),
         }
     }
 
+    /// Everything that would make prepare fail, without fixing anything
+    ///
+    /// VFIO devices that are bound to another driver are reported, even though prepare with fixes may rebind them
+    pub fn check_prepare(&self) -> Vec<String> {
+        let mut results = vec![
+            self.prepare_arch(),
+            self.check_kvm().map(|_| ()),
+            self.check_run_as().map(|_| ()),
+        ];
+        results.extend(self.prepare_disks());
+        results.extend(self.prepare_vfio_roms());
+        results.extend(self.prepare_numa());
+        results.extend(self.prepare_usb());
+        results.extend(self.prepare_input());
+        let ours = self.vfio_devices().map(|x| x.address).collect::<Vec<_>>();
+        results.extend(self.config.vfio.iter().map(|vfio| {
+            let mut vfio = vfio.clone();
+            if let Some(pf) = vfio.physical_function {
+                check_sriov_driver(&pf, vfio.sriov_vfs)?;
+                match sriov_vf_address(&pf, vfio.sriov_vf)? {
+                    Some(address) => vfio.address = address,
+                    // Will be created on prepare
+                    None => return Ok(()),
+                }
+            }
+
+            check_iommu_group(&vfio.address, &ours)?;
+            VirtualMachine::prepare_vfio_device(false, false, &vfio)
+        }));
+        results.extend(self.config.mdev.iter().map(|mdev| match &mdev.uuid {
+            Some(uuid) if mdev_exists(uuid) => Ok(()),
+            _ => check_mdev_type(&mdev.parent, &mdev.mdev_type),
+        }));
+
+        results
+            .into_iter()
+            .filter_map(|x| x.err())
+            .map(|err| format!("{:#}", err))
+            .collect()
+    }
+
     pub fn prepare(&mut self, execute_fixes: bool, force: bool) -> Result<(), anyhow::Error> {
-        let mut results = vec![];
+        let mut results = vec![
+            self.prepare_arch(),
+            self.prepare_kvm(),
+            self.prepare_run_as(),
+        ];
+        results.extend(self.prepare_missing_disks());
         results.extend(self.prepare_disks());
+        results.extend(self.prepare_vfio_roms());
+        results.extend(self.prepare_numa());
+        results.extend(self.prepare_usb());
+        results.extend(self.prepare_input());
         results.extend(self.prepare_vfio(execute_fixes, force));
+        results.extend(self.prepare_mdev(execute_fixes));
         results.extend(self.prepare_shm());
+        results.push(self.prepare_edid());
+        results.push(self.prepare_spice_password());
+        results.push(self.prepare_tpm());
+        results.push(self.prepare_sev());
+        results.push(self.prepare_install_media());
         results.extend(self.prepare_sockets());
         results
             .into_iter()
             .bcollect::<()>()
             .with_context(|| format!("Failed to prepare VM {}", self.config.name))?;
 
+        if execute_fixes {
+            self.vfio_released = false;
+        }
+
         if self.state == VirtualMachineState::Loaded {
             self.state = VirtualMachineState::Prepared;
         }
         Ok(())
     }
 
-    pub fn prepare_shm(&mut self) -> Vec<Result<(), anyhow::Error>> {
+    fn resolve_shm_paths(&mut self) {
+        if self.config.looking_glass.enabled && self.config.looking_glass.mem_path.is_empty() {
+            self.config.looking_glass.mem_path =
+                format!("/dev/shm/vore/{}/looking-glass", self.config.name);
+        }
+
+        if self.config.scream.enabled
+            && self.config.scream.mode == ScreamMode::Ivshmem
+            && self.config.scream.mem_path.is_empty()
+        {
+            self.config.scream.mem_path = format!("/dev/shm/vore/{}/scream", self.config.name);
+        }
+
+        for seat in &mut self.config.seats {
+            if seat.looking_glass && seat.looking_glass_mem_path.is_empty() {
+                seat.looking_glass_mem_path = format!(
+                    "/dev/shm/vore/{}/looking-glass-{}",
+                    self.config.name, seat.name
+                );
+            }
+        }
+    }
+
+    fn shm_files(&self) -> Vec<(&str, u64)> {
         let mut shm = vec![];
         if self.config.looking_glass.enabled {
-            if self.config.looking_glass.mem_path.is_empty() {
-                self.config.looking_glass.mem_path =
-                    format!("/dev/shm/vore/{}/looking-glass", self.config.name);
+            shm.push((
+                self.config.looking_glass.mem_path.as_str(),
+                self.config.looking_glass.buffer_size,
+            ));
+        }
+
+        if self.config.scream.enabled && self.config.scream.mode == ScreamMode::Ivshmem {
+            shm.push((
+                self.config.scream.mem_path.as_str(),
+                self.config.scream.buffer_size,
+            ));
+        }
+
+        for seat in self.config.seats.iter().filter(|x| x.looking_glass) {
+            shm.push((
+                seat.looking_glass_mem_path.as_str(),
+                self.config.looking_glass.buffer_size,
+            ));
+        }
+
+        shm
+    }
+
+    /// Shared memory files that already exist, but don't have the size the config asks for,
+    /// as (path, current size, wanted size)
+    ///
+    /// This happens when e.g. the looking glass resolution changed since the file was created
+    pub fn stale_shm_files(&self) -> Vec<(&str, u64, u64)> {
+        self.shm_files()
+            .into_iter()
+            .filter_map(|(path, wanted)| {
+                let meta = std::fs::metadata(path).ok()?;
+                // Devices (e.g. kvmfr) are sized by their kernel module, not by us
+                if !meta.is_file() || meta.len() == wanted {
+                    return None;
+                }
+
+                Some((path, meta.len(), wanted))
+            })
+            .collect()
+    }
+
+    /// Remove the shared memory files and the SPICE socket QEMU leaves behind, unless machine.keep-shm is set
+    ///
+    /// Only files and sockets are removed, so a kvmfr device as looking-glass.path stays
+    fn remove_shm(&self) {
+        if self.config.keep_shm {
+            return;
+        }
+
+        let mut paths = self
+            .shm_files()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        if self.config.spice.enabled && !self.config.spice.is_tcp() {
+            paths.push(&self.config.spice.socket_path);
+        }
+
+        for path in paths {
+            let removable = std::fs::symlink_metadata(path).map_or(false, |x| {
+                x.file_type().is_file() || x.file_type().is_socket()
+            });
+            if !removable {
+                continue;
+            }
+
+            if let Err(err) = std::fs::remove_file(path) {
+                log::warn!("Failed to remove {} of {}: {}", path, self.name(), err);
+            }
+        }
+
+        // Only goes if it's empty, other files in it are none of our business
+        let _ = std::fs::remove_dir(format!("/dev/shm/vore/{}", self.name()));
+    }
+
+    /// Remove what a QEMU that's gone left behind: the sockets in the working directory and
+    /// everything in /dev/shm/vore/<name>, also when keep-shm is set. Returns what was removed
+    pub fn clean(&mut self) -> Result<Vec<String>, anyhow::Error> {
+        if self.process.is_some() {
+            return Err(RpcError::new(
+                ErrorCode::InvalidState,
+                format!("QEMU of {} still runs, stop or kill it first", self.name()),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        self.resolve_shm_paths();
+        let shm_dir = PathBuf::from(format!("/dev/shm/vore/{}", self.name()));
+        let shm = self
+            .shm_files()
+            .into_iter()
+            .map(|(path, _)| PathBuf::from(path))
+            .collect::<Vec<_>>();
+        let mut paths = shm.clone();
+        if self.config.spice.enabled && !self.config.spice.is_tcp() {
+            paths.push(PathBuf::from(&self.config.spice.socket_path));
+        }
+
+        for dir in &[&self.working_dir, &shm_dir] {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                paths.extend(entries.filter_map(|x| x.ok()).map(|x| x.path()));
+            }
+        }
+
+        paths.sort();
+        paths.dedup();
+
+        let mut removed = vec![];
+        for path in paths {
+            let meta = match std::fs::symlink_metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+
+            // The working directory also has the disks, UEFI variables and TPM state
+            let stale = meta.file_type().is_socket()
+                || (meta.file_type().is_file()
+                    && (path.starts_with(&shm_dir) || shm.contains(&path)));
+            if !stale {
+                continue;
             }
 
-            shm.push(&self.config.looking_glass.mem_path);
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {:?} of {}", path, self.name()))?;
+            removed.push(path.to_string_lossy().to_string());
         }
 
-        if self.config.scream.enabled {
-            if self.config.scream.mem_path.is_empty() {
-                self.config.scream.mem_path = format!("/dev/shm/vore/{}/scream", self.config.name);
+        let _ = std::fs::remove_dir(&shm_dir);
+        Ok(removed)
+    }
+
+    pub fn prepare_shm(&mut self) -> Vec<Result<(), anyhow::Error>> {
+        self.resolve_shm_paths();
+
+        let mut results = vec![];
+        // QEMU creates the file with the right size, but only if it doesn't exist yet
+        if self.process.is_none() {
+            for (path, current, wanted) in self.stale_shm_files() {
+                log::warn!(
+                    "Shared memory file {} is {} bytes but {} bytes are needed, recreating it",
+                    path,
+                    current,
+                    wanted
+                );
+
+                results.push(std::fs::remove_file(path).with_context(|| {
+                    format!("Failed removing outdated shared memory file {}", path)
+                }));
             }
+        }
+
+        results.extend(
+            self.shm_files()
+                .into_iter()
+                .map(|(x, _)| Path::new(x))
+                .filter_map(|x| x.parent())
+                .filter(|x| !x.is_dir())
+                .map(|x| {
+                    std::fs::create_dir_all(&x).with_context(|| {
+                        format!("Failed creating directories for shared memory ({:?})", x)
+                    })
+                }),
+        );
+
+        results
+    }
 
-            shm.push(&self.config.scream.mem_path);
+    /// Write the EDID offering looking-glass.resolutions, for the display of mediated devices,
+    /// or to load into a dummy plug or the EDID override of the guest for passed through GPU's
+    pub fn prepare_edid(&mut self) -> Result<(), anyhow::Error> {
+        let looking_glass = &mut self.config.looking_glass;
+        if !looking_glass.enabled || looking_glass.resolutions.is_empty() {
+            return Ok(());
         }
 
-        shm.into_iter()
-            .map(|x| Path::new(x))
-            .filter_map(|x| x.parent())
-            .filter(|x| !x.is_dir())
-            .map(|x| {
-                std::fs::create_dir_all(&x).with_context(|| {
-                    format!("Failed creating directories for shared memory ({:?})", x)
-                })
-            })
-            .collect()
+        let edid = build_edid(&looking_glass.resolutions, looking_glass.bit_depth)
+            .context("Failed to generate an EDID for looking-glass.resolutions")?;
+        std::fs::create_dir_all(&self.working_dir)?;
+        let path = self.working_dir.join("looking-glass.edid");
+        std::fs::write(&path, edid)
+            .with_context(|| format!("Failed to write EDID to {:?}", path))?;
+        looking_glass.edid_path = path.to_string_lossy().to_string();
+        Ok(())
+    }
+
+    /// Write spice.password where only QEMU reads it, so it doesn't end up on its command line
+    pub fn prepare_spice_password(&mut self) -> Result<(), anyhow::Error> {
+        let spice = &mut self.config.spice;
+        let password = match (&spice.password, spice.enabled) {
+            (Some(password), true) => password,
+            _ => return Ok(()),
+        };
+
+        std::fs::create_dir_all(&self.working_dir)?;
+        let path = self.working_dir.join("spice-password");
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut x| x.write_all(password.as_bytes()))
+            .with_context(|| format!("Failed to write the SPICE password to {:?}", path))?;
+        spice.password_path = Some(path.to_string_lossy().to_string());
+        Ok(())
+    }
+
+    /// Create the directory swtpm keeps the state of the TPM in, which only root can read
+    pub fn prepare_tpm(&mut self) -> Result<(), anyhow::Error> {
+        let tpm = &mut self.config.tpm;
+        if !tpm.enabled {
+            return Ok(());
+        }
+
+        if tpm.state_path.is_empty() {
+            tpm.state_path = self.working_dir.join("tpm").to_string_lossy().to_string();
+        }
+
+        tpm.socket_path = self
+            .working_dir
+            .join("swtpm.sock")
+            .to_string_lossy()
+            .to_string();
+
+        // swtpm has no way to escape them in its options
+        if tpm.state_path.contains(',') || tpm.socket_path.contains(',') {
+            anyhow::bail!(
+                "The TPM state ({}) and working directory of {} can't have a comma in their path",
+                tpm.state_path,
+                self.config.name
+            );
+        }
+
+        std::fs::create_dir_all(&tpm.state_path)
+            .with_context(|| format!("Failed to create TPM state directory {}", tpm.state_path))?;
+        std::fs::set_permissions(&tpm.state_path, std::fs::Permissions::from_mode(0o700))
+            .with_context(|| {
+                format!(
+                    "Failed to change the permissions of TPM state directory {}",
+                    tpm.state_path
+                )
+            })?;
+
+        Ok(())
+    }
+
+    /// Make the CD-ROM's to install the guest agent from, for guest-agent.install-media
+    pub fn prepare_install_media(&mut self) -> Result<(), anyhow::Error> {
+        if !self.config.guest_agent.install_media {
+            return Ok(());
+        }
+
+        let virtio_win = &self.global_config.guest_agent.virtio_win_iso;
+        if !Path::new(virtio_win).is_file() {
+            anyhow::bail!(
+                "guest-agent.install-media needs the virtio-win ISO at {} (guest-agent.virtio-win-iso in vored.toml)",
+                virtio_win
+            );
+        }
+
+        std::fs::create_dir_all(&self.working_dir)?;
+        let tools = self.working_dir.join("vore-tools.iso");
+        build_guest_tools_iso(&tools)?;
+        self.config.guest_agent.install_media_paths =
+            vec![virtio_win.clone(), tools.to_string_lossy().to_string()];
+        Ok(())
+    }
+
+    /// QEMU for the architecture of the guest, from qemu.binaries or PATH
+    fn qemu_binary(&self) -> String {
+        self.global_config
+            .qemu
+            .binaries
+            .get(&self.config.arch)
+            .cloned()
+            .unwrap_or_else(|| format!("qemu-system-{}", self.config.arch))
+    }
+
+    /// Check there's a QEMU, and UEFI firmware if it's used, for the architecture of the guest
+    pub fn prepare_arch(&self) -> Result<(), anyhow::Error> {
+        let binary = self.qemu_binary();
+        if self
+            .global_config
+            .qemu
+            .binaries
+            .contains_key(&self.config.arch)
+        {
+            if !Path::new(&binary).is_file() {
+                anyhow::bail!(
+                    "{} doesn't exist, fix qemu.binaries.{} in the global config",
+                    binary,
+                    self.config.arch
+                );
+            }
+        } else if find_in_path(&binary).is_none() {
+            anyhow::bail!(
+                "{} isn't installed, it's needed for {} guests",
+                binary,
+                self.config.arch
+            );
+        }
+
+        let firmware = self.config.uefi_firmware();
+        if self.config.uefi.enabled {
+            let uefi = self.global_config.uefi.get(firmware).with_context(|| {
+                format!(
+                    "No UEFI firmware for {} guests, configure it as [uefi.{}] in vored.toml",
+                    self.config.arch, firmware
+                )
+            })?;
+
+            for (kind, path) in &[("boot-code", &uefi.boot_code), ("template", &uefi.template)] {
+                if !Path::new(path).is_file() {
+                    anyhow::bail!(
+                        "Firmware file {} for uefi.{}.{} doesn't exist, install OVMF or fix the path in vored.toml",
+                        path,
+                        firmware,
+                        kind
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// If KVM is wanted but not available, and the VM should be emulated with TCG instead
+    fn check_kvm(&self) -> Result<bool, anyhow::Error> {
+        if !self.config.kvm {
+            return Ok(false);
+        }
+
+        let err = match kvm_available() {
+            Ok(_) => return Ok(false),
+            Err(err) => err,
+        };
+
+        if !self.config.allow_tcg || self.config.sev.enabled {
+            anyhow::bail!(
+                "KVM isn't available, /dev/kvm can't be opened ({}), make sure virtualization is enabled in the BIOS and the kvm module is loaded{}",
+                err,
+                if self.config.sev.enabled {
+                    ", SEV can't be emulated"
+                } else {
+                    ", or set machine.allow-tcg = true to emulate the VM (slowly)"
+                }
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Fall back to TCG if KVM isn't available and that's allowed, or try KVM again if it fell back before
+    pub fn prepare_kvm(&mut self) -> Result<(), anyhow::Error> {
+        if mem::take(&mut self.kvm_fallback) {
+            self.config.kvm = true;
+        }
+
+        if self.check_kvm()? {
+            log::warn!(
+                "KVM isn't available, {} is emulated with TCG, which is a lot slower",
+                self.name()
+            );
+            self.config.kvm = false;
+            self.kvm_fallback = true;
+        }
+
+        Ok(())
+    }
+
+    /// Check the user QEMU runs as is in all of qemu.groups, QEMU only keeps the groups of the user
+    /// when it drops to it. Gives the uid and gid of the user if it's configured
+    pub fn check_run_as(&self) -> Result<Option<(u32, u32)>, anyhow::Error> {
+        let run_as = self
+            .config
+            .run_as
+            .as_ref()
+            .or(self.global_config.qemu.run_as.as_ref());
+        let groups = self
+            .config
+            .groups
+            .as_ref()
+            .unwrap_or(&self.global_config.qemu.groups);
+        let user = run_as.map_or("nobody", |x| x.as_str());
+        let (uid, gid, member_of) =
+            get_user_ids(user).with_context(|| format!("QEMU can't run as {}", user))?;
+
+        let mut missing = vec![];
+        for group in groups {
+            if !member_of.contains(&get_gid_by_groupname(group)?) {
+                missing.push(group.as_str());
+            }
+        }
+
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "QEMU runs as {}, which isn't in {}, add it with usermod -aG {} {}",
+                user,
+                missing.join(", "),
+                missing.join(","),
+                user
+            );
+        }
+
+        Ok(run_as.map(|_| (uid, gid)))
+    }
+
+    /// Give the working directory to the user QEMU runs as, unless that's the default nobody
+    pub fn prepare_run_as(&self) -> Result<(), anyhow::Error> {
+        let (uid, gid) = match self.check_run_as()? {
+            Some(ids) => ids,
+            None => return Ok(()),
+        };
+
+        std::fs::create_dir_all(&self.working_dir)?;
+        let path = std::ffi::CString::new(self.working_dir.to_string_lossy().as_bytes())?;
+        if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to change the owner of {:?}", self.working_dir));
+        }
+
+        Ok(())
+    }
+
+    /// Check the host can encrypt the memory of the guest, and fill in what the CPU decides
+    pub fn prepare_sev(&mut self) -> Result<(), anyhow::Error> {
+        let sev = &mut self.config.sev;
+        if !sev.enabled {
+            return Ok(());
+        }
+
+        let name = if sev.snp { "SEV-SNP" } else { "SEV" };
+        if !sev_enabled(sev.snp) {
+            anyhow::bail!(
+                "{} isn't enabled in kvm_amd (/sys/module/kvm_amd/parameters/{}), it needs an AMD EPYC host with it enabled in the BIOS",
+                name,
+                if sev.snp { "sev_snp" } else { "sev" }
+            );
+        }
+
+        if sev.cbitpos.is_none() || sev.reduced_phys_bits.is_none() {
+            let (cbitpos, reduced_phys_bits) = sev_cbitpos()
+                .with_context(|| format!("The CPU doesn't report {} support", name))?;
+            sev.cbitpos.get_or_insert(cbitpos);
+            sev.reduced_phys_bits.get_or_insert(reduced_phys_bits);
+        }
+
+        sev.policy = Some(sev.effective_policy());
+        Ok(())
     }
 
     pub fn prepare_sockets(&mut self) -> Vec<Result<(), anyhow::Error>> {
         let mut sockets = vec![];
-        if self.config.spice.enabled {
+        if self.config.spice.enabled && !self.config.spice.is_tcp() {
             if self.config.spice.socket_path.is_empty() {
                 self.config.spice.socket_path = self
                     .working_dir
@@ -152,6 +924,64 @@ impl VirtualMachine {
             sockets.push(&self.config.spice.socket_path);
         }
 
+        for seat in &mut self.config.seats {
+            if seat.spice && seat.spice_socket_path.is_empty() {
+                seat.spice_socket_path = self
+                    .working_dir
+                    .join(format!("spice-{}.sock", seat.name))
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+            }
+        }
+
+        sockets.extend(
+            self.config
+                .seats
+                .iter()
+                .filter(|x| x.spice)
+                .map(|x| &x.spice_socket_path),
+        );
+
+        if self.config.guest_actions.enabled {
+            if self.config.guest_actions.socket_path.is_empty() {
+                self.config.guest_actions.socket_path = self
+                    .working_dir
+                    .join("guest-actions.sock")
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+            }
+
+            sockets.push(&self.config.guest_actions.socket_path);
+        }
+
+        if self.config.clipboard.enabled {
+            if self.config.clipboard.socket_path.is_empty() {
+                self.config.clipboard.socket_path = self
+                    .working_dir
+                    .join("clipboard.sock")
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+            }
+
+            sockets.push(&self.config.clipboard.socket_path);
+        }
+
+        if self.config.guest_agent.enabled {
+            if self.config.guest_agent.socket_path.is_empty() {
+                self.config.guest_agent.socket_path = self
+                    .working_dir
+                    .join("guest-agent.sock")
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+            }
+
+            sockets.push(&self.config.guest_agent.socket_path);
+        }
+
         sockets
             .into_iter()
             .map(|x| Path::new(x))
@@ -173,27 +1003,145 @@ impl VirtualMachine {
             .disks
             .iter()
             .map(|disk| {
+                // Created on prepare
+                if disk.create_if_missing && !Path::new(&disk.path).exists() {
+                    return Ok(());
+                }
+
                 OpenOptions::new()
                     .read(true)
                     .open(&disk.path)
                     .with_context(|| format!("Failed to open disk {}", disk.path))?;
 
+                if !disk.shared && !disk.read_only {
+                    let users = block_device_users(&disk.path);
+                    if !users.is_empty() {
+                        anyhow::bail!(
+                            "Disk {} is in use by the host: {}",
+                            disk.path,
+                            users.join(", ")
+                        );
+                    }
+                }
+
                 Ok(())
             })
             .collect::<Vec<_>>()
     }
 
-    /// Prepare VFIO related shenanigans,
-    /// This includes if requested via [execute_fixes] unbinding the requested vfio pci devices
-    /// And binding them to vfio-pci
-    ///
-    /// With [execute_fixes] set to false, it will only check if everything is sane, and the correct driver is loaded
-    ///
-    /// [force] can be given to auto-bind PCI devices that are blacklisted anyway. this can result in vore indefinitely hanging.
-    fn prepare_vfio(&mut self, execute_fixes: bool, force: bool) -> Vec<Result<(), Error>> {
-        if self.config.vfio.is_empty() {
-            return vec![];
-        }
+    /// Create the images of the disks with create-if-missing that don't exist yet
+    pub fn prepare_missing_disks(&self) -> Vec<Result<(), anyhow::Error>> {
+        self.config
+            .disks
+            .iter()
+            .filter(|x| x.create_if_missing && !Path::new(&x.path).exists())
+            .map(|disk| {
+                create_disk_image(
+                    &disk.path,
+                    &disk.disk_type,
+                    disk.size.unwrap_or(0),
+                    disk.preallocation.as_deref(),
+                )?;
+                log::info!("Created disk {} of {}", disk.path, self.name());
+                Ok(())
+            })
+            .collect()
+    }
+
+    pub fn prepare_vfio_roms(&self) -> Vec<Result<(), anyhow::Error>> {
+        self.config
+            .vfio
+            .iter()
+            .filter_map(|vfio| vfio.romfile.as_ref())
+            .map(|romfile| {
+                OpenOptions::new()
+                    .read(true)
+                    .open(romfile)
+                    .with_context(|| format!("Failed to open VFIO ROM file {}", romfile))?;
+
+                Ok(())
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Prepare VFIO related shenanigans,
+    /// This includes if requested via [execute_fixes] unbinding the requested vfio pci devices
+    /// And binding them to vfio-pci
+    ///
+    /// With [execute_fixes] set to false, it will only check if everything is sane, and the correct driver is loaded
+    ///
+    /// [force] can be given to auto-bind PCI devices that are blacklisted anyway. this can result in vore indefinitely hanging.
+    pub fn prepare_numa(&self) -> Vec<Result<(), anyhow::Error>> {
+        self.config
+            .numa
+            .iter()
+            .filter_map(|x| x.host_node)
+            .map(|node| {
+                if !Path::new(&format!("/sys/devices/system/node/node{}", node)).exists() {
+                    anyhow::bail!("Host has no NUMA node {} to bind to", node);
+                }
+
+                Ok(())
+            })
+            .collect()
+    }
+
+    /// Check if the USB devices are plugged in, and QEMU can open them
+    pub fn prepare_usb(&self) -> Vec<Result<(), anyhow::Error>> {
+        self.config
+            .usb
+            .iter()
+            .map(VirtualMachine::check_usb_device)
+            .collect()
+    }
+
+    /// Check if the evdev devices exist, and QEMU can read them
+    pub fn prepare_input(&self) -> Vec<Result<(), anyhow::Error>> {
+        self.config
+            .input
+            .devices
+            .iter()
+            .map(|device| {
+                File::open(device)
+                    .with_context(|| format!("Input device {} isn't accessible", device))?;
+                Ok(())
+            })
+            .collect()
+    }
+
+    fn check_usb_device(usb: &UsbConfig) -> Result<(), anyhow::Error> {
+        let device = usb.find_device()?;
+        let sysfs = Path::new("/sys/bus/usb/devices").join(&device);
+        let read_num = |file: &str| {
+            std::fs::read_to_string(sysfs.join(file))
+                .ok()
+                .and_then(|x| x.trim().parse::<u32>().ok())
+                .with_context(|| format!("Failed to read {} of USB device {}", file, device))
+        };
+
+        let node = format!(
+            "/dev/bus/usb/{:03}/{:03}",
+            read_num("busnum")?,
+            read_num("devnum")?
+        );
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&node)
+            .with_context(|| {
+                format!(
+                    "USB device {} ({}) at {} isn't accessible",
+                    usb, device, node
+                )
+            })?;
+
+        Ok(())
+    }
+
+    fn prepare_vfio(&mut self, execute_fixes: bool, force: bool) -> Vec<Result<(), Error>> {
+        if self.config.vfio.is_empty() {
+            return vec![];
+        }
 
         match Command::new("modprobe")
             .arg("vfio-pci")
@@ -209,17 +1157,229 @@ impl VirtualMachine {
             Ok(_) => {}
         }
 
-        self.config
-            .vfio
-            .iter()
-            .map(|vfio| VirtualMachine::prepare_vfio_device(execute_fixes, force, vfio))
-            .collect::<Vec<_>>()
+        let mut results = vec![];
+        let mut prepared = vec![];
+        for i in 0..self.config.vfio.len() {
+            match VirtualMachine::prepare_sriov_vf(execute_fixes, &mut self.config.vfio[i]) {
+                Ok(Some(pf)) => {
+                    log::info!(
+                        "Created SR-IOV virtual functions on {} for {}",
+                        pf,
+                        self.name()
+                    );
+                    self.sriov_created.push(pf);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    results.push(Err(err));
+                    continue;
+                }
+            }
+
+            let result =
+                VirtualMachine::prepare_vfio_device(execute_fixes, force, &self.config.vfio[i]);
+            if result.is_ok() {
+                prepared.push(self.config.vfio[i].address);
+            }
+
+            results.push(result);
+        }
+
+        // Only once all are bound, devices in the same group as another one of this VM don't count
+        let ours = self.vfio_devices().map(|x| x.address).collect::<Vec<_>>();
+        for address in prepared {
+            results.push(check_iommu_group(&address, &ours));
+        }
+
+        results
+    }
+
+    /// Create the SR-IOV virtual function [vfio] asks for if needed, and point [vfio] to it
+    ///
+    /// Returns the physical function if its virtual functions were created by this call
+    fn prepare_sriov_vf(
+        execute_fixes: bool,
+        vfio: &mut VfioConfig,
+    ) -> Result<Option<PciAddress>, Error> {
+        let pf = if let Some(pf) = vfio.physical_function {
+            pf
+        } else {
+            return Ok(None);
+        };
+
+        check_sriov_driver(&pf, vfio.sriov_vfs)?;
+        let created = execute_fixes && create_sriov_vfs(&pf, vfio.sriov_vfs)?;
+        vfio.address = sriov_vf_address(&pf, vfio.sriov_vf)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Virtual function {} of PCI device {} doesn't exist",
+                vfio.sriov_vf,
+                pf
+            )
+        })?;
+
+        Ok(if created { Some(pf) } else { None })
+    }
+
+    /// Create the mediated devices that don't exist yet, and give those without UUID one
+    fn prepare_mdev(&mut self, execute_fixes: bool) -> Vec<Result<(), Error>> {
+        let mut results = vec![];
+        for i in 0..self.config.mdev.len() {
+            let mdev = &self.config.mdev[i];
+            if mdev.uuid.as_deref().map_or(false, mdev_exists) {
+                continue;
+            }
+
+            if !execute_fixes {
+                results.push(check_mdev_type(&mdev.parent, &mdev.mdev_type));
+                continue;
+            }
+
+            let res = match &mdev.uuid {
+                Some(uuid) => Ok(uuid.clone()),
+                None => new_mdev_uuid(),
+            }
+            .and_then(|uuid| create_mdev(&mdev.parent, &mdev.mdev_type, &uuid).map(|_| uuid));
+
+            match res {
+                Ok(uuid) => {
+                    log::info!(
+                        "Created mediated device {} ({}) on {} for {}",
+                        uuid,
+                        mdev.mdev_type,
+                        mdev.parent,
+                        self.name()
+                    );
+                    self.mdevs_created.push(uuid.clone());
+                    self.config.mdev[i].uuid = Some(uuid);
+                }
+                Err(err) => results.push(Err(err)),
+            }
+        }
+
+        results
+    }
+
+    fn remove_mdevs(&mut self) {
+        let created = mem::take(&mut self.mdevs_created);
+        if created.is_empty() {
+            return;
+        }
+
+        for uuid in created {
+            match remove_mdev(&uuid) {
+                Ok(_) => log::info!("Removed mediated device {}", uuid),
+                Err(err) => log::warn!("Failed to remove mediated device {}: {:?}", uuid, err),
+            }
+        }
+
+        self.vfio_released = true;
+    }
+
+    fn restore_host_changes(&mut self) {
+        if !mem::take(&mut self.host_changes).is_empty() {
+            restore_stealth(self.name());
+        }
+
+        if !mem::take(&mut self.isolated_cpus).is_empty() {
+            release_isolated_cpus(self.name());
+        }
+    }
+
+    fn remove_cgroup(&mut self) {
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Err(err) = cgroup.remove() {
+                log::warn!("Failed to remove cgroup of {}: {:?}", self.name(), err);
+            }
+        }
+    }
+
+    /// Remove the SR-IOV virtual functions created for this VM, now that QEMU is gone
+    fn remove_sriov_vfs(&mut self) {
+        let created = mem::take(&mut self.sriov_created);
+        if created.is_empty() {
+            return;
+        }
+
+        for pf in created {
+            let ours = self
+                .config
+                .vfio
+                .iter()
+                .filter(|x| x.physical_function == Some(pf))
+                .map(|x| x.address)
+                .collect::<Vec<_>>();
+
+            match remove_sriov_vfs(&pf, &ours) {
+                Ok(true) => log::info!("Removed SR-IOV virtual functions on {}", pf),
+                Ok(false) => log::info!(
+                    "Keeping SR-IOV virtual functions on {}, they're still in use by another VM",
+                    pf
+                ),
+                Err(err) => log::warn!(
+                    "Failed to remove SR-IOV virtual functions on {}: {:?}",
+                    pf,
+                    err
+                ),
+            }
+        }
+
+        // The virtual functions are gone, prepare has to create them again before the next QEMU
+        self.vfio_released = true;
     }
 
     pub fn should_auto_start(&self) -> bool {
         self.config.auto_start
     }
 
+    pub fn auto_start_delay(&self) -> Duration {
+        Duration::from_secs(self.config.auto_start_delay)
+    }
+
+    pub fn auto_start_order(&self) -> i64 {
+        self.config.auto_start_order
+    }
+
+    /// The entries of machine.requires-host that aren't met right now
+    pub fn unmet_host_requirements(&self) -> Vec<String> {
+        self.config
+            .requires_host
+            .iter()
+            .filter(|x| !HostRequirement::from_str(x).map_or(false, |x| x.is_met()))
+            .cloned()
+            .collect()
+    }
+
+    /// How long auto-start waits for the host requirements, None to wait forever
+    pub fn host_requirements_timeout(&self) -> Option<Duration> {
+        match self.config.requires_host_timeout {
+            0 => None,
+            timeout => Some(Duration::from_secs(timeout)),
+        }
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.config.restart
+    }
+
+    pub fn restart_max_retries(&self) -> u32 {
+        self.config.restart_max_retries
+    }
+
+    /// Time to wait before restarting after [attempts] restarts already happened
+    pub fn restart_backoff(&self, attempts: u32) -> Duration {
+        Duration::from_secs(
+            self.config
+                .restart_backoff
+                .saturating_mul(1 << attempts.min(16))
+                .min(MAX_RESTART_BACKOFF),
+        )
+    }
+
+    /// Returns how QEMU exited since the last call, if it wasn't asked to
+    pub fn take_exit(&mut self) -> Option<VirtualMachineExit> {
+        self.last_exit.take()
+    }
+
     pub fn prepare_vfio_device(
         execute_fixes: bool,
         force: bool,
@@ -253,13 +1413,18 @@ impl VirtualMachine {
         let is_blacklisted = AUTO_UNBIND_BLACKLIST.contains(&driver.as_str()) && !force;
 
         if driver != "vfio-pci" && (!execute_fixes || is_blacklisted) {
-            if !driver.is_empty() && is_blacklisted {
-                anyhow::bail!("PCI device {} it's current driver is {}, but to be used with VFIO needs to be set to vfio-pci, this driver ({1}) has been blacklisted from automatic rebinding because it can't be cleanly unbound, please make sure this device is unbound before running vore", vfio.address, driver)
+            let message = if !driver.is_empty() && is_blacklisted {
+                format!("PCI device {} it's current driver is {}, but to be used with VFIO needs to be set to vfio-pci, this driver ({1}) has been blacklisted from automatic rebinding because it can't be cleanly unbound, please make sure this device is unbound before running vore", vfio.address, driver)
             } else if !driver.is_empty() {
-                anyhow::bail!("PCI device {} it's current driver is {}, but to be used with VFIO needs to be set to vfio-pci", vfio.address, driver)
+                format!("PCI device {} it's current driver is {}, but to be used with VFIO needs to be set to vfio-pci", vfio.address, driver)
             } else {
-                anyhow::bail!("PCI device at {} currently has no driver, but to be used with VFIO needs to be set to vfio-pci", vfio.address)
-            }
+                format!("PCI device at {} currently has no driver, but to be used with VFIO needs to be set to vfio-pci", vfio.address)
+            };
+
+            return Err(RpcError::new(ErrorCode::VfioNotBound, message)
+                .with_detail("address", vfio.address)
+                .with_detail("driver", &driver)
+                .into());
         }
 
         if driver != "vfio-pci" && execute_fixes && !is_blacklisted {
@@ -275,6 +1440,8 @@ impl VirtualMachine {
                 unbind.write_all(&address)?;
             }
 
+            record_vfio_binding(vfio.address, &driver);
+
             {
                 // Set a driver override
                 let mut driver_override = OpenOptions::new().append(true).open(format!(
@@ -295,319 +1462,3273 @@ impl VirtualMachine {
 
             let new_link = read_link(&pci_driver_path)?;
             if !new_link.ends_with("vfio-pci") {
-                anyhow::bail!("Tried to bind {} to vfio-pci but failed to do so (see /sys/bus/pci/devices/{:#} for more info)", vfio.address, vfio.address)
+                return Err(RpcError::new(
+                    ErrorCode::VfioBindFailed,
+                    format!("Tried to bind {} to vfio-pci but failed to do so (see /sys/bus/pci/devices/{:#} for more info)", vfio.address, vfio.address),
+                )
+                .with_detail("address", vfio.address)
+                .into());
             }
         }
 
         Ok(())
     }
 
-    pub fn get_cmd_line(&self) -> Result<Vec<String>, anyhow::Error> {
-        let builder = QemuCommandBuilder::new(&self.global_config, self.working_dir.clone())?;
-        builder.build(&self.config)
-    }
-
-    pub fn pin_qemu_threads(&self) -> Result<(), anyhow::Error> {
-        let pid = if let Some(child) = &self.process {
-            child.id()
-        } else {
-            return Ok(());
-        };
-
-        let list = CpuList::adjacent(self.config.cpu.amount as usize);
-        if list.is_none() {
-            // If we are over provisioning CPU's there's not much use to pinning
-            return Ok(());
+    /// All files in the working directory of this VM
+    pub fn artifacts(&self) -> Result<Vec<Artifact>, anyhow::Error> {
+        let mut artifacts = vec![];
+        if !self.working_dir.is_dir() {
+            return Ok(artifacts);
         }
 
-        let list = list.unwrap();
-
-        let mut kvm_threads = vec![];
-        for item in read_dir(format!("/proc/{}/task", pid))? {
-            let entry = item?;
-            if !entry.file_type()?.is_dir() {
-                continue;
-            }
+        let mut dirs = vec![self.working_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in read_dir(&dir).with_context(|| format!("Failed to list {:?}", dir))? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
 
-            let res = entry
-                .file_name()
-                .to_str()
-                .ok_or_else(|| anyhow::anyhow!(""))
-                .and_then(|x| usize::from_str(x).map_err(From::from));
-            if res.is_err() {
-                continue;
-            }
+                if !meta.is_file() {
+                    continue;
+                }
 
-            let tid = res.unwrap();
-            let name = entry.path().join("comm");
-            let comm = std::fs::read_to_string(name)?;
-            if comm.starts_with("CPU ") {
-                let nr = comm
-                    .chars()
-                    .skip(4)
-                    .take_while(|x| x.is_ascii_digit())
-                    .collect::<String>();
-                let cpu_id = usize::from_str(&nr).unwrap();
-                kvm_threads.push((tid, cpu_id));
+                let path = entry.path();
+                artifacts.push(Artifact {
+                    path: path
+                        .strip_prefix(&self.working_dir)
+                        .unwrap_or(&path)
+                        .to_string_lossy()
+                        .to_string(),
+                    size: meta.len(),
+                    modified: meta
+                        .modified()
+                        .ok()
+                        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |x| x.as_secs()),
+                });
             }
         }
 
-        for (tid, cpu_id) in kvm_threads {
-            if cpu_id >= list.len() {
-                // ???
-                continue;
-            }
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(artifacts)
+    }
 
-            let cpu = &list[cpu_id];
-            unsafe {
-                let mut set = mem::zeroed::<cpu_set_t>();
-                CPU_SET(cpu.id, &mut set);
-                sched_setaffinity(tid as i32, mem::size_of::<cpu_set_t>(), &set);
-            }
+    /// Read a (small) file from the working directory of this VM
+    pub fn fetch_artifact(&self, path: &str, max_size: u64) -> Result<Vec<u8>, anyhow::Error> {
+        let working_dir = self
+            .working_dir
+            .canonicalize()
+            .with_context(|| format!("VM {} has no working directory yet", self.name()))?;
+        let full_path = working_dir
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("No artifact {} found for {}", path, self.name()))?;
+
+        if !full_path.starts_with(&working_dir) || !full_path.is_file() {
+            anyhow::bail!("No artifact {} found for {}", path, self.name());
         }
 
-        Ok(())
+        let size = std::fs::metadata(&full_path)?.len();
+        if size > max_size {
+            anyhow::bail!(
+                "Artifact {} is {} bytes, only artifacts up to {} bytes can be fetched",
+                path,
+                size,
+                max_size
+            );
+        }
+
+        Ok(std::fs::read(&full_path)?)
     }
 
-    pub fn boop(&mut self) -> Result<(), anyhow::Error> {
-        if let Some(qmp) = self.control_socket.as_mut() {
-            qmp.qmp.nop()?;
+    pub fn uefi_vars_path(&self) -> PathBuf {
+        if !self.config.uefi.vars_path.is_empty() {
+            self.working_dir.join(&self.config.uefi.vars_path)
+        } else if self.config.arch == "x86_64" {
+            self.working_dir.join("uefi/OVMF_VARS.fd")
+        } else {
+            // Same as the Lua script picks for the other architectures
+            self.working_dir
+                .join(format!("uefi/{}_VARS.fd", self.config.arch))
         }
+    }
 
-        self.process_qmp_events()?;
+    /// Replace the UEFI variable store of this VM with a fresh copy of the template
+    ///
+    /// The old store is kept next to it with .bak appended, its path is returned if there was one
+    pub fn uefi_reset(&mut self) -> Result<Option<PathBuf>, anyhow::Error> {
+        if !self.config.uefi.enabled {
+            anyhow::bail!("VM {} doesn't use UEFI", self.name());
+        }
 
-        Ok(())
-    }
+        if self.process.is_some() {
+            anyhow::bail!(
+                "Can't reset the UEFI variables of {} while it's running",
+                self.name()
+            );
+        }
 
-    fn process_qmp_events(&mut self) -> anyhow::Result<()> {
-        let events = if let Some(qmp) = self.control_socket.as_mut() {
-            // While we could iter, we keep hold of the mutable reference, so it's easier to just collect the events
-            qmp.qmp.events().collect::<Vec<_>>()
+        let firmware = self.config.uefi_firmware();
+        let template = &self
+            .global_config
+            .uefi
+            .get(firmware)
+            .with_context(|| format!("No uefi.{} is configured in vored.toml", firmware))?
+            .template;
+        let path = self.uefi_vars_path();
+        let backup = if path.is_file() {
+            let mut backup = path.clone().into_os_string();
+            backup.push(".bak");
+            let backup = PathBuf::from(backup);
+            std::fs::rename(&path, &backup)
+                .with_context(|| format!("Failed to move {:?} to {:?}", path, backup))?;
+            Some(backup)
         } else {
-            return Ok(());
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            None
         };
 
-        for event in events {
-            log::info!("vm {} got event: {:?}", self.name(), event);
+        std::fs::copy(template, &path)
+            .with_context(|| format!("Failed to copy UEFI template {} to {:?}", template, path))?;
+        log::info!("Reset UEFI variables of {} from {}", self.name(), template);
+        Ok(backup)
+    }
 
-            match event {
-                Event::STOP { .. } => {
-                    if self.state == VirtualMachineState::Running {
-                        self.state = VirtualMachineState::Paused;
-                    }
-                }
-                Event::RESUME { .. } => {
-                    self.state = VirtualMachineState::Running;
-                }
-                Event::SHUTDOWN { .. } => {
-                    self.state = VirtualMachineState::Stopped;
+    /// List the boot entries in the UEFI variable store of this VM,
+    /// optionally deleting entries and setting a new boot order first
+    pub fn uefi_boot_entries(
+        &mut self,
+        order: Option<&[u16]>,
+        delete: &[u16],
+    ) -> Result<Vec<UefiBootEntry>, anyhow::Error> {
+        if !self.config.uefi.enabled {
+            anyhow::bail!("VM {} doesn't use UEFI", self.name());
+        }
 
-                    if self.quit_after_shutdown {
-                        self.quit()?;
-                    }
-                }
+        let path = self.uefi_vars_path();
+        if !path.is_file() {
+            anyhow::bail!(
+                "VM {} has no UEFI variable store yet, it's created when the VM is first started",
+                self.name()
+            );
+        }
 
-                _ => {}
-            }
+        let mut store = VariableStore::load(&path)?;
+        if order.is_none() && delete.is_empty() {
+            return Ok(store.boot_entries());
         }
 
-        Ok(())
-    }
+        if self.process.is_some() {
+            anyhow::bail!(
+                "Can't modify the UEFI boot entries of {} while it's running",
+                self.name()
+            );
+        }
 
-    pub fn pause(&mut self) -> Result<(), anyhow::Error> {
-        if self.state != VirtualMachineState::Running {
-            return Ok(());
+        for id in delete {
+            store.delete_boot_entry(*id)?;
         }
 
-        self.send_qmp_command(&qapi_qmp::stop {})?;
+        if let Some(order) = order {
+            store.set_boot_order(order)?;
+        }
 
-        Ok(())
+        store.save(&path)?;
+        Ok(store.boot_entries())
     }
 
-    fn send_qmp_command<C: QmpCommand>(&mut self, command: &C) -> Result<C::Ok, anyhow::Error> {
-        let res = if let Some(qmp) = self.control_socket.as_mut() {
-            qmp.qmp.execute(command)?
+    pub fn get_cmd_line(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.get_cmd_line_with_ids().map(|(cmd, _)| cmd)
+    }
+
+    fn get_cmd_line_with_ids(&self) -> Result<(Vec<String>, QemuIdMap), anyhow::Error> {
+        let builder = QemuCommandBuilder::new(&self.global_config, self.working_dir.clone())?;
+        let mut config = self.config.clone();
+        let cdroms = self
+            .cdroms
+            .iter()
+            .chain(&self.config.guest_agent.install_media_paths)
+            .cloned()
+            .collect::<Vec<_>>();
+        config.apply_boot(&cdroms, self.boot_once.as_deref())?;
+        builder.build_with_ids(&config)
+    }
+
+    /// Ids of the devices, objects and backends of the running QEMU
+    pub fn qemu_ids(&self) -> Result<&QemuIdMap, anyhow::Error> {
+        if self.process.is_none() {
+            return Err(RpcError::not_running(self.name()).into());
+        }
+
+        Ok(&self.qemu_ids)
+    }
+
+    /// Fail if the QEMU script didn't add the [kind] with [id] hotplugging needs
+    ///
+    /// QEMU's started by a vored that didn't keep the ids yet are assumed to have it
+    fn check_qemu_id(&self, kind: &str, id: &str, what: &str) -> Result<(), anyhow::Error> {
+        if self.qemu_ids.ids.is_empty() || self.qemu_ids.get(kind, id).is_some() {
+            return Ok(());
+        }
+
+        Err(RpcError::new(
+            ErrorCode::InvalidConfig,
+            format!(
+                "The QEMU script didn't add a {} with id {} to {}, which is needed for {}",
+                kind,
+                id,
+                self.name(),
+                what
+            ),
+        )
+        .with_detail("name", self.name())
+        .into())
+    }
+
+    /// Boot from [device] before the devices in machine.boot-order, only for the next start
+    ///
+    /// cdrom boots the first attached CD-ROM if there's no disk with the iso preset
+    pub fn set_boot_once(&mut self, device: Option<String>) -> Result<(), anyhow::Error> {
+        if let Some(device) = device
+            .as_deref()
+            .filter(|x| *x != "cdrom" || self.cdroms.is_empty())
+        {
+            self.config
+                .check_boot_device(device)
+                .with_context(|| format!("Can't boot {} from {}", self.name(), device))?;
+        }
+
+        self.boot_once = device;
+        Ok(())
+    }
+
+    pub fn boot_once(&self) -> Option<&str> {
+        self.boot_once.as_deref()
+    }
+
+    /// Attach [cdroms] as read-only CD-ROM drives instead of the ones attached before
+    ///
+    /// They're only picked up by a QEMU launched after this, returns if they changed
+    pub fn set_cdroms(&mut self, cdroms: Vec<String>) -> Result<bool, anyhow::Error> {
+        if cdroms == self.cdroms {
+            return Ok(false);
+        }
+
+        if self.process.is_some() && !self.standby {
+            anyhow::bail!(
+                "Can't attach CD-ROM's to {} while it's running, stop it first",
+                self.name()
+            );
+        }
+
+        for cdrom in &cdroms {
+            File::open(cdrom).with_context(|| format!("Can't attach {} as CD-ROM", cdrom))?;
+        }
+
+        self.cdroms = cdroms;
+        Ok(true)
+    }
+
+    /// The host CPU's the vCPU's get pinned to, None if there are more vCPU's than host CPU's
+    ///
+    /// vCPU's of NUMA nodes bound to a host node are pinned to CPU's of that host node,
+    /// unless cpu.pin says where they go
+    pub fn pinned_cpus(&self) -> Option<Vec<Cpu>> {
+        if let Some(pin) = &self.config.cpu.pin {
+            return CpuList::by_ids(pin);
+        }
+
+        if self.config.numa.is_empty() {
+            return CpuList::adjacent(self.config.cpu.amount as usize).map(|x| x.to_vec());
+        }
+
+        let nodes = self
+            .config
+            .numa
+            .iter()
+            .map(|x| (x.cpus as usize, x.host_node))
+            .collect::<Vec<_>>();
+        CpuList::for_nodes(&nodes)
+    }
+
+    /// What `vore bench` measures for this VM: its vCPU threads if QEMU runs, and the host CPU's
+    /// they're pinned to
+    pub fn bench_plan(&self) -> Result<BenchPlan, anyhow::Error> {
+        let cpus = self.pinned_cpus().ok_or_else(|| {
+            anyhow::anyhow!(
+                "VM {} has more vCPU's than the host has CPU's, it's vCPU's aren't pinned",
+                self.name()
+            )
+        })?;
+
+        let vcpu_threads = self
+            .vcpu_threads()?
+            .into_iter()
+            .map(|(tid, vcpu)| (tid, cpus.get(vcpu).map(|x| x.id)))
+            .collect();
+        Ok(BenchPlan {
+            cpus: cpus.iter().map(|x| x.id).collect(),
+            pid: self.process.as_ref().map(|x| x.id()),
+            vcpu_threads,
+        })
+    }
+
+    /// Pin the vCPU's to [cpus] from now on, a running VM is moved to them immediately
+    pub fn repin(&mut self, cpus: Vec<usize>) -> Result<(), anyhow::Error> {
+        self.config.cpu.check_pin(&cpus)?;
+        if let Some(cpu) = cpus.iter().find(|x| CpuList::by_ids(&[**x]).is_none()) {
+            anyhow::bail!("CPU {} doesn't exist on this host", cpu);
+        }
+
+        let previous = self.pinned_cpus();
+        self.config.cpu.pin = Some(cpus.clone());
+        if self.process.is_none() {
+            return Ok(());
+        }
+
+        // The affinity of a thread can't go outside of the cpuset, so it covers both the old and
+        // the new CPU's while the threads move. Without a previous pinning it already covers all
+        if let (Some(cgroup), Some(previous)) = (&self.cgroup, previous) {
+            let mut both = cpus.clone();
+            both.extend(previous.iter().map(|x| x.id));
+            cgroup.set_cpus(&both)?;
+        }
+
+        self.pin_qemu_threads()?;
+
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.set_cpus(&cpus)?;
+        }
+
+        if !self.isolated_cpus.is_empty() {
+            isolate_cpus(self.name(), &cpus)?;
+            self.isolated_cpus = cpus;
+            self.write_runtime_state_logged();
+        }
+
+        Ok(())
+    }
+
+    /// Find the vCPU threads of the QEMU process, as (thread id, vCPU index)
+    fn vcpu_threads(&self) -> Result<Vec<(usize, usize)>, anyhow::Error> {
+        let pid = if let Some(child) = &self.process {
+            child.id()
+        } else {
+            return Ok(vec![]);
+        };
+
+        let mut kvm_threads = vec![];
+        for item in read_dir(format!("/proc/{}/task", pid))? {
+            let entry = item?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let res = entry
+                .file_name()
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!(""))
+                .and_then(|x| usize::from_str(x).map_err(From::from));
+            if res.is_err() {
+                continue;
+            }
+
+            let tid = res.unwrap();
+            let name = entry.path().join("comm");
+            let comm = std::fs::read_to_string(name)?;
+            if comm.starts_with("CPU ") {
+                let nr = comm
+                    .chars()
+                    .skip(4)
+                    .take_while(|x| x.is_ascii_digit())
+                    .collect::<String>();
+                let cpu_id = usize::from_str(&nr).unwrap();
+                kvm_threads.push((tid, cpu_id));
+            }
+        }
+
+        Ok(kvm_threads)
+    }
+
+    pub fn pin_qemu_threads(&self) -> Result<(), anyhow::Error> {
+        let list = self.pinned_cpus();
+        if list.is_none() {
+            // If we are over provisioning CPU's there's not much use to pinning
+            return Ok(());
+        }
+
+        let list = list.unwrap();
+        let vcpu_threads = self.vcpu_threads()?;
+
+        for (tid, cpu_id) in &vcpu_threads {
+            if *cpu_id >= list.len() {
+                // ???
+                continue;
+            }
+
+            let cpu = &list[*cpu_id];
+            unsafe {
+                let mut set = mem::zeroed::<cpu_set_t>();
+                CPU_SET(cpu.id, &mut set);
+                sched_setaffinity(*tid as i32, mem::size_of::<cpu_set_t>(), &set);
+            }
+        }
+
+        // Keep the other threads (main loop, iothreads, workers) on the same CPU's, threads QEMU
+        // creates later inherit this from the main thread. The cgroup cpuset already does this
+        // when available, this also covers hosts without one
+        let pid = if let Some(process) = &self.process {
+            process.id()
+        } else {
+            return Ok(());
+        };
+
+        let mut all = unsafe { mem::zeroed::<cpu_set_t>() };
+        for cpu in list {
+            unsafe { CPU_SET(cpu.id, &mut all) };
+        }
+
+        for item in read_dir(format!("/proc/{}/task", pid))? {
+            let tid = match item?.file_name().to_str().map(usize::from_str) {
+                Some(Ok(tid)) => tid,
+                _ => continue,
+            };
+
+            if vcpu_threads.iter().any(|(vcpu_tid, _)| *vcpu_tid == tid) {
+                continue;
+            }
+
+            unsafe {
+                sched_setaffinity(tid as i32, mem::size_of::<cpu_set_t>(), &all);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gather runtime statistics of this VM, from /proc and QMP
+    pub fn stats(&mut self) -> Result<VirtualMachineStats, anyhow::Error> {
+        let mut stats = VirtualMachineStats {
+            uptime: self.started_at.map(|x| x.elapsed().as_secs()),
+            ..Default::default()
+        };
+
+        let pid = if let Some(child) = &self.process {
+            child.id()
+        } else {
+            stats.disks = self.image_info();
+            return Ok(stats);
+        };
+
+        stats.cpu_time = read_cpu_time(&format!("/proc/{}/stat", pid))?;
+        stats.rss = read_rss(pid)?;
+        let mut vcpu_threads = self.vcpu_threads()?;
+        vcpu_threads.sort_by_key(|(_, cpu_id)| *cpu_id);
+        for (tid, _) in vcpu_threads {
+            stats
+                .vcpu_time
+                .push(read_cpu_time(&format!("/proc/{}/task/{}/stat", pid, tid))?);
+        }
+
+        stats.network = tap_interfaces(pid)
+            .into_iter()
+            .map(|interface| {
+                let read = |name: &str| {
+                    std::fs::read_to_string(format!(
+                        "/sys/class/net/{}/statistics/{}",
+                        interface, name
+                    ))
+                    .ok()
+                    .and_then(|x| u64::from_str(x.trim_end()).ok())
+                    .unwrap_or(0)
+                };
+
+                // The statistics are from the host side of the tap device, so rx and tx are swapped
+                NetworkStats {
+                    rx_bytes: read("tx_bytes"),
+                    tx_bytes: read("rx_bytes"),
+                    rx_packets: read("tx_packets"),
+                    tx_packets: read("rx_packets"),
+                    interface,
+                }
+            })
+            .collect();
+
+        if self.control_socket.is_none() {
+            stats.disks = self.image_info();
+            return Ok(stats);
+        }
+
+        stats.balloon = self.balloon_size();
+        stats.disks = self
+            .send_qmp_command(&qapi_qmp::query_block {})?
+            .into_iter()
+            .filter(|x| !x.removable)
+            .filter_map(|x| {
+                let io_status = x.io_status.map(|x| format!("{:?}", x));
+                x.inserted.map(|inserted| DiskInfo {
+                    path: inserted.file,
+                    format: inserted.image.format,
+                    virtual_size: inserted.image.virtual_size as u64,
+                    allocated: inserted.image.actual_size.map(|x| x as u64),
+                    backing_file: inserted.backing_file.or(inserted.image.backing_filename),
+                    io_status,
+                })
+            })
+            .collect();
+
+        stats.block = self
+            .send_qmp_command(&qapi_qmp::query_blockstats { query_nodes: None })?
+            .into_iter()
+            .map(|x| BlockStats {
+                device: x
+                    .device
+                    .filter(|x| !x.is_empty())
+                    .or(x.node_name)
+                    .or(x.qdev)
+                    .unwrap_or_default(),
+                rd_bytes: x.stats.rd_bytes as u64,
+                wr_bytes: x.stats.wr_bytes as u64,
+                rd_operations: x.stats.rd_operations as u64,
+                wr_operations: x.stats.wr_operations as u64,
+            })
+            .collect();
+
+        stats.looking_glass = self.looking_glass.info.clone();
+        Ok(stats)
+    }
+
+    /// Sizes of the disk images from qemu-img, for when QEMU can't be asked. These are from the
+    /// last time qemu-img was done, asking it again happens in the background
+    fn image_info(&mut self) -> Vec<DiskInfo> {
+        let probe = &mut self.image_info;
+        if probe.pending.as_ref().map_or(false, |x| x.is_finished()) {
+            match probe.pending.take().map(|x| x.join()) {
+                Some(Ok(disks)) => probe.disks = disks,
+                Some(Err(_)) => log::warn!("qemu-img info for {} panicked", self.config.name),
+                None => {}
+            }
+        }
+
+        let due = probe
+            .last_check
+            .map_or(true, |x| x.elapsed() >= IMAGE_INFO_INTERVAL);
+        if probe.pending.is_none() && due {
+            probe.last_check = Some(Instant::now());
+            let disks = self.config.disks.clone();
+            match std::thread::Builder::new()
+                .name(format!("image-info-{}", self.config.name))
+                .spawn(move || read_image_info(&disks))
+            {
+                Ok(handle) => probe.pending = Some(handle),
+                Err(err) => log::warn!("Failed to start qemu-img info thread: {:?}", err),
+            }
+        }
+
+        probe.disks.clone()
+    }
+
+    /// Paths of which the filesystems need disk-space.min-free, the working dir and the qcow2 images
+    fn disk_space_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.working_dir.clone()];
+        paths.extend(
+            self.config
+                .disks
+                .iter()
+                .filter(|x| x.disk_type == "qcow2")
+                .map(|x| PathBuf::from(&x.path)),
+        );
+        paths
+    }
+
+    /// The filesystems with less than disk-space.min-free available, described for the logs
+    fn low_disk_space(&self) -> Vec<String> {
+        let min_free = self.config.disk_space.min_free;
+        if min_free == 0 {
+            return vec![];
+        }
+
+        let mut checked = vec![];
+        let mut low = vec![];
+        for path in self.disk_space_paths() {
+            let dev = match std::fs::metadata(&path) {
+                Ok(meta) => meta.dev(),
+                Err(_) => continue,
+            };
+
+            if checked.contains(&dev) {
+                continue;
+            }
+
+            checked.push(dev);
+            let free = match available_space(&path) {
+                Ok(free) => free / 1024 / 1024,
+                Err(err) => {
+                    log::warn!("Failed to check free space for {:?}: {:?}", path, err);
+                    continue;
+                }
+            };
+
+            if free < min_free {
+                low.push(format!(
+                    "only {} MiB is free on the filesystem of {:?}, while {} MiB is needed",
+                    free, path, min_free
+                ));
+            }
+        }
+
+        low
+    }
+
+    /// Sample the resource usage of QEMU if it's due, `vore list --wide` shows it
+    pub fn check_usage(&mut self) {
+        let pid = match &self.process {
+            Some(process) => process.id(),
+            None => return,
+        };
+
+        let now = Instant::now();
+        if self
+            .usage
+            .last_check
+            .map_or(false, |x| now.duration_since(x) < USAGE_INTERVAL)
+        {
+            return;
+        }
+
+        let cpu_time = match read_cpu_time(&format!("/proc/{}/stat", pid)) {
+            Ok(cpu_time) => cpu_time,
+            Err(err) => {
+                log::debug!("Failed to read CPU time of {}: {:?}", self.name(), err);
+                return;
+            }
+        };
+        let (read, written) = read_io_bytes(pid).unwrap_or((0, 0));
+
+        if let Some(last_check) = self.usage.last_check {
+            let elapsed = now.duration_since(last_check).as_secs_f64();
+            let rate = |now: u64, before: u64| (now.saturating_sub(before) as f64 / elapsed) as u64;
+            self.usage.usage = Some(ResourceUsage {
+                rss: read_rss(pid).unwrap_or(0),
+                // milliseconds of CPU time per second, over 10 makes a percentage
+                cpu_percent: cpu_time.saturating_sub(self.usage.last_cpu_time) as f64
+                    / elapsed
+                    / 10.0,
+                read_rate: rate(read, self.usage.last_read),
+                write_rate: rate(written, self.usage.last_written),
+            });
+        }
+
+        self.usage.last_check = Some(now);
+        self.usage.last_cpu_time = cpu_time;
+        self.usage.last_read = read;
+        self.usage.last_written = written;
+    }
+
+    /// Check the free space of the filesystems if it's due, and warn or pause according to disk-space.on-low
+    pub fn check_disk_space(&mut self) -> Result<(), anyhow::Error> {
+        if self.config.disk_space.min_free == 0
+            || self.standby
+            || self.control_socket.is_none()
+            || (self.state != VirtualMachineState::Running
+                && self.state != VirtualMachineState::Paused)
+        {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if self
+            .disk_space
+            .last_check
+            .map_or(false, |x| now.duration_since(x) < DISK_SPACE_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        self.disk_space.last_check = Some(now);
+        let low = self.low_disk_space();
+        if !low.is_empty() && !self.disk_space.low {
+            self.disk_space.low = true;
+            for low in &low {
+                log::warn!("{} is running low on disk space, {}", self.name(), low);
+            }
+
+            self.run_hook_logged("low-disk-space");
+            if self.config.disk_space.on_low == LowDiskSpaceAction::Pause
+                && self.state == VirtualMachineState::Running
+            {
+                log::warn!(
+                    "Pausing {} until there's enough disk space again",
+                    self.name()
+                );
+                self.pause()?;
+                self.disk_space.paused = true;
+            }
+        } else if low.is_empty() && self.disk_space.low {
+            self.disk_space.low = false;
+            log::info!("{} has enough disk space again", self.name());
+            // Unless it was resumed in the meantime
+            if mem::take(&mut self.disk_space.paused) && self.state == VirtualMachineState::Paused {
+                log::info!("Resuming {}", self.name());
+                self.send_qmp_command(&qapi_qmp::cont {})?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sample the Looking Glass shared memory if it's due, to see if the host application and clients are there
+    pub fn check_looking_glass(&mut self) {
+        let pid = match &self.process {
+            Some(process) if self.config.looking_glass.enabled && !self.standby => process.id(),
+            _ => {
+                self.looking_glass = LookingGlassProbe::default();
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        if self
+            .looking_glass
+            .last_check
+            .map_or(false, |x| now.duration_since(x) < LOOKING_GLASS_INTERVAL)
+        {
+            return;
+        }
+
+        let mem_path = &self.config.looking_glass.mem_path;
+        let header = read_lgmp_header(mem_path);
+        // The header stays behind when the host application quits, only a moving timestamp means it runs
+        let host_running = match (&self.looking_glass.header, &header) {
+            (Some(previous), Some(current)) => previous != current,
+            _ => false,
+        };
+
+        let clients = looking_glass_clients(mem_path, pid);
+        self.check_guest_resolution(now);
+        self.looking_glass.info = Some(LookingGlassInfo {
+            host_running,
+            session_id: header.as_ref().map(|x| x.session_id),
+            clients,
+            guest_resolution: self
+                .looking_glass
+                .resolution
+                .map(|(width, height)| format!("{}x{}", width, height)),
+            buffer_too_small: self.looking_glass.buffer_too_small,
+        });
+        self.looking_glass.header = header;
+        self.looking_glass.last_check = Some(now);
+    }
+
+    /// Ask the guest agent for the resolution of the guest if it's due, and warn when its frames
+    /// don't fit in the Looking Glass shared memory
+    ///
+    /// Only with looking-glass.watch-resolution, as it runs a query in the guest every time. The
+    /// query runs while vored goes on, its output is picked up at the next check. With
+    /// looking-glass.grow the config is changed so the next boot gets enough shared memory
+    fn check_guest_resolution(&mut self, now: Instant) {
+        let probe = &mut self.looking_glass;
+        if !self.config.looking_glass.watch_resolution
+            || !self.config.guest_agent.enabled
+            || self.state != VirtualMachineState::Running
+            || probe.resolution_unavailable
+            || probe
+                .last_resolution_check
+                .map_or(false, |x| now.duration_since(x) < GUEST_RESOLUTION_INTERVAL)
+        {
+            return;
+        }
+
+        probe.last_resolution_check = Some(now);
+        let result =
+            GuestAgent::connect(&self.config.guest_agent.socket_path, Duration::from_secs(1))
+                .context("Failed to connect to the guest agent socket")
+                .and_then(|mut agent| {
+                    // Not up yet, or not installed at all, ask again later
+                    if !agent.sync()? {
+                        return Ok(None);
+                    }
+
+                    match probe.resolution_pid.take() {
+                        Some(pid) => match agent.exec_status(pid)? {
+                            Some((0, output)) => Ok(parse_guest_resolution(&output)),
+                            Some((code, _)) => {
+                                anyhow::bail!("The resolution query exited with {}", code)
+                            }
+                            None => {
+                                probe.resolution_pid = Some(pid);
+                                Ok(None)
+                            }
+                        },
+                        None => {
+                            let (path, args) = GUEST_RESOLUTION_COMMAND;
+                            probe.resolution_pid = Some(agent.exec_captured(path, args)?);
+                            Ok(None)
+                        }
+                    }
+                });
+
+        let (width, height) = match result {
+            Ok(Some(resolution)) => resolution,
+            Ok(None) => return,
+            Err(err) => {
+                log::info!(
+                    "Can't get the resolution of {} from its guest agent, not checking if it fits in the looking-glass shared memory: {:#}",
+                    self.config.name,
+                    err
+                );
+                probe.resolution_unavailable = true;
+                return;
+            }
+        };
+
+        if probe.resolution.replace((width, height)) == Some((width, height)) {
+            return;
+        }
+
+        let looking_glass = &mut self.config.looking_glass;
+        let buffer_size = *probe.buffer_size.get_or_insert(looking_glass.buffer_size);
+        let mut needed = looking_glass.clone();
+        needed.width = width;
+        needed.height = height;
+        needed.calc_buffer_size_from_screen();
+        probe.buffer_too_small = needed.buffer_size > buffer_size;
+        if !probe.buffer_too_small {
+            return;
+        }
+
+        let mut message = format!(
+            "{} switched to {}x{}, which needs {} MiB of looking-glass shared memory but it has {} MiB",
+            self.config.name,
+            width,
+            height,
+            needed.buffer_size / 1024 / 1024,
+            buffer_size / 1024 / 1024
+        );
+        if looking_glass.mem_path.starts_with("/dev/kvmfr") {
+            message.push_str(", raise static_size_mb of the kvmfr module");
+        } else if !looking_glass.grow {
+            message.push_str(", raise looking-glass.width and height");
+        } else if needed.buffer_size > looking_glass.buffer_size {
+            looking_glass.width = width;
+            looking_glass.height = height;
+            looking_glass.buffer_size = needed.buffer_size;
+            message.push_str(", it gets that much on the next boot");
+        } else {
+            message.push_str(", it gets enough on the next boot");
+        }
+
+        log::warn!("{}", message);
+        self.warnings.push(message);
+    }
+
+    /// Warnings since the last call, for the event subscribers
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// Memory the VM starts with in MiB
+    pub fn memory(&self) -> u64 {
+        self.config.memory
+    }
+
+    /// Current size of the memory of the guest in bytes, None if it's not running or has no balloon device
+    pub fn balloon_size(&mut self) -> Option<u64> {
+        if self.control_socket.is_none() || !self.config.balloon {
+            return None;
+        }
+
+        self.send_qmp_command(&qapi_qmp::query_balloon {})
+            .ok()
+            .map(|x| x.actual as u64)
+    }
+
+    /// Ask the guest to use [memory] MiB of memory via the balloon, which can't be more than it started with
+    ///
+    /// The guest gives the memory back (or takes it) in its own time
+    pub fn set_memory(&mut self, memory: u64) -> Result<(), anyhow::Error> {
+        if !self.config.balloon {
+            anyhow::bail!(
+                "VM {} has no balloon device, memory can't be changed while it runs",
+                self.name()
+            );
+        }
+
+        if memory == 0 || memory > self.config.memory {
+            anyhow::bail!(
+                "VM {} can use between 1M and the {}M it started with",
+                self.name(),
+                self.config.memory
+            );
+        }
+
+        if self.control_socket.is_none() {
+            return Err(RpcError::not_running(self.name()).into());
+        }
+
+        self.send_qmp_command(&qapi_qmp::balloon {
+            value: (memory * 1024 * 1024) as isize,
+        })
+        .with_context(|| format!("Failed to resize the balloon of {}", self.name()))?;
+
+        Ok(())
+    }
+
+    /// The removable drives of the running QEMU, in the order they were added
+    fn removable_drives(&mut self) -> Result<Vec<qapi_qmp::BlockInfo>, anyhow::Error> {
+        if self.control_socket.is_none() {
+            return Err(RpcError::not_running(self.name()).into());
+        }
+
+        Ok(self
+            .send_qmp_command(&qapi_qmp::query_block {})?
+            .into_iter()
+            .filter(|x| x.removable)
+            .collect())
+    }
+
+    pub fn cdrom_drives(&mut self) -> Result<Vec<CdromDrive>, anyhow::Error> {
+        if self.control_socket.is_none() {
+            return Ok(vec![]);
+        }
+
+        Ok(self
+            .removable_drives()?
+            .into_iter()
+            .map(|x| CdromDrive {
+                path: x.inserted.map(|x| x.file),
+                tray_open: x.tray_open.unwrap_or(false),
+                locked: x.locked,
+            })
+            .collect())
+    }
+
+    /// QOM path or id of CD-ROM drive [drive], which is what the QMP commands for media take
+    fn cdrom_device(&mut self, drive: usize) -> Result<String, anyhow::Error> {
+        let drives = self.removable_drives()?;
+        let amount = drives.len();
+        drives
+            .into_iter()
+            .nth(drive)
+            .and_then(|x| x.qdev)
+            .with_context(|| {
+                format!(
+                    "VM {} has no CD-ROM drive {}, it has {}",
+                    self.name(),
+                    drive,
+                    amount
+                )
+            })
+    }
+
+    /// Take the ISO out of CD-ROM drive [drive], even if the guest locked it
+    pub fn eject_cdrom(&mut self, drive: usize) -> Result<(), anyhow::Error> {
+        let id = self.cdrom_device(drive)?;
+        #[allow(deprecated)]
+        self.send_qmp_command(&qapi_qmp::eject {
+            id: Some(id),
+            device: None,
+            force: Some(true),
+        })
+        .with_context(|| format!("Failed to eject CD-ROM drive {} of {}", drive, self.name()))?;
+
+        Ok(())
+    }
+
+    /// Put [path] in CD-ROM drive [drive], replacing what's in it
+    pub fn insert_cdrom(&mut self, drive: usize, path: &str) -> Result<(), anyhow::Error> {
+        File::open(path).with_context(|| format!("Can't insert {} as CD-ROM", path))?;
+        let id = self.cdrom_device(drive)?;
+        #[allow(deprecated)]
+        self.send_qmp_command(&qapi_qmp::blockdev_change_medium {
+            id: Some(id),
+            device: None,
+            filename: path.to_string(),
+            format: Some("raw".to_string()),
+            read_only_mode: Some(qapi_qmp::BlockdevChangeReadOnlyMode::read_only),
+        })
+        .with_context(|| {
+            format!(
+                "Failed to insert {} into CD-ROM drive {} of {}",
+                path,
+                drive,
+                self.name()
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// The USB devices passed through to the running VM, from the config and attached since it started
+    pub fn usb_devices(&mut self) -> Result<Vec<UsbDevice>, anyhow::Error> {
+        let mut devices = vec![];
+        let peripherals = self.send_qmp_command(&qapi_qmp::qom_list {
+            path: "/machine/peripheral".to_string(),
+        })?;
+
+        for peripheral in peripherals {
+            if peripheral.type_ != "child<usb-host>" {
+                continue;
+            }
+
+            let path = format!("/machine/peripheral/{}", peripheral.name);
+            let mut get = |property: &str| {
+                self.send_qmp_command(&qapi_qmp::qom_get {
+                    path: path.clone(),
+                    property: property.to_string(),
+                })
+            };
+
+            let vendor = get("vendorid")?.as_u64().unwrap_or(0);
+            let product = get("productid")?.as_u64().unwrap_or(0);
+            let usb = if vendor != 0 || product != 0 {
+                UsbConfig {
+                    vendor: Some(vendor as u16),
+                    product: Some(product as u16),
+                    ..Default::default()
+                }
+            } else {
+                UsbConfig {
+                    bus: get("hostbus")?.as_u64().map(|x| x as u32),
+                    port: get("hostport")?.as_str().map(|x| x.to_string()),
+                    ..Default::default()
+                }
+            };
+
+            devices.push(UsbDevice {
+                hotplugged: peripheral.name.starts_with(USB_HOTPLUG_PREFIX),
+                id: peripheral.name,
+                device: usb.to_string(),
+            });
+        }
+
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(devices)
+    }
+
+    fn check_usb_hotplug(&self) -> Result<(), anyhow::Error> {
+        if self.standby
+            || (self.state != VirtualMachineState::Running
+                && self.state != VirtualMachineState::Paused)
+        {
+            return Err(RpcError::new(
+                ErrorCode::InvalidState,
+                format!(
+                    "VM {} isn't running, USB devices can only be attached to a running VM",
+                    self.name()
+                ),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Pass [usb] through to the running VM, until it's detached or QEMU quits
+    pub fn attach_usb(&mut self, usb: &UsbConfig) -> Result<String, anyhow::Error> {
+        self.check_usb_hotplug()?;
+        let devices = self.usb_devices()?;
+        if let Some(device) = devices.iter().find(|x| x.device == usb.to_string()) {
+            anyhow::bail!(
+                "USB device {} is already attached to {} as {}",
+                usb,
+                self.name(),
+                device.id
+            );
+        }
+
+        VirtualMachine::check_usb_device(usb)?;
+        self.check_qemu_id("device", USB_CONTROLLER, "attaching USB devices")?;
+        let id = (0..)
+            .map(|x| format!("{}{}", USB_HOTPLUG_PREFIX, x))
+            .find(|id| !devices.iter().any(|x| &x.id == id))
+            .unwrap();
+
+        let mut arguments = serde_json::Map::new();
+        match (usb.vendor, usb.product, usb.bus, &usb.port) {
+            (Some(vendor), Some(product), _, _) => {
+                arguments.insert("vendorid".to_string(), vendor.into());
+                arguments.insert("productid".to_string(), product.into());
+            }
+            (_, _, Some(bus), Some(port)) => {
+                arguments.insert("hostbus".to_string(), bus.into());
+                arguments.insert("hostport".to_string(), port.clone().into());
+            }
+            _ => anyhow::bail!("USB device {} has no ids or port", usb),
+        }
+
+        self.send_qmp_command(&qapi_qmp::device_add {
+            driver: "usb-host".to_string(),
+            bus: Some(format!("{}.0", USB_CONTROLLER)),
+            id: Some(id.clone()),
+            arguments,
+        })
+        .with_context(|| format!("Failed to attach USB device {} to {}", usb, self.name()))?;
+
+        Ok(id)
+    }
+
+    /// Unplug the USB device [device] from the running VM, either by its id or as vendor:product or bus-port
+    pub fn detach_usb(&mut self, device: &str) -> Result<(), anyhow::Error> {
+        self.check_usb_hotplug()?;
+        let devices = self.usb_devices()?;
+        let target = UsbConfig::from_str(device).ok().map(|x| x.to_string());
+        let id = devices
+            .into_iter()
+            .find(|x| x.id == device || Some(&x.device) == target.as_ref())
+            .map(|x| x.id)
+            .with_context(|| format!("USB device {} isn't attached to {}", device, self.name()))?;
+
+        // The guest is asked to let go of it, it's gone once QEMU sends DEVICE_DELETED
+        self.send_qmp_command(&qapi_qmp::device_del { id: id.clone() })
+            .with_context(|| format!("Failed to detach USB device {} from {}", id, self.name()))?;
+
+        Ok(())
+    }
+
+    /// PCI devices of the host that are passed through to the running VM, from the config and hotplugged
+    pub fn pci_devices_in_use(&self) -> Vec<PciAddress> {
+        let mut devices = self.vfio_devices().map(|x| x.address).collect::<Vec<_>>();
+        devices.extend(self.pci_hotplugged.iter().map(|x| x.address));
+        devices
+    }
+
+    /// The hotplug slots of the VM, with the device attached to each
+    pub fn pci_slots(&self) -> Vec<PciSlot> {
+        (1..=self.config.pci_hotplug_slots)
+            .map(|slot| {
+                let device = self.pci_hotplugged.iter().find(|x| x.slot == slot);
+                PciSlot {
+                    slot,
+                    address: device.map(|x| x.address),
+                    detaching: device.map_or(false, |x| x.detaching),
+                }
+            })
+            .collect()
+    }
+
+    /// Bind [address] to vfio-pci, and attach it to a free hotplug slot of the running VM
+    pub fn attach_pci(&mut self, address: PciAddress, force: bool) -> Result<(), anyhow::Error> {
+        if self.standby
+            || (self.state != VirtualMachineState::Running
+                && self.state != VirtualMachineState::Paused)
+        {
+            return Err(RpcError::new(
+                ErrorCode::InvalidState,
+                format!(
+                    "VM {} isn't running, PCI devices can only be attached to a running VM",
+                    self.name()
+                ),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        if self.pci_devices_in_use().contains(&address) {
+            anyhow::bail!(
+                "PCI device {} is already passed through to {}",
+                address,
+                self.name()
+            );
+        }
+
+        let slot = (1..=self.config.pci_hotplug_slots)
+            .find(|slot| !self.pci_hotplugged.iter().any(|x| x.slot == *slot))
+            .with_context(|| {
+                format!(
+                    "VM {} has no free PCI hotplug slot, it has {} (machine.pci-hotplug-slots)",
+                    self.name(),
+                    self.config.pci_hotplug_slots
+                )
+            })?;
+        self.check_qemu_id(
+            "device",
+            &format!("{}{}", PCI_HOTPLUG_PORT_PREFIX, slot),
+            "attaching PCI devices",
+        )?;
+
+        check_iommu_group(&address, &self.pci_devices_in_use())?;
+        VirtualMachine::prepare_vfio_device(
+            true,
+            force,
+            &VfioConfig {
+                address,
+                vendor: None,
+                device: None,
+                index: 0,
+                graphics: false,
+                multifunction: false,
+                reserve: false,
+                release: false,
+                physical_function: None,
+                sriov_vf: 0,
+                sriov_vfs: 0,
+                romfile: None,
+                rombar: true,
+            },
+        )?;
+
+        let mut arguments = serde_json::Map::new();
+        arguments.insert("host".to_string(), format!("{:#}", address).into());
+        let res = self
+            .send_qmp_command(&qapi_qmp::device_add {
+                driver: "vfio-pci".to_string(),
+                bus: Some(format!("{}{}", PCI_HOTPLUG_PORT_PREFIX, slot)),
+                id: Some(format!("{}{}", PCI_HOTPLUG_DEVICE_PREFIX, slot)),
+                arguments,
+            })
+            .with_context(|| format!("Failed to attach PCI device {} to {}", address, self.name()));
+
+        if let Err(err) = res {
+            if let Err(release_err) = release_vfio_device(&address) {
+                log::warn!(
+                    "Failed to give {} back to its host driver: {:?}",
+                    address,
+                    release_err
+                );
+            }
+
+            return Err(err);
+        }
+
+        self.pci_hotplugged.push(HotpluggedPci {
+            slot,
+            address,
+            detaching: false,
+        });
+        self.write_runtime_state_logged();
+        Ok(())
+    }
+
+    /// Ask the guest to let go of the hotplugged PCI device [address], it's given back to its host driver
+    /// once QEMU removed it
+    pub fn detach_pci(&mut self, address: PciAddress) -> Result<(), anyhow::Error> {
+        let slot = self
+            .pci_hotplugged
+            .iter()
+            .find(|x| x.address == address)
+            .map(|x| x.slot)
+            .with_context(|| {
+                if self.vfio_devices().any(|x| x.address == address) {
+                    format!(
+                        "PCI device {} is part of the config of {}, only hotplugged devices can be detached",
+                        address,
+                        self.name()
+                    )
+                } else {
+                    format!("PCI device {} isn't attached to {}", address, self.name())
+                }
+            })?;
+
+        self.send_qmp_command(&qapi_qmp::device_del {
+            id: format!("{}{}", PCI_HOTPLUG_DEVICE_PREFIX, slot),
+        })
+        .with_context(|| {
+            format!(
+                "Failed to detach PCI device {} from {}",
+                address,
+                self.name()
+            )
+        })?;
+
+        // DEVICE_DELETED may have come in with the answer already
+        if let Some(device) = self.pci_hotplugged.iter_mut().find(|x| x.slot == slot) {
+            device.detaching = true;
+        }
+
+        Ok(())
+    }
+
+    fn pci_device_deleted(&mut self, id: &str) {
+        let slot = match id
+            .strip_prefix(PCI_HOTPLUG_DEVICE_PREFIX)
+            .and_then(|x| x.parse::<u32>().ok())
+        {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let (removed, kept) = mem::take(&mut self.pci_hotplugged)
+            .into_iter()
+            .partition::<Vec<_>, _>(|x| x.slot == slot);
+        self.pci_hotplugged = kept;
+        for device in removed {
+            log::info!(
+                "PCI device {} was detached from {}",
+                device.address,
+                self.name()
+            );
+            if let Err(err) = release_vfio_device(&device.address) {
+                log::warn!(
+                    "Failed to give {} back to its host driver: {:?}",
+                    device.address,
+                    err
+                );
+            }
+        }
+
+        self.write_runtime_state_logged();
+    }
+
+    /// QEMU is gone, so the hotplugged devices can go back to their host drivers
+    fn release_hotplugged_pci(&mut self) {
+        for device in mem::take(&mut self.pci_hotplugged) {
+            if let Err(err) = release_vfio_device(&device.address) {
+                log::warn!(
+                    "Failed to give {} back to its host driver: {:?}",
+                    device.address,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Take a screenshot if one is due, and check if the guest stalled
+    ///
+    /// Returns true if this check found the VM stalled, it's killed if health.restart is set
+    pub fn check_health(&mut self) -> Result<bool, anyhow::Error> {
+        if !self.config.health.enabled
+            || self.state != VirtualMachineState::Running
+            || self.control_socket.is_none()
+            || self.health.unavailable
+        {
+            return Ok(false);
+        }
+
+        let now = Instant::now();
+        let interval = Duration::from_secs(self.config.health.interval);
+        if self
+            .health
+            .last_check
+            .map_or(false, |x| now.duration_since(x) < interval)
+        {
+            return Ok(false);
+        }
+
+        let pid = if let Some(process) = &self.process {
+            process.id()
+        } else {
+            return Ok(false);
+        };
+
+        let cpu_time = read_cpu_time(&format!("/proc/{}/stat", pid))?;
+        let busy = self.health.last_check.map_or(false, |last| {
+            let available = now.duration_since(last).as_millis() as u64 * self.config.cpu.amount;
+            let used = cpu_time.saturating_sub(self.health.last_cpu_time);
+            available > 0 && used * 100 >= available * self.config.health.cpu_threshold as u64
+        });
+        self.health.last_check = Some(now);
+        self.health.last_cpu_time = cpu_time;
+
+        let screen_hash = match self.screen_hash() {
+            Ok(screen_hash) => screen_hash,
+            Err(err) => {
+                log::warn!(
+                    "Can't take screenshots of {}, disabling its health probe until it restarts: {:?}",
+                    self.name(),
+                    err
+                );
+                self.health.unavailable = true;
+                return Ok(false);
+            }
+        };
+
+        let changed = self.health.screen_hash != Some(screen_hash);
+        self.health.screen_hash = Some(screen_hash);
+        if changed || !busy {
+            if self.health.stalled {
+                log::info!("{} isn't stalled anymore", self.name());
+            }
+
+            self.health.stuck_since = None;
+            self.health.stalled = false;
+            return Ok(false);
+        }
+
+        let stuck_since = *self.health.stuck_since.get_or_insert(now);
+        if self.health.stalled
+            || now.duration_since(stuck_since) < Duration::from_secs(self.config.health.stall_after)
+        {
+            return Ok(false);
+        }
+
+        self.health.stalled = true;
+        log::error!(
+            "{} stalled, its screen didn't change for {}s while its CPU's were busy",
+            self.name(),
+            now.duration_since(stuck_since).as_secs()
+        );
+
+        if self.config.health.restart {
+            self.quit_with("stalled")?;
+            // Counts as a crash, so the restart policy starts it again
+            self.last_exit = Some(VirtualMachineExit::Crashed);
+        }
+
+        Ok(true)
+    }
+
+    fn timezone_marker_path(&self) -> PathBuf {
+        self.working_dir.join("guest-timezone")
+    }
+
+    /// If the guest didn't get its timezone yet, which is normally only on its first boot,
+    /// or after machine.timezone changed
+    fn needs_timezone_push(&self) -> bool {
+        match &self.config.timezone {
+            Some(timezone) if self.config.guest_agent.enabled => {
+                std::fs::read_to_string(self.timezone_marker_path())
+                    .map_or(true, |x| x.trim() != timezone)
+            }
+            _ => false,
+        }
+    }
+
+    /// Set the clock and timezone of the guest through its agent, if that's still due
+    ///
+    /// The agent only answers once the guest booted far enough, so this is retried until then
+    pub fn push_timezone(&mut self) -> Result<(), anyhow::Error> {
+        let now = Instant::now();
+        let push = match self.timezone_push.as_mut() {
+            Some(push) if self.state == VirtualMachineState::Running => push,
+            _ => return Ok(()),
+        };
+
+        if push
+            .last_attempt
+            .map_or(false, |x| now.duration_since(x) < TIMEZONE_PUSH_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        if now.duration_since(push.since) > TIMEZONE_PUSH_TIMEOUT {
+            self.timezone_push = None;
+            anyhow::bail!(
+                "Guest agent didn't answer within {}s, is qemu-ga running in the guest?",
+                TIMEZONE_PUSH_TIMEOUT.as_secs()
+            );
+        }
+
+        push.last_attempt = Some(now);
+        let mut agent =
+            GuestAgent::connect(&self.config.guest_agent.socket_path, Duration::from_secs(1))
+                .context("Failed to connect to the guest agent socket")?;
+        if !agent.sync()? {
+            return Ok(());
+        }
+
+        // Whatever happens now won't be solved by trying again
+        self.timezone_push = None;
+        let timezone = self.config.timezone.clone().unwrap_or_default();
+        let name = timezone_name(&timezone)?;
+        agent
+            .set_time()
+            .context("Failed to set the clock of the guest")?;
+        agent
+            .exec("timedatectl", &["set-timezone", &name])
+            .context("Failed to set the timezone of the guest")?;
+        std::fs::write(self.timezone_marker_path(), &timezone)?;
+        log::info!("Set the clock and timezone ({}) of {}", name, self.name());
+
+        Ok(())
+    }
+
+    /// Connect to the guest agent and check it's there, for commands asked for by a client
+    fn guest_agent(&self) -> Result<GuestAgent, anyhow::Error> {
+        if !self.config.guest_agent.enabled {
+            return Err(RpcError::new(
+                ErrorCode::InvalidConfig,
+                format!("VM {} has no guest agent, enable guest-agent", self.name()),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        if self.state != VirtualMachineState::Running {
+            return Err(RpcError::not_running(self.name()).into());
+        }
+
+        let mut agent =
+            GuestAgent::connect(&self.config.guest_agent.socket_path, Duration::from_secs(2))
+                .context("Failed to connect to the guest agent socket")?;
+        if !agent.sync()? {
+            return Err(RpcError::new(
+                ErrorCode::Timeout,
+                format!(
+                    "Guest agent of {} didn't answer, is qemu-ga running in the guest?",
+                    self.name()
+                ),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        Ok(agent)
+    }
+
+    /// Freeze the filesystems of the guest, so the disks are consistent for a snapshot or backup
+    ///
+    /// Everything in the guest that writes stalls while they're frozen, so they're thawed after
+    /// [timeout] by [check_fsfreeze] if nobody did before. Returns how many were frozen
+    pub fn fsfreeze(&mut self, timeout: Duration) -> Result<u64, anyhow::Error> {
+        let mut agent = self.guest_agent()?;
+        agent.set_timeout(FSFREEZE_TIMEOUT)?;
+        match agent.execute("guest-fsfreeze-freeze", json!({})) {
+            Ok(frozen) => {
+                self.frozen_until = Some(Instant::now() + timeout);
+                log::info!(
+                    "Froze {} filesystem(s) of {} for at most {}s",
+                    frozen,
+                    self.name(),
+                    timeout.as_secs()
+                );
+                Ok(frozen.as_u64().unwrap_or_default())
+            }
+            Err(err) => {
+                // Some filesystems may have been frozen before it failed
+                let _ = agent.execute("guest-fsfreeze-thaw", json!({}));
+                Err(err)
+                    .with_context(|| format!("Failed to freeze the filesystems of {}", self.name()))
+            }
+        }
+    }
+
+    /// Thaw the filesystems of the guest, returns how many were thawed
+    pub fn fsthaw(&mut self) -> Result<u64, anyhow::Error> {
+        let thawed = self
+            .guest_agent()?
+            .execute("guest-fsfreeze-thaw", json!({}))
+            .with_context(|| format!("Failed to thaw the filesystems of {}", self.name()))?;
+        if self.frozen_until.take().is_some() {
+            log::info!("Thawed the filesystems of {}", self.name());
+        }
+
+        Ok(thawed.as_u64().unwrap_or_default())
+    }
+
+    /// "frozen" or "thawed", as the guest agent reports it
+    pub fn fsfreeze_status(&mut self) -> Result<String, anyhow::Error> {
+        Ok(self
+            .guest_agent()?
+            .execute("guest-fsfreeze-status", json!({}))?
+            .as_str()
+            .unwrap_or("unknown")
+            .to_string())
+    }
+
+    /// Thaw the filesystems of the guest once they were frozen for longer than asked for
+    pub fn check_fsfreeze(&mut self) -> Result<(), anyhow::Error> {
+        match self.frozen_until {
+            _ if self.control_socket.is_none() => {
+                self.frozen_until = None;
+                Ok(())
+            }
+            Some(until) if until <= Instant::now() => {
+                log::warn!(
+                    "Filesystems of {} are still frozen, thawing them",
+                    self.name()
+                );
+                self.fsthaw().map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot_groups_path(&self) -> PathBuf {
+        self.working_dir.join("snapshots.json")
+    }
+
+    /// Snapshot groups of this VM, oldest first
+    pub fn snapshots(&self) -> Result<Vec<SnapshotGroup>, anyhow::Error> {
+        load_snapshot_groups(self.snapshot_groups_path())
+    }
+
+    fn snapshot_not_found(&self, name: &str) -> anyhow::Error {
+        RpcError::new(
+            ErrorCode::InvalidConfig,
+            format!("{} has no snapshot named '{}'", self.name(), name),
+        )
+        .with_detail("name", self.name())
+        .into()
+    }
+
+    /// Node names of the images at [paths] in the running QEMU, in the same order
+    fn disk_nodes(&mut self, paths: &[String]) -> Result<Vec<String>, anyhow::Error> {
+        let block = self.send_qmp_command(&qapi_qmp::query_block {})?;
+        paths
+            .iter()
+            .map(|path| {
+                block
+                    .iter()
+                    .filter_map(|x| x.inserted.as_ref())
+                    .find(|x| &x.file == path)
+                    .and_then(|x| x.node_name.clone())
+                    .with_context(|| format!("QEMU of {} doesn't have {} open", self.name(), path))
+            })
+            .collect()
+    }
+
+    /// Snapshot every disk the guest can write to as the group [name], all at the same moment
+    ///
+    /// While QEMU runs they're taken in one QMP transaction, with the filesystems of the guest
+    /// frozen if it has a guest agent, either every disk gets the snapshot or none does
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let mut groups = self.snapshots()?;
+        if name.is_empty()
+            || name.starts_with('-')
+            || !name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || "-_.".contains(x))
+        {
+            return Err(RpcError::new(
+                ErrorCode::InvalidConfig,
+                format!(
+                    "'{}' can't be used as snapshot name, use letters, digits, -, _ and .",
+                    name
+                ),
+            )
+            .into());
+        }
+
+        if groups.iter().any(|x| x.name == name) {
+            return Err(RpcError::new(
+                ErrorCode::InvalidConfig,
+                format!("{} already has a snapshot named '{}'", self.name(), name),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        let disks = snapshot_disks(&self.config)
+            .map_err(|err| RpcError::new(ErrorCode::InvalidConfig, format!("{:#}", err)))?;
+        let live = self.process.is_some();
+        let mut frozen = false;
+        if live {
+            let actions = self
+                .disk_nodes(&disks)?
+                .into_iter()
+                .map(
+                    |device| qapi_qmp::TransactionAction::blockdev_snapshot_internal_sync {
+                        data: qapi_qmp::BlockdevSnapshotInternal {
+                            name: name.to_string(),
+                            device,
+                        },
+                    },
+                )
+                .collect();
+
+            if self.config.guest_agent.enabled && self.state == VirtualMachineState::Running {
+                match self.fsfreeze(SNAPSHOT_FREEZE_TIMEOUT) {
+                    Ok(_) => frozen = true,
+                    Err(err) => log::warn!(
+                        "Failed to freeze the filesystems of {}, the snapshot is only crash consistent: {:#}",
+                        self.name(),
+                        err
+                    ),
+                }
+            }
+
+            let result = self.send_qmp_command(&qapi_qmp::transaction {
+                properties: None,
+                actions,
+            });
+            if frozen {
+                if let Err(err) = self.fsthaw() {
+                    log::warn!(
+                        "Failed to thaw the filesystems of {}: {:#}",
+                        self.name(),
+                        err
+                    );
+                }
+            }
+
+            result.with_context(|| format!("Failed to snapshot the disks of {}", self.name()))?;
+        } else {
+            for (idx, disk) in disks.iter().enumerate() {
+                if let Err(err) = qemu_img_snapshot("-c", name, disk) {
+                    // A group has every disk or none
+                    for disk in &disks[..idx] {
+                        let _ = qemu_img_snapshot("-d", name, disk);
+                    }
+
+                    return Err(err);
+                }
+            }
+        }
+
+        log::info!(
+            "Took snapshot {} of {} ({} disk(s){})",
+            name,
+            self.name(),
+            disks.len(),
+            if frozen { ", filesystems frozen" } else { "" }
+        );
+        groups.push(SnapshotGroup {
+            name: name.to_string(),
+            created: unix_now(),
+            disks,
+            live,
+            frozen,
+        });
+        save_snapshot_groups(self.snapshot_groups_path(), &groups)
+    }
+
+    /// Put every disk of the snapshot group [name] back, if one of them can't be nothing is changed
+    pub fn revert_snapshot(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        if self.process.is_some() {
+            return Err(RpcError::new(
+                ErrorCode::InvalidState,
+                format!(
+                    "Can't revert {} to a snapshot while it's running",
+                    self.name()
+                ),
+            )
+            .with_detail("name", self.name())
+            .into());
+        }
+
+        let group = self
+            .snapshots()?
+            .into_iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| self.snapshot_not_found(name))?;
+        for disk in &group.disks {
+            if !image_snapshots(disk)?.contains(&group.name) {
+                anyhow::bail!(
+                    "{} doesn't have snapshot {} anymore, none of the disks of {} were reverted",
+                    disk,
+                    name,
+                    self.name()
+                );
+            }
+        }
+
+        for disk in self
+            .config
+            .disks
+            .iter()
+            .filter(|x| !x.read_only && x.preset != "iso" && !group.disks.contains(&x.path))
+        {
+            log::warn!(
+                "{} isn't in snapshot {} of {}, it's left as it is",
+                disk.path,
+                name,
+                self.name()
+            );
+        }
+
+        for disk in &group.disks {
+            qemu_img_snapshot("-a", name, disk)?;
+        }
+
+        log::info!("Reverted {} to snapshot {}", self.name(), name);
+        Ok(())
+    }
+
+    /// Delete the snapshot group [name] from every disk in it
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let mut groups = self.snapshots()?;
+        let group = match groups.iter().position(|x| x.name == name) {
+            Some(idx) => groups.remove(idx),
+            None => return Err(self.snapshot_not_found(name)),
+        };
+
+        if self.process.is_some() {
+            for device in self.disk_nodes(&group.disks)? {
+                self.send_qmp_command(&qapi_qmp::blockdev_snapshot_delete_internal_sync {
+                    device,
+                    id: None,
+                    name: Some(name.to_string()),
+                })
+                .with_context(|| {
+                    format!("Failed to delete snapshot {} of {}", name, self.name())
+                })?;
+            }
+        } else {
+            for disk in &group.disks {
+                if image_snapshots(disk)?.contains(&group.name) {
+                    qemu_img_snapshot("-d", name, disk)?;
+                }
+            }
+        }
+
+        log::info!("Deleted snapshot {} of {}", name, self.name());
+        save_snapshot_groups(self.snapshot_groups_path(), &groups)
+    }
+
+    /// Hash of a screenshot of the guest's display
+    fn screen_hash(&mut self) -> Result<u64, anyhow::Error> {
+        let path = self.working_dir.join("health.ppm");
+        self.send_qmp_command(&qapi_qmp::screendump {
+            filename: path.to_str().unwrap().to_string(),
+            head: None,
+            device: None,
+        })?;
+
+        let data = std::fs::read(&path)?;
+        let _ = std::fs::remove_file(&path);
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    pub fn boop(&mut self) -> Result<(), anyhow::Error> {
+        if self.check_exited()?.is_some() {
+            return Ok(());
+        }
+
+        if let Some(qmp) = self.control_socket.as_mut() {
+            qmp.qmp.nop()?;
+        }
+
+        self.process_qmp_events()?;
+
+        Ok(())
+    }
+
+    /// Check if QEMU went away, and clean up after it if so
+    ///
+    /// Reaps the QEMU process if it exited, and returns its exit status
+    pub fn check_exited(&mut self) -> Result<Option<QemuExitStatus>, anyhow::Error> {
+        let status = if let Some(process) = self.process.as_mut() {
+            process.try_wait()?
+        } else {
+            return Ok(None);
+        };
+
+        let status = if let Some(status) = status {
+            status
+        } else {
+            return Ok(None);
+        };
+
+        // QEMU may have quit after a regular shutdown we haven't seen the event of yet
+        let _ = self.process_qmp_events();
+        if self.process.is_none() {
+            return Ok(Some(status));
+        }
+
+        if mem::take(&mut self.standby) {
+            log::warn!("Standby QEMU for {} exited with {}", self.name(), status);
+            self.process = None;
+            self.release_process();
+            return Ok(Some(status));
+        }
+
+        let expected = self.state == VirtualMachineState::Stopped;
+        if expected {
+            log::info!("QEMU for {} exited with {}", self.name(), status);
+        } else {
+            log::error!(
+                "QEMU for {} exited unexpectedly with {}",
+                self.name(),
+                status
+            );
+            self.last_exit = Some(VirtualMachineExit::Crashed);
+        }
+
+        self.process = None;
+        self.release_process();
+        self.state = VirtualMachineState::Stopped;
+        self.record_stop(if expected { "stopped" } else { "crashed" });
+        self.run_hook_logged(if expected { "post-stop" } else { "on-crash" });
+
+        Ok(Some(status))
+    }
+
+    fn process_qmp_events(&mut self) -> anyhow::Result<()> {
+        let events = if let Some(qmp) = self.control_socket.as_mut() {
+            // While we could iter, we keep hold of the mutable reference, so it's easier to just collect the events
+            qmp.qmp.events().collect::<Vec<_>>()
+        } else {
+            return Ok(());
+        };
+
+        for event in events {
+            log::info!("vm {} got event: {:?}", self.name(), event);
+
+            match event {
+                Event::STOP { .. } => {
+                    if self.state == VirtualMachineState::Running {
+                        self.state = VirtualMachineState::Paused;
+                    }
+                }
+                Event::RESUME { .. } => {
+                    self.state = VirtualMachineState::Running;
+                }
+                Event::DEVICE_DELETED { data, .. } => {
+                    if let Some(id) = data.device {
+                        self.pci_device_deleted(&id);
+                    }
+                }
+                Event::SHUTDOWN { .. } => {
+                    self.state = VirtualMachineState::Stopped;
+                    if !self.stop_requested {
+                        self.last_exit = Some(VirtualMachineExit::Shutdown);
+                    }
+
+                    if self.quit_after_shutdown {
+                        self.quit()?;
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<(), anyhow::Error> {
+        if self.state != VirtualMachineState::Running {
+            return Ok(());
+        }
+
+        self.send_qmp_command(&qapi_qmp::stop {})?;
+
+        Ok(())
+    }
+
+    fn send_qmp_command<C: QmpCommand>(&mut self, command: &C) -> Result<C::Ok, anyhow::Error> {
+        let res = if let Some(qmp) = self.control_socket.as_mut() {
+            qmp.qmp.execute(command)?
+        } else {
+            anyhow::bail!("No control socket available")
+        };
+
+        self.process_qmp_events()?;
+        Ok(res)
+    }
+
+    pub fn stop(&mut self) -> Result<(), anyhow::Error> {
+        if self.process.is_none()
+            || self.control_socket.is_none()
+            || self.standby
+            || self.state == VirtualMachineState::Stopped
+        {
+            return Ok(());
+        }
+
+        self.stop_requested = true;
+        self.run_hook_logged("pre-stop");
+        self.send_qmp_command(&qapi_qmp::system_powerdown {})?;
+        Ok(())
+    }
+
+    pub fn wait_till_stopped(&mut self) -> Result<(), anyhow::Error> {
+        self.wait(None, VirtualMachineState::Stopped)?;
+        Ok(())
+    }
+
+    pub fn quit(&mut self) -> Result<(), anyhow::Error> {
+        self.quit_with("stopped")
+    }
+
+    /// Quit QEMU, [reason] is recorded in the history
+    fn quit_with(&mut self, reason: &str) -> Result<(), anyhow::Error> {
+        if self.control_socket.is_none() {
+            return Ok(());
+        }
+
+        match self.send_qmp_command(&qapi_qmp::quit {}) {
+            Err(err)
+                if err.downcast_ref::<io::Error>().map_or(false, |x| {
+                    x.kind() == io::ErrorKind::UnexpectedEof
+                        || x.kind() == io::ErrorKind::ConnectionReset
+                }) => {}
+            err => {
+                err?;
+            }
+        }
+
+        if let Some(mut proc) = self.process.take() {
+            let _ = proc.wait();
+        }
+
+        self.release_process();
+        self.state = VirtualMachineState::Prepared;
+        if !mem::take(&mut self.standby) {
+            self.record_stop(reason);
+            self.run_hook_logged("post-stop");
+        }
+
+        Ok(())
+    }
+
+    /// Clean up everything that was set up for QEMU, now that it's gone
+    fn release_process(&mut self) {
+        self.control_socket = None;
+        self.guest_actions = None;
+        self.clipboard = None;
+        self.seat_forwards.clear();
+        self.stop_tpm();
+        self.qemu_ids = Default::default();
+        self.started_at = None;
+        self.clear_runtime_state();
+        self.remove_sriov_vfs();
+        self.remove_mdevs();
+        self.remove_cgroup();
+        self.restore_host_changes();
+        self.release_hotplugged_pci();
+        self.remove_shm();
+
+        if self.config.vfio.iter().any(|x| x.release) {
+            if let Err(err) = self.release_vfio(false) {
+                log::warn!(
+                    "Failed to give the VFIO devices of {} back to their host driver: {:?}",
+                    self.name(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Give the VFIO devices of this VM back to their host driver, only those with release set unless [all]
+    ///
+    /// Only devices vore bound to vfio-pci itself are released, returns which were
+    pub fn release_vfio(&mut self, all: bool) -> Result<Vec<PciAddress>, anyhow::Error> {
+        if self.process.is_some() {
+            anyhow::bail!(
+                "Can't release the VFIO devices of {} while QEMU runs, stop or kill it first",
+                self.name()
+            );
+        }
+
+        let mut released = vec![];
+        let mut results = vec![];
+        for vfio in self.config.vfio.iter().filter(|x| all || x.release) {
+            // SR-IOV virtual functions are removed instead
+            if vfio.physical_function.is_some() {
+                continue;
+            }
+
+            match release_vfio_device(&vfio.address) {
+                Ok(true) => {
+                    log::info!(
+                        "Gave {} of {} back to its host driver",
+                        vfio.address,
+                        self.name()
+                    );
+                    released.push(vfio.address);
+                }
+                Ok(false) => {}
+                Err(err) => results.push(Err(err).with_context(|| {
+                    format!("Failed to give {} back to its host driver", vfio.address)
+                })),
+            }
+        }
+
+        if !released.is_empty() {
+            self.vfio_released = true;
+        }
+
+        results.into_iter().bcollect::<()>()?;
+        Ok(released)
+    }
+
+    fn wait(
+        &mut self,
+        duration: Option<Duration>,
+        target_state: VirtualMachineState,
+    ) -> Result<bool, anyhow::Error> {
+        let start = Instant::now();
+        while duration.map_or(true, |dur| (Instant::now() - start) < dur) {
+            let has_socket = self
+                .control_socket
+                .as_mut()
+                .map(|x| x.qmp.nop())
+                .transpose()?
+                .is_some();
+
+            if !has_socket {
+                return Ok(self.state == target_state);
+            }
+
+            self.process_qmp_events()?;
+
+            if self.state == target_state {
+                return Ok(true);
+            }
+
+            if duration.is_some() {
+                std::thread::sleep(Duration::from_millis(500));
+            } else {
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+
+        Ok(self.state == target_state)
+    }
+
+    /// Launch QEMU paused ahead of time, so starting the VM later only has to resume it
+    pub fn prelaunch(&mut self) -> Result<(), anyhow::Error> {
+        if self.process.is_some() {
+            return Ok(());
+        }
+
+        if self.state == VirtualMachineState::Loaded || self.vfio_released {
+            self.prepare(true, false)?
+        }
+
+        // A standby QEMU always uses the configured boot order
+        self.boot_once = None;
+        self.launch(&mut |_| {})?;
+        self.standby = true;
+        self.write_runtime_state_logged();
+
+        log::info!("Launched standby QEMU for {}", self.name());
+        Ok(())
+    }
+
+    /// If a standby QEMU should be launched for this VM
+    pub fn wants_standby(&self) -> bool {
+        self.config.standby
+            && self.process.is_none()
+            && matches!(
+                self.state,
+                VirtualMachineState::Loaded
+                    | VirtualMachineState::Prepared
+                    | VirtualMachineState::Stopped
+            )
+    }
+
+    /// If QEMU was launched ahead of time and is waiting to be started
+    pub fn is_standby(&self) -> bool {
+        self.standby
+    }
+
+    /// If QEMU of this VM is there, running, paused or on standby
+    pub fn has_process(&self) -> bool {
+        self.process.is_some()
+    }
+
+    /// Why this VM can't run at the same time as [other], see [InstanceConfig::conflict_with]
+    pub fn conflict_with(&self, other: &VirtualMachine) -> Option<String> {
+        self.config.conflict_with(&other.config)
+    }
+
+    pub fn start(&mut self) -> Result<(), anyhow::Error> {
+        self.start_with_progress(&mut |_| {})
+    }
+
+    /// Start the VM, calling [progress] at the start of every step
+    pub fn start_with_progress(
+        &mut self,
+        progress: &mut dyn FnMut(StartProgress),
+    ) -> Result<(), anyhow::Error> {
+        if let Some(proc) = &mut self.process {
+            if !self.standby && proc.try_wait()?.is_none() {
+                return Ok(());
+            }
+        }
+
+        if self.state == VirtualMachineState::Loaded || self.vfio_released {
+            progress(StartProgress {
+                step: StartStep::Prepare,
+                message: match self.config.vfio.len() {
+                    0 => format!("Preparing {}", self.name()),
+                    n => format!(
+                        "Preparing {}, binding {} VFIO device(s) to vfio-pci",
+                        self.name(),
+                        n
+                    ),
+                },
+            });
+            self.prepare(true, false)?
+        }
+
+        if let Some(low) = self.low_disk_space().into_iter().next() {
+            anyhow::bail!(
+                "Not starting {}, {} (disk-space.min-free)",
+                self.name(),
+                low
+            );
+        }
+
+        self.run_hook("pre-start")?;
+        self.stop_requested = false;
+        self.last_exit = None;
+
+        if !mem::take(&mut self.standby) {
+            self.launch(progress)?;
+        } else {
+            progress(StartProgress {
+                step: StartStep::Launch,
+                message: "Taking over the standby QEMU".to_string(),
+            });
+        }
+
+        self.health = HealthProbe::default();
+        self.looking_glass = LookingGlassProbe::default();
+        self.disk_space = DiskSpaceProbe::default();
+        self.usage = UsageProbe::default();
+
+        progress(StartProgress {
+            step: StartStep::Resume,
+            message: "Starting the vCPU's".to_string(),
+        });
+
+        let mut res = || {
+            self.send_qmp_command(&qapi_qmp::cont {})
+                .context("Failed to send start command on qemu control socket")?;
+
+            self.started_at = Some(Instant::now());
+            self.record_boot();
+            self.timezone_push = if self.needs_timezone_push() {
+                Some(TimezonePush {
+                    since: Instant::now(),
+                    last_attempt: None,
+                })
+            } else {
+                None
+            };
+            self.write_runtime_state_logged();
+
+            self.process_qmp_events()?;
+
+            Ok(())
+        };
+
+        let result_ = res();
+        if result_.is_err() {
+            if let Some(mut qemu) = self.process.take() {
+                let _ = qemu.kill();
+                qemu.wait()?;
+            }
+
+            self.control_socket = None;
+            self.restore_host_changes();
+        } else {
+            self.run_hook_logged("post-start");
+        }
+
+        result_
+    }
+
+    /// Spawn QEMU, which waits paused (-S) until it's told to continue, and connect to it
+    fn launch(&mut self, progress: &mut dyn FnMut(StartProgress)) -> Result<(), anyhow::Error> {
+        progress(StartProgress {
+            step: StartStep::Launch,
+            message: "Launching QEMU".to_string(),
+        });
+
+        let (args, qemu_ids) = self
+            .get_cmd_line_with_ids()
+            .context("Failed to generate qemu command line")?;
+        let mut command = Command::new(self.qemu_binary());
+        command.args(args);
+        self.qemu_ids = qemu_ids;
+
+        let cpus = self
+            .pinned_cpus()
+            .map(|x| x.iter().map(|x| x.id).collect::<Vec<_>>());
+        self.cgroup = VmCgroup::create(self.name(), &self.config.limits, cpus.as_deref())
+            .with_context(|| format!("Failed to create cgroup for {}", self.name()))?;
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.apply(&mut command)?;
+        }
+
+        if self.config.seats.iter().any(|x| x.spice) {
+            // Every seat is a client of the same SPICE server, which only allows one by default
+            command.env("SPICE_DEBUG_ALLOW_MC", "1");
+        }
+
+        if self.config.jack.enabled {
+            // Where PipeWire's JACK finds the session of the user
+            command.env(
+                "XDG_RUNTIME_DIR",
+                format!("/run/user/{}", self.config.jack.user_uid),
+            );
+        }
+
+        // After the ones vore sets, so they can be overridden
+        command.envs(&self.config.env);
+
+        if self.config.sandbox.enabled {
+            self.sandbox()
+                .apply(&mut command)
+                .with_context(|| format!("Failed to set up the sandbox for {}", self.name()))?;
+        }
+
+        if self.config.stealth {
+            self.host_changes = apply_stealth(self.name()).with_context(|| {
+                format!("Failed to apply stealth host settings for {}", self.name())
+            })?;
+        }
+
+        if self.config.cpu.isolate {
+            let res = cpus
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "VM {} has more vCPU's than the host has CPU's, it's vCPU's can't be isolated",
+                        self.name()
+                    )
+                })
+                .and_then(|cpus| isolate_cpus(self.name(), &cpus).map(|_| cpus))
+                .with_context(|| format!("Failed to isolate the CPU's of {}", self.name()));
+            match res {
+                Ok(cpus) => self.isolated_cpus = cpus,
+                Err(err) => {
+                    self.restore_host_changes();
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Err(err) = self.start_tpm() {
+            self.restore_host_changes();
+            return Err(err);
+        }
+
+        match command.spawn() {
+            Ok(child) => self.process = Some(QemuProcess::Child(child)),
+            Err(err) => {
+                self.stop_tpm();
+                self.restore_host_changes();
+                return Err(err.into());
+            }
+        }
+
+        progress(StartProgress {
+            step: StartStep::Connect,
+            message: "Waiting for QEMU's control socket".to_string(),
+        });
+
+        let mut res = || {
+            let qemu_control_socket = self.qemu_control_socket();
+            let mut unix_stream = UnixStream::connect(&qemu_control_socket);
+            let mut time = QMP_SOCKET_TIMEOUT.as_secs() as i64;
+            while let Err(err) = unix_stream {
+                if time < 0 {
+                    return Err(RpcError::new(
+                        ErrorCode::Timeout,
+                        format!(
+                            "After {} seconds, QEMU Control socket ({}) didn't come up: {}",
+                            QMP_SOCKET_TIMEOUT.as_secs(),
+                            qemu_control_socket,
+                            err
+                        ),
+                    )
+                    .into());
+                }
+
+                std::thread::sleep(Duration::from_secs(1));
+                unix_stream = UnixStream::connect(&qemu_control_socket);
+
+                if let Some(proc) = self.process.as_mut() {
+                    if proc.try_wait()?.is_some() {
+                        anyhow::bail!("QEMU quit early")
+                    }
+                }
+
+                time -= 1;
+            }
+
+            let unix_stream = CloneableUnixStream::new(unix_stream.unwrap());
+            let mut qmp = Qmp::from_stream(unix_stream.clone());
+
+            let handshake = qmp.handshake()?;
+
+            let mut control_socket = ControlSocket {
+                unix_stream,
+                qmp,
+                _info: handshake,
+            };
+
+            self.pin_qemu_threads()?;
+            self.connect_guest_actions();
+            self.connect_clipboard();
+
+            if self.config.looking_glass.enabled {
+                self.global_config
+                    .vore
+                    .chown(&self.config.looking_glass.mem_path)?;
+            }
+
+            if self.config.spice.enabled && !self.config.spice.is_tcp() {
+                self.global_config
+                    .vore
+                    .chown(&self.config.spice.socket_path)?;
+            }
+
+            for seat in &self.config.seats {
+                if seat.looking_glass {
+                    apply_seat_owner(seat, &seat.looking_glass_mem_path)?;
+                }
+
+                if seat.spice {
+                    let forward = SocketForward::bind(
+                        &seat.spice_socket_path,
+                        &self.config.spice.socket_path,
+                    )
+                    .with_context(|| {
+                        format!("Failed to create the spice socket of seat {}", seat.name)
+                    })?;
+                    apply_seat_owner(seat, &seat.spice_socket_path)?;
+                    self.seat_forwards.push(forward);
+                }
+            }
+
+            control_socket.qmp.nop()?;
+            self.control_socket = Some(control_socket);
+            Ok(())
+        };
+
+        let result_ = res();
+        if result_.is_err() {
+            if let Some(mut qemu) = self.process.take() {
+                let _ = qemu.kill();
+                qemu.wait()?;
+            }
+
+            self.stop_tpm();
+            self.restore_host_changes();
+        }
+
+        result_
+    }
+
+    /// Start swtpm for tpm.enabled, and wait until its socket is there for QEMU to connect to
+    fn start_tpm(&mut self) -> Result<(), anyhow::Error> {
+        if !self.config.tpm.enabled {
+            return Ok(());
+        }
+
+        let tpm = &self.config.tpm;
+        let log = self.working_dir.join("swtpm.log");
+        let _ = std::fs::remove_file(&tpm.socket_path);
+        let mut child = Command::new("swtpm")
+            .arg("socket")
+            .arg("--tpm2")
+            .arg("--tpmstate")
+            .arg(format!("dir={}", tpm.state_path))
+            .arg("--ctrl")
+            .arg(format!("type=unixio,path={}", tpm.socket_path))
+            .arg("--log")
+            .arg(format!("file={},level=1", log.to_string_lossy()))
+            // Quit once QEMU disconnects, so it doesn't outlive QEMU if vored isn't around
+            .arg("--terminate")
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "Failed to start swtpm for {}, is it installed?",
+                    self.name()
+                )
+            })?;
+
+        for _ in 0..50 {
+            if Path::new(&tpm.socket_path).exists() {
+                self.tpm = Some(QemuProcess::Child(child));
+                return Ok(());
+            }
+
+            if let Some(status) = child.try_wait()? {
+                anyhow::bail!(
+                    "swtpm of {} quit ({}) before its socket came up, see {:?}",
+                    self.name(),
+                    status,
+                    log
+                );
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(RpcError::new(
+            ErrorCode::Timeout,
+            format!(
+                "After 5 seconds, the socket of swtpm ({}) didn't come up",
+                tpm.socket_path
+            ),
+        )
+        .into())
+    }
+
+    fn stop_tpm(&mut self) {
+        if let Some(mut tpm) = self.tpm.take() {
+            // Normally it already quit together with QEMU
+            if !matches!(tpm.try_wait(), Ok(Some(_))) {
+                let _ = tpm.kill();
+            }
+
+            if let Err(err) = tpm.wait() {
+                log::warn!("Failed to wait for swtpm of {}: {}", self.name(), err);
+            }
+        }
+
+        if self.config.tpm.enabled {
+            let _ = std::fs::remove_file(&self.config.tpm.socket_path);
+        }
+    }
+
+    /// Notice swtpm quitting while QEMU still runs, the guest has no working TPM until it's restarted
+    pub fn check_tpm(&mut self) {
+        if self.process.is_none() {
+            return;
+        }
+
+        let status = match self.tpm.as_mut().map(|x| x.try_wait()) {
+            Some(Ok(Some(status))) => status,
+            _ => return,
+        };
+
+        log::error!(
+            "swtpm of {} quit ({}) while QEMU runs, its TPM won't work until it's restarted",
+            self.name(),
+            status
+        );
+        self.tpm = None;
+    }
+
+    /// The sandbox QEMU is launched in, with every path the VM uses
+    fn sandbox(&self) -> Sandbox {
+        let mut sandbox = Sandbox::new(
+            self.working_dir.join("sandbox"),
+            self.config.sandbox.network,
+        );
+        sandbox.add(&self.working_dir, true);
+
+        for disk in &self.config.disks {
+            sandbox.add(&disk.path, !disk.read_only);
+        }
+
+        for cdrom in self
+            .cdroms
+            .iter()
+            .chain(&self.config.guest_agent.install_media_paths)
+        {
+            sandbox.add(cdrom, false);
+        }
+
+        for (path, _) in self.shm_files() {
+            sandbox.add(path, true);
+        }
+
+        // QEMU creates the sockets, so it needs the directories they're in
+        let sockets = vec![
+            (
+                self.config.spice.enabled && !self.config.spice.is_tcp(),
+                &self.config.spice.socket_path,
+            ),
+            (
+                self.config.guest_actions.enabled,
+                &self.config.guest_actions.socket_path,
+            ),
+            (
+                self.config.clipboard.enabled,
+                &self.config.clipboard.socket_path,
+            ),
+            (
+                self.config.guest_agent.enabled,
+                &self.config.guest_agent.socket_path,
+            ),
+        ];
+        for (_, socket) in sockets.into_iter().filter(|(enabled, _)| *enabled) {
+            if let Some(parent) = Path::new(socket).parent() {
+                sandbox.add(parent, true);
+            }
+        }
+
+        if self.config.jack.enabled {
+            sandbox.add(format!("/run/user/{}", self.config.jack.user_uid), true);
+            // jackd keeps its sockets and shared memory here
+            sandbox.add("/dev/shm", true);
+        }
+
+        if self.config.pulse.enabled {
+            if self.config.pulse.socket_path.is_empty() {
+                sandbox.add(
+                    format!("/run/user/{}/pulse", self.config.pulse.user_uid),
+                    true,
+                );
+            } else {
+                sandbox.add(&self.config.pulse.socket_path, true);
+            }
+        }
+
+        for uefi in self.global_config.uefi.values() {
+            sandbox.add(&uefi.template, false);
+            sandbox.add(&uefi.boot_code, false);
+        }
+
+        if self.config.uefi.enabled && !self.config.uefi.vars_path.is_empty() {
+            sandbox.add(self.uefi_vars_path(), true);
+        }
+
+        for path in self
+            .config
+            .net
+            .tftp
+            .iter()
+            .chain(&self.config.net.romfile)
+            .chain(self.config.vfio.iter().filter_map(|x| x.romfile.as_ref()))
+            .chain(&self.config.spice.x509_dir)
+            .chain(&self.config.spice.x509_cert_file)
+            .chain(&self.config.spice.x509_key_file)
+            .chain(&self.config.spice.x509_cacert_file)
+        {
+            sandbox.add(path, false);
+        }
+
+        for path in &self.config.sandbox.paths {
+            sandbox.add(path, true);
+        }
+
+        for path in &self.config.sandbox.read_only_paths {
+            sandbox.add(path, false);
+        }
+
+        sandbox
+    }
+
+    fn history_path(&self) -> PathBuf {
+        self.working_dir.join("history.json")
+    }
+
+    fn load_history(&self) -> Result<History, anyhow::Error> {
+        match std::fs::read_to_string(self.history_path()) {
+            Ok(history) => serde_json::from_str(&history)
+                .with_context(|| format!("History of {} is corrupt", self.name())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(History::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn update_history<F: FnOnce(&mut History)>(&self, update: F) {
+        let res = self.load_history().and_then(|mut history| {
+            update(&mut history);
+            if history.boots.len() > MAX_HISTORY {
+                let remove = history.boots.len() - MAX_HISTORY;
+                history.boots.drain(..remove);
+            }
+
+            std::fs::create_dir_all(&self.working_dir)?;
+            std::fs::write(self.history_path(), serde_json::to_string(&history)?)?;
+            Ok(())
+        });
+
+        if let Err(err) = res {
+            log::warn!("Failed to update history of {}: {:?}", self.name(), err);
+        }
+    }
+
+    fn record_boot(&self) {
+        self.update_history(|history| {
+            history.boots.push(BootRecord {
+                started: unix_now(),
+                stopped: None,
+                reason: None,
+            })
+        });
+    }
+
+    fn record_stop(&self, reason: &str) {
+        self.update_history(|history| {
+            if let Some(boot) = history.boots.last_mut().filter(|x| x.stopped.is_none()) {
+                let now = unix_now();
+                boot.stopped = Some(now);
+                boot.reason = Some(reason.to_string());
+                history.total_uptime += now.saturating_sub(boot.started);
+            }
+        });
+    }
+
+    /// Returns the total uptime in seconds, and the most recent boots
+    pub fn history(&self) -> Result<(u64, Vec<BootRecord>), anyhow::Error> {
+        let history = self.load_history()?;
+        let running = history
+            .boots
+            .last()
+            .filter(|x| x.stopped.is_none())
+            .map_or(0, |x| unix_now().saturating_sub(x.started));
+
+        Ok((history.total_uptime + running, history.boots))
+    }
+
+    fn runtime_state_path(&self) -> PathBuf {
+        self.working_dir.join("runtime.json")
+    }
+
+    fn write_runtime_state(&self) -> Result<(), anyhow::Error> {
+        let pid = if let Some(process) = &self.process {
+            process.id()
         } else {
-            anyhow::bail!("No control socket available")
+            return Ok(());
         };
 
-        self.process_qmp_events()?;
-        Ok(res)
+        let started_at =
+            unix_now().saturating_sub(self.started_at.map_or(0, |x| x.elapsed().as_secs()));
+
+        let state = RuntimeState {
+            host: Some(hostname()),
+            pid,
+            control_socket: self.qemu_control_socket(),
+            started_at,
+            sriov_created: self.sriov_created.clone(),
+            mdevs_created: self.mdevs_created.clone(),
+            host_changes: self.host_changes.clone(),
+            isolated_cpus: self.isolated_cpus.clone(),
+            standby: self.standby,
+            cdroms: self.cdroms.clone(),
+            pci_hotplugged: self.pci_hotplugged.clone(),
+            qemu_ids: self.qemu_ids.clone(),
+            tpm_pid: self.tpm.as_ref().map(|x| x.id()),
+        };
+
+        std::fs::write(self.runtime_state_path(), serde_json::to_string(&state)?)?;
+        Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<(), anyhow::Error> {
-        if self.process.is_none()
-            || self.control_socket.is_none()
-            || self.state == VirtualMachineState::Stopped
-        {
-            return Ok(());
+    fn write_runtime_state_logged(&self) {
+        if let Err(err) = self.write_runtime_state() {
+            log::warn!(
+                "Failed to store runtime state of {}, it won't survive a vored restart: {:?}",
+                self.name(),
+                err
+            );
         }
+    }
 
-        self.send_qmp_command(&qapi_qmp::system_powerdown {})?;
-        Ok(())
+    fn clear_runtime_state(&self) {
+        if let Err(err) = std::fs::remove_file(self.runtime_state_path()) {
+            if err.kind() != ErrorKind::NotFound {
+                log::warn!(
+                    "Failed to remove runtime state of {}: {:?}",
+                    self.name(),
+                    err
+                );
+            }
+        }
     }
 
-    pub fn wait_till_stopped(&mut self) -> Result<(), anyhow::Error> {
-        self.wait(None, VirtualMachineState::Stopped)?;
-        Ok(())
+    fn qemu_control_socket(&self) -> String {
+        format!("{}/qemu.sock", self.working_dir.to_str().unwrap())
     }
 
-    pub fn quit(&mut self) -> Result<(), anyhow::Error> {
-        if self.control_socket.is_none() {
-            return Ok(());
+    /// Take over management of a QEMU that was started by a previous vored
+    ///
+    /// The QEMU may also have been started on a peer sharing the working dir with this host,
+    /// that can't be adopted, but the state it left behind is cleared so it can be started here
+    pub fn reattach(&mut self) -> Result<Reattach, anyhow::Error> {
+        let state = match std::fs::read_to_string(self.runtime_state_path()) {
+            Ok(state) => serde_json::from_str::<RuntimeState>(&state)
+                .with_context(|| format!("Runtime state of {} is corrupt", self.name()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Reattach::Nothing),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(host) = state.host.clone().filter(|x| *x != hostname()) {
+            log::info!(
+                "QEMU of {} (pid {}) was running on {}, which can't be adopted from here",
+                self.name(),
+                state.pid,
+                host
+            );
+            self.clear_runtime_state();
+            return Ok(Reattach::OtherHost(host));
         }
 
-        match self.send_qmp_command(&qapi_qmp::quit {}) {
-            Err(err)
-                if err.downcast_ref::<io::Error>().map_or(false, |x| {
-                    x.kind() == io::ErrorKind::UnexpectedEof
-                        || x.kind() == io::ErrorKind::ConnectionReset
-                }) => {}
-            err => {
-                err?;
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", state.pid)).unwrap_or_default();
+        if !String::from_utf8_lossy(&cmdline).contains("qemu") {
+            log::info!(
+                "QEMU of {} (pid {}) isn't running anymore",
+                self.name(),
+                state.pid
+            );
+            self.clear_runtime_state();
+            return Ok(Reattach::Nothing);
+        }
+
+        let unix_stream = UnixStream::connect(&state.control_socket).with_context(|| {
+            format!(
+                "Failed to connect to control socket of running QEMU ({})",
+                state.control_socket
+            )
+        })?;
+        let unix_stream = CloneableUnixStream::new(unix_stream);
+        let mut qmp = Qmp::from_stream(unix_stream.clone());
+        let handshake = qmp.handshake()?;
+        let status = qmp.execute(&qapi_qmp::query_status {})?;
+
+        self.state = match status.status {
+            _ if state.standby => VirtualMachineState::Prepared,
+            RunState::running => VirtualMachineState::Running,
+            RunState::shutdown => VirtualMachineState::Stopped,
+            _ => VirtualMachineState::Paused,
+        };
+        self.standby = state.standby;
+        self.cdroms = state.cdroms;
+        self.pci_hotplugged = state.pci_hotplugged;
+        self.qemu_ids = state.qemu_ids;
+        self.tpm = state
+            .tpm_pid
+            .filter(|pid| Path::new(&format!("/proc/{}", pid)).exists())
+            .map(QemuProcess::Adopted);
+
+        for vfio in &mut self.config.vfio {
+            if let Some(pf) = vfio.physical_function {
+                if let Ok(Some(address)) = sriov_vf_address(&pf, vfio.sriov_vf) {
+                    vfio.address = address;
+                }
             }
         }
 
-        if let Some(mut proc) = self.process.take() {
-            let _ = proc.wait();
+        self.sriov_created = state.sriov_created;
+        self.mdevs_created = state.mdevs_created;
+        adopt_stealth(self.name(), &state.host_changes);
+        self.host_changes = state.host_changes;
+        if !state.isolated_cpus.is_empty() {
+            adopt_isolated_cpus(self.name(), &state.isolated_cpus);
         }
+        self.isolated_cpus = state.isolated_cpus;
+        self.cgroup = VmCgroup::open(self.name());
+        self.process = Some(QemuProcess::Adopted(state.pid));
+        self.connect_guest_actions();
+        self.connect_clipboard();
+        self.control_socket = Some(ControlSocket {
+            unix_stream,
+            qmp,
+            _info: handshake,
+        });
 
-        self.control_socket = None;
-        self.state = VirtualMachineState::Prepared;
+        let running_for = unix_now().saturating_sub(state.started_at);
+        self.started_at = Instant::now().checked_sub(Duration::from_secs(running_for));
 
-        Ok(())
+        log::info!(
+            "Reattached to running QEMU of {} (pid {}, {})",
+            self.name(),
+            state.pid,
+            self.state
+        );
+
+        Ok(Reattach::Reattached)
     }
 
-    fn wait(
-        &mut self,
-        duration: Option<Duration>,
-        target_state: VirtualMachineState,
-    ) -> Result<bool, anyhow::Error> {
-        let start = Instant::now();
-        while duration.map_or(true, |dur| (Instant::now() - start) < dur) {
-            let has_socket = self
-                .control_socket
-                .as_mut()
-                .map(|x| x.qmp.nop())
-                .transpose()?
-                .is_some();
+    /// Run the configured script for the given hook, if any, and wait for it to finish or time out
+    fn run_hook(&self, hook: &str) -> Result<(), anyhow::Error> {
+        let script = if let Some(script) = self.config.hooks.get(hook) {
+            script
+        } else {
+            return Ok(());
+        };
 
-            if !has_socket {
-                return Ok(self.state == target_state);
-            }
+        log::info!("Running {} hook for {}: {}", hook, self.name(), script);
+        let state = self.state.to_string();
+        run_hook_script(
+            hook,
+            script,
+            &[
+                ("VORE_VM_NAME", OsStr::new(self.name())),
+                ("VORE_VM_STATE", OsStr::new(&state)),
+                ("VORE_HOOK", OsStr::new(hook)),
+                ("VORE_WORKING_DIR", self.working_dir.as_os_str()),
+            ],
+            Duration::from_secs(self.config.hooks.timeout),
+        )
+    }
 
-            self.process_qmp_events()?;
+    /// Run a hook that can't influence the outcome, so only log failures
+    fn run_hook_logged(&self, hook: &str) {
+        if let Err(err) = self.run_hook(hook) {
+            log::warn!("{:?}", err);
+        }
+    }
 
-            if self.state == target_state {
-                return Ok(true);
+    fn connect_guest_actions(&mut self) {
+        if !self.config.guest_actions.enabled {
+            return;
+        }
+
+        match GuestActionChannel::connect(&self.config.guest_actions.socket_path) {
+            Ok(channel) => self.guest_actions = Some(channel),
+            Err(err) => log::warn!(
+                "Failed to connect to guest action channel of {} ({}): {:?}",
+                self.name(),
+                self.config.guest_actions.socket_path,
+                err
+            ),
+        }
+    }
+
+    pub fn guest_actions_stream(&self) -> Option<&UnixStream> {
+        self.guest_actions.as_ref().map(|x| x.stream())
+    }
+
+    /// Actions the guest requested, with the configured action if it's allowed
+    pub fn read_guest_actions(&mut self) -> Vec<(String, Option<GuestAction>)> {
+        let channel = if let Some(channel) = self.guest_actions.as_mut() {
+            channel
+        } else {
+            return vec![];
+        };
+
+        let (still_open, requests) = match channel.read_requests() {
+            Ok(res) => res,
+            Err(err) => {
+                log::warn!(
+                    "Failed reading guest action channel of {}: {:?}",
+                    self.config.name,
+                    err
+                );
+                (false, vec![])
             }
+        };
 
-            if duration.is_some() {
-                std::thread::sleep(Duration::from_millis(500));
-            } else {
-                std::thread::sleep(Duration::from_secs(5));
+        if !still_open {
+            self.guest_actions = None;
+        }
+
+        requests
+            .into_iter()
+            .map(|request| {
+                let action = self.config.guest_actions.actions.get(&request).cloned();
+                (request, action)
+            })
+            .collect()
+    }
+
+    pub fn reply_guest_action(&mut self, result: &Result<(), anyhow::Error>) {
+        if let Some(channel) = self.guest_actions.as_mut() {
+            if let Err(err) = channel.reply(result) {
+                log::warn!(
+                    "Failed answering guest action of {}: {:?}",
+                    self.config.name,
+                    err
+                );
             }
         }
+    }
 
-        Ok(self.state == target_state)
+    fn connect_clipboard(&mut self) {
+        if !self.config.clipboard.enabled {
+            return;
+        }
+
+        match ClipboardChannel::connect(&self.config.clipboard.socket_path) {
+            Ok(channel) => self.clipboard = Some(channel),
+            Err(err) => log::warn!(
+                "Failed to connect to clipboard channel of {} ({}): {:?}",
+                self.name(),
+                self.config.clipboard.socket_path,
+                err
+            ),
+        }
     }
 
-    pub fn start(&mut self) -> Result<(), anyhow::Error> {
-        if let Some(proc) = &mut self.process {
-            if proc.try_wait()?.is_none() {
-                return Ok(());
+    pub fn clipboard_stream(&self) -> Option<&UnixStream> {
+        self.clipboard.as_ref().map(|x| x.stream())
+    }
+
+    /// Handle what the guest agent sent on the clipboard channel
+    pub fn process_clipboard(&mut self) {
+        let res = if let Some(channel) = self.clipboard.as_mut() {
+            channel.process()
+        } else {
+            return;
+        };
+
+        match res {
+            Ok(true) => {}
+            Ok(false) => self.clipboard = None,
+            Err(err) => {
+                log::warn!(
+                    "Failed handling clipboard channel of {}: {:?}",
+                    self.name(),
+                    err
+                );
+                self.clipboard = None;
             }
         }
+    }
 
-        if self.state == VirtualMachineState::Loaded {
-            self.prepare(true, false)?
+    fn clipboard_channel(&mut self) -> Result<&mut ClipboardChannel, anyhow::Error> {
+        if !self.config.clipboard.enabled {
+            anyhow::bail!("VM {} doesn't have clipboard enabled", self.config.name);
         }
 
-        let mut command = Command::new("qemu-system-x86_64");
-        command.args(
-            self.get_cmd_line()
-                .context("Failed to generate qemu command line")?,
-        );
-        self.process = Some(command.spawn()?);
+        let name = self.config.name.clone();
+        self.clipboard
+            .as_mut()
+            .ok_or_else(|| RpcError::not_running(&name).into())
+    }
 
-        let mut res = || {
-            let qemu_control_socket = format!("{}/qemu.sock", self.working_dir.to_str().unwrap());
-            let mut unix_stream = UnixStream::connect(&qemu_control_socket);
-            let mut time = 30;
-            while let Err(err) = unix_stream {
-                if time < 0 {
-                    Err(err).context(format!(
-                        "After 30 seconds, QEMU Control socket ({}) didn't come up",
-                        qemu_control_socket
-                    ))?;
-                }
+    /// Last text copied in the guest
+    pub fn guest_clipboard(&mut self) -> Result<Option<String>, anyhow::Error> {
+        Ok(self.clipboard_channel()?.guest_text().map(str::to_string))
+    }
 
-                std::thread::sleep(Duration::from_secs(1));
-                unix_stream = UnixStream::connect(&qemu_control_socket);
+    /// Make [text] the clipboard of the guest
+    pub fn set_guest_clipboard(&mut self, text: String) -> Result<(), anyhow::Error> {
+        self.clipboard_channel()?
+            .set_host_text(text)
+            .context("Failed to send clipboard to the guest agent")
+    }
 
-                if let Some(proc) = self.process.as_mut() {
-                    if proc.try_wait()?.is_some() {
-                        anyhow::bail!("QEMU quit early")
-                    }
-                }
+    pub fn control_stream(&self) -> Option<&CloneableUnixStream> {
+        self.control_socket.as_ref().map(|x| &x.unix_stream)
+    }
+}
 
-                time -= 1;
-            }
+/// Read the user + system time from a /proc/.../stat file in milliseconds
+/// Bytes available to unprivileged users on the filesystem of [path]
+/// Give [path], one of the endpoints of [seat], the owner and permissions of the seat
+fn apply_seat_owner(seat: &SeatConfig, path: &str) -> Result<(), anyhow::Error> {
+    if seat.user_uid.is_some() || seat.group_gid.is_some() {
+        let path_c = std::ffi::CString::new(path)?;
+        // -1 keeps the current owner or group
+        let res = unsafe {
+            libc::chown(
+                path_c.as_ptr(),
+                seat.user_uid.unwrap_or(u32::MAX),
+                seat.group_gid.unwrap_or(u32::MAX),
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to change the owner of {}", path));
+        }
+    }
 
-            let unix_stream = CloneableUnixStream::new(unix_stream.unwrap());
-            let mut qmp = Qmp::from_stream(unix_stream.clone());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(seat.mode))
+        .with_context(|| format!("Failed to change the permissions of {}", path))
+}
 
-            let handshake = qmp.handshake()?;
+fn available_space(path: &Path) -> Result<u64, anyhow::Error> {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
 
-            let mut control_socket = ControlSocket {
-                unix_stream,
-                qmp,
-                _info: handshake,
-            };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
 
-            self.pin_qemu_threads()?;
+/// Sizes of [disks] from qemu-img
+fn read_image_info(disks: &[DiskConfig]) -> Vec<DiskInfo> {
+    disks
+        .iter()
+        .filter_map(|disk| {
+            // -U, since a QEMU that vored lost track of may still have the image locked
+            let output = Command::new("qemu-img")
+                .args(&["info", "--output=json", "-U", &disk.path])
+                .output()
+                .map_err(|err| {
+                    log::debug!("Failed to run qemu-img info on {}: {:?}", disk.path, err)
+                })
+                .ok()
+                .filter(|x| x.status.success())?;
+            let info = serde_json::from_slice::<serde_json::Value>(&output.stdout).ok()?;
 
-            if self.config.looking_glass.enabled {
-                self.global_config
-                    .vore
-                    .chown(&self.config.looking_glass.mem_path)?;
-            }
+            Some(DiskInfo {
+                path: disk.path.clone(),
+                format: info
+                    .get("format")
+                    .and_then(|x| x.as_str())
+                    .unwrap_or(&disk.disk_type)
+                    .to_string(),
+                virtual_size: info
+                    .get("virtual-size")
+                    .and_then(|x| x.as_u64())
+                    .unwrap_or(0),
+                allocated: info.get("actual-size").and_then(|x| x.as_u64()),
+                backing_file: info
+                    .get("backing-filename")
+                    .and_then(|x| x.as_str())
+                    .map(|x| x.to_string()),
+                io_status: None,
+            })
+        })
+        .collect()
+}
 
-            if self.config.spice.enabled {
-                self.global_config
-                    .vore
-                    .chown(&self.config.spice.socket_path)?;
-            }
+fn read_cpu_time(path: &str) -> Result<u64, anyhow::Error> {
+    let stat = std::fs::read_to_string(path)?;
+    // The process name can contain spaces, so only look at everything after it
+    let fields = stat
+        .rsplit(')')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let field = |idx: usize| fields.get(idx).and_then(|x| u64::from_str(x).ok());
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
 
-            control_socket
-                .qmp
-                .execute(&qapi_qmp::cont {})
-                .context("Failed to send start command on qemu control socket")?;
+    Ok((field(11).unwrap_or(0) + field(12).unwrap_or(0)) * 1000 / ticks)
+}
 
-            control_socket.qmp.nop()?;
-            self.control_socket = Some(control_socket);
+/// Resident memory of a process in bytes
+fn read_rss(pid: u32) -> Result<u64, anyhow::Error> {
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid))?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    Ok(statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| u64::from_str(x).ok())
+        .unwrap_or(0)
+        * page_size)
+}
 
-            self.process_qmp_events()?;
+/// Bytes a process read from and wrote to storage, as (read, written)
+fn read_io_bytes(pid: u32) -> Result<(u64, u64), anyhow::Error> {
+    let io = std::fs::read_to_string(format!("/proc/{}/io", pid))?;
+    let field = |name: &str| {
+        io.lines()
+            .filter_map(|x| x.strip_prefix(name))
+            .find_map(|x| u64::from_str(x.trim()).ok())
+            .unwrap_or(0)
+    };
 
-            Ok(())
-        };
+    Ok((field("read_bytes:"), field("write_bytes:")))
+}
 
-        let result_ = res();
-        if result_.is_err() {
-            if let Some(mut qemu) = self.process.take() {
-                let _ = qemu.kill();
-                qemu.wait()?;
+/// Find the tap interfaces a process has open, the tun driver lists those as iff in fdinfo
+fn tap_interfaces(pid: u32) -> Vec<String> {
+    let mut interfaces = vec![];
+    let entries = match read_dir(format!("/proc/{}/fdinfo", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return interfaces,
+    };
+
+    for entry in entries.flatten() {
+        if let Ok(info) = std::fs::read_to_string(entry.path()) {
+            for line in info.lines() {
+                if let Some(interface) = line.strip_prefix("iff:") {
+                    interfaces.push(interface.trim().to_string());
+                }
             }
         }
+    }
 
-        result_
+    interfaces.sort();
+    interfaces.dedup();
+    interfaces
+}
+
+/// QEMU process of a VM, either started by us, or found running after vored restarted
+#[derive(Debug)]
+enum QemuProcess {
+    Child(Child),
+    Adopted(u32),
+}
+
+impl QemuProcess {
+    fn id(&self) -> u32 {
+        match self {
+            QemuProcess::Child(child) => child.id(),
+            QemuProcess::Adopted(pid) => *pid,
+        }
     }
 
-    pub fn control_stream(&self) -> Option<&CloneableUnixStream> {
-        self.control_socket.as_ref().map(|x| &x.unix_stream)
+    /// The exit status of adopted processes can't be known, since we're not their parent
+    fn try_wait(&mut self) -> io::Result<Option<QemuExitStatus>> {
+        match self {
+            QemuProcess::Child(child) => Ok(child.try_wait()?.map(|x| QemuExitStatus(Some(x)))),
+            QemuProcess::Adopted(pid) => {
+                if Path::new(&format!("/proc/{}", pid)).exists() {
+                    Ok(None)
+                } else {
+                    Ok(Some(QemuExitStatus(None)))
+                }
+            }
+        }
+    }
+
+    fn wait(&mut self) -> io::Result<QemuExitStatus> {
+        match self {
+            QemuProcess::Child(child) => Ok(QemuExitStatus(Some(child.wait()?))),
+            QemuProcess::Adopted(_) => loop {
+                if let Some(status) = self.try_wait()? {
+                    return Ok(status);
+                }
+
+                std::thread::sleep(Duration::from_millis(100));
+            },
+        }
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        match self {
+            QemuProcess::Child(child) => child.kill(),
+            QemuProcess::Adopted(pid) => {
+                if unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct QemuExitStatus(Option<ExitStatus>);
+
+impl fmt::Display for QemuExitStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(status) => fmt::Display::fmt(status, f),
+            None => f.write_str("unknown exit status"),
+        }
     }
 }
 
+/// Boots of a VM, stored in the working dir
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    /// Seconds of uptime of boots that finished, including those no longer in [boots]
+    total_uptime: u64,
+    boots: Vec<BootRecord>,
+}
+
+/// Amount of boots kept in the history
+const MAX_HISTORY: usize = 100;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// What's needed to find a running QEMU back after vored restarted, stored in the working dir
+#[derive(Debug, Serialize, Deserialize)]
+struct RuntimeState {
+    /// Host the QEMU runs on, None for state written before this was stored
+    #[serde(default)]
+    host: Option<String>,
+    pid: u32,
+    control_socket: String,
+    /// Unix timestamp
+    started_at: u64,
+    #[serde(default)]
+    sriov_created: Vec<PciAddress>,
+    #[serde(default)]
+    mdevs_created: Vec<String>,
+    #[serde(default)]
+    host_changes: Vec<HostChange>,
+    #[serde(default)]
+    isolated_cpus: Vec<usize>,
+    #[serde(default)]
+    standby: bool,
+    /// CD-ROM's the running QEMU was launched with
+    #[serde(default)]
+    cdroms: Vec<String>,
+    #[serde(default)]
+    pci_hotplugged: Vec<HotpluggedPci>,
+    #[serde(default)]
+    qemu_ids: QemuIdMap,
+    #[serde(default)]
+    tpm_pid: Option<u32>,
+}
+
 #[derive(Clone, Debug)]
 pub struct CloneableUnixStream(Arc<Mutex<UnixStream>>);
 
@@ -648,3 +4769,146 @@ impl Write for CloneableUnixStream {
         self.lock()?.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{can_launch_vms, MockQemu, MockRunState};
+    use crate::utils::get_username_by_uid;
+    use crate::{
+        GlobalConfig, InstanceConfig, VirtualMachine, VirtualMachineExit, VirtualMachineState,
+    };
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    /// A VM running on a mock QEMU, its working dir goes away with the mock
+    fn mock_vm(name: &str) -> (VirtualMachine, MockQemu) {
+        let dir = std::env::temp_dir().join(format!("vore-vm-{}-{}", std::process::id(), name));
+        let mock = MockQemu::new(&dir).unwrap();
+        // No vCPU threads to pin
+        mock.answer("query-cpus-fast", json!([]));
+
+        let mut global_config =
+            GlobalConfig::load(include_str!("../../config/vored.toml")).unwrap();
+        global_config.qemu.script =
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../config/qemu.lua").to_string();
+        global_config.qemu.binaries.insert(
+            "x86_64".to_string(),
+            mock.binary().to_str().unwrap().to_string(),
+        );
+
+        // Runs as whoever runs the tests, so preparing doesn't need root or KVM
+        let user = get_username_by_uid(unsafe { libc::getuid() })
+            .unwrap()
+            .unwrap();
+        let config = InstanceConfig::from_toml(&format!(
+            "[machine]\nname = \"{}\"\nallow-tcg = true\n[qemu]\nrun-as = \"{}\"\ngroups = []",
+            name, user
+        ))
+        .unwrap();
+
+        (VirtualMachine::new(config, &global_config, &dir), mock)
+    }
+
+    /// Handle what QEMU sends until [vm] is in [state]
+    fn wait_for(vm: &mut VirtualMachine, state: VirtualMachineState) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while vm.state() != state {
+            assert!(
+                Instant::now() < deadline,
+                "{} is {} instead of {}",
+                vm.name(),
+                vm.state(),
+                state
+            );
+            vm.boop().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Wait until the mock QEMU of [vm] is gone, and [vm] saw it go
+    fn wait_for_exit(vm: &mut VirtualMachine) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while vm.check_exited().unwrap().is_none() {
+            assert!(
+                Instant::now() < deadline,
+                "QEMU of {} didn't exit",
+                vm.name()
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        if !can_launch_vms() {
+            eprintln!("Skipping, VM's can't be launched by this user");
+            return;
+        }
+
+        let (mut vm, mock) = mock_vm("stop");
+        vm.start().unwrap();
+        // Running once QEMU said it resumed
+        wait_for(&mut vm, VirtualMachineState::Running);
+        assert_eq!(mock.run_state(), MockRunState::Running);
+        assert!(mock.args().unwrap().contains(&"-S".to_string()));
+        assert_eq!(vm.info().runtime.unwrap().pid as i32, mock.pid().unwrap());
+
+        vm.stop().unwrap();
+        // QEMU is told to quit after the guest shut down, and the VM is left prepared
+        wait_for(&mut vm, VirtualMachineState::Prepared);
+        let commands = mock.commands();
+        let powerdown = commands.iter().position(|x| x == "system_powerdown");
+        assert!(powerdown.is_some(), "{:?}", commands);
+        assert_eq!(commands.last().map(|x| x.as_str()), Some("quit"));
+        // Asked for, so it's not reported as an exit
+        assert_eq!(vm.take_exit(), None);
+        assert!(vm.info().runtime.is_none());
+    }
+
+    #[test]
+    fn test_guest_events() {
+        if !can_launch_vms() {
+            eprintln!("Skipping, VM's can't be launched by this user");
+            return;
+        }
+
+        let (mut vm, mock) = mock_vm("events");
+        vm.start().unwrap();
+        wait_for(&mut vm, VirtualMachineState::Running);
+
+        vm.pause().unwrap();
+        wait_for(&mut vm, VirtualMachineState::Paused);
+        mock.emit("RESUME", json!({})).unwrap();
+        wait_for(&mut vm, VirtualMachineState::Running);
+
+        // The guest powers off by itself
+        mock.emit(
+            "SHUTDOWN",
+            json!({ "guest": true, "reason": "guest-shutdown" }),
+        )
+        .unwrap();
+        wait_for(&mut vm, VirtualMachineState::Prepared);
+        assert_eq!(mock.commands().last().map(|x| x.as_str()), Some("quit"));
+        assert_eq!(vm.take_exit(), Some(VirtualMachineExit::Shutdown));
+    }
+
+    #[test]
+    fn test_crash() {
+        if !can_launch_vms() {
+            eprintln!("Skipping, VM's can't be launched by this user");
+            return;
+        }
+
+        let (mut vm, mock) = mock_vm("crash");
+        vm.start().unwrap();
+        wait_for(&mut vm, VirtualMachineState::Running);
+        mock.crash(1);
+        wait_for_exit(&mut vm);
+        assert_eq!(vm.state(), VirtualMachineState::Stopped);
+        assert_eq!(vm.take_exit(), Some(VirtualMachineExit::Crashed));
+
+        // And it can be started again
+        vm.start().unwrap();
+        wait_for(&mut vm, VirtualMachineState::Running);
+    }
+}
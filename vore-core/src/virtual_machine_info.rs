@@ -1,5 +1,6 @@
 use std::fmt::{Display, Formatter};
 use std::fmt;
+use std::str::FromStr;
 use crate::InstanceConfig;
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
@@ -26,6 +27,21 @@ impl Display for VirtualMachineState {
     }
 }
 
+impl FromStr for VirtualMachineState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "loaded" => VirtualMachineState::Loaded,
+            "prepared" => VirtualMachineState::Prepared,
+            "stopped" => VirtualMachineState::Stopped,
+            "paused" => VirtualMachineState::Paused,
+            "running" => VirtualMachineState::Running,
+            _ => anyhow::bail!("'{}' is not a valid VM state", s)
+        })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct VirtualMachineInfo {
     pub name: String,
@@ -33,4 +49,122 @@ pub struct VirtualMachineInfo {
     pub config: InstanceConfig,
     pub state: VirtualMachineState,
     pub quit_after_shutdown: bool,
-}
\ No newline at end of file
+    /// ISO's attached as CD-ROM drives on top of the configured disks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cdroms: Vec<String>,
+    /// Only set while QEMU is running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeInfo>,
+}
+
+/// Information about the running QEMU of a VM
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RuntimeInfo {
+    pub pid: u32,
+    /// Seconds since the VM was started
+    pub uptime: u64,
+    /// Path to the SPICE socket, or its spice:// address when it listens on TCP, if spice is enabled
+    pub spice_socket: Option<String>,
+    /// QEMU was launched ahead of time (machine.standby), and waits for the VM to be started
+    #[serde(default)]
+    pub standby: bool,
+    /// The health probe found the guest stuck, see health.stall-after
+    #[serde(default)]
+    pub stalled: bool,
+    /// Only set if looking-glass is enabled, and the VM ran long enough to be checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub looking_glass: Option<LookingGlassInfo>,
+    /// Less than disk-space.min-free is available on one of the filesystems of the VM
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub low_disk_space: bool,
+    /// Host settings changed for this VM (by machine.stealth), as "path: original -> value"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_changes: Vec<String>,
+    /// CPU's all other tasks of the host are kept off, see cpu.isolate
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub isolated_cpus: Vec<usize>,
+    /// Only set once vored sampled the QEMU process twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Resource usage of the QEMU process of a VM, sampled by vored every few seconds
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    /// Resident memory in bytes
+    pub rss: u64,
+    /// Host CPU used since the previous sample, 100 per fully used host CPU
+    pub cpu_percent: f64,
+    /// Bytes per second read from and written to storage
+    pub read_rate: u64,
+    pub write_rate: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VirtualMachineStats {
+    /// Seconds since the VM was started
+    pub uptime: Option<u64>,
+    /// CPU time of the whole QEMU process in milliseconds
+    pub cpu_time: u64,
+    /// CPU time per vCPU in milliseconds, indexed by vCPU
+    pub vcpu_time: Vec<u64>,
+    /// Resident memory of the QEMU process in bytes
+    pub rss: u64,
+    /// Current size of the balloon in bytes, if the VM has a balloon device
+    pub balloon: Option<u64>,
+    pub block: Vec<BlockStats>,
+    pub network: Vec<NetworkStats>,
+    #[serde(default)]
+    pub looking_glass: Option<LookingGlassInfo>,
+    #[serde(default)]
+    pub disks: Vec<DiskInfo>,
+}
+
+/// Image of a disk, how big it is for the guest and how much of it takes up space on the host
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DiskInfo {
+    pub path: String,
+    pub format: String,
+    /// Size of the disk the guest sees in bytes
+    pub virtual_size: u64,
+    /// Bytes the image takes up on the host, if that's known
+    pub allocated: Option<u64>,
+    pub backing_file: Option<String>,
+    /// ok, failed or nospace, None when QEMU doesn't run or doesn't track it for this disk
+    pub io_status: Option<String>,
+}
+
+/// Looking Glass session of a running VM
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LookingGlassInfo {
+    /// The host application in the guest is updating the shared memory
+    pub host_running: bool,
+    /// Session of the host application that last initialized the shared memory
+    pub session_id: Option<u32>,
+    /// Pids of the clients (and anything else) that mapped the shared memory
+    pub clients: Vec<u32>,
+    /// Resolution the guest uses, as the guest agent reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_resolution: Option<String>,
+    /// The frames of [guest_resolution] don't fit in the shared memory
+    #[serde(default)]
+    pub buffer_too_small: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BlockStats {
+    pub device: String,
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NetworkStats {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
@@ -3,7 +3,9 @@ use std::os::unix::net::UnixStream;
 use std::path::Path;
 use vore_core::rpc::*;
 use vore_core::rpc::{CommandCenter, Request};
-use vore_core::{CloneableUnixStream, VirtualMachineInfo};
+use vore_core::{
+    CloneableUnixStream, PciAddress, VirtualMachineInfo, VirtualMachineState, VirtualMachineStats,
+};
 
 pub struct Client {
     stream: CloneableUnixStream,
@@ -14,7 +16,23 @@ pub struct Client {
 impl Client {
     pub fn connect<P: AsRef<Path>>(path: P) -> anyhow::Result<Client> {
         let path = path.as_ref();
-        let stream = CloneableUnixStream::new(UnixStream::connect(path)?);
+        let stream = UnixStream::connect(path).map_err(|err| {
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                RpcError::new(
+                    ErrorCode::PermissionDenied,
+                    format!("Not allowed to connect to vored at {:?}", path),
+                )
+            } else {
+                RpcError::new(
+                    ErrorCode::DaemonUnreachable,
+                    format!(
+                        "Can't connect to vored at {:?} ({}), is it running?",
+                        path, err
+                    ),
+                )
+            }
+        })?;
+        let stream = CloneableUnixStream::new(stream);
         log::debug!("Connected to vore socket at {}", path.to_str().unwrap());
 
         Ok(Client {
@@ -33,6 +51,57 @@ impl Client {
         Ok(info)
     }
 
+    /// Send [request] without waiting for the answer, vored carries it out even after we disconnect
+    fn send_detached<R: Request>(&mut self, request: R) -> anyhow::Result<()> {
+        let (_, json) = self.center.write_detached_command(request)?;
+        self.stream.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Like send, but calls [progress] for every progress message that comes before the answer
+    fn send_with_progress<R: Request>(
+        &mut self,
+        request: R,
+        progress: &mut dyn FnMut(StartProgress),
+    ) -> anyhow::Result<R::Response> {
+        let (_, json) = self.center.write_command(request)?;
+        self.stream.write_all(json.as_bytes())?;
+        loop {
+            let mut response = String::new();
+            if self.buf_reader.read_line(&mut response)? == 0 {
+                anyhow::bail!("Connection to vored closed before it answered");
+            }
+
+            if let Some((_, step)) = CommandCenter::read_progress(&response) {
+                progress(step);
+                continue;
+            }
+
+            let (_, info) = CommandCenter::read_answer::<R>(&response)?;
+            return Ok(info);
+        }
+    }
+
+    /// Calls [on_event] for every event that matches the filters, until vored goes away
+    pub fn subscribe(
+        &mut self,
+        names: Vec<String>,
+        kinds: Vec<EventKind>,
+        on_event: &mut dyn FnMut(EventMessage),
+    ) -> anyhow::Result<()> {
+        self.send(SubscribeRequest { names, kinds })?;
+        loop {
+            let mut line = String::new();
+            if self.buf_reader.read_line(&mut line)? == 0 {
+                anyhow::bail!("Connection to vored closed");
+            }
+
+            if let Some((_, message)) = CommandCenter::read_event(&line) {
+                on_event(message);
+            }
+        }
+    }
+
     pub fn load_vm(
         &mut self,
         toml: &str,
@@ -57,22 +126,260 @@ impl Client {
         Ok(self.send(DiskPresetsRequest {})?.presets)
     }
 
+    pub fn vfio_recover(&mut self, dry_run: bool) -> anyhow::Result<Vec<VfioBinding>> {
+        Ok(self.send(VfioRecoverRequest { dry_run })?.devices)
+    }
+
     pub fn host_version(&mut self) -> anyhow::Result<InfoResponse> {
         self.send(InfoRequest {})
     }
 
     pub fn prepare(&mut self, vm: String, cdroms: Vec<String>) -> anyhow::Result<()> {
-        self.send(PrepareRequest { name: vm, cdroms })?;
+        self.send(PrepareRequest {
+            name: vm,
+            cdroms,
+            dry_run: false,
+        })?;
         Ok(())
     }
 
-    pub fn start(&mut self, vm: String, cdroms: Vec<String>) -> anyhow::Result<()> {
-        self.send(StartRequest { name: vm, cdroms })?;
+    /// Returns what would go wrong when preparing the VM
+    pub fn check_prepare(&mut self, vm: String) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .send(PrepareRequest {
+                name: vm,
+                cdroms: vec![],
+                dry_run: true,
+            })?
+            .problems)
+    }
+
+    pub fn start(
+        &mut self,
+        vm: String,
+        cdroms: Vec<String>,
+        boot: Option<String>,
+        progress: &mut dyn FnMut(StartProgress),
+    ) -> anyhow::Result<()> {
+        self.send_with_progress(
+            StartRequest {
+                name: vm,
+                cdroms,
+                boot,
+                progress: true,
+            },
+            progress,
+        )?;
         Ok(())
     }
 
+    /// Ask vored to start [vm] without waiting till it runs
+    pub fn start_detached(
+        &mut self,
+        vm: String,
+        cdroms: Vec<String>,
+        boot: Option<String>,
+    ) -> anyhow::Result<()> {
+        self.send_detached(StartRequest {
+            name: vm,
+            cdroms,
+            boot,
+            progress: false,
+        })
+    }
+
     pub fn stop(&mut self, vm: String) -> anyhow::Result<()> {
         self.send(StopRequest { name: vm })?;
         Ok(())
     }
+
+    /// Ask vored to stop [vm] without waiting till it stopped
+    pub fn stop_detached(&mut self, vm: String) -> anyhow::Result<()> {
+        self.send_detached(StopRequest { name: vm })
+    }
+
+    pub fn release(&mut self, vm: String) -> anyhow::Result<Vec<PciAddress>> {
+        Ok(self.send(ReleaseRequest { name: vm })?.devices)
+    }
+
+    pub fn wait(
+        &mut self,
+        vm: String,
+        state: VirtualMachineState,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<VirtualMachineState> {
+        Ok(self
+            .send(WaitRequest {
+                name: vm,
+                state,
+                timeout,
+            })?
+            .state)
+    }
+
+    pub fn stats(&mut self, vm: String) -> anyhow::Result<VirtualMachineStats> {
+        Ok(self.send(StatsRequest { name: vm })?.stats)
+    }
+
+    pub fn bench(
+        &mut self,
+        vm: String,
+        duration: Option<u64>,
+    ) -> anyhow::Result<Vec<LatencyResult>> {
+        Ok(self.send(BenchRequest { name: vm, duration })?.results)
+    }
+
+    pub fn list_artifacts(&mut self, vm: String) -> anyhow::Result<Vec<Artifact>> {
+        Ok(self.send(ListArtifactsRequest { name: vm })?.artifacts)
+    }
+
+    pub fn fetch_artifact(&mut self, vm: String, path: String) -> anyhow::Result<Vec<u8>> {
+        Ok(self.send(FetchArtifactRequest { name: vm, path })?.data)
+    }
+
+    pub fn history(&mut self, vm: String) -> anyhow::Result<HistoryResponse> {
+        self.send(HistoryRequest { name: vm })
+    }
+
+    pub fn clipboard(&mut self, vm: String, set: Option<String>) -> anyhow::Result<Option<String>> {
+        Ok(self.send(ClipboardRequest { name: vm, set })?.text)
+    }
+
+    pub fn describe(&mut self, vm: String) -> anyhow::Result<DescribeResponse> {
+        self.send(DescribeRequest { name: vm })
+    }
+
+    pub fn memory(&mut self, vm: String, set: Option<u64>) -> anyhow::Result<MemoryResponse> {
+        self.send(MemoryRequest { name: vm, set })
+    }
+
+    pub fn repin(
+        &mut self,
+        vm: String,
+        cpus: Option<Vec<usize>>,
+        save: bool,
+    ) -> anyhow::Result<Option<Vec<usize>>> {
+        Ok(self
+            .send(RepinRequest {
+                name: vm,
+                cpus,
+                save,
+            })?
+            .cpus)
+    }
+
+    pub fn qemu_ids(&mut self, vm: String) -> anyhow::Result<QemuIdMap> {
+        Ok(self.send(QemuIdsRequest { name: vm })?.map)
+    }
+
+    pub fn fsfreeze(
+        &mut self,
+        vm: String,
+        freeze: Option<u64>,
+        thaw: bool,
+    ) -> anyhow::Result<FsfreezeResponse> {
+        self.send(FsfreezeRequest {
+            name: vm,
+            freeze,
+            thaw,
+        })
+    }
+
+    pub fn cdrom(
+        &mut self,
+        vm: String,
+        drive: usize,
+        insert: Option<String>,
+        eject: bool,
+    ) -> anyhow::Result<Vec<CdromDrive>> {
+        Ok(self
+            .send(CdromRequest {
+                name: vm,
+                drive,
+                insert,
+                eject,
+            })?
+            .drives)
+    }
+
+    pub fn usb(
+        &mut self,
+        vm: String,
+        attach: Option<String>,
+        detach: Option<String>,
+    ) -> anyhow::Result<Vec<UsbDevice>> {
+        Ok(self
+            .send(UsbRequest {
+                name: vm,
+                attach,
+                detach,
+            })?
+            .devices)
+    }
+
+    pub fn pci(
+        &mut self,
+        vm: String,
+        attach: Option<PciAddress>,
+        detach: Option<PciAddress>,
+        force: bool,
+    ) -> anyhow::Result<Vec<PciSlot>> {
+        Ok(self
+            .send(PciRequest {
+                name: vm,
+                attach,
+                detach,
+                force,
+            })?
+            .slots)
+    }
+
+    pub fn uefi_boot_entries(
+        &mut self,
+        vm: String,
+        order: Option<Vec<u16>>,
+        delete: Vec<u16>,
+    ) -> anyhow::Result<Vec<UefiBootEntry>> {
+        Ok(self
+            .send(UefiBootEntriesRequest {
+                name: vm,
+                order,
+                delete,
+            })?
+            .entries)
+    }
+
+    pub fn uefi_vars_path(&mut self, vm: String) -> anyhow::Result<UefiVarsPathResponse> {
+        self.send(UefiVarsPathRequest { name: vm })
+    }
+
+    pub fn uefi_reset(&mut self, vm: String) -> anyhow::Result<UefiResetResponse> {
+        self.send(UefiResetRequest { name: vm })
+    }
+
+    pub fn snapshot(
+        &mut self,
+        vm: String,
+        create: Option<String>,
+        revert: Option<String>,
+        delete: Option<String>,
+    ) -> anyhow::Result<Vec<SnapshotGroup>> {
+        Ok(self
+            .send(SnapshotRequest {
+                name: vm,
+                create,
+                revert,
+                delete,
+            })?
+            .snapshots)
+    }
+
+    pub fn unload(&mut self, vm: String) -> anyhow::Result<()> {
+        self.send(UnloadRequest { name: vm })?;
+        Ok(())
+    }
+
+    pub fn clean(&mut self, vm: String) -> anyhow::Result<Vec<String>> {
+        Ok(self.send(CleanRequest { name: vm })?.removed)
+    }
 }
@@ -0,0 +1,188 @@
+// `vore describe`, the effective config of a VM compared to its stored definition
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Eq, PartialEq)]
+enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line diff of [old] and [new], based on their longest common subsequence
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines.extend(old[i..].iter().map(|x| DiffLine::Removed(x)));
+    lines.extend(new[j..].iter().map(|x| DiffLine::Added(x)));
+    lines
+}
+
+/// Render the [loaded] config, marking where the [stored] definition differs from it
+pub fn describe(loaded: &str, stored: Result<Option<String>, String>, color: bool) -> String {
+    let stored = match stored {
+        Ok(Some(stored)) if stored != loaded => stored,
+        Ok(Some(_)) => return format!("# Matches the stored definition\n{}", loaded),
+        Ok(None) => return format!("# The definition of this VM isn't saved\n{}", loaded),
+        Err(err) => {
+            return format!(
+                "# The stored definition couldn't be parsed: {}\n{}",
+                err, loaded
+            )
+        }
+    };
+
+    let mut out = String::from(
+        "# The stored definition changed since the VM was loaded,\n# - is loaded now, + is what loading the stored definition would change\n",
+    );
+    for line in diff_lines(loaded, &stored) {
+        let (prefix, color_code, line) = match line {
+            DiffLine::Same(line) => ("  ", None, line),
+            DiffLine::Removed(line) => ("- ", Some("31"), line),
+            DiffLine::Added(line) => ("+ ", Some("32"), line),
+        };
+
+        match color_code {
+            Some(code) if color => {
+                out.push_str(&format!("\x1b[{}m{}{}\x1b[0m\n", code, prefix, line))
+            }
+            _ => out.push_str(&format!("{}{}\n", prefix, line)),
+        }
+    }
+
+    out
+}
+
+fn terminal_rows() -> Option<usize> {
+    unsafe {
+        if libc::isatty(libc::STDOUT_FILENO) != 1 {
+            return None;
+        }
+
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) != 0 || size.ws_row == 0 {
+            return None;
+        }
+
+        Some(size.ws_row as usize)
+    }
+}
+
+/// Print [text], through $PAGER (or less) if it doesn't fit on the terminal
+pub fn page(text: &str) -> anyhow::Result<()> {
+    if terminal_rows().map_or(true, |rows| text.lines().count() < rows) {
+        print!("{}", text);
+        return Ok(());
+    }
+
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|x| !x.trim().is_empty())
+        .unwrap_or_else(|| "less".to_string());
+    // Like git, let less show colors and quit if everything fits after all
+    let less = std::env::var("LESS").unwrap_or_else(|_| "FRX".to_string());
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&pager)
+        .env("LESS", less)
+        .stdin(Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => {
+            print!("{}", text);
+            return Ok(());
+        }
+    };
+
+    // The pager may be quit before everything is read
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(text.as_bytes());
+    }
+
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::describe::{describe, diff_lines, DiffLine};
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(
+            diff_lines("a\nb\nc\nd", "a\nc\nd\ne"),
+            vec![
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Same("d"),
+                DiffLine::Added("e"),
+            ]
+        );
+        assert_eq!(
+            diff_lines("memory = 4096", "memory = 8192"),
+            vec![
+                DiffLine::Removed("memory = 4096"),
+                DiffLine::Added("memory = 8192"),
+            ]
+        );
+        assert_eq!(diff_lines("", "a"), vec![DiffLine::Added("a")]);
+        assert_eq!(diff_lines("a", ""), vec![DiffLine::Removed("a")]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let loaded = "[machine]\nmemory = 4096\n";
+        assert_eq!(
+            describe(loaded, Ok(Some(loaded.to_string())), false),
+            "# Matches the stored definition\n[machine]\nmemory = 4096\n"
+        );
+        assert!(describe(loaded, Ok(None), false)
+            .starts_with("# The definition of this VM isn't saved\n"));
+
+        let described = describe(
+            loaded,
+            Ok(Some("[machine]\nmemory = 8192\n".to_string())),
+            false,
+        );
+        assert!(described.ends_with("  [machine]\n- memory = 4096\n+ memory = 8192\n"));
+        let colored = describe(
+            loaded,
+            Ok(Some("[machine]\nmemory = 8192\n".to_string())),
+            true,
+        );
+        assert!(colored.contains("\x1b[31m- memory = 4096\x1b[0m\n"));
+    }
+}
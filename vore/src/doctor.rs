@@ -0,0 +1,177 @@
+// `vore doctor`, one report of everything that could stand between the user and a running VM
+
+use crate::client::Client;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use vore_core::utils::{can_access, is_in_group};
+use vore_core::{host_checks, lint};
+
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Report {
+    color: bool,
+    problems: usize,
+}
+
+impl Report {
+    fn section(&self, name: &str) {
+        if self.color {
+            println!("\n\x1b[1m{}\x1b[0m", name);
+        } else {
+            println!("\n{}", name);
+        }
+    }
+
+    fn line(&mut self, status: Status, message: &str) {
+        let (label, color) = match status {
+            Status::Ok => ("ok", "32"),
+            Status::Warn => ("warn", "33"),
+            Status::Fail => ("fail", "31"),
+        };
+
+        if !matches!(status, Status::Ok) {
+            self.problems += 1;
+        }
+
+        if self.color {
+            println!("  [\x1b[{}m{:^4}\x1b[0m] {}", color, label, message);
+        } else {
+            println!("  [{:^4}] {}", label, message);
+        }
+    }
+
+    fn check(&mut self, ok: bool, fail: Status, message: &str) {
+        self.line(if ok { Status::Ok } else { fail }, message)
+    }
+}
+
+pub fn doctor(socket: &str) -> anyhow::Result<()> {
+    let mut report = Report {
+        color: unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+        problems: 0,
+    };
+
+    report.section("Host");
+    for check in host_checks() {
+        report.check(
+            check.ok,
+            Status::Warn,
+            &format!("{}: {}", check.name, check.message),
+        );
+    }
+
+    report.section("Daemon");
+    let socket_meta = fs::metadata(socket);
+    match &socket_meta {
+        Ok(meta) => {
+            let uid = unsafe { libc::geteuid() };
+            if uid == 0 || uid == meta.uid() || is_in_group(meta.gid()) {
+                report.line(Status::Ok, &format!("socket {} is accessible", socket));
+            } else {
+                report.line(
+                    Status::Fail,
+                    &format!(
+                        "socket {} is owned by group {}, which you're not a member of",
+                        socket,
+                        meta.gid()
+                    ),
+                );
+            }
+        }
+        Err(err) => report.line(
+            Status::Fail,
+            &format!(
+                "socket {} doesn't exist ({}), is vored running?",
+                socket, err
+            ),
+        ),
+    }
+
+    let mut client = match Client::connect(socket) {
+        Ok(client) => client,
+        Err(err) => {
+            if socket_meta.is_ok() {
+                report.line(
+                    Status::Fail,
+                    &format!("failed to connect to {}: {}", socket, err),
+                );
+            }
+
+            return finish(report);
+        }
+    };
+
+    match client.host_version() {
+        Ok(info) => report.line(
+            Status::Ok,
+            &format!("vored {} running on {}", info.version, info.name),
+        ),
+        Err(err) => report.line(Status::Fail, &format!("vored didn't answer: {:#}", err)),
+    }
+
+    for vm in client.list_vms()? {
+        report.section(&format!("VM {} ({})", vm.name, vm.state));
+
+        for warning in lint(&vm.config) {
+            report.line(Status::Warn, &warning);
+        }
+
+        match client.check_prepare(vm.name.clone()) {
+            Ok(problems) if problems.is_empty() => report.line(Status::Ok, "prepare would succeed"),
+            Ok(problems) => {
+                for problem in problems {
+                    report.line(Status::Fail, &problem);
+                }
+            }
+            Err(err) => report.line(Status::Fail, &format!("prepare check failed: {:#}", err)),
+        }
+
+        let mut paths = vec![];
+        if vm.config.looking_glass.enabled {
+            paths.push((
+                "looking-glass shared memory",
+                &vm.config.looking_glass.mem_path,
+            ));
+        }
+
+        if vm.config.spice.enabled {
+            paths.push(("spice socket", &vm.config.spice.socket_path));
+        }
+
+        for (kind, path) in paths {
+            if path.is_empty() || !Path::new(path).exists() {
+                report.line(Status::Ok, &format!("{} will be created on start", kind));
+                continue;
+            }
+
+            if can_access(path, true) {
+                report.line(Status::Ok, &format!("{} {} is accessible", kind, path));
+            } else {
+                report.line(
+                    Status::Fail,
+                    &format!(
+                        "{} {} can't be read and written by you, are you in the vore group?",
+                        kind, path
+                    ),
+                );
+            }
+        }
+    }
+
+    finish(report)
+}
+
+fn finish(report: Report) -> anyhow::Result<()> {
+    println!();
+    if report.problems > 0 {
+        anyhow::bail!("Found {} problem(s)", report.problems);
+    }
+
+    println!("No problems found");
+    Ok(())
+}
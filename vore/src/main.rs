@@ -1,21 +1,48 @@
 mod client;
+mod describe;
+mod doctor;
 
 use crate::client::Client;
+use crate::describe::{describe, page};
+use crate::doctor::doctor;
 use anyhow::Context;
 use clap::{App, ArgMatches};
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::option::Option::Some;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, mem};
 use vore_core::consts::VORE_SOCKET;
-use vore_core::rpc::DiskPreset;
-use vore_core::{init_logging, VirtualMachineInfo};
+use vore_core::rpc::{
+    CommandError, DiskPreset, EventKind, EventMessage, LatencyResult, RpcError, UefiBootEntry,
+    VfioBinding,
+};
+use vore_core::{
+    create_disk_image, format_cpu_list, init_logging, iommu_groups, lint, parse_cpu_list,
+    parse_size, InstanceConfig, PciAddress, PciIds, ScreamMode, VirtualMachineInfo,
+    VirtualMachineState, DISK_IMAGE_FORMATS,
+};
 
 fn main() {
     init_logging();
 
     if let Err(err) = main_res() {
-        println!("{:?}", err)
+        println!("{:?}", err);
+        let code = err
+            .chain()
+            .find_map(|x| x.downcast_ref::<CommandError>())
+            .and_then(|x| x.code())
+            .or_else(|| {
+                err.chain()
+                    .find_map(|x| x.downcast_ref::<RpcError>())
+                    .map(|x| x.code)
+            })
+            .unwrap_or_default();
+        std::process::exit(code.exit_code());
     }
 }
 
@@ -23,6 +50,33 @@ fn main_res() -> anyhow::Result<()> {
     let yaml = clap::load_yaml!("../clap.yml");
     let app: App = App::from(yaml);
     let matches = app.get_matches();
+
+    // Doesn't need the daemon, so don't require it to be running
+    if let ("check", Some(args)) = matches.subcommand() {
+        return check(args);
+    }
+
+    // Reads sysfs of the host directly
+    if let ("pci", Some(args)) = matches.subcommand() {
+        if let ("list", _) = args.subcommand() {
+            return pci_list();
+        }
+    }
+
+    // Only runs qemu-img and edits the definition, vored picks the disk up on the next load
+    if let ("disk", Some(args)) = matches.subcommand() {
+        match args.subcommand() {
+            ("import", Some(args)) => return disk_import(args),
+            ("create", Some(args)) => return disk_create(args),
+            _ => {}
+        }
+    }
+
+    // Reports on the daemon connection itself
+    if let ("doctor", _) = matches.subcommand() {
+        return doctor(matches.value_of("vored-socket").unwrap_or(VORE_SOCKET));
+    }
+
     let client = Client::connect(matches.value_of("vored-socket").unwrap_or(VORE_SOCKET))?;
 
     let mut vore = VoreApp { client };
@@ -48,10 +102,98 @@ fn main_res() -> anyhow::Result<()> {
             vore.stop(args)?;
         }
 
+        ("release", Some(args)) => {
+            vore.release(args)?;
+        }
+
+        ("unload", Some(args)) => {
+            vore.unload(args)?;
+        }
+
+        ("clean", Some(args)) => {
+            vore.clean(args)?;
+        }
+
+        ("wait", Some(args)) => {
+            vore.wait(args)?;
+        }
+
+        ("events", Some(args)) => {
+            vore.events(args)?;
+        }
+
+        ("status", Some(args)) => {
+            vore.status(args)?;
+        }
+
+        ("history", Some(args)) => {
+            vore.history(args)?;
+        }
+
+        ("devices", Some(args)) => {
+            vore.devices(args)?;
+        }
+
+        ("describe", Some(args)) => {
+            vore.describe(args)?;
+        }
+
+        ("clipboard", Some(args)) => {
+            vore.clipboard(args)?;
+        }
+
+        ("mem", Some(args)) => {
+            vore.mem(args)?;
+        }
+
+        ("pin", Some(args)) => {
+            vore.pin(args)?;
+        }
+
+        ("cdrom", Some(args)) => {
+            vore.cdrom(args)?;
+        }
+
+        ("snapshot", Some(args)) => {
+            vore.snapshot(args)?;
+        }
+
+        ("usb", Some(args)) => {
+            vore.usb(args)?;
+        }
+
+        ("fsfreeze", Some(args)) => {
+            vore.fsfreeze(args)?;
+        }
+
+        ("pci", Some(args)) => {
+            vore.pci(args)?;
+        }
+
+        ("stats", Some(args)) => {
+            vore.stats(args)?;
+        }
+
+        ("top", Some(args)) => {
+            vore.top(args)?;
+        }
+
+        ("artifacts", Some(args)) => {
+            vore.artifacts(args)?;
+        }
+
+        ("bench", Some(args)) => {
+            vore.bench(args)?;
+        }
+
         ("looking-glass", Some(args)) => {
             vore.looking_glass(args)?;
         }
 
+        ("scream", Some(args)) => {
+            vore.scream(args)?;
+        }
+
         ("daemon", Some(args)) => match args.subcommand() {
             ("version", _) => {
                 vore.daemon_version()?;
@@ -72,6 +214,34 @@ fn main_res() -> anyhow::Result<()> {
             }
         },
 
+        ("vfio", Some(args)) => match args.subcommand() {
+            ("recover", Some(args)) => {
+                vore.vfio_recover(args)?;
+            }
+
+            (s, _) => {
+                log::error!("Subcommand vfio.{} not implemented", s);
+            }
+        },
+
+        ("uefi", Some(args)) => match args.subcommand() {
+            ("bootentries", Some(args)) => {
+                vore.uefi_boot_entries(args)?;
+            }
+
+            ("path", Some(args)) => {
+                vore.uefi_vars_path(args)?;
+            }
+
+            ("reset", Some(args)) => {
+                vore.uefi_reset(args)?;
+            }
+
+            (s, _) => {
+                log::error!("Subcommand uefi.{} not implemented", s);
+            }
+        },
+
         (s, _) => {
             log::error!("Subcommand {} not implemented", s);
         }
@@ -93,13 +263,240 @@ fn get_load_vm_options(args: &ArgMatches) -> anyhow::Result<LoadVirtualMachineOp
 
     Ok(LoadVirtualMachineOptions {
         config,
-        cd_roms: args
-            .values_of("cdrom")
-            .map_or(vec![], |x| x.map(|x| x.to_string()).collect::<Vec<_>>()),
+        cd_roms: cdrom_paths(args)?,
         save: args.is_present("save"),
     })
 }
 
+/// The --cdrom arguments as absolute paths, vored resolves paths from its own working directory
+fn cdrom_paths(args: &ArgMatches) -> anyhow::Result<Vec<String>> {
+    args.values_of("cdrom").map_or(Ok(vec![]), |x| {
+        x.map(|x| {
+            fs::canonicalize(x)
+                .map(|x| x.to_string_lossy().to_string())
+                .with_context(|| format!("Can't find CD-ROM {}", x))
+        })
+        .collect()
+    })
+}
+
+fn check(args: &ArgMatches) -> anyhow::Result<()> {
+    let vm_config_path = args.value_of("vm-config").unwrap();
+    let config = fs::read_to_string(vm_config_path)
+        .with_context(|| format!("Failed to read vm config at {}", vm_config_path))?;
+    let config = InstanceConfig::from_toml(&config)?;
+
+    let warnings = lint(&config);
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+
+    if !warnings.is_empty() {
+        anyhow::bail!("Found {} problem(s) in {}", warnings.len(), vm_config_path);
+    }
+
+    println!("No problems found in {}", vm_config_path);
+    Ok(())
+}
+
+fn pci_list() -> anyhow::Result<()> {
+    let groups = iommu_groups()?;
+    if groups.is_empty() {
+        anyhow::bail!(
+            "No IOMMU groups found, add intel_iommu=on or amd_iommu=on to the kernel command line"
+        );
+    }
+
+    let ids = PciIds::load();
+    for group in groups {
+        println!("IOMMU group {}", group.id);
+        for device in group.devices {
+            let name = match (
+                ids.vendor(device.vendor),
+                ids.device(device.vendor, device.device),
+            ) {
+                (Some(vendor), Some(name)) => format!("{} {}", vendor, name),
+                (Some(vendor), None) => vendor.to_string(),
+                _ => "unknown device".to_string(),
+            };
+
+            let mut notes = vec![format!(
+                "driver: {}",
+                device.driver.as_deref().unwrap_or("none")
+            )];
+            if device.is_bridge() {
+                notes.push("bridge".to_string());
+            }
+
+            println!(
+                "  {:#}  {:04x}:{:04x}  {}  ({})",
+                device.address,
+                device.vendor,
+                device.device,
+                name,
+                notes.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// qemu-img format of a foreign image, by its extension
+fn image_format(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    Some(match extension.as_str() {
+        "vmdk" => "vmdk",
+        "vdi" => "vdi",
+        "vhdx" => "vhdx",
+        "vhd" => "vpc",
+        "qcow2" => "qcow2",
+        "img" | "raw" => "raw",
+        _ => return None,
+    })
+}
+
+fn disk_create(args: &ArgMatches) -> anyhow::Result<()> {
+    let path = args.value_of("path").unwrap();
+    let format = match args.value_of("format") {
+        Some(format) => format,
+        None => match image_format(Path::new(path)) {
+            Some(format) if DISK_IMAGE_FORMATS.contains(&format) => format,
+            _ => anyhow::bail!(
+                "Can't tell the format of {} from its extension, give it with --format",
+                path
+            ),
+        },
+    };
+
+    let size = parse_size(args.value_of("size").unwrap())?;
+    create_disk_image(path, format, size, args.value_of("preallocation"))?;
+    println!("Created {} ({}, {} MiB)", path, format, size);
+    Ok(())
+}
+
+fn disk_import(args: &ArgMatches) -> anyhow::Result<()> {
+    let image = Path::new(args.value_of("image").unwrap());
+    if !image.is_file() {
+        anyhow::bail!("Can't find image {}", image.display());
+    }
+
+    let format = args.value_of("format").unwrap();
+    let name = match args.value_of("name") {
+        Some(name) => name.to_string(),
+        None => format!(
+            "{}.{}",
+            image
+                .file_stem()
+                .context("Image has no file name")?
+                .to_string_lossy(),
+            if format == "raw" { "img" } else { format }
+        ),
+    };
+
+    let pool = Path::new(args.value_of("pool").unwrap());
+    fs::create_dir_all(pool)
+        .with_context(|| format!("Failed to create pool directory {}", pool.display()))?;
+    // vored resolves paths from its own working directory
+    let target = fs::canonicalize(pool)?.join(name);
+    if target.exists() {
+        anyhow::bail!("{} already exists, not overwriting it", target.display());
+    }
+
+    let mut command = Command::new("qemu-img");
+    command.args(&["convert", "-p"]);
+    if let Some(source_format) = image_format(image) {
+        command.args(&["-f", source_format]);
+    }
+
+    command.args(&["-O", format]).arg(image).arg(&target);
+    let status = command.status().context("Failed to run qemu-img")?;
+    if !status.success() {
+        // Don't leave a half converted image behind
+        let _ = fs::remove_file(&target);
+        anyhow::bail!("qemu-img failed to convert {}", image.display());
+    }
+
+    println!("Imported {} as {}", image.display(), target.display());
+
+    if let Some(vm_config_path) = args.value_of("append") {
+        let config = fs::read_to_string(vm_config_path)
+            .with_context(|| format!("Failed to read vm config at {}", vm_config_path))?;
+        let disk = format!(
+            "\n[[disk]]\npreset = {:?}\npath = {:?}\ntype = {:?}\n",
+            args.value_of("preset").unwrap(),
+            target.to_string_lossy(),
+            format
+        );
+
+        // Appended as text, so the comments in the definition are kept
+        let updated = format!("{}{}", config, disk);
+        InstanceConfig::from_toml(&updated)
+            .context("VM definition is invalid with the imported disk added")?;
+        fs::write(vm_config_path, updated)
+            .with_context(|| format!("Failed to write vm config at {}", vm_config_path))?;
+        println!("Added it as [[disk]] to {}", vm_config_path);
+    }
+
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// Format a unix timestamp in local time
+fn format_time(timestamp: u64) -> String {
+    let time = timestamp as libc::time_t;
+    let tm = unsafe {
+        let mut tm = mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return timestamp.to_string();
+        }
+
+        tm
+    };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, seconds % 60)
+    }
+}
+
 struct VoreApp {
     client: Client,
 }
@@ -137,15 +534,38 @@ impl VoreApp {
         let vm_info =
             self.client
                 .load_vm(&vm_options.config, vm_options.save, vm_options.cd_roms)?;
+        for deprecation in &vm_info.config.deprecations {
+            eprintln!("warning: {}", deprecation);
+        }
+
         log::info!("Loaded VM {}", vm_info.name);
         Ok(())
     }
 
-    fn list(&mut self, _: &ArgMatches) -> anyhow::Result<()> {
+    fn list(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
         let items = self.client.list_vms()?;
+        if !args.is_present("wide") {
+            for info in items {
+                println!("{}\t{}", info.name, info.state)
+            }
+
+            return Ok(());
+        }
 
+        println!("NAME\tSTATE\tRSS\tCPU\tREAD\tWRITE");
         for info in items {
-            println!("{}\t{}", info.name, info.state)
+            match info.runtime.and_then(|x| x.usage) {
+                Some(usage) => println!(
+                    "{}\t{}\t{}\t{:.1}%\t{}/s\t{}/s",
+                    info.name,
+                    info.state,
+                    format_size(usage.rss),
+                    usage.cpu_percent,
+                    format_size(usage.read_rate),
+                    format_size(usage.write_rate)
+                ),
+                None => println!("{}\t{}\t-\t-\t-\t-", info.name, info.state),
+            }
         }
 
         Ok(())
@@ -163,21 +583,45 @@ impl VoreApp {
 
     fn prepare(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
         let name = self.get_vm_name(args)?;
-        self.client.prepare(
-            name,
-            args.values_of("cdrom")
-                .map_or(vec![], |x| x.map(|x| x.to_string()).collect::<Vec<_>>()),
-        )?;
+        self.client.prepare(name, cdrom_paths(args)?)?;
         Ok(())
     }
 
     fn start(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
         let name = self.get_vm_name(args)?;
+        if args.is_present("detach") {
+            return self.client.start_detached(
+                name,
+                cdrom_paths(args)?,
+                args.value_of("boot").map(|x| x.to_string()),
+            );
+        }
+
+        let quiet = args.is_present("quiet");
+        let started = Instant::now();
         self.client.start(
-            name,
-            args.values_of("cdrom")
-                .map_or(vec![], |x| x.map(|x| x.to_string()).collect::<Vec<_>>()),
+            name.clone(),
+            cdrom_paths(args)?,
+            args.value_of("boot").map(|x| x.to_string()),
+            &mut |progress| {
+                if !quiet {
+                    println!(
+                        "[{:>5.1}s] {}",
+                        started.elapsed().as_secs_f64(),
+                        progress.message
+                    );
+                }
+            },
         )?;
+
+        if !quiet {
+            println!(
+                "[{:>5.1}s] {} is running",
+                started.elapsed().as_secs_f64(),
+                name
+            );
+        }
+
         Ok(())
     }
 
@@ -187,16 +631,35 @@ impl VoreApp {
             anyhow::bail!("VM '{}' has no looking glass", vm.name);
         }
 
+        let seat = match args.value_of("seat") {
+            Some(name) => Some(
+                vm.config
+                    .seats
+                    .iter()
+                    .find(|x| x.name == name)
+                    .with_context(|| format!("VM '{}' has no seat '{}'", vm.name, name))?,
+            ),
+            None => None,
+        };
+
         let mut command = Command::new(
             std::env::var("LOOKING_GLASS").unwrap_or_else(|_| "looking-glass-client".to_string()),
         );
-        if vm.config.spice.enabled {
+        if let Some(seat) = seat.filter(|x| x.spice) {
+            command.args(&["-c", &seat.spice_socket_path, "-p", "0"]);
+        } else if vm.config.spice.enabled && !vm.config.spice.is_tcp() {
             command.args(&["-c", &vm.config.spice.socket_path, "-p", "0"]);
+        } else if let (true, Some(port)) = (vm.config.spice.enabled, vm.config.spice.port) {
+            command.args(&["-c", &vm.config.spice.listen, "-p", &port.to_string()]);
         } else {
             command.args(&["-s", "no"]);
         }
 
-        command.args(&["-f", &vm.config.looking_glass.mem_path]);
+        if let Some(seat) = seat.filter(|x| x.looking_glass) {
+            command.args(&["-f", &seat.looking_glass_mem_path]);
+        } else {
+            command.args(&["-f", &vm.config.looking_glass.mem_path]);
+        }
         command.args(
             args.values_of("looking-glass-args")
                 .map_or(vec![], |x| x.into_iter().collect::<Vec<_>>()),
@@ -208,9 +671,780 @@ impl VoreApp {
         Ok(())
     }
 
+    fn scream(mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let vm = self.get_vm(args)?;
+        if !vm.config.scream.enabled {
+            anyhow::bail!("VM '{}' has no scream", vm.name);
+        }
+
+        let mut command =
+            Command::new(std::env::var("SCREAM").unwrap_or_else(|_| "scream".to_string()));
+        match vm.config.scream.mode {
+            ScreamMode::Ivshmem => {
+                command.args(&["-m", &vm.config.scream.mem_path]);
+            }
+            ScreamMode::Network => {
+                // The guest can only reach the host with unicast through user networking
+                command.args(&["-u", "-p", &vm.config.scream.port.to_string()]);
+                if let Some(interface) = &vm.config.scream.interface {
+                    command.args(&["-i", interface]);
+                }
+            }
+        }
+
+        command.args(
+            args.values_of("scream-args")
+                .map_or(vec![], |x| x.into_iter().collect::<Vec<_>>()),
+        );
+
+        mem::drop(self);
+        command.exec();
+
+        Ok(())
+    }
+
+    fn wait(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let state = VirtualMachineState::from_str(args.value_of("state").unwrap())?;
+        let timeout = args
+            .value_of("timeout")
+            .map(u64::from_str)
+            .transpose()
+            .context("Timeout should be a number of seconds")?;
+
+        self.client.wait(name, state, timeout)?;
+        Ok(())
+    }
+
+    fn events(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let names = args
+            .values_of("vm")
+            .map_or_else(Vec::new, |x| x.map(str::to_string).collect());
+        let kinds = args
+            .values_of("type")
+            .map_or_else(|| Ok(vec![]), |x| x.map(EventKind::from_name).collect())?;
+
+        self.client.subscribe(names, kinds, &mut |message| {
+            match message {
+                EventMessage::Event(event) => match (event.state, &event.message) {
+                    (_, Some(message)) => println!("{}\t{}\t{}", event.name, event.kind, message),
+                    (Some(state), None) => println!("{}\t{}\t{}", event.name, event.kind, state),
+                    (None, None) => println!("{}\t{}", event.name, event.kind),
+                },
+                EventMessage::Lost(count) => {
+                    eprintln!(
+                        "Missed {} event(s), vored dropped them since we didn't keep up",
+                        count
+                    )
+                }
+            }
+            let _ = std::io::stdout().flush();
+        })
+    }
+
+    fn status(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let vm = self.get_vm(args)?;
+        let history = self.client.history(vm.name.clone())?;
+
+        println!("name\t{}", vm.name);
+        println!("state\t{}", vm.state);
+        for cdrom in &vm.cdroms {
+            println!("cdrom\t{}", cdrom);
+        }
+
+        if let Some(runtime) = &vm.runtime {
+            println!("pid\t{}", runtime.pid);
+            if runtime.standby {
+                println!("standby\tyes");
+            }
+
+            if runtime.stalled {
+                println!("stalled\tyes");
+            }
+
+            println!("uptime\t{}", format_duration(runtime.uptime));
+            if let Some(spice_socket) = &runtime.spice_socket {
+                println!("spice\t{}", spice_socket);
+            }
+
+            if let Some(looking_glass) = &runtime.looking_glass {
+                println!(
+                    "looking-glass\thost {}, {} client(s){}",
+                    if looking_glass.host_running {
+                        "running"
+                    } else {
+                        "not running"
+                    },
+                    looking_glass.clients.len(),
+                    if looking_glass.clients.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            " (pid {})",
+                            looking_glass
+                                .clients
+                                .iter()
+                                .map(|x| x.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    }
+                );
+
+                if let Some(resolution) = &looking_glass.guest_resolution {
+                    println!(
+                        "looking-glass\tguest at {}{}",
+                        resolution,
+                        if looking_glass.buffer_too_small {
+                            ", too large for the shared memory"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+
+            if runtime.low_disk_space {
+                println!("disk space\tlow");
+            }
+
+            for change in &runtime.host_changes {
+                println!("host\t{}", change);
+            }
+
+            if !runtime.isolated_cpus.is_empty() {
+                println!("isolated\t{}", format_cpu_list(&runtime.isolated_cpus));
+            }
+        }
+
+        for disk in self.client.stats(vm.name.clone())?.disks {
+            let mut line = format!(
+                "disk\t{}\t{} of {} ({})",
+                disk.path,
+                disk.allocated.map_or_else(|| "?".to_string(), format_size),
+                format_size(disk.virtual_size),
+                disk.format
+            );
+            if let Some(backing_file) = &disk.backing_file {
+                line.push_str(&format!(", backing file {}", backing_file));
+            }
+
+            match disk.io_status.as_deref() {
+                None | Some("ok") => {}
+                Some(status) => line.push_str(&format!(", io error: {}", status)),
+            }
+
+            println!("{}", line);
+        }
+
+        if let Some(boot) = history.boots.last() {
+            println!("last boot\t{}", format_time(boot.started));
+            if let Some(stopped) = boot.stopped {
+                println!(
+                    "last stop\t{} ({})",
+                    format_time(stopped),
+                    boot.reason.as_deref().unwrap_or("unknown")
+                );
+            }
+        } else {
+            println!("last boot\tnever");
+        }
+
+        println!("total uptime\t{}", format_duration(history.total_uptime));
+        Ok(())
+    }
+
+    fn history(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let history = self.client.history(name)?;
+
+        // Boots are the only history kept for now, so they're shown with or without --boots
+        for boot in history.boots {
+            let (stopped, uptime) = match boot.stopped {
+                Some(stopped) => (format_time(stopped), stopped.saturating_sub(boot.started)),
+                None => ("-".to_string(), unix_now().saturating_sub(boot.started)),
+            };
+
+            println!(
+                "{}\t{}\t{}\t{}",
+                format_time(boot.started),
+                stopped,
+                format_duration(uptime),
+                boot.reason.as_deref().unwrap_or("running")
+            );
+        }
+
+        Ok(())
+    }
+
+    fn devices(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let map = self.client.qemu_ids(name)?;
+
+        if args.is_present("counters") {
+            for (name, value) in map.counters {
+                println!("{}\t{}", name, value);
+            }
+        } else {
+            for id in map.ids {
+                println!("{}\t{}\t{}", id.kind, id.id, id.driver);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stats(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let stats = self.client.stats(name)?;
+
+        println!(
+            "uptime\t{}",
+            stats
+                .uptime
+                .map_or_else(|| "-".to_string(), |x| format!("{}s", x))
+        );
+        println!("cpu time\t{:.2}s", stats.cpu_time as f64 / 1000.0);
+        for (vcpu, time) in stats.vcpu_time.iter().enumerate() {
+            println!("vcpu {} time\t{:.2}s", vcpu, *time as f64 / 1000.0);
+        }
+
+        println!("rss\t{} MiB", stats.rss / 1024 / 1024);
+        if let Some(balloon) = stats.balloon {
+            println!("balloon\t{} MiB", balloon / 1024 / 1024);
+        }
+
+        for block in stats.block {
+            println!(
+                "block {}\tread {} bytes ({} ops)\twritten {} bytes ({} ops)",
+                block.device,
+                block.rd_bytes,
+                block.rd_operations,
+                block.wr_bytes,
+                block.wr_operations
+            );
+        }
+
+        for net in stats.network {
+            println!(
+                "network {}\trx {} bytes ({} packets)\ttx {} bytes ({} packets)",
+                net.interface, net.rx_bytes, net.rx_packets, net.tx_bytes, net.tx_packets
+            );
+        }
+
+        Ok(())
+    }
+
+    fn top(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let interval = args
+            .value_of("interval")
+            .map(u64::from_str)
+            .transpose()
+            .context("Interval should be a number of seconds")?
+            .unwrap_or(2)
+            .max(1);
+
+        // cpu time and disk bytes of the previous round, to calculate rates from
+        let mut previous: HashMap<String, (Instant, u64, u64, u64)> = HashMap::new();
+
+        loop {
+            let mut lines = vec![format!(
+                "{:<20} {:<10} {:>8} {:>10} {:>12} {:>12}",
+                "NAME", "STATE", "CPU%", "MEM MiB", "READ KiB/s", "WRITE KiB/s"
+            )];
+
+            let mut current = HashMap::new();
+            for info in self.client.list_vms()? {
+                let stats = match self.client.stats(info.name.clone()) {
+                    Ok(stats) => stats,
+                    Err(err) => {
+                        log::debug!("Failed to fetch stats of {}: {:?}", info.name, err);
+                        Default::default()
+                    }
+                };
+
+                let now = Instant::now();
+                let read = stats.block.iter().map(|x| x.rd_bytes).sum::<u64>();
+                let written = stats.block.iter().map(|x| x.wr_bytes).sum::<u64>();
+                let (cpu, read_rate, write_rate) = match previous.get(&info.name) {
+                    Some((at, prev_cpu, prev_read, prev_written)) if stats.uptime.is_some() => {
+                        // cpu time is in ms, so this is directly the percentage of one core
+                        let elapsed = now.duration_since(*at).as_millis().max(1) as f64;
+                        let per_second = |bytes: u64| bytes as f64 / elapsed * 1000.0 / 1024.0;
+                        (
+                            format!(
+                                "{:.1}",
+                                stats.cpu_time.saturating_sub(*prev_cpu) as f64 / elapsed * 100.0
+                            ),
+                            format!("{:.0}", per_second(read.saturating_sub(*prev_read))),
+                            format!("{:.0}", per_second(written.saturating_sub(*prev_written))),
+                        )
+                    }
+                    _ => ("-".to_string(), "-".to_string(), "-".to_string()),
+                };
+
+                current.insert(info.name.clone(), (now, stats.cpu_time, read, written));
+                lines.push(format!(
+                    "{:<20} {:<10} {:>8} {:>10} {:>12} {:>12}",
+                    info.name,
+                    info.state,
+                    cpu,
+                    stats.rss / 1024 / 1024,
+                    read_rate,
+                    write_rate
+                ));
+            }
+
+            // Clear the screen and move the cursor home before redrawing
+            println!("\x1b[2J\x1b[H{}", lines.join("\n"));
+            std::io::stdout().flush()?;
+
+            previous = current;
+            std::thread::sleep(Duration::from_secs(interval));
+        }
+    }
+
+    fn artifacts(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+
+        if let Some(path) = args.value_of("fetch") {
+            let data = self.client.fetch_artifact(name, path.to_string())?;
+            if let Some(output) = args.value_of("output") {
+                fs::write(output, data)
+                    .with_context(|| format!("Failed to write artifact to {}", output))?;
+            } else {
+                std::io::stdout().write_all(&data)?;
+            }
+
+            return Ok(());
+        }
+
+        for artifact in self.client.list_artifacts(name)? {
+            println!(
+                "{}\t{}\t{}",
+                artifact.path, artifact.size, artifact.modified
+            );
+        }
+
+        Ok(())
+    }
+
+    fn clipboard(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+
+        if args.is_present("set") {
+            let mut text = String::new();
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .context("Failed to read clipboard text from stdin")?;
+            self.client.clipboard(name, Some(text))?;
+            return Ok(());
+        }
+
+        if let Some(text) = self.client.clipboard(name, None)? {
+            std::io::stdout().write_all(text.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn describe(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let described = self.client.describe(name)?;
+        let loaded = described.config.to_toml()?;
+        let stored = described
+            .stored
+            .map(|stored| InstanceConfig::from_toml(&stored).and_then(|x| x.to_toml()))
+            .transpose()
+            .map_err(|err| format!("{:#}", err));
+
+        let color = unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 };
+        page(&describe(&loaded, stored, color))
+    }
+
+    fn mem(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let set = if let ("set", Some(set_args)) = args.subcommand() {
+            Some(parse_size(set_args.value_of("size").unwrap())?)
+        } else {
+            None
+        };
+
+        let memory = self.client.memory(name, set)?;
+        println!("memory\t{} MiB", memory.memory);
+        if let Some(balloon) = memory.balloon {
+            println!("balloon\t{} MiB", balloon / 1024 / 1024);
+        }
+
+        Ok(())
+    }
+
+    fn pin(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let cpus = args.value_of("cpus").map(parse_cpu_list).transpose()?;
+
+        match self.client.repin(name, cpus, args.is_present("save"))? {
+            Some(cpus) => {
+                for (vcpu, cpu) in cpus.iter().enumerate() {
+                    println!("vcpu {}\tcpu {}", vcpu, cpu);
+                }
+            }
+            None => println!("Not pinned, the VM has more vCPU's than the host has CPU's"),
+        }
+
+        Ok(())
+    }
+
+    fn cdrom(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let drive = args
+            .value_of("drive")
+            .map(usize::from_str)
+            .transpose()
+            .context("Drive should be a number")?
+            .unwrap_or(0);
+        let (insert, eject) = match args.subcommand() {
+            ("insert", Some(insert_args)) => (cdrom_paths(insert_args)?.pop(), false),
+            ("eject", _) => (None, true),
+            _ => (None, false),
+        };
+
+        let drives = self.client.cdrom(name, drive, insert, eject)?;
+        for (i, drive) in drives.iter().enumerate() {
+            let mut state = drive.path.clone().unwrap_or_else(|| "empty".to_string());
+            if drive.tray_open {
+                state.push_str(" (open)");
+            } else if drive.locked {
+                state.push_str(" (locked)");
+            }
+
+            println!("{}\t{}", i, state);
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let snapshot = |args: Option<&ArgMatches>| {
+            args.and_then(|x| x.value_of("snapshot"))
+                .map(|x| x.to_string())
+        };
+        let (create, revert, delete) = match args.subcommand() {
+            ("create", args) => (snapshot(args), None, None),
+            ("revert", args) => (None, snapshot(args), None),
+            ("delete", args) => (None, None, snapshot(args)),
+            _ => (None, None, None),
+        };
+
+        for group in self.client.snapshot(name, create, revert, delete)? {
+            println!(
+                "{}\t{}\t{} disk(s)\t{}",
+                group.name,
+                format_time(group.created),
+                group.disks.len(),
+                match (group.live, group.frozen) {
+                    (true, true) => "live, filesystems frozen",
+                    (true, false) => "live, crash consistent",
+                    (false, _) => "offline",
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    fn fsfreeze(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let timeout = |args: &ArgMatches| {
+            u64::from_str(args.value_of("timeout").unwrap())
+                .context("Timeout should be a number of seconds")
+        };
+
+        match args.subcommand() {
+            ("freeze", Some(args)) => {
+                let name = self.get_vm_name(args)?;
+                let frozen = self.client.fsfreeze(name, Some(timeout(args)?), false)?;
+                println!("Froze {} filesystem(s)", frozen.filesystems);
+            }
+
+            ("thaw", Some(args)) => {
+                let name = self.get_vm_name(args)?;
+                let thawed = self.client.fsfreeze(name, None, true)?;
+                println!("Thawed {} filesystem(s)", thawed.filesystems);
+            }
+
+            ("run", Some(args)) => {
+                let name = self.get_vm_name(args)?;
+                let command = args.values_of("command").unwrap().collect::<Vec<_>>();
+                let frozen = match self
+                    .client
+                    .fsfreeze(name.clone(), Some(timeout(args)?), false)
+                {
+                    Ok(_) => true,
+                    Err(err) if !args.is_present("strict") => {
+                        eprintln!(
+                            "warning: couldn't freeze the filesystems of {}, running {} anyway: {:#}",
+                            name, command[0], err
+                        );
+                        false
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                let status = Command::new(command[0]).args(&command[1..]).status();
+                if frozen {
+                    self.client.fsfreeze(name, None, true)?;
+                }
+
+                let status = status.with_context(|| format!("Failed to run {}", command[0]))?;
+                if !status.success() {
+                    anyhow::bail!("{} failed ({})", command[0], status);
+                }
+            }
+
+            (_, Some(args)) => {
+                let name = self.get_vm_name(args)?;
+                println!("{}", self.client.fsfreeze(name, None, false)?.status);
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn pci(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let address = |args: &ArgMatches| {
+            args.value_of("address")
+                .map(PciAddress::from_str)
+                .transpose()
+                .context("Address should be a PCI address, e.g. 0000:03:00.0")
+        };
+
+        let (name, attach, detach, force) = match args.subcommand() {
+            ("attach", Some(attach_args)) => (
+                self.get_vm_name(attach_args)?,
+                address(attach_args)?,
+                None,
+                attach_args.is_present("force"),
+            ),
+            ("detach", Some(detach_args)) => (
+                self.get_vm_name(detach_args)?,
+                None,
+                address(detach_args)?,
+                false,
+            ),
+            _ => (self.get_vm_name(args)?, None, None, false),
+        };
+
+        let slots = self.client.pci(name, attach, detach, force)?;
+        if slots.is_empty() {
+            println!("No hotplug slots, set machine.pci-hotplug-slots to add them");
+        }
+
+        for slot in slots {
+            let device = match slot.address {
+                Some(address) if slot.detaching => format!("{} (detaching)", address),
+                Some(address) => address.to_string(),
+                None => "empty".to_string(),
+            };
+            println!("{}\t{}", slot.slot, device);
+        }
+
+        Ok(())
+    }
+
+    fn usb(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let (name, attach, detach) = match args.subcommand() {
+            ("attach", Some(attach_args)) => (
+                self.get_vm_name(attach_args)?,
+                attach_args.value_of("device").map(|x| x.to_string()),
+                None,
+            ),
+            ("detach", Some(detach_args)) => (
+                self.get_vm_name(detach_args)?,
+                None,
+                detach_args.value_of("device").map(|x| x.to_string()),
+            ),
+            _ => (self.get_vm_name(args)?, None, None),
+        };
+
+        let devices = self.client.usb(name, attach, detach)?;
+        for device in devices {
+            let source = if device.hotplugged {
+                "attached"
+            } else {
+                "config"
+            };
+            println!("{}\t{}\t{}", device.id, device.device, source);
+        }
+
+        Ok(())
+    }
+
+    fn bench(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let duration = args
+            .value_of("duration")
+            .map(u64::from_str)
+            .transpose()
+            .context("Duration should be a number of seconds")?;
+
+        let results = self.client.bench(name, duration)?;
+
+        println!("mode\tcpu\tsamples\tmin (us)\tavg (us)\tmax (us)");
+        for LatencyResult {
+            mode,
+            cpu,
+            samples,
+            min_ns,
+            avg_ns,
+            max_ns,
+            error,
+        } in results
+        {
+            let cpu = cpu.map_or_else(|| "-".to_string(), |x| x.to_string());
+            if let Some(error) = error {
+                println!("{}\t{}\tfailed: {}", mode, cpu, error);
+                continue;
+            }
+
+            println!(
+                "{}\t{}\t{}\t{:.1}\t{:.1}\t{:.1}",
+                mode,
+                cpu,
+                samples,
+                min_ns as f64 / 1000.0,
+                avg_ns as f64 / 1000.0,
+                max_ns as f64 / 1000.0
+            );
+        }
+
+        Ok(())
+    }
+
+    fn vfio_recover(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let dry_run = args.is_present("dry-run");
+        let devices = self.client.vfio_recover(dry_run)?;
+        if devices.is_empty() {
+            println!("No devices are left bound to vfio-pci");
+            return Ok(());
+        }
+
+        for VfioBinding { address, driver } in devices {
+            let driver = if driver.is_empty() { "-" } else { &driver };
+            if dry_run {
+                println!("{}	{}	would be released", address, driver);
+            } else {
+                println!("{}	{}	released", address, driver);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn uefi_boot_entries(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let parse_ids = |values: clap::Values| {
+            values
+                .map(|x| {
+                    u16::from_str_radix(x.trim_start_matches("Boot"), 16)
+                        .with_context(|| format!("'{}' is not a valid boot entry id", x))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        let order = args.values_of("order").map(parse_ids).transpose()?;
+        let delete = args
+            .values_of("delete")
+            .map(parse_ids)
+            .transpose()?
+            .unwrap_or_default();
+
+        let entries = self.client.uefi_boot_entries(name, order, delete)?;
+
+        for UefiBootEntry {
+            id,
+            description,
+            active,
+            position,
+        } in entries
+        {
+            println!(
+                "Boot{:04X}\t{}\t{}\t{}",
+                id,
+                position.map_or_else(|| "-".to_string(), |x| x.to_string()),
+                if active { "active" } else { "inactive" },
+                description
+            )
+        }
+
+        Ok(())
+    }
+
+    fn uefi_vars_path(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let resp = self.client.uefi_vars_path(name)?;
+        println!("{}", resp.path);
+        if !resp.exists {
+            eprintln!("warning: it doesn't exist yet, it's created when the VM is first started");
+        }
+
+        Ok(())
+    }
+
+    fn uefi_reset(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let resp = self.client.uefi_reset(name.clone())?;
+        println!("Reset the UEFI variables of {} ({})", name, resp.path);
+        if let Some(backup) = resp.backup {
+            println!("The previous ones were kept at {}", backup);
+        }
+
+        Ok(())
+    }
+
     fn stop(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
         let name = self.get_vm_name(args)?;
+        if args.is_present("detach") {
+            return self.client.stop_detached(name);
+        }
+
         self.client.stop(name)?;
         Ok(())
     }
+
+    fn release(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let devices = self.client.release(name)?;
+        if devices.is_empty() {
+            println!("No devices were bound to vfio-pci by vore");
+        }
+
+        for address in devices {
+            println!("{}\treleased", address);
+        }
+
+        Ok(())
+    }
+
+    fn unload(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        self.client.unload(name)?;
+        Ok(())
+    }
+
+    fn clean(&mut self, args: &ArgMatches) -> anyhow::Result<()> {
+        let name = self.get_vm_name(args)?;
+        let removed = self.client.clean(name)?;
+        if removed.is_empty() {
+            println!("Nothing was left behind");
+        }
+
+        for path in removed {
+            println!("{}\tremoved", path);
+        }
+
+        Ok(())
+    }
 }
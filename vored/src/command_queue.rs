@@ -0,0 +1,117 @@
+// Queue of the commands the daemon still has to handle
+//
+// Every connection gets its own FIFO, and those are drained round-robin one command at a time,
+// so the commands of a connection are handled in the order they were sent, and a connection
+// sending a lot of them can't keep the others waiting until it's done
+
+use crate::slots::SlotId;
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+pub struct CommandQueue<T> {
+    /// Connections with queued commands, in the order they get their next turn,
+    /// None for commands of connections that went away but asked to be executed regardless
+    queues: VecDeque<(Option<SlotId>, VecDeque<T>)>,
+}
+
+impl<T> Default for CommandQueue<T> {
+    fn default() -> Self {
+        CommandQueue {
+            queues: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> CommandQueue<T> {
+    /// Queue [item] after everything else [connection] queued
+    pub fn push(&mut self, connection: Option<SlotId>, item: T) {
+        if let Some((_, queue)) = self.queues.iter_mut().find(|(id, _)| *id == connection) {
+            queue.push_back(item);
+        } else {
+            self.queues
+                .push_back((connection, VecDeque::from(vec![item])));
+        }
+    }
+
+    /// The oldest command of the connection whose turn it is
+    pub fn pop(&mut self) -> Option<(Option<SlotId>, T)> {
+        let (connection, mut queue) = self.queues.pop_front()?;
+        let item = queue.pop_front()?;
+        if !queue.is_empty() {
+            self.queues.push_back((connection, queue));
+        }
+
+        Some((connection, item))
+    }
+
+    /// Remove everything [connection] queued, oldest first
+    pub fn take(&mut self, connection: Option<SlotId>) -> VecDeque<T> {
+        match self.queues.iter().position(|(id, _)| *id == connection) {
+            Some(index) => self
+                .queues
+                .remove(index)
+                .map(|(_, x)| x)
+                .unwrap_or_default(),
+            None => VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_queue::CommandQueue;
+    use crate::slots::Slots;
+
+    #[test]
+    fn test_connection_order_is_kept() {
+        let mut slots = Slots::default();
+        let a = Some(slots.insert(()));
+        let mut queue = CommandQueue::default();
+        queue.push(a, 1);
+        queue.push(a, 2);
+        queue.push(a, 3);
+
+        assert_eq!(queue.pop(), Some((a, 1)));
+        assert_eq!(queue.pop(), Some((a, 2)));
+        assert_eq!(queue.pop(), Some((a, 3)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_interleaved_connections_make_progress() {
+        let mut slots = Slots::default();
+        let flood = Some(slots.insert(()));
+        let other = Some(slots.insert(()));
+        let mut queue = CommandQueue::default();
+        for i in 0..100 {
+            queue.push(flood, i);
+        }
+        queue.push(other, 1000);
+        queue.push(None, 2000);
+        queue.push(other, 1001);
+
+        assert_eq!(queue.pop(), Some((flood, 0)));
+        assert_eq!(queue.pop(), Some((other, 1000)));
+        assert_eq!(queue.pop(), Some((None, 2000)));
+        assert_eq!(queue.pop(), Some((flood, 1)));
+        assert_eq!(queue.pop(), Some((other, 1001)));
+        assert_eq!(queue.pop(), Some((flood, 2)));
+        assert_eq!(queue.pop(), Some((flood, 3)));
+    }
+
+    #[test]
+    fn test_take_connection() {
+        let mut slots = Slots::default();
+        let a = Some(slots.insert(()));
+        let b = Some(slots.insert(()));
+        let mut queue = CommandQueue::default();
+        queue.push(a, 1);
+        queue.push(b, 2);
+        queue.push(a, 3);
+
+        assert_eq!(queue.take(a).into_iter().collect::<Vec<_>>(), vec![1, 3]);
+        assert!(queue.take(a).is_empty());
+        assert_eq!(queue.pop(), Some((b, 2)));
+        assert_eq!(queue.pop(), None);
+    }
+}
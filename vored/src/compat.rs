@@ -0,0 +1,356 @@
+// virsh-like calls for automation that was written against libvirt, while it's moved to vore
+//
+// This isn't the libvirt RPC protocol, only the basic domain calls under their virsh names, as
+// JSON-RPC 2.0 with one call per line. Existing tooling (e.g. the Terraform libvirt provider or
+// cockpit-machines) needs a small adapter translating its calls to these. Every call is answered by
+// running the vore call that does the same, so they behave exactly like `vore start` etc.
+
+use serde_json::{json, Value};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+use vore_core::rpc::{self, AllRequests, AllResponses, ErrorCode, Request, RpcError};
+use vore_core::{VirtualMachineInfo, VirtualMachineState};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The vore call failed, its error code is in the data of the error
+const CALL_FAILED: i64 = -32000;
+
+/// Longest call line that's read, it's parsed as it is when it gets this long
+const MAX_CALL: usize = 64 * 1024;
+
+/// How long a client gets to send its call and read the answer before the connection is dropped
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompatMethod {
+    /// Only the running and paused domains, unless all is set, like `virsh list [--all]`
+    List {
+        all: bool,
+    },
+    DomState(String),
+    DomInfo(String),
+    Start(String),
+    Shutdown(String),
+    Destroy(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct CompatCall {
+    id: Value,
+    method: CompatMethod,
+}
+
+impl CompatCall {
+    /// Parse a call, or give the error answer for it
+    pub fn parse(line: &str) -> Result<CompatCall, String> {
+        let call = serde_json::from_str::<Value>(line)
+            .map_err(|err| error_answer(&Value::Null, PARSE_ERROR, &err.to_string(), None))?;
+        let id = call.get("id").cloned().unwrap_or(Value::Null);
+        let method = call
+            .get("method")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| error_answer(&id, INVALID_REQUEST, "No method given", None))?;
+        let params = call.get("params").cloned().unwrap_or(Value::Null);
+        let domain = || {
+            params
+                .get("domain")
+                .and_then(|x| x.as_str())
+                .map(|x| x.to_string())
+                .ok_or_else(|| {
+                    error_answer(
+                        &id,
+                        INVALID_PARAMS,
+                        &format!("{} needs a domain", method),
+                        None,
+                    )
+                })
+        };
+
+        let method = match method {
+            "list" => CompatMethod::List {
+                all: params.get("all").and_then(|x| x.as_bool()).unwrap_or(false),
+            },
+            "domstate" => CompatMethod::DomState(domain()?),
+            "dominfo" => CompatMethod::DomInfo(domain()?),
+            "start" => CompatMethod::Start(domain()?),
+            "shutdown" => CompatMethod::Shutdown(domain()?),
+            "destroy" => CompatMethod::Destroy(domain()?),
+            _ => {
+                return Err(error_answer(
+                    &id,
+                    METHOD_NOT_FOUND,
+                    &format!("Unknown method {}", method),
+                    None,
+                ))
+            }
+        };
+
+        Ok(CompatCall { id, method })
+    }
+
+    /// The vore call that does what this call asks
+    pub fn request(&self) -> AllRequests {
+        match &self.method {
+            CompatMethod::List { .. } | CompatMethod::DomState(_) | CompatMethod::DomInfo(_) => {
+                rpc::ListRequest {}.into_enum()
+            }
+            CompatMethod::Start(name) => rpc::StartRequest {
+                name: name.clone(),
+                cdroms: vec![],
+                boot: None,
+                progress: false,
+            }
+            .into_enum(),
+            CompatMethod::Shutdown(name) => rpc::StopRequest { name: name.clone() }.into_enum(),
+            CompatMethod::Destroy(name) => rpc::KillRequest { name: name.clone() }.into_enum(),
+        }
+    }
+
+    /// The answer to this call, from the outcome of its vore call
+    pub fn answer(&self, response: Result<AllResponses, anyhow::Error>) -> String {
+        match response.and_then(|x| self.result(x)) {
+            Ok(result) => {
+                let mut answer =
+                    json!({ "jsonrpc": "2.0", "id": self.id, "result": result }).to_string();
+                answer.push('\n');
+                answer
+            }
+            Err(err) => {
+                let code = err
+                    .chain()
+                    .find_map(|x| x.downcast_ref::<RpcError>())
+                    .map_or(ErrorCode::Other, |x| x.code);
+                error_answer(&self.id, CALL_FAILED, &format!("{:#}", err), Some(code))
+            }
+        }
+    }
+
+    fn result(&self, response: AllResponses) -> Result<Value, anyhow::Error> {
+        let items = match response {
+            AllResponses::List(list) => list.items,
+            _ => return Ok(Value::Null),
+        };
+
+        let find = |name: &str| {
+            items
+                .iter()
+                .find(|x| x.name == name)
+                .ok_or_else(|| anyhow::Error::from(RpcError::vm_not_found(name)))
+        };
+
+        Ok(match &self.method {
+            CompatMethod::List { all } => {
+                let mut domains = items
+                    .iter()
+                    .filter(|x| *all || is_active(x.state))
+                    .map(|x| {
+                        json!({
+                            "id": domain_id(x),
+                            "name": x.name,
+                            "state": domain_state(x.state),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                domains.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+                Value::Array(domains)
+            }
+            CompatMethod::DomState(name) => json!(domain_state(find(name)?.state)),
+            CompatMethod::DomInfo(name) => {
+                let info = find(name)?;
+                json!({
+                    "id": domain_id(info),
+                    "name": info.name,
+                    "state": domain_state(info.state),
+                    "cpus": info.config.cpu.amount,
+                    "max-memory-kib": info.config.memory * 1024,
+                    "autostart": info.config.auto_start,
+                })
+            }
+            _ => Value::Null,
+        })
+    }
+}
+
+/// A connection making one call, read and answered as the poller says it's ready, so a client that
+/// stays silent or doesn't read its answer can't hold up the daemon
+#[derive(Debug)]
+pub struct CompatConnection {
+    pub stream: UnixStream,
+    pub accepted: Instant,
+    call: Vec<u8>,
+    /// The answer, and how much of it is written, once the call is in
+    answer: Option<(Vec<u8>, usize)>,
+}
+
+impl CompatConnection {
+    pub fn new(stream: UnixStream) -> Result<CompatConnection, io::Error> {
+        stream.set_nonblocking(true)?;
+        Ok(CompatConnection {
+            stream,
+            accepted: Instant::now(),
+            call: vec![],
+            answer: None,
+        })
+    }
+
+    /// Read what's there of the call, its line once all of it is in
+    pub fn read_call(&mut self) -> Result<Option<String>, io::Error> {
+        let mut buffer = [0u8; 1024];
+        while !self.call.contains(&b'\n') && self.call.len() < MAX_CALL {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(amount) => self.call.extend_from_slice(&buffer[..amount]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let line = self.call.split(|x| *x == b'\n').next().unwrap_or_default();
+        Ok(Some(String::from_utf8_lossy(line).to_string()))
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.answer.is_some()
+    }
+
+    pub fn answer(&mut self, answer: String) {
+        self.answer = Some((answer.into_bytes(), 0));
+    }
+
+    /// Write what the socket takes of the answer, true once all of it is written
+    pub fn write_answer(&mut self) -> Result<bool, io::Error> {
+        let (answer, written) = match &mut self.answer {
+            Some(answer) => answer,
+            None => return Ok(false),
+        };
+
+        while *written < answer.len() {
+            match self.stream.write(&answer[*written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(amount) => *written += amount,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+fn error_answer(id: &Value, code: i64, message: &str, vore_code: Option<ErrorCode>) -> String {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(vore_code) = vore_code {
+        error["data"] = json!({ "code": vore_code.to_string() });
+    }
+
+    let mut answer = json!({ "jsonrpc": "2.0", "id": id, "error": error }).to_string();
+    answer.push('\n');
+    answer
+}
+
+fn is_active(state: VirtualMachineState) -> bool {
+    matches!(
+        state,
+        VirtualMachineState::Running | VirtualMachineState::Paused
+    )
+}
+
+/// State as virsh names it, there's nothing like loaded or prepared in libvirt
+fn domain_state(state: VirtualMachineState) -> &'static str {
+    match state {
+        VirtualMachineState::Running => "running",
+        VirtualMachineState::Paused => "paused",
+        _ => "shut off",
+    }
+}
+
+/// libvirt numbers the running domains, the pid of QEMU is the closest vore has
+fn domain_id(info: &VirtualMachineInfo) -> Value {
+    match &info.runtime {
+        Some(runtime) if is_active(info.state) => json!(runtime.pid),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compat::{CompatCall, CompatConnection, CompatMethod};
+    use serde_json::Value;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use vore_core::rpc::{AllRequests, RpcError};
+
+    #[test]
+    fn test_parse() {
+        let call = CompatCall::parse(
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "shutdown", "params": {"domain": "win10"}}"#,
+        )
+        .unwrap();
+        assert_eq!(call.method, CompatMethod::Shutdown("win10".to_string()));
+        assert!(matches!(call.request(), AllRequests::Stop(x) if x.name == "win10"));
+
+        let call =
+            CompatCall::parse(r#"{"id": 1, "method": "list", "params": {"all": true}}"#).unwrap();
+        assert_eq!(call.method, CompatMethod::List { all: true });
+
+        for (line, code) in &[
+            ("{", -32700),
+            (r#"{"id": 2}"#, -32600),
+            (
+                r#"{"id": 2, "method": "undefine", "params": {"domain": "a"}}"#,
+                -32601,
+            ),
+            (r#"{"id": 2, "method": "start"}"#, -32602),
+        ] {
+            let answer =
+                serde_json::from_str::<Value>(&CompatCall::parse(line).unwrap_err()).unwrap();
+            assert_eq!(answer["error"]["code"], *code, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_error_answer() {
+        let call =
+            CompatCall::parse(r#"{"id": "a", "method": "start", "params": {"domain": "x"}}"#)
+                .unwrap();
+        let answer = call.answer(Err(RpcError::vm_not_found("x").into()));
+        let answer = serde_json::from_str::<Value>(&answer).unwrap();
+        assert_eq!(answer["id"], "a");
+        assert_eq!(answer["error"]["code"], -32000);
+        assert_eq!(answer["error"]["data"]["code"], "vm_not_found");
+    }
+
+    #[test]
+    fn test_connection_in_parts() {
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        let mut connection = CompatConnection::new(stream).unwrap();
+
+        // A client that stays silent doesn't block
+        assert_eq!(connection.read_call().unwrap(), None);
+        client.write_all(br#"{"id": 1, "method": "#).unwrap();
+        assert_eq!(connection.read_call().unwrap(), None);
+        assert!(!connection.write_answer().unwrap());
+
+        client.write_all(b"\"list\"}\n").unwrap();
+        assert_eq!(
+            connection.read_call().unwrap().as_deref(),
+            Some(r#"{"id": 1, "method": "list"}"#)
+        );
+
+        connection.answer("{}\n".to_string());
+        assert!(connection.is_answered());
+        assert!(connection.write_answer().unwrap());
+        drop(connection);
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "{}\n");
+    }
+}
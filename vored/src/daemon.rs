@@ -1,24 +1,47 @@
+use crate::command_queue::CommandQueue;
+use crate::compat::{self, CompatCall, CompatConnection};
+use crate::event_targets::{EventTarget, EventTargets};
+use crate::events::Subscription;
+use crate::lease::{Lease, LeaseHolder, LeaseRenewer};
+use crate::logind::Logind;
+use crate::metrics;
+use crate::metrics::Scrape;
+use crate::notify::Notifier;
+use crate::self_check::self_check;
+use crate::slots::{SlotId, Slots};
 use anyhow::Context;
 use polling::{Event, Poller};
-use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::consts::{SIGCHLD, SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::{Handle, Signals, SignalsInfo};
 use signal_hook::low_level::signal_name;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::{read_dir, read_to_string, DirEntry};
 use std::io::{Read, Write};
 use std::mem::size_of;
+use std::net::TcpListener;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{io, mem};
 use vore_core::consts::{VORE_CONFIG, VORE_DIRECTORY, VORE_SOCKET};
-use vore_core::rpc::{AllRequests, AllResponses, Command, CommandCenter, DiskPreset, Response};
+use vore_core::rpc::{
+    AllRequests, AllResponses, Command, CommandCenter, DiskPreset, ErrorCode, EventKind,
+    LatencyResult, Response, RpcError, StartProgress, VfioBinding, VmEvent,
+};
 use vore_core::utils::get_username_by_uid;
-use vore_core::{rpc, QemuCommandBuilder, VirtualMachineInfo};
-use vore_core::{GlobalConfig, InstanceConfig, VirtualMachine};
+use vore_core::{
+    format_cpu_list, release_vfio_binding, set_definition_value, stale_vfio_bindings, GlobalConfig,
+    GuestAction, InstanceConfig, LegacyKeys, Reattach, RestartPolicy, UsbConfig, VirtualMachine,
+    VirtualMachineExit, QMP_SOCKET_TIMEOUT,
+};
+use vore_core::{
+    rpc, with_log_context, QemuCommandBuilder, VirtualMachineInfo, VirtualMachineState,
+};
 
 #[derive(Debug)]
 struct RpcConnection {
@@ -28,6 +51,9 @@ struct RpcConnection {
     uid: u32,
     user: Option<String>,
     pid: i32,
+    /// Event key the connection is registered in the poller with
+    key: usize,
+    subscription: Option<Subscription>,
 }
 
 impl Write for RpcConnection {
@@ -49,11 +75,22 @@ impl Read for RpcConnection {
 #[allow(clippy::char_lit_as_u8)]
 const NEWLINE: u8 = '\n' as u8;
 
+/// Artifacts are sent inline in the answer, so keep them small
+const MAX_ARTIFACT_SIZE: u64 = 4 * 1024 * 1024;
+
 impl RpcConnection {
+    /// What to wait for on the connection, writable too while events are waiting to be written
+    fn interest(&self) -> Event {
+        match &self.subscription {
+            Some(subscription) if !subscription.is_drained() => Event::all(self.key),
+            _ => Event::readable(self.key),
+        }
+    }
+
     pub fn handle_input(
         &mut self,
-        own_id: usize,
-    ) -> Result<(bool, Vec<(usize, Command)>), anyhow::Error> {
+        own_id: SlotId,
+    ) -> Result<(bool, Vec<(SlotId, Command)>), anyhow::Error> {
         let mut still_open = true;
         loop {
             let mut buffer = vec![0u8; 4096];
@@ -91,8 +128,12 @@ impl RpcConnection {
             let lossy = String::from_utf8_lossy(part);
 
             match CommandCenter::read_command(&lossy) {
-                Ok(cmd) => {
-                    log::debug!("Got command: {:?}", cmd);
+                Ok(mut cmd) => {
+                    cmd.request_id = Some(format!(
+                        "{}.{} uid={} pid={}",
+                        own_id.index, cmd.id, self.uid, self.pid
+                    ));
+                    in_request(&cmd, || log::debug!("Got command: {:?}", cmd.data));
                     commands.push((own_id, cmd));
                 }
 
@@ -106,76 +147,326 @@ impl RpcConnection {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-enum EventTarget {
-    RpcListener,
-    Machine(String),
-    RpcConnection(usize),
-    None,
+#[derive(Debug)]
+struct PendingWait {
+    connection: SlotId,
+    command: Command,
+    name: String,
+    state: VirtualMachineState,
+    deadline: Option<Instant>,
+}
+
+/// A bench measuring on its own thread, answered once it's done
+#[derive(Debug)]
+struct PendingBench {
+    connection: SlotId,
+    command: Command,
+    handle: JoinHandle<Vec<LatencyResult>>,
 }
 
 #[derive(Debug)]
 pub struct Daemon {
-    event_key_storage: Vec<EventTarget>,
+    event_targets: EventTargets,
     global_config: GlobalConfig,
     machines: HashMap<String, VirtualMachine>,
-    connections: Vec<Option<RpcConnection>>,
+    connections: Slots<RpcConnection>,
     rpc_listener: UnixListener,
+    metrics_listener: Option<TcpListener>,
+    /// Scrapes being read or answered, by their event key
+    scrapes: HashMap<usize, Scrape>,
+    compat_listener: Option<(UnixListener, PathBuf)>,
+    /// Virsh-like calls being read or answered, by their event key
+    compat_connections: HashMap<usize, CompatConnection>,
     socket_path: PathBuf,
-    poller: Poller,
+    /// Where the definitions and the working directories of the machines are
+    directory: PathBuf,
+    /// Shared with the threads that have to wake up the daemon when they're done
+    poller: Arc<Poller>,
     signals: SignalsInfo,
     signals_handle: Handle,
     queue: Vec<Event>,
-    command_queue: Vec<(usize, Command)>,
+    /// Commands to execute, per connection to answer on, None if that connection is gone
+    command_queue: CommandQueue<Command>,
+    pending_waits: Vec<PendingWait>,
+    pending_benches: Vec<PendingBench>,
+    auto_start_queue: VecDeque<String>,
+    next_auto_start: Option<Instant>,
+    /// Since when the first machine in the auto-start queue waits for its host requirements
+    auto_start_waiting: Option<Instant>,
+    pending_restarts: Vec<(Instant, String)>,
+    /// Restarts in a row per machine, with the time of the last one
+    restart_attempts: HashMap<String, (u32, Instant)>,
+    /// Machines that won't get a standby QEMU until they're prepared or started again
+    standby_held: HashSet<String>,
+    /// Connection and start command to report the progress of the start that's running to
+    start_progress: Option<(SlotId, Command)>,
+    notifier: Notifier,
+    logind: Option<Logind>,
+    /// Until when the guests get to shut down, since the host is shutting down
+    host_shutdown: Option<Instant>,
+    /// Lease that makes this the vored managing the machines, if failover is set up
+    lease: Option<LeaseRenewer>,
+    /// Machines that were running on a peer that went away, to start here
+    peer_machines: Vec<String>,
+    /// State of every machine as last told to the subscribers
+    published_states: HashMap<String, VirtualMachineState>,
 }
 
+/// Run [f] with the request id of [command] in front of everything it logs
+fn in_request<T, F: FnOnce() -> T>(command: &Command, f: F) -> T {
+    match &command.request_id {
+        Some(request_id) => with_log_context(request_id, f),
+        None => f(),
+    }
+}
+
+/// A machine that stayed up this long since its last restart starts counting retries from 0 again
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(600);
+
+/// Time between checks of the host requirements of a machine waiting to be auto-started
+const HOST_REQUIREMENT_INTERVAL: Duration = Duration::from_secs(2);
+
 impl Daemon {
-    pub fn new() -> Result<Daemon, anyhow::Error> {
+    /// With [standby] this waits until the vored holding the lease goes away, and takes over from it
+    pub fn new(standby: bool) -> Result<Daemon, anyhow::Error> {
         log::debug!("Loading global config ({})", VORE_CONFIG);
         let toml = std::fs::read_to_string(VORE_CONFIG)?;
-        let mut global_config = GlobalConfig::load(&toml)?;
+        let global_config = GlobalConfig::load(&toml)?;
+        Daemon::with_paths(
+            global_config,
+            PathBuf::from_str(VORE_SOCKET)?,
+            PathBuf::from(VORE_DIRECTORY),
+            standby,
+        )
+    }
+
+    /// Daemon listening on [socket_path], keeping the definitions and working directories of the
+    /// machines in [directory]
+    fn with_paths(
+        mut global_config: GlobalConfig,
+        socket_path: PathBuf,
+        directory: PathBuf,
+        standby: bool,
+    ) -> Result<Daemon, anyhow::Error> {
+        self_check(&mut global_config)?;
+        let lease = Daemon::take_lease(&global_config, standby, &socket_path)?;
         log::debug!("Creating vore daemon");
-        let signals = Signals::new(&[SIGINT, SIGHUP])?;
+        let signals = Signals::new(&[SIGINT, SIGHUP, SIGCHLD])?;
         let handle = signals.handle();
         log::debug!("Bound signal handlers");
-        let poller = Poller::new().context("Failed to make poller")?;
-        let socket_path = PathBuf::from_str(VORE_SOCKET)?;
+        let poller = Arc::new(Poller::new().context("Failed to make poller")?);
+        let lease = lease
+            .map(|lease| {
+                let poller = poller.clone();
+                LeaseRenewer::spawn(lease, move || {
+                    if let Err(err) = poller.notify() {
+                        log::warn!(
+                            "Failed to wake up the daemon after losing the lease: {:?}",
+                            err
+                        );
+                    }
+                })
+            })
+            .transpose()?;
         let rpc_listener =
             UnixListener::bind(&socket_path).context("Failed to bind vore socket")?;
 
         global_config.vore.chown(socket_path.to_str().unwrap())?;
 
         rpc_listener.set_nonblocking(true)?;
-        log::debug!("Bound to {}", VORE_SOCKET);
+        log::debug!("Bound to {:?}", socket_path);
+
+        let metrics_listener = global_config
+            .metrics
+            .listen
+            .as_ref()
+            .map(|listen| -> Result<TcpListener, anyhow::Error> {
+                let listener = TcpListener::bind(listen)
+                    .with_context(|| format!("Failed to bind metrics listener on {}", listen))?;
+                listener.set_nonblocking(true)?;
+                log::info!("Serving prometheus metrics on http://{}/metrics", listen);
+                Ok(listener)
+            })
+            .transpose()?;
+
+        let compat_listener = match global_config.compat.listen.clone() {
+            Some(listen) => {
+                // The vore socket is bound by now, so this was left behind by a vored that's gone
+                match fs::remove_file(&listen) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                        return Err(err).with_context(|| {
+                            format!("Failed to remove stale compat socket {}", listen)
+                        })
+                    }
+                    _ => {}
+                }
+
+                let listener = UnixListener::bind(&listen)
+                    .with_context(|| format!("Failed to bind compat socket {}", listen))?;
+                global_config.vore.chown(&listen)?;
+                listener.set_nonblocking(true)?;
+                log::info!("Serving virsh-like calls on {}", listen);
+                Some((listener, PathBuf::from(listen)))
+            }
+            None => None,
+        };
 
         let mut daemon = Daemon {
-            event_key_storage: vec![],
+            event_targets: EventTargets::default(),
             global_config,
             machines: Default::default(),
-            connections: vec![],
+            connections: Slots::default(),
             rpc_listener,
+            metrics_listener,
+            scrapes: HashMap::new(),
+            compat_listener,
+            compat_connections: HashMap::new(),
             poller,
             signals,
             signals_handle: handle,
             queue: vec![],
-            command_queue: vec![],
+            command_queue: Default::default(),
+            pending_waits: vec![],
+            pending_benches: vec![],
+            auto_start_queue: VecDeque::new(),
+            next_auto_start: None,
+            auto_start_waiting: None,
+            pending_restarts: vec![],
+            restart_attempts: HashMap::new(),
+            standby_held: HashSet::new(),
+            start_progress: None,
+            notifier: Notifier::from_env(),
+            logind: None,
+            host_shutdown: None,
+            lease,
+            peer_machines: vec![],
+            published_states: HashMap::new(),
             socket_path,
+            directory,
         };
 
         daemon.init()?;
         Ok(daemon)
     }
 
+    /// Become the vored managing the machines, if failover is set up. [socket_path] is where a
+    /// previous vored on this host would still answer
+    fn take_lease(
+        global_config: &GlobalConfig,
+        standby: bool,
+        socket_path: &Path,
+    ) -> Result<Option<Lease>, anyhow::Error> {
+        let path = match &global_config.failover.lease_file {
+            Some(path) => path,
+            None if standby => {
+                anyhow::bail!(
+                    "--standby needs failover.lease-file to be set in {}",
+                    VORE_CONFIG
+                )
+            }
+            None => return Ok(None),
+        };
+        let timeout = Duration::from_secs(global_config.failover.lease_timeout);
+
+        if !standby {
+            if let Some(holder) = Lease::live_holder(path, timeout)? {
+                anyhow::bail!(
+                    "The machines are managed by {} (lease {}), use --standby to take over when it goes away",
+                    holder,
+                    path
+                );
+            }
+
+            return Lease::acquire(path, timeout).map(Some);
+        }
+
+        let mut waiting_for: Option<LeaseHolder> = None;
+        loop {
+            let holder = Lease::live_holder(path, timeout)?;
+            // A primary on this host that still answers keeps its machines, even if it's late renewing
+            let primary_answers = UnixStream::connect(socket_path).is_ok();
+            if holder.is_none() && !primary_answers {
+                match Lease::acquire(path, timeout) {
+                    Ok(lease) => {
+                        match &waiting_for {
+                            Some(previous) => log::info!("Took over from {}", previous),
+                            None => log::info!("Took lease {}", path),
+                        }
+
+                        // Left behind by the previous vored, if it ran on this host
+                        match fs::remove_file(socket_path) {
+                            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                                return Err(err)
+                                    .context("Failed to remove socket of the previous vored")
+                            }
+                            _ => {}
+                        }
+
+                        return Ok(Some(lease));
+                    }
+                    Err(err) => log::warn!("Failed to take over: {:?}", err),
+                }
+            }
+
+            if let Some(holder) = holder {
+                let is_new = waiting_for
+                    .as_ref()
+                    .map_or(true, |x| x.host != holder.host || x.pid != holder.pid);
+                if is_new {
+                    log::info!("Standing by for {} (lease {})", holder, path);
+                    waiting_for = Some(holder);
+                }
+            }
+
+            std::thread::sleep(timeout / 3);
+        }
+    }
+
     pub fn init(&mut self) -> Result<(), anyhow::Error> {
-        let new_key = self.add_target(EventTarget::RpcListener);
+        let new_key = self.event_targets.add(EventTarget::RpcListener);
         self.poller
             .add(&self.rpc_listener, Event::readable(new_key))?;
 
+        if self.metrics_listener.is_some() {
+            let new_key = self.event_targets.add(EventTarget::MetricsListener);
+            if let Some(metrics_listener) = &self.metrics_listener {
+                self.poller
+                    .add(metrics_listener, Event::readable(new_key))?;
+            }
+        }
+
+        if self.compat_listener.is_some() {
+            let new_key = self.event_targets.add(EventTarget::CompatListener);
+            if let Some((compat_listener, _)) = &self.compat_listener {
+                self.poller.add(compat_listener, Event::readable(new_key))?;
+            }
+        }
+
+        if self.global_config.host_shutdown.enabled {
+            match Logind::monitor().and_then(|mut logind| logind.inhibit().map(|_| logind)) {
+                Ok(logind) => {
+                    let new_key = self.event_targets.add(EventTarget::Logind);
+                    self.poller
+                        .add(logind.stream(), Event::readable(new_key))?;
+                    self.logind = Some(logind);
+                }
+                Err(err) => log::warn!(
+                    "Can't watch for host shutdowns, VMs won't be shut down before the host is: {:?}",
+                    err
+                ),
+            }
+        }
+
         Ok(())
     }
 
+    /// Where the definition of machine [name] is saved
+    fn definition_path(&self, name: &str) -> String {
+        format!("{}/definitions/{}.toml", self.directory.display(), name)
+    }
+
     pub fn load_definitions(&mut self) -> Result<(), anyhow::Error> {
-        let vm_dir = PathBuf::from(format!("{}/definitions", VORE_DIRECTORY));
+        let vm_dir = self.directory.join("definitions");
         if !vm_dir.is_dir() {
             return Ok(());
         }
@@ -193,7 +484,7 @@ impl Daemon {
 
             let toml = read_to_string(path)
                 .with_context(|| format!("Failed to read VM definition {}", path))?;
-            self.load_virtual_machine(&toml, None, false)?;
+            self.load_virtual_machine(&toml, &[], None, false)?;
             Ok(())
         };
 
@@ -209,7 +500,8 @@ impl Daemon {
     pub fn reserve_vfio_devices(&mut self) {
         for machine in self.machines.values() {
             for vfio_device in machine.vfio_devices() {
-                if !vfio_device.reserve {
+                // SR-IOV virtual functions only exist once the VM is prepared
+                if !vfio_device.reserve || vfio_device.physical_function.is_some() {
                     continue;
                 }
 
@@ -232,211 +524,1729 @@ impl Daemon {
     }
 
     pub fn auto_start_machines(&mut self) {
-        for machine in self.machines.values_mut() {
-            if !machine.should_auto_start() {
-                continue;
+        let mut machines = self
+            .machines
+            .values()
+            .filter(|x| x.should_auto_start() || self.peer_machines.iter().any(|y| y == x.name()))
+            .map(|x| (x.auto_start_order(), x.name().to_string()))
+            .collect::<Vec<_>>();
+        machines.sort();
+
+        self.auto_start_queue = machines.into_iter().map(|(_, name)| name).collect();
+        self.auto_start_waiting = None;
+        self.schedule_auto_start();
+        self.handle_auto_start();
+    }
+
+    fn schedule_auto_start(&mut self) {
+        self.next_auto_start = self
+            .auto_start_queue
+            .front()
+            .and_then(|name| self.machines.get(name))
+            .map(|machine| Instant::now() + machine.auto_start_delay());
+    }
+
+    /// Start the machines in the auto-start queue whose delay has passed
+    pub fn handle_auto_start(&mut self) {
+        if self.host_shutdown.is_some() {
+            return;
+        }
+
+        while self
+            .next_auto_start
+            .map_or(false, |next| next <= Instant::now())
+        {
+            let name = if let Some(name) = self.auto_start_queue.front().cloned() {
+                name
+            } else {
+                break;
+            };
+
+            if let Some(machine) = self.machines.get(&name) {
+                let unmet = machine.unmet_host_requirements();
+                if !unmet.is_empty() {
+                    let timeout = machine.host_requirements_timeout();
+                    let since = match self.auto_start_waiting {
+                        Some(since) => since,
+                        None => {
+                            log::info!(
+                                "Waiting for {} before auto-starting {}",
+                                unmet.join(", "),
+                                name
+                            );
+                            let now = Instant::now();
+                            self.auto_start_waiting = Some(now);
+                            now
+                        }
+                    };
+
+                    // Later machines wait too, so the auto-start order is kept
+                    if timeout.map_or(true, |x| since.elapsed() < x) {
+                        self.next_auto_start = Some(Instant::now() + HOST_REQUIREMENT_INTERVAL);
+                        break;
+                    }
+
+                    log::error!(
+                        "Not auto-starting {}, {} still not there after {} seconds",
+                        name,
+                        unmet.join(", "),
+                        since.elapsed().as_secs()
+                    );
+                    self.auto_start_queue.pop_front();
+                    self.auto_start_waiting = None;
+                    self.schedule_auto_start();
+                    continue;
+                }
             }
 
-            if let Err(err) = machine.start() {
-                log::error!("Failed to auto-start {}: {:?}", machine.name(), err);
+            self.auto_start_queue.pop_front();
+            self.auto_start_waiting = None;
+            if let Err(err) = self.start_machine(&name, &[], None) {
+                log::error!("Failed to auto-start {}: {:?}", name, err);
             } else {
-                log::info!("Autostarted {}", machine.name());
+                log::info!("Autostarted {}", name);
             }
+
+            self.schedule_auto_start();
         }
     }
 
-    pub fn run(&mut self) -> Result<(), anyhow::Error> {
-        self.load_definitions()?;
-        self.reserve_vfio_devices();
-        self.auto_start_machines();
+    /// Start a machine and listen to its control socket
+    ///
+    /// If [boot] is given the machine boots from that device first, for this start only,
+    /// [cdroms] replace the CD-ROM's attached before if any are given
+    fn start_machine(
+        &mut self,
+        name: &str,
+        cdroms: &[String],
+        boot: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        // A standby QEMU may have gone away without being reaped yet
+        self.reap_machines()?;
+        self.standby_held.remove(name);
+
+        if let Some(machine) = self.machines.get_mut(name) {
+            let cdroms_changed = !cdroms.is_empty() && machine.set_cdroms(cdroms.to_vec())?;
+            machine.set_boot_once(boot)?;
+            // The standby QEMU was launched with the configured boot order, so it can't be used
+            if machine.boot_once().is_some() || cdroms_changed {
+                self.quit_standby(name)?;
+            }
+        } else {
+            return Err(RpcError::vm_not_found(name).into());
+        }
 
-        loop {
-            let res = self
-                .wait()
-                .context("Got error while waiting for new notifications");
-            match res {
-                // Interrupted is uh "always" when we get a signal
-                Err(err)
-                    if err
-                        .downcast_ref::<io::Error>()
-                        .map(|x| x.kind() == io::ErrorKind::Interrupted)
-                        .unwrap_or(false) =>
-                {
-                    if !self.handle_exit_code()? {
-                        break;
+        self.check_conflicts(name)?;
+
+        let mut start_progress = self.start_progress.take();
+        let connections = &mut self.connections;
+        let mut progress = |progress: StartProgress| {
+            let (id, command) = match &start_progress {
+                Some((id, command)) => (*id, command),
+                None => return,
+            };
+
+            let res = CommandCenter::write_progress(command, progress).and_then(|line| {
+                let conn = connections
+                    .get_mut(id)
+                    .context("RPC connection went away")?;
+                match &mut conn.subscription {
+                    // Behind whatever part of an event is still being written
+                    Some(subscription) => {
+                        subscription.send(line);
+                        subscription.flush(&mut conn.stream)?;
                     }
+                    None => conn.write_all(line.as_bytes())?,
                 }
-                err => err?,
-            }
 
-            if !self.handle_event_queue()? {
-                break;
+                Ok(())
+            });
+
+            // The start goes on without the client
+            if let Err(err) = res {
+                log::debug!("Failed to send start progress: {:?}", err);
+                start_progress = None;
             }
+        };
 
-            self.handle_command_queue()?;
+        let machine = self.machines.get_mut(name).unwrap();
+        let standby = machine.is_standby();
+        machine.start_with_progress(&mut progress)?;
+
+        // Already watched since it was launched
+        if standby {
+            return Ok(());
+        }
+
+        self.watch_machine(name)
+    }
+
+    /// VM's with QEMU launched that [name] can't run at the same time as, with the reason why
+    fn conflicting_machines(&self, name: &str) -> Vec<(String, String)> {
+        let machine = match self.machines.get(name) {
+            Some(machine) => machine,
+            None => return vec![],
+        };
+
+        self.machines
+            .values()
+            .filter(|x| x.name() != name && x.has_process())
+            .filter_map(|x| {
+                machine
+                    .conflict_with(x)
+                    .map(|reason| (x.name().to_string(), reason))
+            })
+            .collect()
+    }
+
+    /// Make sure [name] can start, conflicting VM's that are only on standby give way,
+    /// and don't launch a standby QEMU again until they're prepared or started
+    fn check_conflicts(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let conflicts = self.conflicting_machines(name);
+        if let Some((other, reason)) = conflicts
+            .iter()
+            .find(|(other, _)| !self.machines[other].is_standby())
+        {
+            return Err(RpcError::new(
+                ErrorCode::Conflict,
+                format!("Can't start {} while {} runs, {}", name, other, reason),
+            )
+            .with_detail("name", other)
+            .with_detail("reason", reason)
+            .into());
+        }
+
+        for (other, reason) in conflicts {
+            log::info!(
+                "Quitting the standby QEMU of {} to start {}, {}",
+                other,
+                name,
+                reason
+            );
+            self.quit_standby(&other)?;
+            self.standby_held.insert(other);
         }
 
-        // TODO: clean up
-        log::info!("vore daemon has ended");
-        std::fs::remove_file(&self.socket_path).context("Failed cleaning up socket")?;
         Ok(())
     }
 
-    pub fn handle_command_queue(&mut self) -> Result<(), anyhow::Error> {
-        while let Some((id, command)) = self.command_queue.pop() {
-            let resp = self.handle_command(&command);
-            if let Err(err) = &resp {
-                log::warn!("Command {:?} failed with error: {:?}", command, err)
+    /// Quit the standby QEMU of [name] if it has one, e.g. because it was launched with other drives
+    fn quit_standby(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let machine = match self.machines.get_mut(name) {
+            Some(machine) if machine.is_standby() => machine,
+            _ => return Ok(()),
+        };
+
+        let control_stream = machine.control_stream().cloned();
+        machine.quit()?;
+        if let Some(control_stream) = control_stream {
+            let _ = self.poller.delete(&control_stream);
+        }
+
+        self.event_targets.release_machine(name);
+        Ok(())
+    }
+
+    /// Launch a standby QEMU for the machines that want one and don't have one (anymore)
+    fn handle_standby(&mut self) {
+        if self.host_shutdown.is_some() {
+            return;
+        }
+
+        let names = self
+            .machines
+            .values()
+            .filter(|x| x.wants_standby() && !self.standby_held.contains(x.name()))
+            .filter(|x| self.conflicting_machines(x.name()).is_empty())
+            .map(|x| x.name().to_string())
+            .collect::<Vec<_>>();
+
+        for name in names {
+            let res = self
+                .machines
+                .get_mut(&name)
+                .unwrap()
+                .prelaunch()
+                .and_then(|_| self.watch_machine(&name));
+
+            if let Err(err) = res {
+                log::error!(
+                    "Failed to launch standby QEMU for {}, not trying again until it's prepared or started: {:?}",
+                    name,
+                    err
+                );
+                self.standby_held.insert(name);
+            }
+        }
+    }
+
+    fn watch_machine(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let cloned = self
+            .machines
+            .get(name)
+            .and_then(|x| x.control_stream())
+            .cloned();
+
+        if let Some(cloned) = cloned {
+            let new_id = self.event_targets.add_machine(name);
+            self.poller.add(&cloned, Event::readable(new_id))?;
+        }
+
+        let has_guest_actions = self
+            .machines
+            .get(name)
+            .and_then(|x| x.guest_actions_stream())
+            .is_some();
+
+        if has_guest_actions {
+            let new_id = self
+                .event_targets
+                .add(EventTarget::GuestActions(name.to_string()));
+            if let Some(stream) = self
+                .machines
+                .get(name)
+                .and_then(|x| x.guest_actions_stream())
+            {
+                self.poller.add(stream, Event::readable(new_id))?;
             }
+        }
 
-            if let Some(conn) = self.connections[id].as_mut() {
-                conn.write_all(CommandCenter::write_answer(&command, resp)?.as_bytes())?;
+        let has_clipboard = self
+            .machines
+            .get(name)
+            .and_then(|x| x.clipboard_stream())
+            .is_some();
+
+        if has_clipboard {
+            let new_id = self
+                .event_targets
+                .add(EventTarget::Clipboard(name.to_string()));
+            if let Some(stream) = self.machines.get(name).and_then(|x| x.clipboard_stream()) {
+                self.poller.add(stream, Event::readable(new_id))?;
             }
         }
 
         Ok(())
     }
 
-    pub fn load_virtual_machine(
-        &mut self,
-        toml: &str,
-        working_directory: Option<String>,
-        save: bool,
-    ) -> anyhow::Result<VirtualMachineInfo> {
-        let config = InstanceConfig::from_toml(&toml)?;
-        if save {
-            let save_file = format!("{}/definitions/{}.toml", VORE_DIRECTORY, config.name);
-            let file_path = Path::new(&save_file);
-            if let Some(parent_dir) = file_path.parent() {
-                if !parent_dir.is_dir() {
-                    fs::create_dir_all(parent_dir)?;
-                }
-            }
+    fn handle_clipboard(&mut self, name: &str, key: usize) -> Result<(), anyhow::Error> {
+        if let Some(machine) = self.machines.get_mut(name) {
+            machine.process_clipboard();
+        }
 
-            fs::write(&save_file, toml).with_context(|| {
-                format!(
-                    "Failed to save vm definition for {} to {}",
-                    config.name, save_file
-                )
-            })?;
+        if let Some(stream) = self.machines.get(name).and_then(|x| x.clipboard_stream()) {
+            self.poller.modify(stream, Event::readable(key))?;
+        } else {
+            self.event_targets.remove(key);
         }
 
-        let working_dir = working_directory
-            .unwrap_or_else(|| format!("{}/instance/{}", VORE_DIRECTORY, config.name));
-        let vm = VirtualMachine::new(config, &self.global_config, working_dir);
-        let info = vm.info();
-        self.mount_machine(vm);
-        Ok(info)
+        Ok(())
     }
 
-    pub fn handle_command(&mut self, command: &Command) -> Result<AllResponses, anyhow::Error> {
-        let resp = match &command.data {
-            AllRequests::Info(_) => rpc::InfoResponse {
-                name: "vore".to_string(),
-                version: format!(
-                    "{}.{}.{}{}",
-                    env!("CARGO_PKG_VERSION_MAJOR"),
-                    env!("CARGO_PKG_VERSION_MINOR"),
-                    env!("CARGO_PKG_VERSION_PATCH"),
-                    option_env!("CARGO_PKG_VERSION_PRE").unwrap_or("")
-                ),
-            }
-            .into_enum(),
-            AllRequests::List(_) => rpc::ListResponse {
-                items: self.machines.values().map(|x| x.info()).collect(),
-            }
-            .into_enum(),
-            AllRequests::Load(val) => rpc::LoadResponse {
-                info: self.load_virtual_machine(
-                    &val.toml,
-                    val.working_directory.as_ref().cloned(),
-                    val.save,
-                )?,
+    /// Handle the actions the guest of [name] requested
+    fn handle_guest_actions(&mut self, name: &str, key: usize) -> Result<(), anyhow::Error> {
+        let requests = if let Some(machine) = self.machines.get_mut(name) {
+            machine.read_guest_actions()
+        } else {
+            return Ok(());
+        };
+
+        for (request, action) in requests {
+            log::info!("Guest of {} requested action {}", name, request);
+            let result = match action {
+                Some(action) => self.run_guest_action(name, &request, action),
+                None => Err(anyhow::anyhow!("Action {} is not allowed", request)),
+            };
+
+            if let Err(err) = &result {
+                log::warn!("Guest action {} of {} failed: {:?}", request, name, err);
             }
-            .into_enum(),
-            AllRequests::Prepare(val) => {
-                if let Some(machine) = self.machines.get_mut(&val.name) {
-                    machine.prepare(true, false)?;
-                } else {
-                    anyhow::bail!("No machine with the name {} exists", val.name);
-                }
 
-                rpc::PrepareResponse {}.into_enum()
+            if let Some(machine) = self.machines.get_mut(name) {
+                machine.reply_guest_action(&result);
             }
-            AllRequests::Start(val) => {
-                let cloned = if let Some(machine) = self.machines.get_mut(&val.name) {
-                    machine.start()?;
+        }
 
-                    machine.control_stream().cloned()
-                } else {
-                    anyhow::bail!("No machine with the name {} exists", val.name);
-                };
+        if let Some(stream) = self
+            .machines
+            .get(name)
+            .and_then(|x| x.guest_actions_stream())
+        {
+            self.poller.modify(stream, Event::readable(key))?;
+        } else {
+            self.event_targets.remove(key);
+        }
 
-                if let Some(cloned) = cloned {
-                    let new_id = self.add_target(EventTarget::Machine(val.name.clone()));
-                    self.poller.add(&cloned, Event::readable(new_id))?;
-                }
+        Ok(())
+    }
 
-                rpc::StartResponse {}.into_enum()
+    fn run_guest_action(
+        &mut self,
+        name: &str,
+        request: &str,
+        action: GuestAction,
+    ) -> Result<(), anyhow::Error> {
+        match action {
+            GuestAction::Start(vm) => {
+                self.cancel_restart(&vm);
+                self.start_machine(&vm, &[], None)
             }
-            AllRequests::Stop(val) => {
-                if let Some(machine) = self.machines.get_mut(&val.name) {
-                    machine.stop()?;
+            GuestAction::Stop(vm) => {
+                self.cancel_restart(&vm);
+                if let Some(machine) = self.machines.get_mut(&vm) {
+                    machine.stop()
                 } else {
-                    anyhow::bail!("No machine with the name {} exists", val.name);
+                    Err(RpcError::vm_not_found(&vm).into())
                 }
-
-                rpc::StartResponse {}.into_enum()
             }
-            AllRequests::Unload(_) => {
-                anyhow::bail!("Unimplemented");
+            GuestAction::Command(command) => {
+                let mut child = std::process::Command::new(&command[0])
+                    .args(&command[1..])
+                    .env("VORE_VM_NAME", name)
+                    .env("VORE_ACTION", request)
+                    .spawn()
+                    .with_context(|| format!("Failed to run {}", command[0]))?;
+
+                // Don't block the daemon on it, but do reap it
+                std::thread::spawn(move || child.wait());
+                Ok(())
             }
-            AllRequests::Kill(val) => {
-                if let Some(machine) = self.machines.get_mut(&val.name) {
-                    machine.quit()?;
-                } else {
-                    anyhow::bail!("No machine with the name {} exists", val.name);
-                }
+        }
+    }
 
-                rpc::StartResponse {}.into_enum()
-            }
-            AllRequests::DiskPresets(_) => {
-                let builder =
-                    QemuCommandBuilder::new(&self.global_config, PathBuf::from("/dev/empty"))?;
+    /// Pick up machines that were still running when the previous vored went away
+    pub fn reattach_machines(&mut self) {
+        let names = self.machines.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            let reattached = match self.machines.get_mut(&name).map(|x| x.reattach()) {
+                Some(Ok(Reattach::OtherHost(host))) => {
+                    if self.global_config.failover.restart_peer_machines {
+                        log::info!("{} was running on {}, starting it here", name, host);
+                        self.peer_machines.push(name);
+                    }
 
-                rpc::DiskPresetsResponse {
-                    presets: builder
-                        .list_presets()?
-                        .into_iter()
-                        .map(|(name, description)| DiskPreset { name, description })
-                        .collect(),
+                    continue;
+                }
+                Some(Ok(reattached)) => reattached == Reattach::Reattached,
+                Some(Err(err)) => {
+                    log::error!("Failed to reattach to running {}: {:?}", name, err);
+                    continue;
+                }
+                None => continue,
+            };
+
+            if reattached {
+                if let Err(err) = self.watch_machine(&name) {
+                    log::error!("Failed to watch control socket of {}: {:?}", name, err);
                 }
-                .into_enum()
             }
-        };
+        }
+    }
 
-        Ok(resp)
+    /// Devices vore bound to vfio-pci that none of the loaded machines use
+    fn stale_vfio_devices(&self) -> Result<Vec<VfioBinding>, anyhow::Error> {
+        let in_use = self
+            .machines
+            .values()
+            .flat_map(|x| x.pci_devices_in_use())
+            .collect::<Vec<_>>();
+        stale_vfio_bindings(&in_use)
     }
 
-    pub fn handle_exit_code(&mut self) -> Result<bool, anyhow::Error> {
-        for signal in self.signals.pending() {
-            log::info!(
-                "Received signal {} ({})",
+    /// Give devices left bound to vfio-pci, e.g. by a vored that crashed, back to their host driver
+    ///
+    /// Without vfio.auto-recover they're only mentioned, `vore vfio recover` releases them
+    fn recover_vfio_devices(&mut self) {
+        let stale = match self.stale_vfio_devices() {
+            Ok(stale) => stale,
+            Err(err) => {
+                log::warn!("Failed to check for stale vfio-pci bindings: {:?}", err);
+                return;
+            }
+        };
+
+        for binding in stale {
+            if !self.global_config.vfio.auto_recover {
+                log::warn!(
+                    "{} is still bound to vfio-pci but no loaded VM uses it, run `vore vfio recover` to give it back to the host",
+                    binding.address
+                );
+                continue;
+            }
+
+            match release_vfio_binding(&binding) {
+                Ok(_) => log::info!(
+                    "Gave {} back to the host, no loaded VM uses it",
+                    binding.address
+                ),
+                Err(err) => log::error!(
+                    "Failed to give {} back to the host: {:?}",
+                    binding.address,
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Give guests that are due their clock and timezone through their guest agent,
+    /// and thaw filesystems that were frozen for too long
+    fn handle_guest_agents(&mut self) {
+        for machine in self.machines.values_mut() {
+            if let Err(err) = machine.push_timezone() {
+                log::warn!(
+                    "Failed to set the timezone of {}: {:?}",
+                    machine.name(),
+                    err
+                );
+            }
+
+            if let Err(err) = machine.check_fsfreeze() {
+                log::error!(
+                    "Failed to thaw the filesystems of {}: {:?}",
+                    machine.name(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Check free disk space of the running machines, pausing them with disk-space.on-low = pause
+    fn handle_disk_space(&mut self) {
+        for machine in self.machines.values_mut() {
+            if let Err(err) = machine.check_disk_space() {
+                log::error!(
+                    "Failed to act on low disk space of {}: {:?}",
+                    machine.name(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Sample the Looking Glass sessions of the machines, for `vore status` and the metrics
+    fn handle_looking_glass(&mut self) {
+        for machine in self.machines.values_mut() {
+            machine.check_looking_glass();
+        }
+    }
+
+    /// Notice swtpm processes that quit while their QEMU still runs
+    fn handle_tpm(&mut self) {
+        for machine in self.machines.values_mut() {
+            machine.check_tpm();
+        }
+    }
+
+    /// Sample the resource usage of the running machines, for `vore list --wide`
+    fn handle_usage(&mut self) {
+        for machine in self.machines.values_mut() {
+            machine.check_usage();
+        }
+    }
+
+    /// Handle the PrepareForShutdown signals logind sent, shutting down the machines when the host
+    /// is about to shut down, and taking the delay lock again when that's cancelled
+    fn handle_logind(&mut self, key: usize) -> Result<(), anyhow::Error> {
+        let (signals, open) = if let Some(logind) = self.logind.as_mut() {
+            logind.read_signals()?
+        } else {
+            return Ok(());
+        };
+
+        for active in signals {
+            if active {
+                self.begin_host_shutdown();
+            } else if self.host_shutdown.take().is_some() {
+                log::info!("Host shutdown was cancelled");
+                if let Some(Err(err)) = self.logind.as_mut().map(|x| x.inhibit()) {
+                    log::warn!("Failed to take a shutdown delay lock again: {:?}", err);
+                }
+            }
+        }
+
+        if let Some(logind) = self.logind.as_ref().filter(|_| open) {
+            self.poller.modify(logind.stream(), Event::readable(key))?;
+        } else {
+            log::warn!("Stopped watching for host shutdowns, busctl went away");
+            self.event_targets.remove(key);
+            self.logind = None;
+        }
+
+        Ok(())
+    }
+
+    /// Shut down every running machine, the delay lock is released once they're all gone
+    fn begin_host_shutdown(&mut self) {
+        if self.host_shutdown.is_some() {
+            return;
+        }
+
+        let timeout = Duration::from_secs(self.global_config.host_shutdown.timeout);
+        log::info!(
+            "Host is shutting down, giving the VMs {}s to shut down",
+            timeout.as_secs()
+        );
+        self.host_shutdown = Some(Instant::now() + timeout);
+        self.auto_start_queue.clear();
+        self.next_auto_start = None;
+        self.pending_restarts.clear();
+
+        let names = self.machines.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            if let Err(err) = self.quit_standby(&name) {
+                log::error!("Failed to quit standby QEMU of {}: {:?}", name, err);
+            }
+
+            if let Err(err) = self.machines.get_mut(&name).unwrap().stop() {
+                log::error!("Failed to shut down {}: {:?}", name, err);
+            }
+        }
+    }
+
+    /// Release the delay lock once the machines shut down, quitting the ones that didn't in time
+    fn handle_host_shutdown(&mut self) {
+        let deadline = match self.host_shutdown {
+            Some(deadline) if self.logind.as_ref().map_or(false, |x| x.is_inhibiting()) => deadline,
+            _ => return,
+        };
+
+        let running = self
+            .machines
+            .values()
+            .filter(|x| {
+                x.state() == VirtualMachineState::Running
+                    || x.state() == VirtualMachineState::Paused
+            })
+            .map(|x| x.name().to_string())
+            .collect::<Vec<_>>();
+
+        if !running.is_empty() && deadline > Instant::now() {
+            return;
+        }
+
+        for name in running {
+            log::warn!("{} didn't shut down in time, quitting QEMU", name);
+            let machine = self.machines.get_mut(&name).unwrap();
+            let control_stream = machine.control_stream().cloned();
+            if let Err(err) = machine.quit() {
+                log::error!("Failed to quit QEMU of {}: {:?}", name, err);
+            }
+
+            if let Some(control_stream) = control_stream {
+                let _ = self.poller.delete(&control_stream);
+            }
+
+            self.event_targets.release_machine(&name);
+        }
+
+        log::info!("VMs are shut down, letting the host shut down");
+        if let Some(logind) = self.logind.as_mut() {
+            logind.release();
+        }
+    }
+
+    /// Run the health probes of the machines, stalled machines that got killed are restarted by their restart policy
+    fn handle_health(&mut self) {
+        let names = self.machines.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            let machine = if let Some(machine) = self.machines.get_mut(&name) {
+                machine
+            } else {
+                continue;
+            };
+
+            let control_stream = machine.control_stream().cloned();
+            match machine.check_health() {
+                Ok(true) if machine.control_stream().is_none() => {
+                    if let Some(control_stream) = control_stream {
+                        let _ = self.poller.delete(&control_stream);
+                    }
+
+                    self.event_targets.release_machine(&name);
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("Health check of {} failed: {:?}", name, err),
+            }
+        }
+    }
+
+    /// Schedule restarts for machines that went away according to their restart policy,
+    /// and start the machines of which the backoff has passed
+    pub fn handle_restarts(&mut self) {
+        let exits = self
+            .machines
+            .values_mut()
+            .filter_map(|machine| {
+                machine
+                    .take_exit()
+                    .map(|exit| (machine.name().to_string(), exit))
+            })
+            .collect::<Vec<_>>();
+
+        for (name, exit) in exits {
+            let restart = match self.machines.get(&name).map(|x| x.restart_policy()) {
+                Some(RestartPolicy::Always) => true,
+                Some(RestartPolicy::OnFailure) => exit == VirtualMachineExit::Crashed,
+                _ => false,
+            };
+
+            if restart {
+                self.schedule_restart(&name);
+            }
+        }
+
+        let now = Instant::now();
+        for (at, name) in mem::take(&mut self.pending_restarts) {
+            if at > now {
+                self.pending_restarts.push((at, name));
+                continue;
+            }
+
+            log::info!("Restarting {}", name);
+            if let Err(err) = self.start_machine(&name, &[], None) {
+                log::error!("Failed to restart {}: {:?}", name, err);
+                self.schedule_restart(&name);
+            }
+        }
+    }
+
+    fn schedule_restart(&mut self, name: &str) {
+        if self.host_shutdown.is_some() {
+            return;
+        }
+
+        let machine = if let Some(machine) = self.machines.get(name) {
+            machine
+        } else {
+            return;
+        };
+
+        let now = Instant::now();
+        let attempts = match self.restart_attempts.get(name) {
+            Some((attempts, last)) if now.duration_since(*last) < RESTART_RESET_AFTER => *attempts,
+            _ => 0,
+        };
+
+        let max_retries = machine.restart_max_retries();
+        if max_retries > 0 && attempts >= max_retries {
+            log::error!(
+                "{} went away {} times in a row, not restarting it again",
+                name,
+                attempts + 1
+            );
+            return;
+        }
+
+        let delay = machine.restart_backoff(attempts);
+        log::warn!(
+            "Restarting {} in {}s (attempt {})",
+            name,
+            delay.as_secs(),
+            attempts + 1
+        );
+        self.restart_attempts
+            .insert(name.to_string(), (attempts + 1, now + delay));
+        self.pending_restarts.push((now + delay, name.to_string()));
+    }
+
+    fn cancel_restart(&mut self, name: &str) {
+        self.pending_restarts.retain(|(_, x)| x != name);
+        self.restart_attempts.remove(name);
+    }
+
+    pub fn run(&mut self) -> Result<(), anyhow::Error> {
+        self.load_definitions()?;
+        self.reattach_machines();
+        self.reserve_vfio_devices();
+        self.recover_vfio_devices();
+        self.auto_start_machines();
+        self.handle_standby();
+        self.notifier.ready();
+        self.update_status();
+
+        loop {
+            let res = self
+                .wait()
+                .context("Got error while waiting for new notifications");
+            match res {
+                // Interrupted is uh "always" when we get a signal
+                Err(err)
+                    if err
+                        .downcast_ref::<io::Error>()
+                        .map(|x| x.kind() == io::ErrorKind::Interrupted)
+                        .unwrap_or(false) => {}
+                err => err?,
+            }
+
+            // Signals can also arrive while we're not waiting, so always check for them
+            if !self.handle_exit_code()? {
+                break;
+            }
+
+            if !self.handle_event_queue()? {
+                break;
+            }
+
+            if !self.handle_lease() {
+                break;
+            }
+
+            self.handle_command_queue()?;
+            self.handle_pending_waits()?;
+            self.handle_pending_benches()?;
+            self.handle_auto_start();
+            self.handle_health();
+            self.handle_guest_agents();
+            self.handle_looking_glass();
+            self.handle_tpm();
+            self.handle_usage();
+            self.handle_disk_space();
+            self.handle_host_shutdown();
+            self.handle_restarts();
+            self.handle_standby();
+            self.handle_events();
+            self.notifier.watchdog();
+            self.update_status();
+        }
+
+        self.notifier.stopping();
+        // QEMU's that keep running are picked up again by the next vored, they keep their files
+        for machine in self.machines.values_mut().filter(|x| !x.has_process()) {
+            if let Err(err) = machine.clean() {
+                log::warn!("Failed to clean up after {}: {:?}", machine.name(), err);
+            }
+        }
+
+        log::info!("vore daemon has ended");
+        std::fs::remove_file(&self.socket_path).context("Failed cleaning up socket")?;
+        if let Some((_, path)) = &self.compat_listener {
+            std::fs::remove_file(path).context("Failed cleaning up compat socket")?;
+        }
+        Ok(())
+    }
+
+    /// Check the lease is still held, returns false if it was lost and another vored manages the machines now
+    fn handle_lease(&mut self) -> bool {
+        let lease = match &self.lease {
+            Some(lease) => lease,
+            None => return true,
+        };
+
+        match lease.lost() {
+            None => true,
+            Some(err) => {
+                // Leave the machines running, they're the other vored's now
+                log::error!("Lost lease {:?}, stopping: {}", lease.path(), err);
+                false
+            }
+        }
+    }
+
+    fn update_status(&mut self) {
+        let running = self
+            .machines
+            .values()
+            .filter(|x| x.state() == VirtualMachineState::Running)
+            .count();
+
+        self.notifier.status(format!(
+            "{} VM(s) loaded, {} running",
+            self.machines.len(),
+            running
+        ));
+    }
+
+    pub fn handle_command_queue(&mut self) -> Result<(), anyhow::Error> {
+        while let Some((id, command)) = self.command_queue.pop() {
+            let id = if let Some(id) = id {
+                id
+            } else {
+                // Connection went away, but the command asked to be executed regardless,
+                // waiting or measuring is no use without anyone to tell the outcome
+                if let AllRequests::Wait(_) | AllRequests::Bench(_) = &command.data {
+                    continue;
+                }
+
+                if let Err(err) = self.handle_command(&command) {
+                    in_request(&command, || {
+                        log::warn!(
+                            "Detached command {:?} failed with error: {:?}",
+                            command.data,
+                            err
+                        )
+                    });
+                }
+
+                continue;
+            };
+
+            if let AllRequests::Wait(val) = &command.data {
+                // Only answer once the machine reached the state, or the wait timed out
+                if self
+                    .machines
+                    .get(&val.name)
+                    .map_or(false, |x| x.state() != val.state)
+                {
+                    self.pending_waits.push(PendingWait {
+                        connection: id,
+                        name: val.name.clone(),
+                        state: val.state,
+                        deadline: val.timeout.map(|x| Instant::now() + Duration::from_secs(x)),
+                        command,
+                    });
+                    continue;
+                }
+            }
+
+            if let AllRequests::Bench(val) = &command.data {
+                // Measuring takes up to minutes, which the daemon can't be blocked for
+                match self.start_bench(&val.name, val.duration) {
+                    Ok(handle) => self.pending_benches.push(PendingBench {
+                        connection: id,
+                        command,
+                        handle,
+                    }),
+                    Err(err) => self.send_answer(id, &command, Err(err))?,
+                }
+
+                continue;
+            }
+
+            if let AllRequests::Subscribe(val) = &command.data {
+                // Replaces the filters of an earlier subscription of the connection, what it still
+                // has to write goes out first
+                if let Some(conn) = self.connections.get_mut(id) {
+                    match &mut conn.subscription {
+                        Some(subscription) => subscription.resubscribe(
+                            command.id,
+                            val.names.clone(),
+                            val.kinds.clone(),
+                        ),
+                        None => {
+                            conn.subscription = Some(Subscription::new(
+                                command.id,
+                                val.names.clone(),
+                                val.kinds.clone(),
+                            ))
+                        }
+                    }
+                }
+            }
+
+            self.start_progress = match &command.data {
+                AllRequests::Start(val) if val.progress => Some((id, command.clone())),
+                _ => None,
+            };
+
+            let resp = self.handle_command(&command);
+            self.start_progress = None;
+            self.send_answer(id, &command, resp)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_pending_waits(&mut self) -> Result<(), anyhow::Error> {
+        let now = Instant::now();
+        for wait in mem::take(&mut self.pending_waits) {
+            if !self.connections.contains(wait.connection) {
+                continue;
+            }
+
+            let resp = match self.machines.get(&wait.name).map(|x| x.state()) {
+                Some(state) if state == wait.state => self.handle_command(&wait.command),
+                Some(state) if wait.deadline.map_or(false, |x| x <= now) => Err(RpcError::new(
+                    ErrorCode::Timeout,
+                    format!(
+                        "Timed out waiting for {} to become {} (currently {})",
+                        wait.name, wait.state, state
+                    ),
+                )
+                .with_detail("name", &wait.name)
+                .into()),
+                Some(_) => {
+                    self.pending_waits.push(wait);
+                    continue;
+                }
+                None => Err(RpcError::vm_not_found(&wait.name).into()),
+            };
+
+            self.send_answer(wait.connection, &wait.command, resp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Measure the latency for machine [name] on a thread, which wakes up the daemon once it's done
+    fn start_bench(
+        &self,
+        name: &str,
+        duration: Option<u64>,
+    ) -> Result<JoinHandle<Vec<LatencyResult>>, anyhow::Error> {
+        let plan = self
+            .machines
+            .get(name)
+            .ok_or_else(|| RpcError::vm_not_found(name))?
+            .bench_plan()?;
+        let duration = Duration::from_secs(duration.unwrap_or(2).min(30));
+        let poller = self.poller.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("bench-{}", name))
+            .spawn(move || {
+                let results = plan.run(duration);
+                if let Err(err) = poller.notify() {
+                    log::warn!("Failed to wake up the daemon after a bench: {:?}", err);
+                }
+
+                results
+            })
+            .context("Failed to start bench thread")?;
+        Ok(handle)
+    }
+
+    pub fn handle_pending_benches(&mut self) -> Result<(), anyhow::Error> {
+        for bench in mem::take(&mut self.pending_benches) {
+            if !bench.handle.is_finished() {
+                self.pending_benches.push(bench);
+                continue;
+            }
+
+            let resp = match bench.handle.join() {
+                Ok(results) => Ok(rpc::BenchResponse { results }.into_enum()),
+                Err(_) => Err(anyhow::anyhow!("Bench thread panicked")),
+            };
+            self.send_answer(bench.connection, &bench.command, resp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop all queued work of a closed connection, except commands that asked to be detached
+    fn orphan_commands(&mut self, connection: SlotId) {
+        self.pending_waits.retain(|x| x.connection != connection);
+        // Their threads finish on their own
+        self.pending_benches.retain(|x| x.connection != connection);
+        for command in self.command_queue.take(Some(connection)) {
+            if command.detach {
+                self.command_queue.push(None, command);
+            } else {
+                log::info!(
+                    "Cancelled command {} of closed RPC connection {}",
+                    command.id,
+                    connection.index
+                );
+            }
+        }
+    }
+
+    fn send_answer(
+        &mut self,
+        id: SlotId,
+        command: &Command,
+        resp: Result<AllResponses, anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        if let Err(err) = &resp {
+            in_request(command, || {
+                log::warn!("Command {:?} failed with error: {:?}", command.data, err)
+            });
+        }
+
+        if let Some(conn) = self.connections.get_mut(id) {
+            let answer = CommandCenter::write_answer(command, resp)?;
+            match &mut conn.subscription {
+                // Behind whatever part of an event is still being written
+                Some(subscription) => subscription.send(answer),
+                None => {
+                    // A detached client doesn't wait for its answer, the close shows up on the next poll
+                    if let Err(err) = conn.write_all(answer.as_bytes()) {
+                        log::debug!(
+                            "RPC connection went away before it got its answer: {:?}",
+                            err
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tell the subscribers about machines that were loaded, unloaded, changed state or have
+    /// warnings, and write out what they have queued
+    fn handle_events(&mut self) {
+        let mut events = vec![];
+        for (name, machine) in self.machines.iter_mut() {
+            let state = machine.state();
+            let kind = match self.published_states.insert(name.clone(), state) {
+                None => Some(EventKind::Loaded),
+                Some(previous) if previous != state => Some(EventKind::State),
+                Some(_) => None,
+            };
+
+            if let Some(kind) = kind {
+                events.push(VmEvent {
+                    kind,
+                    name: name.clone(),
+                    state: Some(state),
+                    message: None,
+                });
+            }
+
+            for message in machine.take_warnings() {
+                events.push(VmEvent {
+                    kind: EventKind::Warning,
+                    name: name.clone(),
+                    state: Some(state),
+                    message: Some(message),
+                });
+            }
+        }
+
+        let machines = &self.machines;
+        self.published_states.retain(|name, _| {
+            if machines.contains_key(name) {
+                return true;
+            }
+
+            events.push(VmEvent {
+                kind: EventKind::Unloaded,
+                name: name.clone(),
+                state: None,
+                message: None,
+            });
+            false
+        });
+
+        for (id, conn) in self.connections.iter_mut() {
+            let subscription = match &mut conn.subscription {
+                Some(subscription) => subscription,
+                None => continue,
+            };
+
+            for event in &events {
+                subscription.push(event);
+            }
+
+            if let Err(err) = subscription.flush(&mut conn.stream) {
+                log::info!(
+                    "Failed to send events to RPC connection {}: {:?}",
+                    id.index,
+                    err
+                );
+                conn.subscription = None;
+            }
+
+            if let Err(err) = self.poller.modify(&conn.stream, conn.interest()) {
+                log::warn!("Failed to update RPC connection {}: {:?}", id.index, err);
+            }
+        }
+    }
+
+    pub fn load_virtual_machine(
+        &mut self,
+        toml: &str,
+        cdroms: &[String],
+        working_directory: Option<String>,
+        save: bool,
+    ) -> anyhow::Result<VirtualMachineInfo> {
+        let mut config = InstanceConfig::from_toml(toml).map_err(|err| {
+            RpcError::new(
+                ErrorCode::InvalidConfig,
+                format!("Invalid VM definition: {:#}", err),
+            )
+        })?;
+        match self.global_config.vore.legacy_config_keys {
+            LegacyKeys::Allow => config.deprecations.clear(),
+            LegacyKeys::Warn => {
+                for deprecation in &config.deprecations {
+                    log::warn!("Definition of {}: {}", config.name, deprecation);
+                }
+            }
+            LegacyKeys::Deny if !config.deprecations.is_empty() => {
+                return Err(RpcError::new(
+                    ErrorCode::InvalidConfig,
+                    format!(
+                        "Invalid VM definition: {}",
+                        config
+                            .deprecations
+                            .iter()
+                            .map(|x| x.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                )
+                .into());
+            }
+            LegacyKeys::Deny => {}
+        }
+
+        // The daemon waits for hooks, a hook running this long makes systemd restart it
+        if let Some(watchdog) = self.notifier.watchdog_timeout() {
+            if Duration::from_secs(config.hooks.timeout) >= watchdog {
+                log::warn!(
+                    "hooks.timeout of {} ({}s) isn't shorter than the systemd watchdog ({}s), raise WatchdogSec of vored.service",
+                    config.name,
+                    config.hooks.timeout,
+                    watchdog.as_secs()
+                );
+            }
+        }
+
+        // A standby takes over once the lease is this old, it shouldn't be shorter than the daemon blocks
+        if self.lease.is_some() {
+            let lease_timeout = self.global_config.failover.lease_timeout;
+            if config.hooks.timeout.max(QMP_SOCKET_TIMEOUT.as_secs()) >= lease_timeout {
+                log::warn!(
+                    "failover.lease-timeout ({}s) isn't longer than hooks.timeout of {} ({}s) or the wait for QEMU ({}s), raise it in {}",
+                    lease_timeout,
+                    config.name,
+                    config.hooks.timeout,
+                    QMP_SOCKET_TIMEOUT.as_secs(),
+                    VORE_CONFIG
+                );
+            }
+        }
+
+        if save {
+            let save_file = self.definition_path(&config.name);
+            let file_path = Path::new(&save_file);
+            if let Some(parent_dir) = file_path.parent() {
+                if !parent_dir.is_dir() {
+                    fs::create_dir_all(parent_dir)?;
+                }
+            }
+
+            fs::write(&save_file, toml).with_context(|| {
+                format!(
+                    "Failed to save vm definition for {} to {}",
+                    config.name, save_file
+                )
+            })?;
+        }
+
+        let working_dir = working_directory
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.directory.join("instance").join(&config.name));
+        let mut vm = VirtualMachine::new(config, &self.global_config, working_dir);
+        vm.set_cdroms(cdroms.to_vec())
+            .map_err(|err| RpcError::new(ErrorCode::InvalidConfig, format!("{:#}", err)))?;
+        for (path, current, wanted) in vm.stale_shm_files() {
+            log::warn!(
+                "Shared memory file {} of {} is {} bytes but {} bytes are needed, it will be recreated when the VM is prepared",
+                path,
+                vm.name(),
+                current,
+                wanted
+            );
+        }
+
+        let info = vm.info();
+        self.mount_machine(vm);
+        Ok(info)
+    }
+
+    /// Store [cpus] as cpu.pin in the saved definition of [name], at [path]
+    fn save_pin(path: &str, name: &str, cpus: &[usize]) -> anyhow::Result<()> {
+        let toml = match read_to_string(&path) {
+            Ok(toml) => toml,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(RpcError::new(
+                    ErrorCode::InvalidConfig,
+                    format!(
+                        "{} has no saved definition, load it with --save to store the pinning",
+                        name
+                    ),
+                )
+                .into())
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read definition {}", path))
+            }
+        };
+
+        let toml = set_definition_value(&toml, "cpu", "pin", &format_cpu_list(cpus))?;
+        fs::write(&path, toml)
+            .with_context(|| format!("Failed to save vm definition for {} to {}", name, path))
+    }
+
+    /// Execute [command], with its request id in front of everything logged meanwhile
+    pub fn handle_command(&mut self, command: &Command) -> Result<AllResponses, anyhow::Error> {
+        in_request(command, || self.execute_command(command))
+    }
+
+    fn execute_command(&mut self, command: &Command) -> Result<AllResponses, anyhow::Error> {
+        let resp = match &command.data {
+            AllRequests::Info(_) => rpc::InfoResponse {
+                name: "vore".to_string(),
+                version: format!(
+                    "{}.{}.{}{}",
+                    env!("CARGO_PKG_VERSION_MAJOR"),
+                    env!("CARGO_PKG_VERSION_MINOR"),
+                    env!("CARGO_PKG_VERSION_PATCH"),
+                    option_env!("CARGO_PKG_VERSION_PRE").unwrap_or("")
+                ),
+            }
+            .into_enum(),
+            AllRequests::List(_) => rpc::ListResponse {
+                items: self.machines.values().map(|x| x.info()).collect(),
+            }
+            .into_enum(),
+            AllRequests::Load(val) => rpc::LoadResponse {
+                info: self.load_virtual_machine(
+                    &val.toml,
+                    &val.cdroms,
+                    val.working_directory.as_ref().cloned(),
+                    val.save,
+                )?,
+            }
+            .into_enum(),
+            AllRequests::Prepare(val) => {
+                let mut problems = vec![];
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if !val.cdroms.is_empty()
+                        && !val.dry_run
+                        && machine.set_cdroms(val.cdroms.clone())?
+                    {
+                        self.quit_standby(&val.name)?;
+                    }
+
+                    let machine = self.machines.get_mut(&val.name).unwrap();
+                    if val.dry_run {
+                        problems = machine.check_prepare();
+                    } else {
+                        machine.prepare(true, false)?;
+                        self.standby_held.remove(&val.name);
+                    }
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+
+                rpc::PrepareResponse { problems }.into_enum()
+            }
+            AllRequests::Start(val) => {
+                self.cancel_restart(&val.name);
+                self.start_machine(&val.name, &val.cdroms, val.boot.clone())?;
+
+                rpc::StartResponse {}.into_enum()
+            }
+            AllRequests::Stop(val) => {
+                self.cancel_restart(&val.name);
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    machine.stop()?;
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+
+                rpc::StartResponse {}.into_enum()
+            }
+            AllRequests::Unload(val) => {
+                if !self.machines.contains_key(&val.name) {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+
+                self.quit_standby(&val.name)?;
+                let machine = self.machines.get_mut(&val.name).unwrap();
+                for path in machine.clean()? {
+                    log::debug!("Removed {} of {}", path, val.name);
+                }
+
+                self.machines.remove(&val.name);
+                self.event_targets.release_machine(&val.name);
+                self.cancel_restart(&val.name);
+                self.standby_held.remove(&val.name);
+                self.auto_start_queue.retain(|x| x != &val.name);
+                log::info!("Unloaded {}", val.name);
+
+                rpc::UnloadResponse {}.into_enum()
+            }
+            AllRequests::Wait(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    rpc::WaitResponse {
+                        state: machine.state(),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Kill(val) => {
+                self.cancel_restart(&val.name);
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    machine.quit()?;
+                    // A killed machine shouldn't come back as standby QEMU either
+                    self.standby_held.insert(val.name.clone());
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+
+                rpc::StartResponse {}.into_enum()
+            }
+            AllRequests::Release(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    rpc::ReleaseResponse {
+                        devices: machine.release_vfio(true)?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::VfioRecover(val) => {
+                let devices = self.stale_vfio_devices()?;
+                if !val.dry_run {
+                    for binding in &devices {
+                        release_vfio_binding(binding).with_context(|| {
+                            format!("Failed to give {} back to the host", binding.address)
+                        })?;
+                        log::info!("Gave {} back to the host", binding.address);
+                    }
+                }
+
+                rpc::VfioRecoverResponse { devices }.into_enum()
+            }
+            // Only a connection can subscribe, see handle_command_queue
+            AllRequests::Subscribe(_) => rpc::SubscribeResponse {}.into_enum(),
+            AllRequests::DiskPresets(_) => {
+                let builder =
+                    QemuCommandBuilder::new(&self.global_config, PathBuf::from("/dev/empty"))?;
+
+                rpc::DiskPresetsResponse {
+                    presets: builder
+                        .list_presets()?
+                        .into_iter()
+                        .map(|(name, description)| DiskPreset { name, description })
+                        .collect(),
+                }
+                .into_enum()
+            }
+            AllRequests::Stats(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    rpc::StatsResponse {
+                        stats: machine.stats()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            // Measured on its own thread by handle_command_queue, there's no answer right away
+            AllRequests::Bench(_) => anyhow::bail!("Bench can't be answered right away"),
+            AllRequests::ListArtifacts(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    rpc::ListArtifactsResponse {
+                        artifacts: machine.artifacts()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::FetchArtifact(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    rpc::FetchArtifactResponse {
+                        data: machine.fetch_artifact(&val.path, MAX_ARTIFACT_SIZE)?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::History(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    let (total_uptime, boots) = machine.history()?;
+                    rpc::HistoryResponse {
+                        total_uptime,
+                        boots,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Clipboard(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(text) = &val.set {
+                        machine.set_guest_clipboard(text.clone())?;
+                    }
+
+                    rpc::ClipboardResponse {
+                        text: machine.guest_clipboard()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Describe(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    let path = self.definition_path(&val.name);
+                    let stored = match read_to_string(&path) {
+                        Ok(stored) => Some(stored),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                        Err(err) => {
+                            return Err(err)
+                                .with_context(|| format!("Failed to read definition {}", path))
+                        }
+                    };
+
+                    rpc::DescribeResponse {
+                        config: machine.info().config,
+                        stored,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Repin(val) => {
+                let definition = self.definition_path(&val.name);
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(cpus) = &val.cpus {
+                        machine.repin(cpus.clone()).map_err(|err| {
+                            RpcError::new(ErrorCode::InvalidConfig, format!("{:#}", err))
+                        })?;
+
+                        if val.save {
+                            Self::save_pin(&definition, &val.name, cpus)?;
+                        }
+                    }
+
+                    rpc::RepinResponse {
+                        cpus: machine
+                            .pinned_cpus()
+                            .map(|x| x.iter().map(|x| x.id).collect()),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Fsfreeze(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    let mut filesystems = 0;
+                    if let Some(timeout) = val.freeze {
+                        filesystems = machine.fsfreeze(Duration::from_secs(timeout))?;
+                    }
+
+                    if val.thaw {
+                        filesystems = machine.fsthaw()?;
+                    }
+
+                    rpc::FsfreezeResponse {
+                        status: machine.fsfreeze_status()?,
+                        filesystems,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Memory(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(memory) = val.set {
+                        machine.set_memory(memory)?;
+                    }
+
+                    rpc::MemoryResponse {
+                        memory: machine.memory(),
+                        balloon: machine.balloon_size(),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Cdrom(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if val.eject {
+                        machine.eject_cdrom(val.drive)?;
+                    }
+
+                    if let Some(path) = &val.insert {
+                        machine.insert_cdrom(val.drive, path)?;
+                    }
+
+                    rpc::CdromResponse {
+                        drives: machine.cdrom_drives()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Usb(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(device) = &val.detach {
+                        machine.detach_usb(device)?;
+                    }
+
+                    if let Some(device) = &val.attach {
+                        let usb = device.parse::<UsbConfig>().map_err(|err| {
+                            RpcError::new(ErrorCode::InvalidConfig, format!("{:#}", err))
+                        })?;
+                        machine.attach_usb(&usb)?;
+                    }
+
+                    rpc::UsbResponse {
+                        devices: machine.usb_devices()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Pci(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(address) = val.detach {
+                        machine.detach_pci(address)?;
+                    }
+
+                    if let Some(address) = val.attach {
+                        machine.attach_pci(address, val.force)?;
+                    }
+
+                    rpc::PciResponse {
+                        slots: machine.pci_slots(),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::QemuIds(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    rpc::QemuIdsResponse {
+                        map: machine.qemu_ids()?.clone(),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::UefiBootEntries(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    rpc::UefiBootEntriesResponse {
+                        entries: machine.uefi_boot_entries(val.order.as_deref(), &val.delete)?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+
+            AllRequests::UefiVarsPath(val) => {
+                if let Some(machine) = self.machines.get(&val.name) {
+                    let path = machine.uefi_vars_path();
+                    rpc::UefiVarsPathResponse {
+                        exists: path.is_file(),
+                        path: path.to_string_lossy().to_string(),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+
+            AllRequests::UefiReset(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    let backup = machine.uefi_reset()?;
+                    rpc::UefiResetResponse {
+                        path: machine.uefi_vars_path().to_string_lossy().to_string(),
+                        backup: backup.map(|x| x.to_string_lossy().to_string()),
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+
+            AllRequests::Snapshot(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    if let Some(snapshot) = &val.create {
+                        machine.create_snapshot(snapshot)?;
+                    }
+
+                    if let Some(snapshot) = &val.revert {
+                        machine.revert_snapshot(snapshot)?;
+                    }
+
+                    if let Some(snapshot) = &val.delete {
+                        machine.delete_snapshot(snapshot)?;
+                    }
+
+                    rpc::SnapshotResponse {
+                        snapshots: machine.snapshots()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+            AllRequests::Clean(val) => {
+                if let Some(machine) = self.machines.get_mut(&val.name) {
+                    rpc::CleanResponse {
+                        removed: machine.clean()?,
+                    }
+                    .into_enum()
+                } else {
+                    return Err(RpcError::vm_not_found(&val.name).into());
+                }
+            }
+        };
+
+        Ok(resp)
+    }
+
+    pub fn handle_exit_code(&mut self) -> Result<bool, anyhow::Error> {
+        for signal in self.signals.pending() {
+            log::info!(
+                "Received signal {} ({})",
                 signal_name(signal).unwrap_or("<unknown>"),
                 signal
             );
             match signal {
                 SIGINT | SIGTERM => return Ok(false),
+                SIGCHLD => self.reap_machines()?,
                 _ => {}
             }
         }
         Ok(true)
     }
 
+    /// Clean up machines of which QEMU exited
+    fn reap_machines(&mut self) -> Result<(), anyhow::Error> {
+        let names = self.machines.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            let machine = if let Some(machine) = self.machines.get_mut(&name) {
+                machine
+            } else {
+                continue;
+            };
+
+            let control_stream = machine.control_stream().cloned();
+            if let Some(status) = machine.check_exited()? {
+                log::info!(
+                    "Reaped QEMU of {} ({}), now {}",
+                    name,
+                    status,
+                    machine.state()
+                );
+
+                if let Some(control_stream) = control_stream {
+                    let _ = self.poller.delete(&control_stream);
+                }
+
+                self.event_targets.release_machine(&name);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn handle_event_queue(&mut self) -> Result<bool, anyhow::Error> {
         let queue = mem::take(&mut self.queue);
         for event in queue {
-            let target = self.event_key_storage.get(event.key).cloned();
+            let target = self.event_targets.get(event.key);
             if let Some(item) = target {
                 log::debug!("Handling {:?} from target {:?}", event, item);
 
@@ -446,7 +2256,32 @@ impl Daemon {
                             .modify(&self.rpc_listener, Event::readable(event.key))?;
                         self.accept_rpc_connections()?;
                     }
-                    EventTarget::Machine(name) if self.machines.contains_key(&name) => {
+                    EventTarget::MetricsListener => {
+                        if let Some(metrics_listener) = &self.metrics_listener {
+                            self.poller
+                                .modify(metrics_listener, Event::readable(event.key))?;
+                        }
+
+                        self.accept_metrics_connections()?;
+                    }
+                    EventTarget::Scrape => {
+                        self.handle_scrape(event.key)?;
+                    }
+                    EventTarget::CompatListener => {
+                        if let Some((compat_listener, _)) = &self.compat_listener {
+                            self.poller
+                                .modify(compat_listener, Event::readable(event.key))?;
+                        }
+
+                        self.accept_compat_connections()?;
+                    }
+                    EventTarget::Compat => {
+                        self.handle_compat(event.key)?;
+                    }
+                    EventTarget::Machine(name, generation)
+                        if self.machines.contains_key(&name)
+                            && self.event_targets.is_current_machine(&name, generation) =>
+                    {
                         if let Some(machine) = self.machines.get_mut(&name) {
                             machine.boop()?;
                         }
@@ -456,22 +2291,31 @@ impl Daemon {
                         {
                             self.poller
                                 .modify(control_socket, Event::readable(event.key))?;
+                        } else {
+                            // QEMU went away, the socket is closed so nothing will come from it
+                            self.event_targets.remove(event.key);
                         }
                     }
+                    EventTarget::GuestActions(name) => {
+                        self.handle_guest_actions(&name, event.key)?;
+                    }
+                    EventTarget::Clipboard(name) => {
+                        self.handle_clipboard(&name, event.key)?;
+                    }
+                    EventTarget::Logind => {
+                        self.handle_logind(event.key)?;
+                    }
                     EventTarget::RpcConnection(rpc_connection_id)
-                        if self
-                            .connections
-                            .get(rpc_connection_id)
-                            .map(Option::is_some)
-                            .unwrap_or(false) =>
+                        if self.connections.contains(rpc_connection_id) =>
                     {
                         let (still_open, mut commands) = if let Some(rpc_connection) =
-                            &mut self.connections[rpc_connection_id]
+                            self.connections.get_mut(rpc_connection_id)
                         {
                             let input_res = rpc_connection.handle_input(rpc_connection_id)?;
                             if input_res.0 {
+                                // The rest of the queued events are written in handle_events
                                 self.poller
-                                    .modify(&rpc_connection.stream, Event::readable(event.key))?;
+                                    .modify(&rpc_connection.stream, rpc_connection.interest())?;
                             }
 
                             input_res
@@ -479,12 +2323,20 @@ impl Daemon {
                             (false, vec![])
                         };
 
-                        if !still_open {
-                            log::info!("RPC connection {} closed", rpc_connection_id);
-                            self.connections[rpc_connection_id] = None;
+                        for (id, command) in commands.drain(..) {
+                            self.command_queue.push(Some(id), command);
                         }
 
-                        self.command_queue.append(&mut commands)
+                        if !still_open {
+                            log::info!("RPC connection {} closed", rpc_connection_id.index);
+                            if let Some(rpc_connection) = self.connections.remove(rpc_connection_id)
+                            {
+                                let _ = self.poller.delete(&rpc_connection.stream);
+                            }
+
+                            self.event_targets.remove(event.key);
+                            self.orphan_commands(rpc_connection_id);
+                        }
                     }
                     _ => continue,
                 }
@@ -526,10 +2378,12 @@ impl Daemon {
                 uid: ucred.uid,
                 user,
                 pid: ucred.pid,
+                key: 0,
+                subscription: None,
             };
 
-            log::info!(
-                "Got new RPC connection from {} (pid: {}, socket: {:?})",
+            let description = format!(
+                "{} (pid: {}, socket: {:?})",
                 conn.user.as_ref().map_or_else(
                     || format!("uid:{}", conn.uid),
                     |x| format!("{} ({})", x, conn.uid),
@@ -538,51 +2392,229 @@ impl Daemon {
                 conn.address,
             );
 
-            let id = self.add_rpc_connection(conn);
-            let event_target = self.add_target(EventTarget::RpcConnection(id));
-            self.poller.add(
-                &self.connections[id].as_ref().unwrap().stream,
-                Event::readable(event_target),
-            )?;
+            let id = self.connections.insert(conn);
+            log::info!("Got new RPC connection {} from {}", id.index, description);
+            let event_target = self.event_targets.add(EventTarget::RpcConnection(id));
+            let conn = self.connections.get_mut(id).unwrap();
+            conn.key = event_target;
+            self.poller
+                .add(&conn.stream, Event::readable(event_target))?;
         }
     }
 
-    pub fn wait(&mut self) -> Result<(), anyhow::Error> {
-        self.poller
-            .wait(&mut self.queue, Some(Duration::from_secs(5)))?;
+    fn accept_metrics_connections(&mut self) -> Result<(), anyhow::Error> {
+        // Scrapers that never finished their request don't get to keep their connection open
+        let stale = self
+            .scrapes
+            .iter()
+            .filter(|(_, x)| x.accepted.elapsed() > metrics::SCRAPE_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in stale {
+            log::debug!("Dropping metrics scrape that didn't finish in time");
+            self.close_scrape(key);
+        }
+
+        loop {
+            let stream = match self.metrics_listener.as_ref().map(|x| x.accept()) {
+                Some(Ok((stream, _))) => stream,
+                Some(Err(err)) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Some(Err(err)) => return Err(err.into()),
+                None => return Ok(()),
+            };
+
+            let scrape = Scrape::new(stream)?;
+            let key = self.event_targets.add(EventTarget::Scrape);
+            self.poller.add(&scrape.stream, Event::readable(key))?;
+            self.scrapes.insert(key, scrape);
+        }
+    }
+
+    /// Read the request of the scrape under [key] and write its answer, as far as that goes
+    /// without blocking
+    fn handle_scrape(&mut self, key: usize) -> Result<(), anyhow::Error> {
+        let done = match self.serve_scrape(key) {
+            Ok(done) => done,
+            Err(err) => {
+                log::warn!("Failed to serve metrics: {:?}", err);
+                true
+            }
+        };
+
+        if done {
+            self.close_scrape(key);
+        } else if let Some(scrape) = self.scrapes.get(&key) {
+            let interest = if scrape.is_answered() {
+                Event::writable(key)
+            } else {
+                Event::readable(key)
+            };
+
+            self.poller.modify(&scrape.stream, interest)?;
+        }
+
         Ok(())
     }
 
-    fn add_target(&mut self, event_target: EventTarget) -> usize {
-        let id = self
-            .event_key_storage
-            .iter()
-            .enumerate()
-            .find(|(_, target)| target.eq(&&EventTarget::None));
-        if let Some((id, _)) = id {
-            self.event_key_storage[id] = event_target;
-            return id;
+    /// True once the scrape under [key] is answered, or gone
+    fn serve_scrape(&mut self, key: usize) -> Result<bool, anyhow::Error> {
+        let scrape = match self.scrapes.get_mut(&key) {
+            Some(scrape) => scrape,
+            None => return Ok(true),
+        };
+
+        if !scrape.is_answered() {
+            if !scrape.read_request()? {
+                return Ok(false);
+            }
+
+            let all_machines = &mut self.machines;
+            scrape.answer(|| {
+                let mut machines = vec![];
+                for machine in all_machines.values_mut() {
+                    let stats = machine.stats().unwrap_or_else(|err| {
+                        log::warn!("Failed to gather stats for {}: {:?}", machine.name(), err);
+                        Default::default()
+                    });
+
+                    machines.push((machine.name().to_string(), machine.state(), stats));
+                }
+
+                machines.sort_by(|a, b| a.0.cmp(&b.0));
+                metrics::render(&machines)
+            });
+        }
+
+        Ok(scrape.write_response()?)
+    }
+
+    fn close_scrape(&mut self, key: usize) {
+        if let Some(scrape) = self.scrapes.remove(&key) {
+            let _ = self.poller.delete(&scrape.stream);
         }
 
-        let new_id = self.event_key_storage.len();
-        self.event_key_storage.push(event_target);
-        new_id
+        self.event_targets.remove(key);
     }
 
-    fn add_rpc_connection(&mut self, rpc_connection: RpcConnection) -> usize {
-        let id = self
-            .connections
+    fn accept_compat_connections(&mut self) -> Result<(), anyhow::Error> {
+        // Clients that never finished their call or read their answer lose their connection
+        let stale = self
+            .compat_connections
             .iter()
-            .enumerate()
-            .find(|(_, target)| target.is_none());
-        if let Some((id, _)) = id {
-            self.connections[id] = Some(rpc_connection);
-            return id;
+            .filter(|(_, x)| x.accepted.elapsed() > compat::CALL_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in stale {
+            log::debug!("Dropping compat connection that didn't finish in time");
+            self.close_compat(key);
+        }
+
+        loop {
+            let stream = match self.compat_listener.as_ref().map(|(x, _)| x.accept()) {
+                Some(Ok((stream, _))) => stream,
+                Some(Err(err)) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Some(Err(err)) => return Err(err.into()),
+                None => return Ok(()),
+            };
+
+            let connection = CompatConnection::new(stream)?;
+            let key = self.event_targets.add(EventTarget::Compat);
+            self.poller.add(&connection.stream, Event::readable(key))?;
+            self.compat_connections.insert(key, connection);
+        }
+    }
+
+    /// Read the call of the compat connection under [key] and write its answer, as far as that
+    /// goes without blocking
+    fn handle_compat(&mut self, key: usize) -> Result<(), anyhow::Error> {
+        let done = match self.serve_compat(key) {
+            Ok(done) => done,
+            Err(err) => {
+                log::warn!("Failed to serve compat call: {:?}", err);
+                true
+            }
+        };
+
+        if done {
+            self.close_compat(key);
+        } else if let Some(connection) = self.compat_connections.get(&key) {
+            let interest = if connection.is_answered() {
+                Event::writable(key)
+            } else {
+                Event::readable(key)
+            };
+
+            self.poller.modify(&connection.stream, interest)?;
+        }
+
+        Ok(())
+    }
+
+    /// True once the call under [key] is answered, or gone. One call per connection
+    fn serve_compat(&mut self, key: usize) -> Result<bool, anyhow::Error> {
+        let line = match self.compat_connections.get_mut(&key) {
+            Some(connection) if !connection.is_answered() => match connection.read_call()? {
+                Some(line) => Some(line),
+                None => return Ok(false),
+            },
+            Some(_) => None,
+            None => return Ok(true),
+        };
+
+        if let Some(line) = line {
+            let answer = match CompatCall::parse(&line) {
+                Ok(call) => {
+                    let command = Command {
+                        id: 0,
+                        detach: false,
+                        data: call.request(),
+                        request_id: Some("compat".to_string()),
+                    };
+                    in_request(&command, || log::debug!("Got command: {:?}", command.data));
+                    call.answer(self.handle_command(&command))
+                }
+                Err(answer) => answer,
+            };
+
+            if let Some(connection) = self.compat_connections.get_mut(&key) {
+                connection.answer(answer);
+            }
+        }
+
+        match self.compat_connections.get_mut(&key) {
+            Some(connection) => Ok(connection.write_answer()?),
+            None => Ok(true),
+        }
+    }
+
+    fn close_compat(&mut self, key: usize) {
+        if let Some(connection) = self.compat_connections.remove(&key) {
+            let _ = self.poller.delete(&connection.stream);
         }
 
-        let new_id = self.connections.len();
-        self.connections.push(Some(rpc_connection));
-        new_id
+        self.event_targets.remove(key);
+    }
+
+    pub fn wait(&mut self) -> Result<(), anyhow::Error> {
+        // Wake up in time for the first pending wait to time out, the next auto-start or restart,
+        // the end of the host shutdown timeout or the next watchdog ping
+        let now = Instant::now();
+        let timeout = self
+            .pending_waits
+            .iter()
+            .filter_map(|x| x.deadline)
+            .chain(self.next_auto_start)
+            .chain(self.pending_restarts.iter().map(|(at, _)| *at))
+            .chain(
+                self.host_shutdown
+                    .filter(|_| self.logind.as_ref().map_or(false, |x| x.is_inhibiting())),
+            )
+            .chain(self.notifier.next_ping())
+            .map(|x| x.saturating_duration_since(now))
+            .fold(Duration::from_secs(5), Duration::min);
+
+        self.poller.wait(&mut self.queue, Some(timeout))?;
+        Ok(())
     }
 
     fn mount_machine(&mut self, vm: VirtualMachine) {
@@ -591,3 +2623,197 @@ impl Daemon {
         self.machines.insert(name, vm);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::Daemon;
+    use crate::lease::Lease;
+    use serde_json::json;
+    use signal_hook::consts::SIGINT;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use vore_core::rpc::{self, CommandCenter, Request};
+    use vore_core::utils::{get_username_by_uid, hostname};
+    use vore_core::{can_launch_vms, GlobalConfig, MockQemu, MockRunState, VirtualMachineState};
+
+    /// Send [request] to vored and read its answer
+    fn call<R: Request>(reader: &mut BufReader<UnixStream>, request: R) -> R::Response {
+        let (_, json) = CommandCenter::default().write_command(request).unwrap();
+        reader.get_mut().write_all(json.as_bytes()).unwrap();
+        let mut answer = String::new();
+        reader.read_line(&mut answer).unwrap();
+        CommandCenter::read_answer::<R>(&answer).unwrap().1
+    }
+
+    fn wait_for(reader: &mut BufReader<UnixStream>, name: &str, state: VirtualMachineState) {
+        call(
+            reader,
+            rpc::WaitRequest {
+                name: name.to_string(),
+                state,
+                timeout: Some(10),
+            },
+        );
+    }
+
+    fn start(reader: &mut BufReader<UnixStream>, name: &str) {
+        call(
+            reader,
+            rpc::StartRequest {
+                name: name.to_string(),
+                cdroms: vec![],
+                boot: None,
+                progress: false,
+            },
+        );
+    }
+
+    #[test]
+    fn test_machine_lifecycle() {
+        if !can_launch_vms() {
+            eprintln!("Skipping, VM's can't be launched by this user");
+            return;
+        }
+
+        // The VM runs in the directory of the mock, the daemon keeps its socket and definitions in
+        // one under it, as cleaning up after the VM removes the sockets in its working directory
+        let dir = std::env::temp_dir().join(format!("vore-daemon-{}", std::process::id()));
+        let mock = MockQemu::new(&dir).unwrap();
+        let vored_dir = dir.join("vored");
+        std::fs::create_dir_all(&vored_dir).unwrap();
+        mock.answer("query-cpus-fast", json!([]));
+        let mut global_config =
+            GlobalConfig::load(include_str!("../../config/vored.toml")).unwrap();
+        // The socket stays owned by whoever runs the tests
+        global_config.vore.group = None;
+        let compat_path = vored_dir.join("compat.sock");
+        global_config.compat.listen = Some(compat_path.to_str().unwrap().to_string());
+        global_config.qemu.script =
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../config/qemu.lua").to_string();
+        global_config.qemu.binaries.insert(
+            "x86_64".to_string(),
+            mock.binary().to_str().unwrap().to_string(),
+        );
+
+        let socket_path = vored_dir.join("vore.sock");
+        let daemon = {
+            let socket_path = socket_path.clone();
+            std::thread::spawn(move || {
+                Daemon::with_paths(global_config, socket_path, vored_dir, false)?.run()
+            })
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let stream = loop {
+            match UnixStream::connect(&socket_path) {
+                Ok(stream) => break stream,
+                Err(_) if daemon.is_finished() => {
+                    panic!("vored stopped: {:?}", daemon.join().unwrap());
+                }
+                Err(err) => {
+                    assert!(Instant::now() < deadline, "vored didn't come up: {}", err);
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+        let mut reader = BufReader::new(stream);
+
+        let user = get_username_by_uid(unsafe { libc::getuid() })
+            .unwrap()
+            .unwrap();
+        let info = call(
+            &mut reader,
+            rpc::LoadRequest {
+                toml: format!(
+                    "[machine]\nname = \"mock\"\nallow-tcg = true\n[qemu]\nrun-as = \"{}\"\ngroups = []",
+                    user
+                ),
+                cdroms: vec![],
+                save: false,
+                working_directory: Some(dir.to_str().unwrap().to_string()),
+            },
+        )
+        .info;
+        assert_eq!(info.state, VirtualMachineState::Loaded);
+
+        // A compat client that stays silent doesn't hold up the next one
+        let _silent = UnixStream::connect(&compat_path).unwrap();
+        let mut compat = UnixStream::connect(&compat_path).unwrap();
+        compat
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        compat
+            .write_all(
+                b"{\"id\": 1, \"method\": \"domstate\", \"params\": {\"domain\": \"mock\"}}\n",
+            )
+            .unwrap();
+        let mut answer = String::new();
+        BufReader::new(compat).read_line(&mut answer).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&answer).unwrap()["result"],
+            "shut off"
+        );
+
+        start(&mut reader, "mock");
+        wait_for(&mut reader, "mock", VirtualMachineState::Running);
+        assert_eq!(mock.run_state(), MockRunState::Running);
+
+        call(
+            &mut reader,
+            rpc::StopRequest {
+                name: "mock".to_string(),
+            },
+        );
+        wait_for(&mut reader, "mock", VirtualMachineState::Prepared);
+        assert_eq!(mock.commands().last().map(|x| x.as_str()), Some("quit"));
+
+        start(&mut reader, "mock");
+        wait_for(&mut reader, "mock", VirtualMachineState::Running);
+        mock.crash(1);
+        wait_for(&mut reader, "mock", VirtualMachineState::Stopped);
+        let list = call(&mut reader, rpc::ListRequest {});
+        assert!(list.items[0].runtime.is_none());
+
+        signal_hook::low_level::raise(SIGINT).unwrap();
+        daemon.join().unwrap().unwrap();
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn test_standby_takes_over() {
+        let dir = std::env::temp_dir().join(format!("vore-takeover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lease_path = dir.join("vored.lease");
+        let socket_path = dir.join("vore.sock");
+
+        let mut global_config =
+            GlobalConfig::load(include_str!("../../config/vored.toml")).unwrap();
+        global_config.failover.lease_file = Some(lease_path.to_str().unwrap().to_string());
+        global_config.failover.lease_timeout = 1;
+
+        // A vored on another host that stops renewing in a few seconds
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let peer = json!({ "host": format!("{}-peer", hostname()), "pid": 1, "renewed": now + 2 });
+        std::fs::write(&lease_path, peer.to_string()).unwrap();
+        // And the socket of a vored that ran on this host before
+        drop(UnixListener::bind(&socket_path).unwrap());
+
+        assert!(Daemon::take_lease(&global_config, false, &socket_path).is_err());
+
+        let lease = Daemon::take_lease(&global_config, true, &socket_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Lease::holder(&lease_path).unwrap().unwrap().pid,
+            std::process::id()
+        );
+        assert!(!socket_path.exists());
+        drop(lease);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
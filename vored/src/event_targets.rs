@@ -0,0 +1,140 @@
+// What the keys the daemon registers with the poller belong to
+//
+// The key of a machine's control socket remembers the generation of the machine it was added in,
+// releasing the keys of a machine bumps it, so an event that was already queued for a previous QEMU
+// can't reach the next one
+
+use crate::slots::SlotId;
+use std::collections::HashMap;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum EventTarget {
+    RpcListener,
+    MetricsListener,
+    /// A metrics scrape that's being read or answered
+    Scrape,
+    CompatListener,
+    /// A virsh-like call that's being read or answered
+    Compat,
+    /// Control socket of a machine, for the QEMU it had in this generation
+    Machine(String, u64),
+    GuestActions(String),
+    Clipboard(String),
+    Logind,
+    RpcConnection(SlotId),
+    None,
+}
+
+#[derive(Debug, Default)]
+pub struct EventTargets {
+    targets: Vec<EventTarget>,
+    /// Bumped every time the event keys of a machine are released, so keys for a previous QEMU don't match
+    machine_generations: HashMap<String, u64>,
+}
+
+impl EventTargets {
+    /// Store [target] under the first free key
+    pub fn add(&mut self, target: EventTarget) -> usize {
+        if let Some(key) = self.targets.iter().position(|x| *x == EventTarget::None) {
+            self.targets[key] = target;
+            return key;
+        }
+
+        self.targets.push(target);
+        self.targets.len() - 1
+    }
+
+    /// Store a key for the control socket of the current QEMU of machine [name]
+    pub fn add_machine(&mut self, name: &str) -> usize {
+        let generation = self.machine_generation(name);
+        self.add(EventTarget::Machine(name.to_string(), generation))
+    }
+
+    pub fn get(&self, key: usize) -> Option<EventTarget> {
+        self.targets.get(key).cloned()
+    }
+
+    /// Free [key], so it can be handed out again
+    pub fn remove(&mut self, key: usize) {
+        if let Some(target) = self.targets.get_mut(key) {
+            *target = EventTarget::None;
+        }
+    }
+
+    fn machine_generation(&self, name: &str) -> u64 {
+        self.machine_generations.get(name).copied().unwrap_or(0)
+    }
+
+    /// If a key added in [generation] of machine [name] is still for its current QEMU
+    pub fn is_current_machine(&self, name: &str, generation: u64) -> bool {
+        self.machine_generation(name) == generation
+    }
+
+    /// Free the keys of machine [name], so they can't fire for it anymore
+    pub fn release_machine(&mut self, name: &str) {
+        for target in self.targets.iter_mut() {
+            if let EventTarget::Machine(target_name, _) = target {
+                if target_name == name {
+                    *target = EventTarget::None;
+                }
+            }
+        }
+
+        *self
+            .machine_generations
+            .entry(name.to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_targets::{EventTarget, EventTargets};
+
+    #[test]
+    fn test_released_machine_key_is_stale() {
+        let mut targets = EventTargets::default();
+        let key = targets.add_machine("win10");
+        let target = targets.get(key).unwrap();
+        assert_eq!(target, EventTarget::Machine("win10".to_string(), 0));
+        assert!(targets.is_current_machine("win10", 0));
+
+        targets.release_machine("win10");
+        assert_eq!(targets.get(key), Some(EventTarget::None));
+        // An event for the old key that was already queued doesn't reach the next QEMU
+        assert!(!targets.is_current_machine("win10", 0));
+
+        let new_key = targets.add_machine("win10");
+        assert_eq!(new_key, key);
+        assert_eq!(
+            targets.get(new_key),
+            Some(EventTarget::Machine("win10".to_string(), 1))
+        );
+        assert!(targets.is_current_machine("win10", 1));
+    }
+
+    #[test]
+    fn test_release_machine_keeps_other_keys() {
+        let mut targets = EventTargets::default();
+        let listener = targets.add(EventTarget::RpcListener);
+        let win10 = targets.add_machine("win10");
+        let actions = targets.add(EventTarget::GuestActions("win10".to_string()));
+        let linux = targets.add_machine("linux");
+
+        targets.release_machine("win10");
+        assert_eq!(targets.get(listener), Some(EventTarget::RpcListener));
+        assert_eq!(targets.get(win10), Some(EventTarget::None));
+        assert_eq!(
+            targets.get(actions),
+            Some(EventTarget::GuestActions("win10".to_string()))
+        );
+        assert_eq!(
+            targets.get(linux),
+            Some(EventTarget::Machine("linux".to_string(), 0))
+        );
+        assert!(targets.is_current_machine("linux", 0));
+
+        // The freed key is handed out first
+        assert_eq!(targets.add(EventTarget::Logind), win10);
+    }
+}
@@ -0,0 +1,210 @@
+// Events for the RPC connections that subscribed to them
+//
+// Every subscriber gets a bounded queue, when it doesn't read fast enough the oldest events are
+// dropped and it's told how many it lost, so a stuck client can't make vored buffer forever
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::mem;
+use vore_core::rpc::{CommandCenter, EventKind, VmEvent};
+
+/// Events kept for a subscriber that isn't reading them, before the oldest are dropped
+pub const MAX_QUEUED_EVENTS: usize = 256;
+
+#[derive(Debug)]
+pub struct Subscription {
+    /// Id of the subscribe call, the events are sent with it
+    command_id: u64,
+    names: Vec<String>,
+    kinds: Vec<EventKind>,
+    queue: VecDeque<VmEvent>,
+    /// Events dropped since the last one that was written
+    lost: u64,
+    /// What has to go out before the next event, the rest of a partially written event or answers
+    pending: Vec<u8>,
+}
+
+impl Subscription {
+    pub fn new(command_id: u64, names: Vec<String>, kinds: Vec<EventKind>) -> Subscription {
+        Subscription {
+            command_id,
+            names,
+            kinds,
+            queue: VecDeque::new(),
+            lost: 0,
+            pending: vec![],
+        }
+    }
+
+    /// Subscribe again with other filters, keeps what's still waiting to be written
+    pub fn resubscribe(&mut self, command_id: u64, names: Vec<String>, kinds: Vec<EventKind>) {
+        self.command_id = command_id;
+        self.names = names;
+        self.kinds = kinds;
+    }
+
+    pub fn matches(&self, event: &VmEvent) -> bool {
+        (self.names.is_empty() || self.names.contains(&event.name))
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+    }
+
+    /// Queue [event] if it matches, dropping the oldest queued one if the queue is full
+    pub fn push(&mut self, event: &VmEvent) {
+        if !self.matches(event) {
+            return;
+        }
+
+        if self.queue.len() >= MAX_QUEUED_EVENTS {
+            self.queue.pop_front();
+            self.lost += 1;
+        }
+
+        self.queue.push_back(event.clone());
+    }
+
+    /// Send [line] (an answer) in order with the events, it's never dropped
+    pub fn send(&mut self, line: String) {
+        self.pending.extend_from_slice(line.as_bytes());
+    }
+
+    /// If there's nothing left to write
+    pub fn is_drained(&self) -> bool {
+        self.pending.is_empty() && self.lost == 0 && self.queue.is_empty()
+    }
+
+    /// Write as much as [writer] takes without blocking, returns true if everything was written
+    pub fn flush<W: Write>(&mut self, writer: &mut W) -> Result<bool, anyhow::Error> {
+        loop {
+            if self.pending.is_empty() {
+                // The lost events were in front of the ones still queued
+                let line = if self.lost > 0 {
+                    CommandCenter::write_lost(self.command_id, mem::take(&mut self.lost))?
+                } else if let Some(event) = self.queue.pop_front() {
+                    CommandCenter::write_event(self.command_id, event)?
+                } else {
+                    return Ok(true);
+                };
+
+                self.pending = line.into_bytes();
+            }
+
+            match writer.write(&self.pending) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(amount) => {
+                    self.pending.drain(..amount);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{Subscription, MAX_QUEUED_EVENTS};
+    use std::io;
+    use std::io::Write;
+    use vore_core::rpc::{CommandCenter, EventKind, EventMessage, VmEvent};
+
+    /// Takes [room] bytes and then blocks
+    struct SlowWriter {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let amount = buf.len().min(self.room);
+            if amount == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            self.room -= amount;
+            self.written.extend_from_slice(&buf[..amount]);
+            Ok(amount)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(name: &str) -> VmEvent {
+        VmEvent {
+            kind: EventKind::Loaded,
+            name: name.to_string(),
+            state: None,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_resubscribe_keeps_pending() {
+        let mut subscription = Subscription::new(3, vec![], vec![]);
+        subscription.push(&event("win10"));
+        let mut writer = SlowWriter {
+            written: vec![],
+            room: 10,
+        };
+        assert!(!subscription.flush(&mut writer).unwrap());
+
+        // The rest of the event goes out first, before anything of the new subscription
+        subscription.resubscribe(4, vec!["linux".to_string()], vec![]);
+        subscription.push(&event("win10"));
+        subscription.push(&event("linux"));
+        writer.room = usize::MAX;
+        assert!(subscription.flush(&mut writer).unwrap());
+
+        let written = String::from_utf8(writer.written).unwrap();
+        let lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            CommandCenter::write_event(3, event("win10"))
+                .unwrap()
+                .trim_end()
+        );
+        assert_eq!(
+            lines[1],
+            CommandCenter::write_event(4, event("linux"))
+                .unwrap()
+                .trim_end()
+        );
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let mut subscription = Subscription::new(3, vec![], vec![EventKind::Loaded]);
+        subscription.push(&VmEvent {
+            kind: EventKind::Unloaded,
+            name: "filtered".to_string(),
+            state: None,
+            message: None,
+        });
+        for idx in 0..MAX_QUEUED_EVENTS + 2 {
+            subscription.push(&event(&idx.to_string()));
+        }
+
+        let mut writer = SlowWriter {
+            written: vec![],
+            room: 10,
+        };
+        assert!(!subscription.flush(&mut writer).unwrap());
+        writer.room = usize::MAX;
+        assert!(subscription.flush(&mut writer).unwrap());
+        assert!(subscription.is_drained());
+
+        let written = String::from_utf8(writer.written).unwrap();
+        let messages = written
+            .lines()
+            .map(|x| CommandCenter::read_event(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), MAX_QUEUED_EVENTS + 1);
+        assert!(messages.iter().all(|(id, _)| *id == 3));
+        assert!(matches!(messages[0].1, EventMessage::Lost(2)));
+        assert!(matches!(&messages[1].1, EventMessage::Event(x) if x.name == "2"));
+    }
+}
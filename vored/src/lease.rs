@@ -0,0 +1,369 @@
+// Lease on a file on storage shared between the vored's that can manage the same VMs
+//
+// The vored holding it renews it every few seconds, a vored started with --standby waits for
+// the holder to stop renewing it (or for its process to be gone, when it ran on the same host)
+// before it takes over the running VMs
+//
+// Renewing happens on a thread of its own, the daemon loop blocks on hooks and QEMU for longer
+// than the lease lasts, which would let a standby take over machines that are still being started
+//
+// Reading and writing the lease happens under a flock on a lock file next to it, so two vored's can't
+// both see it free and take it. On NFS this relies on the client mapping flock to NFS locks, which
+// Linux does, other network filesystems may not lock across hosts at all
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vore_core::utils::hostname;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaseHolder {
+    pub host: String,
+    pub pid: u32,
+    /// Unix timestamp of the last renewal
+    pub renewed: u64,
+}
+
+impl LeaseHolder {
+    fn is_us(&self) -> bool {
+        self.host == hostname() && self.pid == std::process::id()
+    }
+
+    /// If the holder should still be considered alive
+    fn is_alive(&self, timeout: Duration) -> bool {
+        if self.host == hostname() && !Path::new(&format!("/proc/{}", self.pid)).exists() {
+            return false;
+        }
+
+        unix_now().saturating_sub(self.renewed) < timeout.as_secs()
+    }
+}
+
+impl std::fmt::Display for LeaseHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "vored {} on {}", self.pid, self.host)
+    }
+}
+
+#[derive(Debug)]
+pub struct Lease {
+    path: PathBuf,
+    timeout: Duration,
+    renewed: Instant,
+}
+
+impl Lease {
+    /// Who holds the lease at [path], None if nobody ever took it or the last holder gave it up
+    pub fn holder<P: AsRef<Path>>(path: P) -> Result<Option<LeaseHolder>, anyhow::Error> {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read lease {:?}", path))
+            }
+        };
+
+        let value: Value = serde_json::from_str(&contents)
+            .with_context(|| format!("Lease {:?} is corrupt", path))?;
+        let holder = (|| {
+            Some(LeaseHolder {
+                host: value.get("host")?.as_str()?.to_string(),
+                pid: value.get("pid")?.as_u64()? as u32,
+                renewed: value.get("renewed")?.as_u64()?,
+            })
+        })();
+
+        holder
+            .map(Some)
+            .with_context(|| format!("Lease {:?} is corrupt", path))
+    }
+
+    /// The holder of the lease at [path] if it's still alive
+    pub fn live_holder<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+    ) -> Result<Option<LeaseHolder>, anyhow::Error> {
+        Ok(Lease::holder(path)?.filter(|x| !x.is_us() && x.is_alive(timeout)))
+    }
+
+    /// Take the lease at [path], fails if another vored holds it and is still alive
+    pub fn acquire<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Lease, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+        let _lock = lock(&path)?;
+        if let Some(holder) = Lease::live_holder(&path, timeout)? {
+            anyhow::bail!("Lease {:?} is held by {}", path, holder);
+        }
+
+        let mut lease = Lease {
+            path,
+            timeout,
+            renewed: Instant::now(),
+        };
+        lease.write()?;
+        Ok(lease)
+    }
+
+    fn write(&mut self) -> Result<(), anyhow::Error> {
+        let contents = json!({
+            "host": hostname(),
+            "pid": std::process::id(),
+            "renewed": unix_now(),
+        });
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&tmp, contents.to_string())
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to move {:?} to {:?}", tmp, self.path))?;
+        self.renewed = Instant::now();
+        Ok(())
+    }
+
+    /// When the lease should be renewed next
+    pub fn next_renew(&self) -> Instant {
+        self.renewed + self.timeout / 3
+    }
+
+    /// Renew the lease if it's time to, fails if another vored took it over in the meantime
+    pub fn renew(&mut self) -> Result<(), anyhow::Error> {
+        if Instant::now() < self.next_renew() {
+            return Ok(());
+        }
+
+        let _lock = lock(&self.path)?;
+        match Lease::holder(&self.path)? {
+            Some(holder) if !holder.is_us() => {
+                anyhow::bail!("Lease {:?} was taken over by {}", self.path, holder)
+            }
+            _ => self.write(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Give it up right away, so a standby doesn't have to wait for it to expire
+        let _lock = lock(&self.path);
+        if let Ok(Some(holder)) = Lease::holder(&self.path) {
+            if holder.is_us() {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RenewerState {
+    stopped: bool,
+    /// Why the lease was lost, once it is
+    lost: Option<String>,
+}
+
+/// Keeps renewing a [Lease] on its own thread, until it's dropped or the lease is lost
+#[derive(Debug)]
+pub struct LeaseRenewer {
+    path: PathBuf,
+    state: Arc<(Mutex<RenewerState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LeaseRenewer {
+    /// Renew [lease] from a new thread, which calls [on_lost] if another vored takes it over
+    pub fn spawn<F: FnOnce() + Send + 'static>(
+        mut lease: Lease,
+        on_lost: F,
+    ) -> Result<LeaseRenewer, anyhow::Error> {
+        let path = lease.path().to_path_buf();
+        let state = Arc::new((Mutex::new(RenewerState::default()), Condvar::new()));
+        let thread_state = state.clone();
+        let thread = std::thread::Builder::new()
+            .name("lease".to_string())
+            .spawn(move || {
+                let (lock, condvar) = &*thread_state;
+                loop {
+                    let mut state = lock.lock().unwrap();
+                    while !state.stopped && Instant::now() < lease.next_renew() {
+                        let wait = lease.next_renew().saturating_duration_since(Instant::now());
+                        state = condvar.wait_timeout(state, wait).unwrap().0;
+                    }
+
+                    if state.stopped {
+                        break;
+                    }
+
+                    drop(state);
+                    if let Err(err) = lease.renew() {
+                        lock.lock().unwrap().lost = Some(format!("{:?}", err));
+                        on_lost();
+                        break;
+                    }
+                }
+
+                // Dropping the lease gives it up, if it's still ours
+                drop(lease);
+            })
+            .context("Failed to start lease thread")?;
+
+        Ok(LeaseRenewer {
+            path,
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Why the lease was lost, None while it's still held
+    pub fn lost(&self) -> Option<String> {
+        self.state.0.lock().unwrap().lost.clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LeaseRenewer {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().stopped = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Lock the lease at [path] against other vored's, until the returned file is dropped
+fn lock(path: &Path) -> Result<File, anyhow::Error> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock {:?}", lock_path))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to lock {:?}", lock_path));
+    }
+
+    Ok(file)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lease::{lock, unix_now, Lease, LeaseRenewer};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use vore_core::utils::hostname;
+
+    #[test]
+    fn test_lease() {
+        let dir = std::env::temp_dir().join(format!("vore-lease-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vored.lease");
+        let timeout = Duration::from_secs(15);
+
+        let lease = Lease::acquire(&path, timeout).unwrap();
+        assert_eq!(
+            Lease::holder(&path).unwrap().unwrap().pid,
+            std::process::id()
+        );
+        assert_eq!(Lease::live_holder(&path, timeout).unwrap(), None);
+        drop(lease);
+        assert!(!path.exists());
+
+        // Another host that renewed just now
+        let peer =
+            json!({ "host": format!("{}-peer", hostname()), "pid": 1, "renewed": unix_now() });
+        std::fs::write(&path, peer.to_string()).unwrap();
+        assert!(Lease::acquire(&path, timeout).is_err());
+
+        // Which stopped renewing it
+        let peer =
+            json!({ "host": format!("{}-peer", hostname()), "pid": 1, "renewed": unix_now() - 20 });
+        std::fs::write(&path, peer.to_string()).unwrap();
+        let lease = Lease::acquire(&path, timeout).unwrap();
+        drop(lease);
+
+        // A vored on this host that's gone
+        let dead = json!({ "host": hostname(), "pid": u32::MAX, "renewed": unix_now() });
+        std::fs::write(&path, dead.to_string()).unwrap();
+        let lease = Lease::acquire(&path, timeout).unwrap();
+        drop(lease);
+
+        // Taking it waits for a vored that's busy checking or renewing it
+        let locked = lock(&path).unwrap();
+        let taking = {
+            let path = path.clone();
+            std::thread::spawn(move || Lease::acquire(&path, timeout))
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!taking.is_finished());
+        drop(locked);
+        drop(taking.join().unwrap().unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_renewer() {
+        let dir = std::env::temp_dir().join(format!("vore-renewer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vored.lease");
+        let timeout = Duration::from_secs(1);
+
+        // Keeps renewing without anyone asking it to
+        let renewer = LeaseRenewer::spawn(Lease::acquire(&path, timeout).unwrap(), || {}).unwrap();
+        let renewed = Lease::holder(&path).unwrap().unwrap().renewed;
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(Lease::holder(&path).unwrap().unwrap().renewed > renewed);
+        assert_eq!(renewer.lost(), None);
+        drop(renewer);
+        assert!(!path.exists());
+
+        // Another vored took it over
+        let lost = Arc::new(AtomicBool::new(false));
+        let renewer = {
+            let lost = lost.clone();
+            LeaseRenewer::spawn(Lease::acquire(&path, timeout).unwrap(), move || {
+                lost.store(true, Ordering::SeqCst)
+            })
+            .unwrap()
+        };
+        let peer =
+            json!({ "host": format!("{}-peer", hostname()), "pid": 1, "renewed": unix_now() });
+        std::fs::write(&path, peer.to_string()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !lost.load(Ordering::SeqCst) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        assert!(renewer.lost().is_some());
+        drop(renewer);
+        // It's the peer's, so it stays
+        assert!(path.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
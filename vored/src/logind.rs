@@ -0,0 +1,158 @@
+// Host shutdowns and reboots announced by logind, so the VMs can be shut down before the host goes away
+//
+// Instead of talking D-Bus ourselves, busctl monitors for PrepareForShutdown, and systemd-inhibit holds
+// the delay lock that makes logind wait for us. logind only waits up to InhibitDelayMaxSec (5 seconds by
+// default) for the lock to be released, so that has to be raised to give the VMs time to shut down
+
+use anyhow::Context;
+use serde_json::Value;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+const MATCH: &str = "type='signal',sender='org.freedesktop.login1',interface='org.freedesktop.login1.Manager',member='PrepareForShutdown'";
+
+#[derive(Debug)]
+pub struct Logind {
+    monitor: Child,
+    stdout: ChildStdout,
+    buffer: Vec<u8>,
+    inhibitor: Option<Child>,
+}
+
+/// Let [command] die with vored, KillMode=process would leave it behind otherwise
+fn kill_with_parent(command: &mut Command) -> &mut Command {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Logind {
+    /// Start monitoring for PrepareForShutdown
+    pub fn monitor() -> Result<Logind, anyhow::Error> {
+        let mut monitor = kill_with_parent(
+            Command::new("busctl")
+                .args(&["--system", "--json=short"])
+                .arg(format!("--match={}", MATCH))
+                .arg("monitor")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        .spawn()
+        .context("Failed to start busctl to monitor logind")?;
+
+        let stdout = monitor.stdout.take().unwrap();
+        unsafe {
+            let flags = libc::fcntl(stdout.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(stdout.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+
+        Ok(Logind {
+            monitor,
+            stdout,
+            buffer: vec![],
+            inhibitor: None,
+        })
+    }
+
+    pub fn stream(&self) -> &ChildStdout {
+        &self.stdout
+    }
+
+    /// Take the delay lock, if it isn't held already
+    pub fn inhibit(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(inhibitor) = self.inhibitor.as_mut() {
+            if inhibitor.try_wait()?.is_none() {
+                return Ok(());
+            }
+        }
+
+        // The lock is held as long as cat runs, which is until its stdin is closed (by us, or when vored dies)
+        let inhibitor = Command::new("systemd-inhibit")
+            .args(&[
+                "--what=shutdown",
+                "--mode=delay",
+                "--who=vore",
+                "--why=Shutting down the VMs",
+                "cat",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("Failed to take a shutdown delay lock with systemd-inhibit")?;
+        self.inhibitor = Some(inhibitor);
+        Ok(())
+    }
+
+    /// Release the delay lock, which lets the shutdown continue
+    pub fn release(&mut self) {
+        if let Some(mut inhibitor) = self.inhibitor.take() {
+            drop(inhibitor.stdin.take());
+            let _ = inhibitor.wait();
+        }
+    }
+
+    pub fn is_inhibiting(&self) -> bool {
+        self.inhibitor.is_some()
+    }
+
+    /// The PrepareForShutdown signals that came in, true when a shutdown starts, false when it's cancelled
+    ///
+    /// The second value is false once busctl went away
+    pub fn read_signals(&mut self) -> Result<(Vec<bool>, bool), anyhow::Error> {
+        let mut open = true;
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stdout.read(&mut buffer) {
+                Ok(0) => {
+                    open = false;
+                    break;
+                }
+                Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut signals = vec![];
+        while let Some(idx) = self.buffer.iter().position(|x| *x == b'\n') {
+            let line = self.buffer.drain(..=idx).collect::<Vec<_>>();
+            let message = match serde_json::from_slice::<Value>(&line) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+
+            if message.get("member").and_then(|x| x.as_str()) != Some("PrepareForShutdown") {
+                continue;
+            }
+
+            if let Some(active) = message.pointer("/payload/data/0").and_then(|x| x.as_bool()) {
+                signals.push(active);
+            }
+        }
+
+        if !open {
+            let _ = self.monitor.wait();
+        }
+
+        Ok((signals, open))
+    }
+}
+
+impl Drop for Logind {
+    fn drop(&mut self) {
+        self.release();
+        let _ = self.monitor.kill();
+        let _ = self.monitor.wait();
+    }
+}
@@ -1,11 +1,22 @@
 use crate::daemon::Daemon;
 use vore_core::init_logging;
 
+mod command_queue;
+mod compat;
 mod daemon;
+mod event_targets;
+mod events;
+mod lease;
+mod logind;
+mod metrics;
+mod notify;
+mod self_check;
+mod slots;
 
 fn main() {
     init_logging();
 
-    let mut daemon = Daemon::new().unwrap();
+    let standby = std::env::args().skip(1).any(|x| x == "--standby");
+    let mut daemon = Daemon::new(standby).unwrap();
     daemon.run().unwrap();
 }
@@ -0,0 +1,418 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use vore_core::{VirtualMachineState, VirtualMachineStats};
+
+/// Largest request a scrape is allowed to send, it's answered with what came in by then
+const MAX_REQUEST: usize = 8192;
+
+/// How long a scraper gets to send its request before the connection is dropped
+pub const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const STATES: &[VirtualMachineState] = &[
+    VirtualMachineState::Loaded,
+    VirtualMachineState::Prepared,
+    VirtualMachineState::Stopped,
+    VirtualMachineState::Paused,
+    VirtualMachineState::Running,
+];
+
+/// Name, help text and getter of a metric with a series per device
+type DeviceMetric<T, V = u64> = (&'static str, &'static str, fn(&T) -> V);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the given machines as prometheus text exposition format
+pub fn render(machines: &[(String, VirtualMachineState, VirtualMachineStats)]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP vore_vm_state Current state of the VM");
+    let _ = writeln!(out, "# TYPE vore_vm_state gauge");
+    for (name, state, _) in machines {
+        for option in STATES {
+            let _ = writeln!(
+                out,
+                "vore_vm_state{{vm=\"{}\",state=\"{}\"}} {}",
+                escape(name),
+                option,
+                (option == state) as u8
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_uptime_seconds Seconds since the VM was started"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_uptime_seconds gauge");
+    for (name, _, stats) in machines {
+        if let Some(uptime) = stats.uptime {
+            let _ = writeln!(
+                out,
+                "vore_vm_uptime_seconds{{vm=\"{}\"}} {}",
+                escape(name),
+                uptime
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_vcpu_seconds_total CPU time used by a vCPU thread"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_vcpu_seconds_total counter");
+    for (name, _, stats) in machines {
+        for (vcpu, time) in stats.vcpu_time.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "vore_vm_vcpu_seconds_total{{vm=\"{}\",vcpu=\"{}\"}} {:.3}",
+                escape(name),
+                vcpu,
+                *time as f64 / 1000.0
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_rss_bytes Resident memory of the QEMU process"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_rss_bytes gauge");
+    for (name, _, stats) in machines {
+        if stats.rss > 0 {
+            let _ = writeln!(
+                out,
+                "vore_vm_rss_bytes{{vm=\"{}\"}} {}",
+                escape(name),
+                stats.rss
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_balloon_bytes Current size of the memory balloon"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_balloon_bytes gauge");
+    for (name, _, stats) in machines {
+        if let Some(balloon) = stats.balloon {
+            let _ = writeln!(
+                out,
+                "vore_vm_balloon_bytes{{vm=\"{}\"}} {}",
+                escape(name),
+                balloon
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_looking_glass_host_running If the Looking Glass host application in the guest runs"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_looking_glass_host_running gauge");
+    for (name, _, stats) in machines {
+        if let Some(looking_glass) = &stats.looking_glass {
+            let _ = writeln!(
+                out,
+                "vore_vm_looking_glass_host_running{{vm=\"{}\"}} {}",
+                escape(name),
+                looking_glass.host_running as u8
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vore_vm_looking_glass_clients Processes that mapped the Looking Glass shared memory"
+    );
+    let _ = writeln!(out, "# TYPE vore_vm_looking_glass_clients gauge");
+    for (name, _, stats) in machines {
+        if let Some(looking_glass) = &stats.looking_glass {
+            let _ = writeln!(
+                out,
+                "vore_vm_looking_glass_clients{{vm=\"{}\"}} {}",
+                escape(name),
+                looking_glass.clients.len()
+            );
+        }
+    }
+
+    let block_metrics: &[DeviceMetric<vore_core::BlockStats>] = &[
+        (
+            "vore_vm_block_read_bytes_total",
+            "Bytes read from a block device",
+            |x| x.rd_bytes,
+        ),
+        (
+            "vore_vm_block_written_bytes_total",
+            "Bytes written to a block device",
+            |x| x.wr_bytes,
+        ),
+        (
+            "vore_vm_block_read_operations_total",
+            "Read operations on a block device",
+            |x| x.rd_operations,
+        ),
+        (
+            "vore_vm_block_write_operations_total",
+            "Write operations on a block device",
+            |x| x.wr_operations,
+        ),
+    ];
+
+    for (metric, help, getter) in block_metrics {
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} counter", metric);
+        for (name, _, stats) in machines {
+            for block in &stats.block {
+                let _ = writeln!(
+                    out,
+                    "{}{{vm=\"{}\",device=\"{}\"}} {}",
+                    metric,
+                    escape(name),
+                    escape(&block.device),
+                    getter(block)
+                );
+            }
+        }
+    }
+
+    let disk_metrics: &[DeviceMetric<vore_core::DiskInfo, Option<u64>>] = &[
+        (
+            "vore_vm_disk_virtual_bytes",
+            "Size of a disk as the guest sees it",
+            |x| Some(x.virtual_size),
+        ),
+        (
+            "vore_vm_disk_allocated_bytes",
+            "Space the image of a disk takes up on the host",
+            |x| x.allocated,
+        ),
+    ];
+
+    for (metric, help, getter) in disk_metrics {
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} gauge", metric);
+        for (name, _, stats) in machines {
+            for disk in &stats.disks {
+                if let Some(value) = getter(disk) {
+                    let _ = writeln!(
+                        out,
+                        "{}{{vm=\"{}\",path=\"{}\"}} {}",
+                        metric,
+                        escape(name),
+                        escape(&disk.path),
+                        value
+                    );
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// A scrape connection, read and answered as the poller says it's ready, so a slow scraper can't
+/// hold up the daemon
+#[derive(Debug)]
+pub struct Scrape {
+    pub stream: TcpStream,
+    pub accepted: Instant,
+    request: Vec<u8>,
+    /// The answer, and how much of it is written, once the request is in
+    response: Option<(Vec<u8>, usize)>,
+}
+
+impl Scrape {
+    pub fn new(stream: TcpStream) -> Result<Scrape, io::Error> {
+        stream.set_nonblocking(true)?;
+        Ok(Scrape {
+            stream,
+            accepted: Instant::now(),
+            request: vec![],
+            response: None,
+        })
+    }
+
+    /// Read what's there of the request, true once all of it is in
+    pub fn read_request(&mut self) -> Result<bool, io::Error> {
+        let mut buffer = [0u8; 1024];
+        while !self.request.windows(4).any(|x| x == b"\r\n\r\n") && self.request.len() < MAX_REQUEST
+        {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(amount) => self.request.extend_from_slice(&buffer[..amount]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.response.is_some()
+    }
+
+    /// Answer the request, with the metrics from [body] if it asked for them
+    pub fn answer<F: FnOnce() -> String>(&mut self, body: F) {
+        self.response = Some((response(&self.request, body), 0));
+    }
+
+    /// Write what the socket takes of the answer, true once all of it is written
+    pub fn write_response(&mut self) -> Result<bool, io::Error> {
+        let (response, written) = match &mut self.response {
+            Some(response) => response,
+            None => return Ok(false),
+        };
+
+        while *written < response.len() {
+            match self.stream.write(&response[*written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(amount) => *written += amount,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// The HTTP answer to [request], with the metrics from [body] if it asked for /metrics
+fn response<F: FnOnce() -> String>(request: &[u8], body: F) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    if request.split_whitespace().nth(1) != Some("/metrics") {
+        return b"HTTP/1.0 404 Not Found\r\nConnection: close\r\n\r\n".to_vec();
+    }
+
+    let body = body();
+    format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{render, response, Scrape};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+    use vore_core::{BlockStats, LookingGlassInfo, VirtualMachineState, VirtualMachineStats};
+
+    #[test]
+    fn test_render() {
+        let stats = VirtualMachineStats {
+            uptime: Some(120),
+            vcpu_time: vec![1500, 250],
+            rss: 4096,
+            balloon: None,
+            block: vec![BlockStats {
+                device: "disk0".to_string(),
+                rd_bytes: 512,
+                ..Default::default()
+            }],
+            looking_glass: Some(LookingGlassInfo {
+                host_running: true,
+                clients: vec![1, 2],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let out = render(&[
+            ("win\"10\"".to_string(), VirtualMachineState::Running, stats),
+            (
+                "linux".to_string(),
+                VirtualMachineState::Stopped,
+                Default::default(),
+            ),
+        ]);
+        let lines = out.lines().collect::<Vec<_>>();
+
+        for expected in &[
+            "# TYPE vore_vm_state gauge",
+            "vore_vm_state{vm=\"win\\\"10\\\"\",state=\"running\"} 1",
+            "vore_vm_state{vm=\"win\\\"10\\\"\",state=\"stopped\"} 0",
+            "vore_vm_state{vm=\"linux\",state=\"stopped\"} 1",
+            "vore_vm_uptime_seconds{vm=\"win\\\"10\\\"\"} 120",
+            "vore_vm_vcpu_seconds_total{vm=\"win\\\"10\\\"\",vcpu=\"0\"} 1.500",
+            "vore_vm_vcpu_seconds_total{vm=\"win\\\"10\\\"\",vcpu=\"1\"} 0.250",
+            "vore_vm_rss_bytes{vm=\"win\\\"10\\\"\"} 4096",
+            "vore_vm_looking_glass_host_running{vm=\"win\\\"10\\\"\"} 1",
+            "vore_vm_looking_glass_clients{vm=\"win\\\"10\\\"\"} 2",
+            "# TYPE vore_vm_block_read_bytes_total counter",
+            "vore_vm_block_read_bytes_total{vm=\"win\\\"10\\\"\",device=\"disk0\"} 512",
+        ] {
+            assert!(
+                lines.contains(expected),
+                "{} missing from\n{}",
+                expected,
+                out
+            );
+        }
+
+        // Stopped machines and missing devices don't get a series
+        assert!(!out.contains("vore_vm_uptime_seconds{vm=\"linux\"}"));
+        assert!(!out.contains("vore_vm_rss_bytes{vm=\"linux\"}"));
+        assert!(!out.contains("vore_vm_balloon_bytes{"));
+    }
+
+    #[test]
+    fn test_response() {
+        let ok = String::from_utf8(response(
+            b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            || "vore_vm_state 1\n".to_string(),
+        ))
+        .unwrap();
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"), "{}", ok);
+        assert!(ok.contains("Content-Length: 16\r\n"), "{}", ok);
+        assert!(ok.ends_with("\r\n\r\nvore_vm_state 1\n"), "{}", ok);
+
+        let not_found = response(b"GET / HTTP/1.1\r\n\r\n", || unreachable!());
+        assert!(not_found.starts_with(b"HTTP/1.0 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_scrape_in_parts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut scrape = Scrape::new(listener.accept().unwrap().0).unwrap();
+
+        // Nothing sent yet, that doesn't block
+        assert!(!scrape.read_request().unwrap());
+        client.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        client.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!scrape.read_request().unwrap());
+        assert!(!scrape.write_response().unwrap());
+
+        client.write_all(b"\r\n").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !scrape.read_request().unwrap() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        scrape.answer(|| "vore_vm_state 1\n".to_string());
+        assert!(scrape.is_answered());
+        assert!(scrape.write_response().unwrap());
+        drop(scrape);
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.0 200 OK\r\n"), "{}", answer);
+        assert!(answer.ends_with("vore_vm_state 1\n"), "{}", answer);
+    }
+}
@@ -0,0 +1,156 @@
+// Minimal sd_notify(3), so vored can run as a Type=notify service with a watchdog
+//
+// Everything here is a no-op when vored isn't started by systemd (NOTIFY_SOCKET isn't set)
+
+use libc::{c_void, sa_family_t, sockaddr, sockaddr_un, socklen_t, AF_UNIX, MSG_NOSIGNAL};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+use std::{io, mem};
+
+#[derive(Debug)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, String)>,
+    watchdog_interval: Option<Duration>,
+    last_ping: Instant,
+    last_status: String,
+}
+
+impl Notifier {
+    /// Picks up the notify socket and watchdog settings from the environment, and removes them from it
+    /// so they aren't passed on to QEMU or hooks
+    pub fn from_env() -> Notifier {
+        let mut notifier = Notifier {
+            socket: None,
+            watchdog_interval: None,
+            last_ping: Instant::now(),
+            last_status: String::new(),
+        };
+
+        let path = if let Some(path) = std::env::var_os("NOTIFY_SOCKET") {
+            path
+        } else {
+            return notifier;
+        };
+
+        let path = path.to_string_lossy().to_string();
+        match socket_addr(&path).and_then(|_| open_socket()) {
+            Ok(socket) => notifier.socket = Some((socket, path)),
+            Err(err) => log::warn!("Failed to open systemd notify socket {:?}: {:?}", path, err),
+        }
+
+        // The watchdog is meant for us only if WATCHDOG_PID is our pid (or not given)
+        let for_us = std::env::var("WATCHDOG_PID")
+            .map_or(true, |pid| pid.parse::<u32>() == Ok(std::process::id()));
+        if let Some(usec) = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok())
+            .filter(|_| for_us)
+        {
+            // Ping twice per timeout, as systemd recommends
+            notifier.watchdog_interval = Some(Duration::from_micros(usec / 2));
+        }
+
+        std::env::remove_var("NOTIFY_SOCKET");
+        std::env::remove_var("WATCHDOG_USEC");
+        std::env::remove_var("WATCHDOG_PID");
+
+        notifier
+    }
+
+    fn send(&self, message: &str) {
+        let (socket, path) = if let Some(socket) = &self.socket {
+            socket
+        } else {
+            return;
+        };
+
+        // Already validated when opening the socket
+        let (addr, addr_len) = socket_addr(path).unwrap();
+        let res = unsafe {
+            libc::sendto(
+                socket.as_raw_fd(),
+                message.as_ptr() as *const c_void,
+                message.len(),
+                MSG_NOSIGNAL,
+                &addr as *const sockaddr_un as *const sockaddr,
+                addr_len,
+            )
+        };
+
+        if res < 0 {
+            log::warn!(
+                "Failed to notify systemd ({}): {:?}",
+                message.trim_end(),
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    pub fn ready(&mut self) {
+        self.send("READY=1\n");
+        self.last_ping = Instant::now();
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1\n");
+    }
+
+    /// Update the status line shown by systemctl status, only sent if it changed
+    pub fn status(&mut self, status: String) {
+        if self.socket.is_none() || status == self.last_status {
+            return;
+        }
+
+        self.send(&format!("STATUS={}\n", status));
+        self.last_status = status;
+    }
+
+    /// How long systemd lets vored go without a ping, if the watchdog is enabled
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog_interval.map(|x| x * 2)
+    }
+
+    /// When the next watchdog ping is due, if the watchdog is enabled
+    pub fn next_ping(&self) -> Option<Instant> {
+        self.watchdog_interval.map(|x| self.last_ping + x)
+    }
+
+    /// Ping the watchdog if it's due
+    pub fn watchdog(&mut self) {
+        if self.next_ping().map_or(false, |x| x <= Instant::now()) {
+            self.send("WATCHDOG=1\n");
+            self.last_ping = Instant::now();
+        }
+    }
+}
+
+fn socket_addr(path: &str) -> Result<(sockaddr_un, socklen_t), io::Error> {
+    let mut addr: sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = AF_UNIX as sa_family_t;
+
+    // A leading @ means an abstract socket, which starts with a null byte instead
+    let bytes = path.as_bytes();
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid socket path",
+        ));
+    }
+
+    for (i, byte) in bytes.iter().enumerate() {
+        addr.sun_path[i] = if i == 0 && *byte == b'@' { 0 } else { *byte } as libc::c_char;
+    }
+
+    let addr_len = (mem::size_of::<sa_family_t>() + bytes.len()) as socklen_t;
+    Ok((addr, addr_len))
+}
+
+fn open_socket() -> Result<UnixDatagram, io::Error> {
+    let fd = unsafe { libc::socket(AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { UnixDatagram::from_raw_fd(fd) })
+}
@@ -0,0 +1,80 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use vore_core::{host_checks, GlobalConfig, QemuCommandBuilder};
+
+/// Check if the host and global config are usable, and log a summary of it
+///
+/// Problems with the global config are returned as error, since no VM will start with them,
+/// missing host features and UEFI firmware are only warned about since not every VM needs them
+pub fn self_check(global_config: &mut GlobalConfig) -> Result<(), anyhow::Error> {
+    log::info!(
+        "vore daemon {} starting",
+        option_env!("CARGO_PKG_VERSION").unwrap_or("<unknown>")
+    );
+
+    for check in host_checks() {
+        if check.ok {
+            log::info!("{}: {}", check.name, check.message);
+        } else {
+            log::warn!("{}: {}", check.name, check.message);
+        }
+    }
+
+    let presets = QemuCommandBuilder::new(global_config, PathBuf::from("/dev/empty"))
+        .and_then(|builder| builder.list_presets())
+        .map_err(|err| {
+            anyhow::anyhow!(
+                "Lua script ({}) failed to load, fix it before starting vored: {:?}",
+                global_config.qemu.script,
+                err
+            )
+        })?;
+    log::info!(
+        "Lua script: {} loaded ({} disk presets)",
+        global_config.qemu.script,
+        presets.len()
+    );
+
+    let mut firmwares = 0;
+    for (name, uefi) in &global_config.uefi {
+        let mut found = true;
+        for (kind, path) in &[("boot-code", &uefi.boot_code), ("template", &uefi.template)] {
+            if !Path::new(path).is_file() {
+                log::warn!(
+                    "Firmware file {} for uefi.{}.{} doesn't exist, VMs using it won't start until OVMF is installed or the path in the global config is fixed",
+                    path,
+                    name,
+                    kind
+                );
+                found = false;
+            }
+        }
+
+        if found {
+            firmwares += 1;
+        }
+    }
+    log::info!("UEFI: {} firmware(s) found", firmwares);
+
+    for (arch, path) in &global_config.qemu.binaries {
+        if !Path::new(path).is_file() {
+            anyhow::bail!(
+                "QEMU {} for qemu.binaries.{} doesn't exist, install it or fix the path in the global config",
+                path,
+                arch
+            );
+        }
+
+        log::info!("QEMU for {}: {}", arch, path);
+    }
+
+    let gid = global_config.vore.get_gid().context(
+        "Socket group can't be resolved, create it or change vore.group in the global config",
+    )?;
+    match gid {
+        Some(gid) => log::info!("Socket group: {}", gid),
+        None => log::info!("Socket group: none, only root will be able to use vore"),
+    }
+
+    Ok(())
+}
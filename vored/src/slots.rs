@@ -0,0 +1,137 @@
+// Storage with reusable slots, used for the RPC connections of the daemon
+//
+// Every slot counts how often it was freed, a SlotId remembers the generation it was handed out
+// in, so anything still holding on to an id after its slot was reused (an event or a queued command
+// of a closed connection) can't reach the new occupant
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SlotId {
+    pub index: usize,
+    pub generation: u64,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u64,
+    value: Option<T>,
+}
+
+#[derive(Debug)]
+pub struct Slots<T> {
+    slots: Vec<Slot<T>>,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Slots { slots: vec![] }
+    }
+}
+
+impl<T> Slots<T> {
+    /// Store [value] in the first free slot
+    pub fn insert(&mut self, value: T) -> SlotId {
+        if let Some(index) = self.slots.iter().position(|x| x.value.is_none()) {
+            let slot = &mut self.slots[index];
+            slot.value = Some(value);
+            return SlotId {
+                index,
+                generation: slot.generation,
+            };
+        }
+
+        self.slots.push(Slot {
+            generation: 0,
+            value: Some(value),
+        });
+        SlotId {
+            index: self.slots.len() - 1,
+            generation: 0,
+        }
+    }
+
+    pub fn get(&self, id: SlotId) -> Option<&T> {
+        self.slots
+            .get(id.index)
+            .filter(|x| x.generation == id.generation)
+            .and_then(|x| x.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: SlotId) -> Option<&mut T> {
+        self.slots
+            .get_mut(id.index)
+            .filter(|x| x.generation == id.generation)
+            .and_then(|x| x.value.as_mut())
+    }
+
+    pub fn contains(&self, id: SlotId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (SlotId, &mut T)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let generation = slot.generation;
+                slot.value
+                    .as_mut()
+                    .map(|value| (SlotId { index, generation }, value))
+            })
+    }
+
+    /// Free the slot of [id], ids handed out for it before won't match anything anymore
+    pub fn remove(&mut self, id: SlotId) -> Option<T> {
+        let slot = self
+            .slots
+            .get_mut(id.index)
+            .filter(|x| x.generation == id.generation)?;
+        let value = slot.value.take();
+        if value.is_some() {
+            slot.generation += 1;
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::slots::Slots;
+
+    #[test]
+    fn test_reused_slot_gets_new_generation() {
+        let mut slots = Slots::default();
+        let first = slots.insert("first");
+        assert_eq!(slots.remove(first), Some("first"));
+
+        let second = slots.insert("second");
+        assert_eq!(second.index, first.index);
+        assert_ne!(second.generation, first.generation);
+        assert_eq!(slots.get(first), None);
+        assert_eq!(slots.get(second), Some(&"second"));
+    }
+
+    #[test]
+    fn test_stale_id_cant_remove() {
+        let mut slots = Slots::default();
+        let first = slots.insert(1);
+        slots.remove(first);
+        let second = slots.insert(2);
+
+        assert_eq!(slots.remove(first), None);
+        assert!(slots.contains(second));
+    }
+
+    #[test]
+    fn test_free_slot_is_reused_first() {
+        let mut slots = Slots::default();
+        let a = slots.insert('a');
+        let b = slots.insert('b');
+        slots.remove(a);
+
+        let c = slots.insert('c');
+        assert_eq!(c.index, a.index);
+        assert_eq!(slots.get(b), Some(&'b'));
+        assert_eq!(slots.get_mut(c).map(|x| *x), Some('c'));
+    }
+}